@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, io};
+
+use crate::{
+    builtin::Output,
+    parser::{self, Operator, Token},
+    shell::Shell,
+    util::write_and_flush_str,
+};
+
+/// expand_aliases replaces unquoted words in command position that are alias names
+/// with the tokens of the alias value. If the value ends with a blank, the word after
+/// it is checked for an alias too. An alias isn't expanded again inside its own value.
+pub(crate) fn expand_aliases(
+    tokens: Vec<Token>,
+    aliases: &BTreeMap<String, String>,
+) -> Result<Vec<Token>, String> {
+    if aliases.is_empty() {
+        return Ok(tokens);
+    }
+
+    let mut expander = AliasExpander {
+        aliases,
+        active: Vec::new(),
+        tokens: Vec::new(),
+        redirect_target: false,
+    };
+    expander.expand(tokens, true)?;
+    Ok(expander.tokens)
+}
+
+struct AliasExpander<'a> {
+    aliases: &'a BTreeMap<String, String>,
+    /// Aliases being expanded, which aren't expanded again to prevent infinite recursion.
+    active: Vec<String>,
+    tokens: Vec<Token>,
+    /// Whether the next word is the target of a redirection.
+    redirect_target: bool,
+}
+
+impl AliasExpander<'_> {
+    /// expand appends the tokens with aliases expanded, returning whether the word that
+    /// follows them is in command position and should be checked for an alias.
+    fn expand(&mut self, tokens: Vec<Token>, mut check: bool) -> Result<bool, String> {
+        for token in tokens {
+            let word = match &token {
+                Token::Word(word) => word,
+                Token::Operator(op) => {
+                    check = !matches!(op, Operator::RParen);
+                    self.tokens.push(token);
+                    continue;
+                }
+                Token::Redirect { .. } => {
+                    self.redirect_target = true;
+                    self.tokens.push(token);
+                    continue;
+                }
+            };
+
+            if self.redirect_target {
+                self.redirect_target = false;
+                self.tokens.push(token);
+                continue;
+            }
+            let value = match self.aliases.get(word) {
+                Some(value) if check && !self.active.contains(word) => value,
+                _ => {
+                    check = check && keeps_command_position(word);
+                    self.tokens.push(token);
+                    continue;
+                }
+            };
+
+            self.active.push(word.clone());
+            let result = self.expand(parser::tokenize(value)?, true);
+            self.active.pop();
+            check = result? || value.ends_with([' ', '\t']);
+        }
+        Ok(check)
+    }
+}
+
+/// keeps_command_position returns whether the word after `word` is still in command
+/// position, as it is after assignments and the reserved words that start a command
+/// list. `(` is an operator, so it's handled with the others.
+fn keeps_command_position(word: &str) -> bool {
+    is_assignment(word)
+        || matches!(
+            word,
+            "!" | "{" | "if" | "then" | "elif" | "else" | "while" | "until" | "do"
+        )
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => parser::is_name(name),
+        None => false,
+    }
+}
+
+/// is_alias_name returns whether `name` can be defined as an alias, which excludes
+/// names with quotes, expansions, `/` or `=`.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || "/$`=\\'\"|&;()<>".contains(c))
+}
+
+/// format_alias formats the alias as the `alias` command that defines it.
+fn format_alias(name: &str, value: &str) -> String {
+    format!("alias {name}='{}'", value.replace('\'', r"'\''"))
+}
+
+/// alias defines or prints aliases.
+///  - `alias` or `alias -p` prints all aliases in a reusable form.
+///  - `alias name=value` defines an alias.
+///  - `alias name` prints the alias.
+pub(crate) fn alias<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let (print_all, args) = match args.split_first() {
+        Some((&"-p", rest)) => (true, rest),
+        Some((&"--", rest)) => (false, rest),
+        _ => (args.is_empty(), args),
+    };
+    if print_all {
+        for (name, value) in &shell.aliases {
+            write_and_flush_str(&mut w.out, &format_alias(name, value))?;
+        }
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) if is_alias_name(name) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            Some((name, _)) => {
+                write_and_flush_str(&mut w.err, &format!("alias: `{name}': invalid alias name"))?;
+                status = 1;
+            }
+            None => match shell.aliases.get(*arg) {
+                Some(value) => write_and_flush_str(&mut w.out, &format_alias(arg, value))?,
+                None => {
+                    write_and_flush_str(&mut w.err, &format!("alias: {arg}: not found"))?;
+                    status = 1;
+                }
+            },
+        }
+    }
+    Ok(status)
+}
+
+/// unalias removes the named aliases, or all of them with `-a`.
+pub(crate) fn unalias<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    match args.first() {
+        Some(&"-a") => {
+            shell.aliases.clear();
+            return Ok(0);
+        }
+        None => {
+            write_and_flush_str(&mut w.err, "unalias: usage: unalias [-a] name [name ...]")?;
+            return Ok(2);
+        }
+        _ => (),
+    }
+
+    let names = match args.first() {
+        Some(&"--") => &args[1..],
+        _ => args,
+    };
+    let mut status = 0;
+    for name in names {
+        if shell.aliases.remove(*name).is_none() {
+            write_and_flush_str(&mut w.err, &format!("unalias: {name}: not found"))?;
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod alias_test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        alias::expand_aliases,
+        parser::{tokenize, Token},
+    };
+
+    fn expand(input: &str, aliases: &[(&str, &str)]) -> String {
+        let aliases: BTreeMap<String, String> = aliases
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        expand_aliases(tokenize(input).unwrap(), &aliases)
+            .unwrap()
+            .iter()
+            .map(Token::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_command_position() {
+        let aliases = [("ll", "ls -l")];
+        assert_eq!(expand("ll src", &aliases), "ls -l src");
+        assert_eq!(expand("echo ll", &aliases), "echo ll");
+        assert_eq!(expand("'ll'; ll", &aliases), "'ll' ; ls -l");
+        assert_eq!(expand("true && FOO=1 ll", &aliases), "true && FOO=1 ls -l");
+        assert_eq!(expand("echo > ll", &aliases), "echo > ll");
+    }
+
+    #[test]
+    fn test_reserved_words() {
+        let aliases = [("ll", "ls -l")];
+        assert_eq!(
+            expand("while ll; do ll; done", &aliases),
+            "while ls -l ; do ls -l ; done"
+        );
+        assert_eq!(expand("! ll", &aliases), "! ls -l");
+        assert_eq!(expand("{ ll; }", &aliases), "{ ls -l ; }");
+        assert_eq!(expand("(ll)", &aliases), "( ls -l )");
+        assert_eq!(expand("done ll", &aliases), "done ll");
+    }
+
+    #[test]
+    fn test_recursion() {
+        let aliases = [("ls", "ls -F"), ("ll", "ls -l"), ("a", "b"), ("b", "a")];
+        assert_eq!(expand("ll", &aliases), "ls -F -l");
+        assert_eq!(expand("a", &aliases), "a");
+    }
+
+    #[test]
+    fn test_trailing_blank() {
+        let aliases = [("sudo", "sudo "), ("ll", "ls -l"), ("k", "kubectl")];
+        assert_eq!(expand("sudo ll", &aliases), "sudo ls -l");
+        assert_eq!(expand("k ll", &aliases), "kubectl ll");
+        assert_eq!(expand("sudo sudo ll", &aliases), "sudo sudo ls -l");
+    }
+}
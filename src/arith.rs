@@ -0,0 +1,588 @@
+use std::io;
+
+use crate::{builtin::Output, parser, shell::Shell, util::write_and_flush_str};
+
+/// How deeply parentheses can nest, counting the values of variables that are
+/// expressions themselves as parenthesized.
+const MAX_DEPTH: usize = 200;
+
+/// Operators, longest first so that each is lexed whole.
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^",
+    "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENT_OPERATORS: [&str; 11] = [
+    "=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+/// let evaluates each argument as an arithmetic expression. The status is 0 if the last
+/// one is not 0, and 1 otherwise.
+pub(crate) fn let_cmd<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    if args.is_empty() {
+        write_and_flush_str(&mut w.err, "let: expression expected")?;
+        return Ok(1);
+    }
+    let mut value = 0;
+    for arg in args {
+        value = match evaluate(shell, arg) {
+            Ok(value) => value,
+            Err(e) => {
+                write_and_flush_str(&mut w.err, &format!("let: {e}"))?;
+                return Ok(1);
+            }
+        };
+    }
+    Ok(i32::from(value == 0))
+}
+
+/// evaluate evaluates the arithmetic expression with 64-bit integers that wrap around
+/// on overflow. The operators are those of C with the same precedence, plus `**` for
+/// powers, and names without `$` are variables, which are 0 if unset or empty and
+/// otherwise evaluated as expressions themselves. Numbers are decimal, hexadecimal with
+/// `0x`, octal with a leading `0`, or in any base from 2 to 64 as `base#digits`.
+pub(crate) fn evaluate(shell: &mut Shell, expr: &str) -> Result<i64, String> {
+    evaluate_nested(shell, expr, 0)
+}
+
+fn evaluate_nested(shell: &mut Shell, expr: &str, depth: usize) -> Result<i64, String> {
+    let mut evaluator = Evaluator {
+        shell,
+        expr,
+        tokens: Vec::new(),
+        pos: 0,
+        skip: 0,
+        depth,
+    };
+    if depth > MAX_DEPTH {
+        return Err(evaluator.error("expression recursion level exceeded", 0));
+    }
+    evaluator.tokens = evaluator.tokenize()?;
+    if evaluator.tokens.is_empty() {
+        return Ok(0);
+    }
+
+    let value = evaluator.comma()?;
+    match evaluator.peek() {
+        None => Ok(value),
+        Some(Token::Operator(op)) if ASSIGNMENT_OPERATORS.contains(op) => {
+            Err(evaluator.error_here("attempted assignment to non-variable"))
+        }
+        Some(_) => Err(evaluator.error_here("syntax error in expression")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A number as written, which is only checked once it's used.
+    Number(String),
+    Name(String),
+    Operator(&'static str),
+}
+
+struct Evaluator<'a> {
+    shell: &'a mut Shell,
+    expr: &'a str,
+    /// The tokens with their byte offsets in the expression.
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// Whether the current operand won't be used, e.g. the right side of `0 && x`, in
+    /// which case nothing is assigned and division by zero isn't an error.
+    skip: usize,
+    /// How deeply the current operand is nested, see [`MAX_DEPTH`].
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn tokenize(&self) -> Result<Vec<(Token, usize)>, String> {
+        let mut tokens = Vec::new();
+        let mut idx = 0;
+        while let Some(ch) = self.expr[idx..].chars().next() {
+            if ch.is_whitespace() {
+                idx += ch.len_utf8();
+                continue;
+            }
+            let rest = &self.expr[idx..];
+            let word_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '#' | '@')))
+                .unwrap_or(rest.len());
+            let token = if ch.is_ascii_digit() {
+                Token::Number(rest[..word_len].to_string())
+            } else if ch.is_ascii_alphabetic() || ch == '_' {
+                let mut len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                // An element of an array, with its subscript
+                if let Some(end) = rest[len..]
+                    .strip_prefix('[')
+                    .and_then(parser::subscript_end)
+                {
+                    len += end + 2;
+                }
+                Token::Name(rest[..len].to_string())
+            } else {
+                match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                    Some(op) => Token::Operator(op),
+                    None => {
+                        return Err(self.error("syntax error: invalid arithmetic operator", idx))
+                    }
+                }
+            };
+            let len = match &token {
+                Token::Number(s) | Token::Name(s) => s.len(),
+                Token::Operator(op) => op.len(),
+            };
+            tokens.push((token, idx));
+            idx += len;
+        }
+        Ok(tokens)
+    }
+
+    /// error formats the error with the part of the expression from `offset` on, which
+    /// is where it went wrong.
+    fn error(&self, message: &str, offset: usize) -> String {
+        format!(
+            "{}: {message} (error token is \"{}\")",
+            self.expr,
+            &self.expr[offset..]
+        )
+    }
+
+    /// error_here is an error at the current token, or the last one at the end.
+    fn error_here(&self, message: &str) -> String {
+        let offset = match self
+            .tokens
+            .get(self.pos.min(self.tokens.len().saturating_sub(1)))
+        {
+            Some((_, offset)) => *offset,
+            None => 0,
+        };
+        self.error(message, offset)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.expr.len(), |(_, offset)| *offset)
+    }
+
+    fn expect(&mut self, op: &str, message: &str) -> Result<(), String> {
+        if self.peek_operator() != Some(op) {
+            return Err(self.error_here(message));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// skipping evaluates `f` without side effects if `skip` is set.
+    fn skipping<F>(&mut self, skip: bool, f: F) -> Result<i64, String>
+    where
+        F: FnOnce(&mut Self) -> Result<i64, String>,
+    {
+        self.skip += usize::from(skip);
+        let result = f(self);
+        self.skip -= usize::from(skip);
+        result
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assignment()?;
+        while self.peek_operator() == Some(",") {
+            self.pos += 1;
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, String> {
+        let (Some(Token::Name(name)), Some((Token::Operator(op), _))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        else {
+            return self.conditional();
+        };
+        if !ASSIGNMENT_OPERATORS.contains(op) {
+            return self.conditional();
+        }
+        let (name, op) = (name.clone(), *op);
+        self.pos += 2;
+        let offset = self.offset();
+        let right = self.assignment()?;
+        let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+            Some(op) => {
+                let left = self.variable(&name)?;
+                self.apply(left, op, right, offset)?
+            }
+            None => right,
+        };
+        self.assign(&name, value)?;
+        Ok(value)
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = self.binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.skipping(condition == 0, Self::comma)?;
+        self.expect(":", "`:' expected for conditional expression")?;
+        let otherwise = self.skipping(condition != 0, Self::conditional)?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    /// binary evaluates the operators binding at least as tightly as `min_precedence`.
+    fn binary(&mut self, min_precedence: usize) -> Result<i64, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.peek_operator() {
+            let Some(precedence) = precedence(op).filter(|p| *p >= min_precedence) else {
+                break;
+            };
+            self.pos += 1;
+            let offset = self.offset();
+            // `**` is right associative, the others left associative
+            let next = match op {
+                "**" => precedence,
+                _ => precedence + 1,
+            };
+            left = match op {
+                "&&" => {
+                    let right = self.skipping(left == 0, |e| e.binary(next))?;
+                    i64::from(left != 0 && right != 0)
+                }
+                "||" => {
+                    let right = self.skipping(left != 0, |e| e.binary(next))?;
+                    i64::from(left != 0 || right != 0)
+                }
+                _ => {
+                    let right = self.binary(next)?;
+                    self.apply(left, op, right, offset)?
+                }
+            };
+        }
+        Ok(left)
+    }
+
+    /// apply applies a binary operator, with `offset` where the right operand starts for
+    /// errors about it.
+    fn apply(&self, left: i64, op: &str, right: i64, offset: usize) -> Result<i64, String> {
+        let value = match op {
+            "/" | "%" if right == 0 => match self.skip {
+                0 => return Err(self.error("division by 0", offset)),
+                _ => 0,
+            },
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" => match u32::try_from(right) {
+                Ok(right) => left.wrapping_pow(right),
+                Err(_) if right < 0 => return Err(self.error("exponent less than 0", offset)),
+                // The exponent is too large for anything but these not to overflow
+                Err(_) => match left {
+                    0 | 1 => left,
+                    -1 => 1 - 2 * (right & 1),
+                    _ => 0,
+                },
+            },
+            "*" => left.wrapping_mul(right),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "<" => i64::from(left < right),
+            "<=" => i64::from(left <= right),
+            ">" => i64::from(left > right),
+            ">=" => i64::from(left >= right),
+            "==" => i64::from(left == right),
+            "!=" => i64::from(left != right),
+            "&" => left & right,
+            "^" => left ^ right,
+            "|" => left | right,
+            _ => unreachable!("not a binary operator: {op}"),
+        };
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let Some(op) = self.peek_operator() else {
+            return self.postfix();
+        };
+        match op {
+            "++" | "--" => {
+                if let Some((Token::Name(name), _)) = self.tokens.get(self.pos + 1) {
+                    let name = name.clone();
+                    self.pos += 2;
+                    let value = match op {
+                        "++" => self.variable(&name)?.wrapping_add(1),
+                        _ => self.variable(&name)?.wrapping_sub(1),
+                    };
+                    self.assign(&name, value)?;
+                    return Ok(value);
+                }
+                // Without a variable, these are two signs
+                self.pos += 1;
+                self.unary()
+            }
+            "-" | "+" | "!" | "~" => {
+                self.pos += 1;
+                let value = self.unary()?;
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "+" => value,
+                    "!" => i64::from(value == 0),
+                    _ => !value,
+                })
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<i64, String> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error_here("syntax error: operand expected"));
+        };
+        match token {
+            Token::Number(number) => {
+                let offset = self.offset();
+                self.pos += 1;
+                parse_number(&number).ok_or_else(|| self.error("value too great for base", offset))
+            }
+            Token::Name(name) => {
+                self.pos += 1;
+                let value = self.variable(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_operator() {
+                    self.pos += 1;
+                    let new = match op {
+                        "++" => value.wrapping_add(1),
+                        _ => value.wrapping_sub(1),
+                    };
+                    self.assign(&name, new)?;
+                }
+                Ok(value)
+            }
+            Token::Operator("(") => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(self.error_here("expression recursion level exceeded"));
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let value = self.comma();
+                self.depth -= 1;
+                let value = value?;
+                self.expect(")", "missing `)'")?;
+                Ok(value)
+            }
+            Token::Operator(_) => Err(self.error_here("syntax error: operand expected")),
+        }
+    }
+
+    /// variable returns the value of the variable, evaluating it if it's an expression.
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        if self.skip > 0 {
+            return Ok(0);
+        }
+        let value = match parser::split_subscript(name) {
+            Some((name, Some(subscript), "")) => {
+                self.shell.element(name, subscript)?.unwrap_or_default()
+            }
+            _ => self.shell.var(name).unwrap_or_default().to_string(),
+        };
+        match value.trim().parse() {
+            Ok(value) => Ok(value),
+            Err(_) => evaluate_nested(self.shell, &value, self.depth + 1),
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if self.skip > 0 {
+            return Ok(());
+        }
+        match parser::split_subscript(name) {
+            Some((name, subscript, "")) => {
+                self.shell
+                    .assign(name, subscript, &value.to_string(), false)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// precedence returns how tightly the binary operator binds, or None if it isn't one.
+fn precedence(op: &str) -> Option<usize> {
+    let precedence = match op {
+        "||" => 0,
+        "&&" => 1,
+        "|" => 2,
+        "^" => 3,
+        "&" => 4,
+        "==" | "!=" => 5,
+        "<" | "<=" | ">" | ">=" => 6,
+        "<<" | ">>" => 7,
+        "+" | "-" => 8,
+        "*" | "/" | "%" => 9,
+        "**" => 10,
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+/// parse_number parses a number in one of the bases of arithmetic expressions, or
+/// returns None if it has digits not in its base.
+fn parse_number(s: &str) -> Option<i64> {
+    let (base, digits) = if let Some((base, digits)) = s.split_once('#') {
+        (base.parse().ok().filter(|b| (2..=64).contains(b))?, digits)
+    } else if let Some(digits) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (16, digits)
+    } else if s.len() > 1 && s.starts_with('0') {
+        (8, &s[1..])
+    } else {
+        (10, s)
+    };
+    if base != 16 && digits.is_empty() {
+        return None;
+    }
+
+    digits.chars().try_fold(0i64, |value, ch| {
+        let digit = match ch {
+            '0'..='9' => ch as u32 - '0' as u32,
+            // Up to base 36, letters are digits whatever their case
+            'a'..='z' => ch as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => ch as u32 - 'A' as u32 + 10,
+            'A'..='Z' => ch as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        (digit < base).then(|| {
+            value
+                .wrapping_mul(i64::from(base))
+                .wrapping_add(i64::from(digit))
+        })
+    })
+}
+
+#[cfg(test)]
+mod arith_test {
+    use crate::{
+        arith::{evaluate, parse_number},
+        shell::Shell,
+    };
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x1F"), Some(31));
+        assert_eq!(parse_number("0x"), Some(0));
+        assert_eq!(parse_number("017"), Some(15));
+        assert_eq!(parse_number("2#101"), Some(5));
+        assert_eq!(parse_number("36#Zz"), Some(1295));
+        assert_eq!(parse_number("64#@_"), Some(4031));
+        assert_eq!(parse_number("09"), None);
+        assert_eq!(parse_number("2#12"), None);
+        assert_eq!(parse_number("65#1"), None);
+        assert_eq!(parse_number("12abc"), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut shell = Shell::new();
+        let mut eval = |expr: &str| evaluate(&mut shell, expr);
+        assert_eq!(eval(""), Ok(0));
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2"), Ok(5));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-7 / 2, -7 % 2"), Ok(-1));
+        assert_eq!(eval("1 << 4 | 1 & 3 ^ 2"), Ok(19));
+        assert_eq!(eval("!5 + ~0"), Ok(-1));
+        assert_eq!(eval("3 > 2 && 2 >= 2 || 0"), Ok(1));
+        assert_eq!(eval("0 ? 1 : 2 ? 3 : 4"), Ok(3));
+        assert_eq!(eval("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval("--5"), Ok(5));
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut shell = Shell::new();
+        assert_eq!(evaluate(&mut shell, "x = 5, y = x++ + ++x, x *= 2"), Ok(14));
+        assert_eq!((shell.var("x"), shell.var("y")), (Some("14"), Some("12")));
+        assert_eq!(evaluate(&mut shell, "z <<= 1, unset_var_xyz + z--"), Ok(0));
+        assert_eq!(shell.var("z"), Some("-1"));
+
+        shell.set_var("e", "x + 1");
+        assert_eq!(evaluate(&mut shell, "e * 2"), Ok(30));
+        // Nothing is assigned or evaluated on the side that isn't taken
+        assert_eq!(
+            evaluate(&mut shell, "0 && (x = 1 / 0), 1 || x++, 1 ? x : x--"),
+            Ok(14)
+        );
+        assert_eq!(shell.var("x"), Some("14"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut shell = Shell::new();
+        let mut eval = |expr: &str| evaluate(&mut shell, expr).unwrap_err();
+        assert_eq!(
+            eval("5/0+1"),
+            "5/0+1: division by 0 (error token is \"0+1\")"
+        );
+        assert_eq!(
+            eval("1 +"),
+            "1 +: syntax error: operand expected (error token is \"+\")"
+        );
+        assert_eq!(
+            eval("1=2"),
+            "1=2: attempted assignment to non-variable (error token is \"=2\")"
+        );
+        assert_eq!(eval("(1+2"), "(1+2: missing `)' (error token is \"2\")");
+        assert_eq!(
+            eval("09"),
+            "09: value too great for base (error token is \"09\")"
+        );
+        assert_eq!(
+            eval("2**-1"),
+            "2**-1: exponent less than 0 (error token is \"-1\")"
+        );
+        assert_eq!(
+            eval("3 4"),
+            "3 4: syntax error in expression (error token is \"4\")"
+        );
+        assert_eq!(
+            eval("1?2"),
+            "1?2: `:' expected for conditional expression (error token is \"2\")"
+        );
+        assert_eq!(
+            eval("1 $ 2"),
+            "1 $ 2: syntax error: invalid arithmetic operator (error token is \"$ 2\")"
+        );
+
+        shell.set_var("r", "r");
+        assert_eq!(
+            evaluate(&mut shell, "r").unwrap_err(),
+            "r: expression recursion level exceeded (error token is \"r\")"
+        );
+        let nested = format!("{}1{}", "(".repeat(300), ")".repeat(300));
+        assert!(evaluate(&mut shell, &nested)
+            .unwrap_err()
+            .contains("expression recursion level exceeded"));
+    }
+}
@@ -0,0 +1,661 @@
+use std::{
+    io::{self, Write as _},
+    path::Path,
+};
+
+use rustyline::{
+    config::{BellStyle, Configurer},
+    At, Cmd, ConditionalEventHandler, Editor, Event, EventContext, EventHandler, Movement,
+    RepeatCount, Word,
+};
+use strum::{Display, EnumString};
+
+use crate::{
+    builtin::Output,
+    inputrc::{self, format_keys, key_events, Action, Line},
+    shell::{self, Shell},
+    util::{self, write_and_flush_str},
+};
+
+/// Readline functions that keys can be bound to, by name.
+const FUNCTIONS: &[(&str, Cmd)] = &[
+    ("abort", Cmd::Abort),
+    ("accept-line", Cmd::AcceptLine),
+    ("backward-char", Cmd::Move(Movement::BackwardChar(1))),
+    ("backward-delete-char", Cmd::Kill(Movement::BackwardChar(1))),
+    ("backward-kill-line", Cmd::Kill(Movement::BeginningOfLine)),
+    (
+        "backward-kill-word",
+        Cmd::Kill(Movement::BackwardWord(1, Word::Emacs)),
+    ),
+    (
+        "backward-word",
+        Cmd::Move(Movement::BackwardWord(1, Word::Emacs)),
+    ),
+    ("beginning-of-history", Cmd::BeginningOfHistory),
+    ("beginning-of-line", Cmd::Move(Movement::BeginningOfLine)),
+    ("capitalize-word", Cmd::CapitalizeWord),
+    ("clear-screen", Cmd::ClearScreen),
+    ("complete", Cmd::Complete),
+    ("delete-char", Cmd::Kill(Movement::ForwardChar(1))),
+    ("downcase-word", Cmd::DowncaseWord),
+    ("end-of-file", Cmd::EndOfFile),
+    ("end-of-history", Cmd::EndOfHistory),
+    ("end-of-line", Cmd::Move(Movement::EndOfLine)),
+    ("forward-char", Cmd::Move(Movement::ForwardChar(1))),
+    ("forward-search-history", Cmd::ForwardSearchHistory),
+    (
+        "forward-word",
+        Cmd::Move(Movement::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+    ),
+    ("history-search-backward", Cmd::HistorySearchBackward),
+    ("history-search-forward", Cmd::HistorySearchForward),
+    ("kill-line", Cmd::Kill(Movement::EndOfLine)),
+    ("kill-whole-line", Cmd::Kill(Movement::WholeLine)),
+    (
+        "kill-word",
+        Cmd::Kill(Movement::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+    ),
+    ("next-history", Cmd::NextHistory),
+    ("previous-history", Cmd::PreviousHistory),
+    ("quoted-insert", Cmd::QuotedInsert),
+    ("redraw-current-line", Cmd::Repaint),
+    ("reverse-search-history", Cmd::ReverseSearchHistory),
+    ("transpose-chars", Cmd::TransposeChars),
+    ("transpose-words", Cmd::TransposeWords(1)),
+    ("undo", Cmd::Undo(1)),
+    ("unix-line-discard", Cmd::Kill(Movement::BeginningOfLine)),
+    (
+        "unix-word-rubout",
+        Cmd::Kill(Movement::BackwardWord(1, Word::Big)),
+    ),
+    ("upcase-word", Cmd::UpcaseWord),
+    ("yank", Cmd::Yank(1, rustyline::Anchor::Before)),
+    ("yank-pop", Cmd::YankPop),
+];
+
+/// Editing mode of the line editor, the `editing-mode` readline variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Binding {
+    keys: Vec<char>,
+    action: Action,
+}
+
+/// Settings of the line editor made with `bind`, `set -o` and the inputrc file. The
+/// shell only keeps them, they're applied to the editor before each line is read.
+#[derive(Debug, Default)]
+pub(crate) struct EditorSettings {
+    pub(crate) edit_mode: EditMode,
+    bell_style: Option<String>,
+    completion_query_items: Option<usize>,
+    keyseq_timeout: Option<u16>,
+    /// Bindings in the order they were made.
+    bindings: Vec<Binding>,
+    /// Key sequences bound or unbound since the settings were last applied.
+    changed: Vec<Vec<char>>,
+}
+
+impl EditorSettings {
+    fn bind(&mut self, keys: Vec<char>, action: Action) {
+        self.unbind(&keys);
+        self.bindings.push(Binding { keys, action });
+    }
+
+    fn unbind(&mut self, keys: &[char]) {
+        self.bindings.retain(|binding| binding.keys != keys);
+        self.changed.push(keys.to_vec());
+    }
+
+    /// set_variable sets a readline variable. Like readline, unknown variables and
+    /// invalid values are ignored.
+    fn set_variable(&mut self, name: &str, value: &str) {
+        match name {
+            "editing-mode" => {
+                if let Ok(mode) = value.parse() {
+                    self.edit_mode = mode;
+                }
+            }
+            "bell-style" if ["none", "visible", "audible"].contains(&value) => {
+                self.bell_style = Some(value.to_string());
+            }
+            "completion-query-items" => {
+                if let Ok(items) = value.parse() {
+                    self.completion_query_items = Some(items);
+                }
+            }
+            "keyseq-timeout" => {
+                if let Ok(timeout) = value.parse() {
+                    self.keyseq_timeout = Some(timeout);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// variables returns the readline variables that can be set, with their values.
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "bell-style",
+                self.bell_style.clone().unwrap_or("audible".to_string()),
+            ),
+            (
+                "completion-query-items",
+                self.completion_query_items.unwrap_or(100).to_string(),
+            ),
+            ("editing-mode", self.edit_mode.to_string()),
+            (
+                "keyseq-timeout",
+                self.keyseq_timeout.unwrap_or(500).to_string(),
+            ),
+        ]
+    }
+
+    /// apply_line makes the setting or the binding of a line of the inputrc file.
+    fn apply_line(&mut self, line: Line) -> Result<(), String> {
+        match line {
+            Line::Set { name, value } => self.set_variable(&name, &value),
+            Line::Bind {
+                action: Action::Function(name),
+                ..
+            } if function(&name).is_none() => {
+                return Err(format!("`{name}': unknown function name"));
+            }
+            Line::Bind { keys, action } => self.bind(keys, action),
+        }
+        Ok(())
+    }
+
+    /// apply updates the editor with the settings. Keys bound with `bind -x` while a
+    /// line is read are only bound once the next line is read.
+    pub(crate) fn apply<H, I>(&mut self, rl: &mut Editor<H, I>)
+    where
+        H: rustyline::Helper,
+        I: rustyline::history::History,
+    {
+        rl.set_edit_mode(match self.edit_mode {
+            EditMode::Emacs => rustyline::EditMode::Emacs,
+            EditMode::Vi => rustyline::EditMode::Vi,
+        });
+        match self.bell_style.as_deref() {
+            Some("none") => rl.set_bell_style(BellStyle::None),
+            Some("visible") => rl.set_bell_style(BellStyle::Visible),
+            Some(_) => rl.set_bell_style(BellStyle::Audible),
+            None => (),
+        }
+        if let Some(items) = self.completion_query_items {
+            rl.set_completion_prompt_limit(items);
+        }
+        if let Some(timeout) = self.keyseq_timeout {
+            rl.set_keyseq_timeout(Some(timeout));
+        }
+
+        for keys in self.changed.drain(..) {
+            let event = Event::KeySeq(key_events(&keys));
+            let binding = self.bindings.iter().find(|binding| binding.keys == keys);
+            let handler = match binding.map(|binding| &binding.action) {
+                Some(Action::Function(name)) => match function(name) {
+                    Some(cmd) => EventHandler::Conditional(Box::new(RunFunction(cmd))),
+                    None => continue,
+                },
+                Some(Action::Macro(text)) => EventHandler::Simple(Cmd::Insert(1, text.clone())),
+                Some(Action::Command(command)) => {
+                    EventHandler::Conditional(Box::new(RunCommand(command.clone())))
+                }
+                None => {
+                    rl.unbind_sequence(event);
+                    continue;
+                }
+            };
+            rl.bind_sequence(event, handler);
+        }
+    }
+}
+
+struct RunFunction(Cmd);
+
+impl ConditionalEventHandler for RunFunction {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        // Moving past the end of the line accepts the hint, as End and Right do
+        let forward = matches!(
+            self.0,
+            Cmd::Move(Movement::EndOfLine | Movement::ForwardChar(_))
+        );
+        match forward && ctx.has_hint() && ctx.pos() == ctx.line().len() {
+            true => Some(Cmd::CompleteHint),
+            false => Some(self.0.clone()),
+        }
+    }
+}
+
+struct RunCommand(String);
+
+impl ConditionalEventHandler for RunCommand {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        shell::with_editor_shell(|shell, prompt| {
+            let (line, pos) = (ctx.line(), ctx.pos());
+            let width = util::terminal_width();
+            let cursor_row = last_row(&format!("{prompt}{}", &line[..pos]), width);
+            let hint = ctx.hint_text().unwrap_or_default();
+            let end_row = last_row(&format!("{prompt}{line}{hint}"), width);
+
+            // Like bash, clear the prompt and line for the output of the command
+            let mut stdout = io::stdout();
+            let clear = match cursor_row {
+                0 => "\r\x1b[J".to_string(),
+                rows => format!("\x1b[{rows}A\r\x1b[J"),
+            };
+            write!(stdout, "{clear}")
+                .and_then(|_| stdout.flush())
+                .ok()?;
+            let edited = run_command(shell, &self.0, line, pos);
+            if let Err(e) = &edited {
+                write_and_flush_str(&mut io::stderr(), &format!("bind: {e}")).ok()?;
+            }
+
+            // The editor redraws the line from `cursor_row` rows above the cursor after
+            // clearing down to `end_row`, so leave the cursor where that's after the output
+            let room = match end_row - cursor_row {
+                0 => "\n".repeat(end_row),
+                up => format!("{}\x1b[{up}A", "\n".repeat(end_row)),
+            };
+            write!(stdout, "{room}").and_then(|_| stdout.flush()).ok()?;
+            Some(match edited {
+                Ok((new_line, new_pos)) => util::edit_line(line, pos, &new_line, new_pos),
+                Err(_) => Cmd::Repaint,
+            })
+        })?
+    }
+}
+
+/// last_row returns the row, counting from 0, that `text` ends on when it's written
+/// from the start of a row on a terminal `width` columns wide, like the editor lays
+/// out the prompt and the line.
+fn last_row(text: &str, width: usize) -> usize {
+    let (mut row, mut col) = (0, 0);
+    for ch in text.chars() {
+        if ch == '\n' {
+            (row, col) = (row + 1, 0);
+            continue;
+        }
+        col += 1;
+        if col > width {
+            (row, col) = (row + 1, 1);
+        }
+    }
+    match col == width {
+        true => row + 1,
+        false => row,
+    }
+}
+
+/// run_command runs a command bound with `bind -x`. The line and the cursor position,
+/// in chars, are in `READLINE_LINE` and `READLINE_POINT`, where the command can change
+/// them. Returns the line and the byte position of the cursor to go on editing with.
+/// Like `PROMPT_COMMAND`, it doesn't change `$?`.
+fn run_command(
+    shell: &mut Shell,
+    command: &str,
+    line: &str,
+    point: usize,
+) -> anyhow::Result<(String, usize)> {
+    let point = line.get(..point).unwrap_or(line).chars().count();
+    shell.set_var("READLINE_LINE", line);
+    shell.set_var("READLINE_POINT", &point.to_string());
+
+    let status = shell.last_status;
+    shell.run(command)?;
+    shell.control = None;
+    shell.last_status = status;
+
+    let line = shell.var("READLINE_LINE").unwrap_or_default().to_string();
+    let point = shell
+        .var("READLINE_POINT")
+        .and_then(|point| point.parse().ok())
+        .unwrap_or(point);
+    shell.unset_var("READLINE_LINE");
+    shell.unset_var("READLINE_POINT");
+    let point = line
+        .char_indices()
+        .nth(point)
+        .map_or(line.len(), |(idx, _)| idx);
+    Ok((line, point))
+}
+
+/// Keymaps that can be named with `bind -m`.
+const KEYMAPS: &[&str] = &[
+    "emacs",
+    "emacs-standard",
+    "emacs-meta",
+    "emacs-ctlx",
+    "vi",
+    "vi-move",
+    "vi-command",
+    "vi-insert",
+];
+
+/// Usage of the `bind` builtin.
+const BIND_USAGE: &str = "bind: usage: bind [-lpsvPSVX] [-m keymap] [-f filename] \
+                          [-q name] [-u name] [-r keyseq] [-x keyseq:shell-command] \
+                          [keyseq:readline-function or readline-command]";
+
+/// bind binds keys and sets readline variables like the inputrc file does, and prints
+/// the bindings and variables. Options are done in the order they're given.
+///  - `-l` lists the functions, `-p` and `-P` the keys bound to them, `-s` and `-S`
+///    the macros, `-X` the shell commands and `-v` and `-V` the variables.
+///  - `-q name` prints the keys bound to a function and `-u name` unbinds them.
+///  - `-r keyseq` removes a binding.
+///  - `-x keyseq:command` binds the keys to a shell command.
+///  - `-f file` reads an inputrc file.
+///  - Other arguments are lines like those of the inputrc file.
+///
+/// Only the bindings made in the shell are listed, not those the editor has by default.
+/// Bindings apply in every keymap, so `-m keymap` is checked but otherwise ignored.
+pub(crate) fn bind<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let mut status = 0;
+    let mut idx = 0;
+    while idx < args.len() {
+        let arg = args[idx];
+        idx += 1;
+        let flags = match arg.strip_prefix('-') {
+            Some("-") => break,
+            Some(flags) if !flags.is_empty() => flags,
+            _ => {
+                if let Err(e) = bind_line(&mut shell.editor, arg) {
+                    write_and_flush_str(&mut w.err, &format!("bind: {e}"))?;
+                    status = 1;
+                }
+                continue;
+            }
+        };
+
+        for (pos, flag) in flags.char_indices() {
+            if !"mfqurx".contains(flag) {
+                if let Err(e) = print_settings(&shell.editor, &mut w.out, flag) {
+                    write_and_flush_str(&mut w.err, &format!("bind: -{flag}: {e}"))?;
+                    write_and_flush_str(&mut w.err, BIND_USAGE)?;
+                    return Ok(2);
+                }
+                continue;
+            }
+
+            // The rest of the word or the next argument is the option's value
+            let value = match &flags[pos + 1..] {
+                "" if idx < args.len() => {
+                    idx += 1;
+                    args[idx - 1]
+                }
+                "" => {
+                    write_and_flush_str(
+                        &mut w.err,
+                        &format!("bind: -{flag}: option requires an argument"),
+                    )?;
+                    write_and_flush_str(&mut w.err, BIND_USAGE)?;
+                    return Ok(2);
+                }
+                value => value,
+            };
+            if bind_option(shell, w, flag, value)? != 0 {
+                status = 1;
+            }
+            break;
+        }
+    }
+
+    for arg in &args[idx..] {
+        if let Err(e) = bind_line(&mut shell.editor, arg) {
+            write_and_flush_str(&mut w.err, &format!("bind: {e}"))?;
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// bind_line makes the setting or the binding of an argument of `bind` that's written
+/// like a line of the inputrc file.
+fn bind_line(settings: &mut EditorSettings, line: &str) -> Result<(), String> {
+    match inputrc::parse_line(line)? {
+        Some(line) => settings.apply_line(line),
+        None => Ok(()),
+    }
+}
+
+/// print_settings prints the bindings or variables for one of the listing options of
+/// `bind`.
+fn print_settings<T: io::Write>(
+    settings: &EditorSettings,
+    out: &mut T,
+    flag: char,
+) -> Result<(), String> {
+    let bound = |name: &str| -> Vec<String> {
+        settings
+            .bindings
+            .iter()
+            .filter(|binding| binding.action == Action::Function(name.to_string()))
+            .map(|binding| format!("\"{}\"", format_keys(&binding.keys)))
+            .collect()
+    };
+
+    let mut lines = Vec::new();
+    match flag {
+        'l' => lines.extend(FUNCTIONS.iter().map(|(name, _)| name.to_string())),
+        'p' | 's' | 'S' | 'X' => {
+            for binding in &settings.bindings {
+                let keys = format_keys(&binding.keys);
+                match (&binding.action, flag) {
+                    (Action::Function(name), 'p') => lines.push(format!("\"{keys}\": {name}")),
+                    (Action::Macro(text), 's') => {
+                        let text = format_keys(&text.chars().collect::<Vec<_>>());
+                        lines.push(format!("\"{keys}\": \"{text}\""));
+                    }
+                    (Action::Macro(text), 'S') => {
+                        let text = format_keys(&text.chars().collect::<Vec<_>>());
+                        lines.push(format!("{keys} outputs {text}"));
+                    }
+                    (Action::Command(command), 'X') => {
+                        lines.push(format!("\"{keys}\": \"{command}\""));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        'P' => {
+            for (name, _) in FUNCTIONS {
+                let keys = bound(name);
+                if !keys.is_empty() {
+                    lines.push(format!("{name} can be found on {}.", keys.join(", ")));
+                }
+            }
+        }
+        'v' => {
+            for (name, value) in settings.variables() {
+                lines.push(format!("set {name} {value}"));
+            }
+        }
+        'V' => {
+            for (name, value) in settings.variables() {
+                lines.push(format!("{name} is set to `{value}'"));
+            }
+        }
+        _ => return Err("invalid option".into()),
+    }
+    for line in lines {
+        write_and_flush_str(out, &line).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// bind_option does one of the options of `bind` that take a value.
+fn bind_option<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    flag: char,
+    value: &str,
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    if "qu".contains(flag) && function(value).is_none() {
+        write_and_flush_str(
+            &mut w.err,
+            &format!("bind: `{value}': unknown function name"),
+        )?;
+        return Ok(1);
+    }
+    let action = Action::Function(value.to_string());
+    let bound_to = |settings: &EditorSettings| -> Vec<Vec<char>> {
+        settings
+            .bindings
+            .iter()
+            .filter(|binding| binding.action == action)
+            .map(|binding| binding.keys.clone())
+            .collect()
+    };
+
+    let result = match flag {
+        'm' if KEYMAPS.contains(&value) => Ok(()),
+        'm' => Err(format!("`{value}': invalid keymap name")),
+        'f' => match read_inputrc(shell, Path::new(value)) {
+            Ok(errors) => {
+                for e in &errors {
+                    write_and_flush_str(&mut w.err, &format!("bind: {e}"))?;
+                }
+                return Ok(i32::from(!errors.is_empty()));
+            }
+            Err(e) => Err(format!("{value}: {}", util::error_message(&e))),
+        },
+        'q' => {
+            let keys: Vec<String> = bound_to(&shell.editor)
+                .iter()
+                .map(|keys| format!("\"{}\"", format_keys(keys)))
+                .collect();
+            if keys.is_empty() {
+                write_and_flush_str(&mut w.out, &format!("{value} is not bound to any keys."))?;
+                return Ok(1);
+            }
+            let line = format!("{value} can be invoked via {}.", keys.join(", "));
+            write_and_flush_str(&mut w.out, &line)?;
+            Ok(())
+        }
+        'u' => {
+            for keys in bound_to(&shell.editor) {
+                shell.editor.unbind(&keys);
+            }
+            Ok(())
+        }
+        // Like bash, the sequence to remove may also be given without its quotes
+        'r' if value.starts_with('\\') => {
+            shell.editor.unbind(&inputrc::unescape(value));
+            Ok(())
+        }
+        'r' => inputrc::parse_keys(value).map(|(keys, _)| shell.editor.unbind(&keys)),
+        _ => inputrc::parse_command(value)
+            .map(|(keys, command)| shell.editor.bind(keys, Action::Command(command))),
+    };
+    match result {
+        Ok(()) => Ok(0),
+        Err(e) => {
+            write_and_flush_str(&mut w.err, &format!("bind: {e}"))?;
+            Ok(1)
+        }
+    }
+}
+
+/// read_inputrc reads an inputrc file into the settings of the shell. Errors in lines
+/// don't stop the rest of the file from being read, they're returned with the line
+/// numbers instead.
+pub(crate) fn read_inputrc(shell: &mut Shell, path: &Path) -> io::Result<Vec<String>> {
+    let mut reader = inputrc::Reader::new(
+        &shell.editor.edit_mode.to_string(),
+        shell.var("TERM").unwrap_or_default(),
+        shell.var("HOME").unwrap_or_default(),
+    );
+    let settings = &mut shell.editor;
+    reader.read(path, &mut |line| settings.apply_line(line))?;
+    Ok(reader.errors)
+}
+
+fn function(name: &str) -> Option<Cmd> {
+    FUNCTIONS
+        .iter()
+        .find(|(function, _)| *function == name)
+        .map(|(_, cmd)| cmd.clone())
+}
+
+#[cfg(test)]
+mod bind_test {
+    use crate::{
+        bind::{last_row, run_command},
+        shell::Shell,
+    };
+
+    #[test]
+    fn test_bind() {
+        let mut shell = Shell::new();
+        let output = shell
+            .capture(
+                r#"bind '"\C-g": forward-word' 'C-o: "git status"'
+                bind -x '"\C-t": "echo $READLINE_LINE"' 'set editing-mode vi'
+                bind -p; bind -s; bind -X; bind -v
+                bind -q forward-word; bind -r '\C-g'; bind -q forward-word; echo $?
+                bind '"\C-g": no-such-function' 2>&1; echo $?"#,
+            )
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                r#""\C-g": forward-word"#,
+                r#""\C-o": "git status""#,
+                r#""\C-t": "echo $READLINE_LINE""#,
+                "set bell-style audible",
+                "set completion-query-items 100",
+                "set editing-mode vi",
+                "set keyseq-timeout 500",
+                r#"forward-word can be invoked via "\C-g"."#,
+                "forward-word is not bound to any keys.",
+                "1",
+                "bind: `no-such-function': unknown function name",
+                "1",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_run_command() {
+        let mut shell = Shell::new();
+        shell.last_status = 3;
+        let command =
+            r#"READLINE_LINE="$READLINE_LINE $READLINE_POINT é"; READLINE_POINT=2; false"#;
+        let edited = run_command(&mut shell, command, "ls é", 5).unwrap();
+        assert_eq!(edited, ("ls é 4 é".to_string(), 2));
+        assert_eq!(shell.last_status, 3);
+        assert_eq!(shell.var("READLINE_LINE"), None);
+    }
+
+    #[test]
+    fn test_last_row() {
+        assert_eq!(last_row("$ ls", 10), 0);
+        assert_eq!(last_row("$ ls -l /tm", 10), 1);
+        assert_eq!(last_row("$ ls -l /t", 10), 1);
+        assert_eq!(last_row("$ ls -l /", 10), 0);
+        assert_eq!(last_row("user@host\n$ ls", 10), 1);
+    }
+}
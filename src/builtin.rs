@@ -1,13 +1,18 @@
 use std::{
     collections, env, fs, io,
-    path::PathBuf,
+    os::unix::process::{CommandExt as _, ExitStatusExt as _},
+    path::{Path, PathBuf},
     process::{self},
 };
 
 use anyhow::Context;
 use strum::EnumString;
 
-use crate::util::{write_and_flush_buf, write_and_flush_str};
+use crate::{
+    alias, arith, bind, completion, cond, declare, dirstack, hash, printf, read,
+    shell::{Control, Shell},
+    util::{self, write_and_flush_str},
+};
 
 #[derive(Debug, PartialEq, EnumString)]
 pub(crate) enum Command {
@@ -17,6 +22,21 @@ pub(crate) enum Command {
     #[strum(serialize = "echo")]
     Echo,
 
+    #[strum(serialize = "printf")]
+    Printf,
+
+    #[strum(serialize = "read")]
+    Read,
+
+    #[strum(serialize = "test")]
+    Test,
+
+    #[strum(serialize = "[")]
+    Bracket,
+
+    #[strum(serialize = "let")]
+    Let,
+
     #[strum(serialize = "type")]
     Type,
 
@@ -26,6 +46,57 @@ pub(crate) enum Command {
     #[strum(serialize = "cd")]
     Cd,
 
+    #[strum(serialize = "complete")]
+    Complete,
+
+    #[strum(serialize = "compgen")]
+    Compgen,
+
+    #[strum(serialize = "declare")]
+    Declare,
+
+    #[strum(serialize = "typeset")]
+    Typeset,
+
+    #[strum(serialize = "local")]
+    Local,
+
+    #[strum(serialize = "unset")]
+    Unset,
+
+    #[strum(serialize = "return")]
+    Return,
+
+    #[strum(serialize = "hash")]
+    Hash,
+
+    #[strum(serialize = "exec")]
+    Exec,
+
+    #[strum(serialize = "pushd")]
+    Pushd,
+
+    #[strum(serialize = "popd")]
+    Popd,
+
+    #[strum(serialize = "dirs")]
+    Dirs,
+
+    #[strum(serialize = "alias")]
+    Alias,
+
+    #[strum(serialize = "unalias")]
+    Unalias,
+
+    #[strum(serialize = "source", serialize = ".")]
+    Source,
+
+    #[strum(serialize = "set")]
+    Set,
+
+    #[strum(serialize = "bind")]
+    Bind,
+
     #[strum(disabled)]
     Executable { name: String },
 }
@@ -35,8 +106,8 @@ where
     T: io::Write,
     K: io::Write,
 {
-    out: T,
-    err: K,
+    pub(crate) out: T,
+    pub(crate) err: K,
 }
 
 impl<T: io::Write, K: io::Write> Output<T, K> {
@@ -45,6 +116,19 @@ impl<T: io::Write, K: io::Write> Output<T, K> {
     }
 }
 
+/// What a command name runs, as reported by `type`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CommandType {
+    Alias(String),
+    Function,
+    Builtin,
+    /// A program remembered in the hash table.
+    Hashed(PathBuf),
+    /// A program in `$PATH`, or the path to one.
+    File(String),
+    NotFound,
+}
+
 impl Command {
     pub(crate) fn parse(command: &str) -> Self {
         match Self::try_from(command) {
@@ -55,251 +139,613 @@ impl Command {
         }
     }
 
-    pub(crate) fn available_commands() -> Vec<String> {
+    pub(crate) fn available_commands(shell: &mut Shell) -> Vec<String> {
         let mut set = collections::HashSet::new();
         set.extend(vec![
             "echo".to_string(),
+            "printf".to_string(),
+            "read".to_string(),
+            "test".to_string(),
+            "[".to_string(),
+            "let".to_string(),
             "type".to_string(),
             "exit".to_string(),
             "pwd".to_string(),
             "cd".to_string(),
+            "complete".to_string(),
+            "compgen".to_string(),
+            "declare".to_string(),
+            "typeset".to_string(),
+            "local".to_string(),
+            "unset".to_string(),
+            "return".to_string(),
+            "hash".to_string(),
+            "exec".to_string(),
+            "pushd".to_string(),
+            "popd".to_string(),
+            "dirs".to_string(),
+            "alias".to_string(),
+            "unalias".to_string(),
+            "source".to_string(),
+            ".".to_string(),
+            "set".to_string(),
+            "bind".to_string(),
         ]);
-        set.extend(Self::all_executables());
+        set.extend(shell.executables());
         set.into_iter().collect()
     }
 
-    pub(crate) fn execute<T, K>(&self, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<()>
+    /// execute runs the command and returns its exit status.
+    pub(crate) fn execute<T, K>(
+        &self,
+        shell: &mut Shell,
+        w: &mut Output<T, K>,
+        args: &[&str],
+    ) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
         match self {
-            Self::Exit => Self::exit(w, args),
+            Self::Exit => Self::exit(shell, w, args),
             Self::Echo => Self::echo(w, args),
-            Self::Type => Self::type_cmd(w, args),
-            Self::Pwd => Self::pwd(w, args),
-            Self::Cd => Self::cd(w, args),
-            Self::Executable { name } => match Self::find_executable_in_path(&name) {
-                Some(path) => Self::exec(w, name, path, args),
-                None => Self::command_not_found(&mut w.err, &name),
+            Self::Printf => printf::printf(shell, w, args),
+            Self::Read => read::read(shell, w, args),
+            Self::Test => cond::test(shell, w, args),
+            Self::Bracket => cond::bracket(shell, w, args),
+            Self::Let => arith::let_cmd(shell, w, args),
+            Self::Type => Self::type_cmd(shell, w, args),
+            Self::Pwd => Self::pwd(shell, w, args),
+            Self::Cd => Self::cd(shell, w, args),
+            Self::Complete => completion::complete(shell, w, args),
+            Self::Compgen => completion::compgen(shell, w, args),
+            Self::Declare => declare::declare(shell, w, args),
+            Self::Typeset => declare::typeset(shell, w, args),
+            Self::Local => declare::local(shell, w, args),
+            Self::Unset => declare::unset(shell, w, args),
+            Self::Return => Self::return_cmd(shell, w, args),
+            Self::Hash => hash::hash(shell, w, args),
+            Self::Exec => Self::exec_cmd(shell, w, args),
+            Self::Pushd => dirstack::pushd(shell, w, args),
+            Self::Popd => dirstack::popd(shell, w, args),
+            Self::Dirs => dirstack::dirs(shell, w, args),
+            Self::Alias => alias::alias(shell, w, args),
+            Self::Unalias => alias::unalias(shell, w, args),
+            Self::Source => Self::source(shell, w, args),
+            Self::Set => Self::set(shell, w, args),
+            Self::Bind => bind::bind(shell, w, args),
+            Self::Executable { name } => match Self::resolve(shell, name) {
+                Ok(path) => Self::spawn(shell, w, name, path, args),
+                Err((status, e)) => {
+                    write_and_flush_str(&mut w.err, &e)?;
+                    Ok(status)
+                }
             },
         }
     }
 
     /// exit terminates the shell with specified code.
     /// If the argument is invalid, code is set to 0 instead.
-    fn exit<T, K>(_: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<()>
+    fn exit<T, K>(shell: &Shell, _: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
         let code = match args.first() {
-            Some(arg) => match arg.parse::<i32>() {
-                Ok(c) => c,
-                Err(_) => 0,
-            },
+            Some(arg) => arg.parse::<i32>().unwrap_or_default(),
             None => 0,
         };
 
-        process::exit(code)
+        shell.exit(code)
     }
 
-    /// echo prints the same message back.
-    fn echo<T, K>(w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<()>
+    /// echo prints the arguments separated by spaces.
+    ///  - `-n` leaves out the trailing newline.
+    ///  - `-e` expands backslash escapes, where `\c` stops the output, and `-E` doesn't.
+    fn echo<T, K>(w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
-        write_and_flush_str(&mut w.out, &args.join(" "))
+        let mut newline = true;
+        let mut escapes = false;
+        let mut args = args;
+        while let Some(flags) = args.first().and_then(|arg| arg.strip_prefix('-')) {
+            if flags.is_empty() || !flags.chars().all(|flag| "neE".contains(flag)) {
+                break;
+            }
+            for flag in flags.chars() {
+                match flag {
+                    'n' => newline = false,
+                    'e' => escapes = true,
+                    _ => escapes = false,
+                }
+            }
+            args = &args[1..];
+        }
+
+        let mut output = Vec::new();
+        for (idx, arg) in args.iter().enumerate() {
+            if idx > 0 {
+                output.push(b' ');
+            }
+            if !escapes {
+                output.extend_from_slice(arg.as_bytes());
+                continue;
+            }
+            let (expanded, stopped) = printf::echo_escapes(arg);
+            output.extend(expanded);
+            if stopped {
+                newline = false;
+                break;
+            }
+        }
+        if newline {
+            output.push(b'\n');
+        }
+        util::write_and_flush_bytes(&mut w.out, &output)?;
+        Ok(0)
     }
 
     /// type prints if command is a shell builtin, executable in `$PATH`` or unknown command.
+    ///  - If command is an alias: ``<command> is aliased to `<value>'``.
+    ///  - If command is a function: `<command> is a function`.
     ///  - If command is a shell builtin: `<command> is a shell builtin`.
+    ///  - If command is a remembered executable: `<command> is hashed (<path>)`.
     ///  - If command is an executable in PATH: `<command> is <path>`.
     ///  - If command is unknown: `<command>: not found`.
-    fn type_cmd<T, K>(w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<()>
+    fn type_cmd<T, K>(shell: &mut Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
-        let mut outputs = Vec::new();
+        let mut status = 0;
         for arg in args {
-            let output = match Self::parse(&arg) {
-                Self::Executable { name } => match Self::find_executable_in_path(&name) {
-                    Some(path) => format!("{name} is {}", path.display()),
-                    None => format!("{name}: not found"),
-                },
-                _ => format!("{arg} is a shell builtin"),
+            let description = match Self::command_type(shell, arg) {
+                CommandType::Alias(value) => format!("{arg} is aliased to `{value}'"),
+                CommandType::Function => format!("{arg} is a function"),
+                CommandType::Builtin => format!("{arg} is a shell builtin"),
+                CommandType::Hashed(path) => format!("{arg} is hashed ({})", path.display()),
+                CommandType::File(path) => format!("{arg} is {path}"),
+                CommandType::NotFound => {
+                    write_and_flush_str(&mut w.out, &format!("{arg}: not found"))?;
+                    status = 1;
+                    continue;
+                }
             };
-            outputs.push(output);
+            write_and_flush_str(&mut w.out, &description)?;
         }
 
-        write_and_flush_str(&mut w.out, &outputs.join("\n"))?;
-        Ok(())
+        Ok(status)
     }
 
-    fn pwd<T, K>(w: &mut Output<T, K>, _: &[&str]) -> anyhow::Result<()>
+    /// command_type returns what running the command name would run, in the order
+    /// the shell looks it up.
+    pub(crate) fn command_type(shell: &mut Shell, name: &str) -> CommandType {
+        if let Some(value) = shell.aliases.get(name) {
+            return CommandType::Alias(value.clone());
+        }
+        if shell.has_function(name) {
+            return CommandType::Function;
+        }
+        if !matches!(Self::parse(name), Self::Executable { .. }) {
+            return CommandType::Builtin;
+        }
+        if let Some(path) = shell.hashed_command(name) {
+            return CommandType::Hashed(path);
+        }
+        let found = match name.contains('/') {
+            true => hash::is_executable_file(Path::new(name)).then(|| name.to_string()),
+            false => hash::find_in_path(shell.var("PATH").unwrap_or_default(), name)
+                .map(|path| path.display().to_string()),
+        };
+        match found {
+            Some(path) => CommandType::File(path),
+            None => CommandType::NotFound,
+        }
+    }
+
+    /// pwd prints the current directory, including the symlinks followed to get there
+    /// unless `-P` is given.
+    fn pwd<T, K>(shell: &Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
-        let path = env::current_dir().context("failed to get current dir")?;
-        write_and_flush_buf(&mut w.out, path.into_os_string().as_encoded_bytes())
+        let physical = match Self::parse_path_options("pwd", args) {
+            Ok((physical, [])) => physical,
+            Ok(_) => {
+                write_and_flush_str(&mut w.err, "pwd: too many arguments")?;
+                return Ok(1);
+            }
+            Err(e) => {
+                write_and_flush_str(&mut w.err, &e)?;
+                return Ok(2);
+            }
+        };
+
+        let path = match physical {
+            true => env::current_dir()
+                .context("failed to get current dir")?
+                .to_string_lossy()
+                .to_string(),
+            false => shell.current_dir(),
+        };
+        write_and_flush_str(&mut w.out, &path)?;
+        Ok(0)
     }
 
-    fn cd<T, K>(w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<()>
+    /// cd changes the current directory.
+    ///  - `cd` goes to `$HOME`, `cd -` goes to `$OLDPWD` and prints it.
+    ///  - Relative directories are looked up in each directory of `$CDPATH`.
+    ///  - `-L` (the default) follows symlinks logically, so that `..` goes back to where
+    ///    the symlink was. `-P` resolves symlinks first.
+    fn cd<T, K>(shell: &mut Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
-        if args.len() == 0 {
-            return Ok(());
-        }
+        let (physical, args) = match Self::parse_path_options("cd", args) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                write_and_flush_str(&mut w.err, &e)?;
+                return Ok(2);
+            }
+        };
         if args.len() > 1 {
             write_and_flush_str(&mut w.err, "cd: too many arguments")?;
-            return Ok(());
+            return Ok(1);
         }
 
-        let dir = Self::replace_with_home_dir(&args[0]);
-        if env::set_current_dir(&dir).is_err() {
-            write_and_flush_str(
-                &mut w.out,
-                &format!("cd: {}: No such file or directory", dir),
-            )?;
+        let mut print = false;
+        let dir = match args.first() {
+            None => match shell.var("HOME") {
+                Some(home) => home.to_string(),
+                None => {
+                    write_and_flush_str(&mut w.err, "cd: HOME not set")?;
+                    return Ok(1);
+                }
+            },
+            Some(&"-") => match shell.var("OLDPWD") {
+                Some(old) => {
+                    print = true;
+                    old.to_string()
+                }
+                None => {
+                    write_and_flush_str(&mut w.err, "cd: OLDPWD not set")?;
+                    return Ok(1);
+                }
+            },
+            Some(dir) => dir.to_string(),
+        };
+        let dir = match Self::search_cdpath(shell, &dir) {
+            Some(found) => {
+                print = true;
+                found
+            }
+            None => dir,
+        };
+
+        if let Err(e) = shell.change_dir(&dir, physical) {
+            write_and_flush_str(&mut w.err, &format!("cd: {e}"))?;
+            return Ok(1);
+        }
+        if print {
+            write_and_flush_str(&mut w.out, &shell.current_dir())?;
+        }
+        Ok(0)
+    }
+
+    /// parse_path_options parses the `-L` and `-P` options of `cd` and `pwd`, returning
+    /// whether the last one was `-P` and the remaining arguments.
+    fn parse_path_options<'a, 'b>(
+        builtin: &str,
+        args: &'a [&'b str],
+    ) -> Result<(bool, &'a [&'b str]), String> {
+        let mut physical = false;
+        let mut idx = 0;
+        while let Some(flags) = args
+            .get(idx)
+            .filter(|arg| arg.len() > 1)
+            .and_then(|arg| arg.strip_prefix('-'))
+        {
+            idx += 1;
+            if flags == "-" {
+                break;
+            }
+            for flag in flags.chars() {
+                match flag {
+                    'L' => physical = false,
+                    'P' => physical = true,
+                    _ => return Err(format!("{builtin}: -{flag}: invalid option")),
+                }
+            }
+        }
+        Ok((physical, &args[idx..]))
+    }
+
+    /// search_cdpath returns the first `$CDPATH` directory that contains `dir`, unless
+    /// `dir` is absolute or starts with `.` or `..`. An empty entry means the current
+    /// directory, which isn't reported as found so that `cd` doesn't print it.
+    fn search_cdpath(shell: &Shell, dir: &str) -> Option<String> {
+        let first = dir.split('/').next().unwrap_or_default();
+        if dir.is_empty() || dir.starts_with('/') || first == "." || first == ".." {
+            return None;
+        }
+
+        for entry in shell.var("CDPATH")?.split(':') {
+            let candidate = match entry {
+                "" | "." => PathBuf::from(dir),
+                entry => Path::new(entry).join(dir),
+            };
+            if candidate.is_dir() {
+                return match entry {
+                    "" | "." => None,
+                    _ => Some(candidate.to_string_lossy().to_string()),
+                };
+            }
         }
-        Ok(())
+        None
     }
 
-    fn exec<T, K>(
+    /// return stops the running function with the specified code,
+    /// or the status of the last command if there is none.
+    fn return_cmd<T, K>(
+        shell: &mut Shell,
         w: &mut Output<T, K>,
-        name: &str,
-        path: PathBuf,
         args: &[&str],
-    ) -> anyhow::Result<()>
+    ) -> anyhow::Result<i32>
     where
         T: io::Write,
         K: io::Write,
     {
-        let output = process::Command::new(name)
-            .args(args)
-            .output()
-            .context(format!(
-                "failed to execute program {name} ({})",
-                path.display()
-            ))?;
-
-        w.out
-            .write_all(&output.stdout)
-            .context("failed to write program output")?;
-        w.err
-            .write_all(&output.stderr)
-            .context("failed to write program errors")?;
-        Ok(())
-    }
+        let code = match args.first() {
+            Some(arg) => match arg.parse::<i32>() {
+                Ok(code) => code & 0xff,
+                Err(_) => {
+                    write_and_flush_str(
+                        &mut w.err,
+                        &format!("return: {arg}: numeric argument required"),
+                    )?;
+                    2
+                }
+            },
+            None => shell.last_status,
+        };
 
-    fn command_not_found<T: io::Write>(w: &mut T, command: &str) -> anyhow::Result<()> {
-        write_and_flush_str(w, &format!("{command}: command not found"))
+        shell.control = Some(Control::Return);
+        Ok(code)
     }
 
-    fn find_executable_in_path(name: &str) -> Option<PathBuf> {
-        let path_env_var = env::var("PATH");
-        if path_env_var.is_err() {
-            return None;
-        }
-
-        let path_env_var = path_env_var.unwrap();
-        let splits = path_env_var.split(":");
+    /// source runs the commands in a file in the current shell, so that they can set
+    /// variables and change directory. A name without a slash is searched in `$PATH`,
+    /// then in the current directory. Arguments are the positional parameters while
+    /// the file runs, and `return` stops running the file.
+    fn source<T, K>(shell: &mut Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
+    where
+        T: io::Write,
+        K: io::Write,
+    {
+        let args = match args.first() {
+            Some(&"--") => &args[1..],
+            _ => args,
+        };
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => {
+                write_and_flush_str(&mut w.err, "source: filename argument required")?;
+                write_and_flush_str(&mut w.err, "source: usage: source filename [arguments]")?;
+                return Ok(2);
+            }
+        };
 
-        for p in splits {
-            let entries = match fs::read_dir(p) {
-                Ok(entry) => entry,
-                Err(_) => continue,
-            };
+        let path = match name.contains('/') {
+            true => None,
+            false => hash::find_file_in_path(shell.var("PATH").unwrap_or_default(), name),
+        };
+        let path = path.unwrap_or_else(|| PathBuf::from(name));
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                let e = format!("source: {name}: {}", util::error_message(&e));
+                write_and_flush_str(&mut w.err, &e)?;
+                return Ok(1);
+            }
+        };
 
-            for entry in entries {
-                let path = match entry {
-                    Ok(entry) => entry.path(),
-                    Err(_) => continue,
-                };
+        let saved_positional = match args.is_empty() {
+            true => None,
+            false => Some(shell.set_positional(args.iter().map(|arg| arg.to_string()).collect())),
+        };
+        let status = shell.run_source(
+            &path.display().to_string(),
+            &String::from_utf8_lossy(&contents),
+        );
+        if shell.control == Some(Control::Return) {
+            shell.control = None;
+        }
+        if let Some(positional) = saved_positional {
+            shell.set_positional(positional);
+        }
+        status
+    }
 
-                if !path.is_file() {
-                    continue;
+    /// set turns the options of the shell on and off.
+    ///  - `set -o` prints the options and `set +o` the commands that restore them.
+    ///  - `set -o name` turns an option on and `set +o name` turns it off. The options
+    ///    are the editing modes `emacs` and `vi`, and turning one off turns the other on.
+    fn set<T, K>(shell: &mut Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
+    where
+        T: io::Write,
+        K: io::Write,
+    {
+        let modes = [bind::EditMode::Emacs, bind::EditMode::Vi];
+        let mut idx = 0;
+        while idx < args.len() {
+            let on = match args[idx] {
+                "-o" => true,
+                "+o" => false,
+                arg => {
+                    write_and_flush_str(&mut w.err, &format!("set: {arg}: invalid option"))?;
+                    write_and_flush_str(&mut w.err, "set: usage: set [-o option-name]")?;
+                    return Ok(2);
                 }
-
-                match path.file_name() {
-                    Some(filename) => {
-                        if filename == name {
-                            return Some(path);
-                        }
+            };
+            idx += 1;
+
+            let name = match args.get(idx) {
+                Some(name) => name,
+                None => {
+                    for mode in modes {
+                        let line = match (on, shell.editor.edit_mode == mode) {
+                            (true, true) => format!("{:<15}\ton", mode.to_string()),
+                            (true, false) => format!("{:<15}\toff", mode.to_string()),
+                            (false, true) => format!("set -o {mode}"),
+                            (false, false) => format!("set +o {mode}"),
+                        };
+                        write_and_flush_str(&mut w.out, &line)?;
                     }
-                    None => continue,
+                    return Ok(0);
                 }
-            }
+            };
+            idx += 1;
+            let mode = match modes.iter().find(|mode| mode.to_string() == *name) {
+                Some(mode) => *mode,
+                None => {
+                    write_and_flush_str(&mut w.err, &format!("set: {name}: invalid option name"))?;
+                    return Ok(1);
+                }
+            };
+            shell.editor.edit_mode = match (on, mode) {
+                (true, mode) => mode,
+                (false, bind::EditMode::Emacs) => bind::EditMode::Vi,
+                (false, bind::EditMode::Vi) => bind::EditMode::Emacs,
+            };
         }
-
-        None
+        Ok(0)
     }
 
-    fn all_executables() -> Vec<String> {
-        let mut executables = vec![];
-        let path_env_var = env::var("PATH");
-        if path_env_var.is_err() {
-            return executables;
-        }
+    /// exec replaces the shell with the command. Without a command, the redirections
+    /// are kept for the rest of the session, which is done when they're applied.
+    fn exec_cmd<T, K>(shell: &mut Shell, w: &mut Output<T, K>, args: &[&str]) -> anyhow::Result<i32>
+    where
+        T: io::Write,
+        K: io::Write,
+    {
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => return Ok(0),
+        };
+        let path = match Self::resolve(shell, name) {
+            Ok(path) => path,
+            Err((status, e)) => {
+                write_and_flush_str(&mut w.err, &format!("exec: {e}"))?;
+                return Ok(status);
+            }
+        };
 
-        let path_env_var = path_env_var.unwrap();
-        let splits = path_env_var.split(":");
+        w.out.flush().context("failed to flush output")?;
+        w.err.flush().context("failed to flush errors")?;
+        // exec only returns if the program couldn't be executed
+        let e = process::Command::new(&path)
+            .arg0(name)
+            .args(args)
+            .env_clear()
+            .envs(shell.exported_vars())
+            .exec();
+        if e.raw_os_error() == Some(libc::ENOEXEC) {
+            shell.set_positional(args.iter().map(|a| a.to_string()).collect());
+            let status = shell.run_file(&path)?;
+            shell.exit(status);
+        }
+        write_and_flush_str(
+            &mut w.err,
+            &format!("exec: {name}: {}", util::error_message(&e)),
+        )?;
+        Ok(126)
+    }
 
-        for p in splits {
-            let entries = match fs::read_dir(p) {
-                Ok(entry) => entry,
-                Err(_) => continue,
-            };
+    /// spawn runs the program at `path` with `argv[0]` set to the name it was called by,
+    /// and waits for it to finish. Files the kernel can't execute, like scripts without
+    /// a `#!` line, are run by the shell instead.
+    fn spawn<T, K>(
+        shell: &mut Shell,
+        w: &mut Output<T, K>,
+        name: &str,
+        path: PathBuf,
+        args: &[&str],
+    ) -> anyhow::Result<i32>
+    where
+        T: io::Write,
+        K: io::Write,
+    {
+        w.out.flush().context("failed to flush output")?;
+        w.err.flush().context("failed to flush errors")?;
 
-            for entry in entries {
-                let path = match entry {
-                    Ok(entry) => entry.path(),
-                    Err(_) => continue,
+        let status = match process::Command::new(&path)
+            .arg0(name)
+            .args(args)
+            .env_clear()
+            .envs(shell.exported_vars())
+            .status()
+        {
+            Ok(status) => status,
+            Err(e) if e.raw_os_error() == Some(libc::ENOEXEC) => {
+                let args = args.iter().map(|a| a.to_string()).collect();
+                return shell.run_script(&path, args);
+            }
+            Err(e) => {
+                let status = match e.kind() {
+                    io::ErrorKind::NotFound => 127,
+                    _ => 126,
                 };
+                write_and_flush_str(&mut w.err, &format!("{name}: {}", util::error_message(&e)))?;
+                return Ok(status);
+            }
+        };
 
-                if !path.is_file() {
-                    continue;
-                }
+        match status.code() {
+            Some(code) => Ok(code),
+            None => Ok(128 + status.signal().unwrap_or_default()),
+        }
+    }
 
-                match path.file_name() {
-                    Some(filename) => executables.push(filename.to_str().unwrap().to_string()),
-                    None => continue,
-                }
+    /// resolve finds the file to run for the command, which is the command itself if it
+    /// contains a `/`, otherwise the first executable in `$PATH`. Errors come with the
+    /// exit status: 127 if there is no such file and 126 if it can't be executed.
+    fn resolve(shell: &mut Shell, name: &str) -> Result<PathBuf, (i32, String)> {
+        if !name.contains('/') {
+            if let Some(path) = shell.find_command(name) {
+                return Ok(path);
             }
+            // A file without the execute bit is still a better error than not found
+            return match hash::find_file_in_path(shell.var("PATH").unwrap_or_default(), name) {
+                Some(_) => Err((126, format!("{name}: Permission denied"))),
+                None => Err((127, format!("{name}: command not found"))),
+            };
         }
 
-        executables
-    }
-
-    fn home_dir() -> String {
-        match env::var("HOME") {
-            Ok(home) => home,
-            Err(_) => "".into(),
+        match fs::metadata(name) {
+            Ok(metadata) if metadata.is_dir() => Err((126, format!("{name}: Is a directory"))),
+            Ok(metadata) if !hash::is_executable(&metadata) => {
+                Err((126, format!("{name}: Permission denied")))
+            }
+            Ok(_) => Ok(PathBuf::from(name)),
+            Err(_) => Err((127, format!("{name}: No such file or directory"))),
         }
     }
 
-    fn replace_with_home_dir(path: &str) -> String {
-        match path.split_once('/') {
-            Some((a, b)) => {
-                if a == "~" {
-                    // Replace '~' with HOME dir
-                    format!("{}/{}", Self::home_dir(), b)
-                } else {
-                    path.into()
-                }
-            }
-            None => {
-                if path == "~" {
-                    Self::home_dir()
-                } else {
-                    path.into()
-                }
-            }
+    /// replace_home_with_tilde abbreviates `$HOME` at the start of the path as `~`, the
+    /// reverse of tilde expansion.
+    pub(crate) fn replace_home_with_tilde(shell: &Shell, path: &str) -> String {
+        let home = shell.var("HOME").unwrap_or_default().trim_end_matches('/');
+        if home.is_empty() {
+            return path.into();
+        }
+        match path.strip_prefix(home) {
+            Some("") => "~".into(),
+            Some(rest) if rest.starts_with('/') => format!("~{rest}"),
+            _ => path.into(),
         }
     }
 }
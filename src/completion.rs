@@ -0,0 +1,991 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io,
+    os::fd::AsRawFd as _,
+    rc::Rc,
+};
+
+use rustyline::completion::Pair;
+use strum::EnumString;
+
+use crate::{
+    builtin::{self, Output},
+    expand, hash,
+    shell::Shell,
+    util::{self, write_and_flush_str, SavedFds},
+    variable::Value,
+};
+
+pub(crate) struct ShellCompleter {
+    shell: Rc<RefCell<Shell>>,
+}
+
+impl ShellCompleter {
+    pub(crate) fn new(shell: Rc<RefCell<Shell>>) -> Self {
+        Self { shell }
+    }
+}
+
+impl rustyline::completion::Completer for ShellCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
+        let word = Word::parse(&line[..pos]);
+        let mut shell = match self.shell.try_borrow_mut() {
+            Ok(shell) => shell,
+            Err(_) => return Ok((word.start, Vec::new())),
+        };
+
+        let spec = match word.command() {
+            Some(command) if !word.command_position => find_spec(&shell.completions, command),
+            _ => None,
+        };
+        let candidates = if word.command_position && !word.text.contains('/') {
+            complete_command(&mut shell, &word)
+        } else if let Some(spec) = spec {
+            complete_with_spec(&mut shell, &spec, &word, line, pos)
+        } else {
+            complete_path(&shell, &word, word.command_position)
+        };
+
+        Ok((word.start, candidates))
+    }
+}
+
+/// find_spec looks up the completion spec by the command name, then by its basename.
+fn find_spec(completions: &HashMap<String, CompSpec>, command: &str) -> Option<CompSpec> {
+    completions
+        .get(command)
+        .or_else(|| completions.get(command.rsplit('/').next()?))
+        .cloned()
+}
+
+/// The partial word under the cursor that is being completed.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Word {
+    /// Byte index in the line where the word starts, including any opening quote.
+    pub(crate) start: usize,
+    /// The word with quotes and escapes removed.
+    pub(crate) text: String,
+    /// The quote that is still open at the cursor, if any.
+    pub(crate) quote: Option<char>,
+    /// Whether the word is in command position, e.g. first word or after `|`, `;`, `&&`.
+    pub(crate) command_position: bool,
+    /// The words of the current command before this one, with quotes removed.
+    pub(crate) words: Vec<String>,
+}
+
+impl Word {
+    /// parse finds the word that ends at the end of `line`.
+    pub(crate) fn parse(line: &str) -> Self {
+        let mut start = 0;
+        let mut text = String::new();
+        let mut words = Vec::new();
+        let mut quote: Option<char> = None;
+        let mut escaped = false;
+        let mut in_word = false;
+        let mut expect_command = true;
+        let mut after_redirect = false;
+
+        for (idx, ch) in line.char_indices() {
+            if !in_word {
+                start = idx;
+            }
+
+            if escaped {
+                escaped = false;
+                // In double quotes only some chars can be escaped
+                if quote == Some('"') && !matches!(ch, '\\' | '$' | '"' | '`') {
+                    text.push('\\');
+                }
+                text.push(ch);
+                in_word = true;
+                continue;
+            }
+
+            match (quote, ch) {
+                (Some(q), _) if ch == q => quote = None,
+                (Some('"'), '\\') => escaped = true,
+                (Some(_), _) => text.push(ch),
+                (None, '\\') => {
+                    escaped = true;
+                    in_word = true;
+                }
+                (None, '\'' | '"') => {
+                    quote = Some(ch);
+                    in_word = true;
+                }
+                (None, _) if ch.is_whitespace() || is_operator(ch) => {
+                    if in_word {
+                        // A finished word consumes the command position, except when
+                        // it's a redirect target or a variable assignment.
+                        if after_redirect {
+                            after_redirect = false;
+                        } else if !is_assignment(&text) {
+                            expect_command = false;
+                        }
+                        words.push(std::mem::take(&mut text));
+                        in_word = false;
+                    }
+                    match ch {
+                        '|' | ';' | '&' | '(' | ')' => {
+                            expect_command = true;
+                            after_redirect = false;
+                            words.clear();
+                        }
+                        '<' | '>' => after_redirect = true,
+                        _ => (),
+                    }
+                }
+                (None, _) => {
+                    text.push(ch);
+                    in_word = true;
+                }
+            }
+        }
+
+        if !in_word {
+            start = line.len();
+        }
+
+        Self {
+            start,
+            text,
+            quote,
+            command_position: expect_command && !after_redirect,
+            words,
+        }
+    }
+
+    /// command returns the name of the command the word is an argument of.
+    pub(crate) fn command(&self) -> Option<&str> {
+        self.words
+            .iter()
+            .find(|w| !is_assignment(w))
+            .map(String::as_str)
+    }
+
+    /// quote_replacement quotes `s` the same way the word was quoted when typed.
+    /// Unquoted words have special chars escaped with a backslash.
+    fn quote_replacement(&self, s: &str) -> String {
+        // Leading `~` or `~user` must stay unquoted for tilde expansion to happen
+        let (tilde, rest) = match s.strip_prefix('~') {
+            Some(_) if self.quote.is_none() => match s.find('/') {
+                Some(idx) => s.split_at(idx),
+                None => (s, ""),
+            },
+            _ => ("", s),
+        };
+
+        let mut out = String::from(tilde);
+        match self.quote {
+            Some('\'') => {
+                out.push('\'');
+                out.push_str(&rest.replace('\'', r"'\''"));
+            }
+            Some(q) => {
+                out.push(q);
+                for ch in rest.chars() {
+                    if matches!(ch, '\\' | '$' | '"' | '`') {
+                        out.push('\\');
+                    }
+                    out.push(ch);
+                }
+            }
+            None => {
+                for ch in rest.chars() {
+                    if ch.is_whitespace() || is_special(ch) {
+                        out.push('\\');
+                    }
+                    out.push(ch);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn is_operator(ch: char) -> bool {
+    matches!(ch, '|' | ';' | '&' | '<' | '>' | '(' | ')')
+}
+
+fn is_special(ch: char) -> bool {
+    is_operator(ch)
+        || matches!(
+            ch,
+            '\\' | '\'' | '"' | '`' | '$' | '*' | '?' | '[' | ']' | '{' | '}' | '!' | '#'
+        )
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => crate::parser::is_name(name),
+        None => false,
+    }
+}
+
+/// command_names returns the names of all aliases, functions, builtins and executables
+/// in `$PATH`.
+fn command_names(shell: &mut Shell) -> BTreeSet<String> {
+    let mut names: BTreeSet<String> = builtin::Command::available_commands(shell)
+        .into_iter()
+        .collect();
+    names.extend(shell.function_names());
+    names.extend(shell.aliases.keys().cloned());
+    names
+}
+
+/// complete_command completes aliases, functions, builtins and executables in `$PATH`.
+fn complete_command(shell: &mut Shell, word: &Word) -> Vec<Pair> {
+    let matches: Vec<String> = command_names(shell)
+        .into_iter()
+        .filter(|c| c.starts_with(&word.text))
+        .collect();
+
+    let single = matches.len() == 1;
+    matches
+        .into_iter()
+        .map(|name| {
+            let mut replacement = word.quote_replacement(&name);
+            if single {
+                if let Some(q) = word.quote {
+                    replacement.push(q);
+                }
+                replacement.push(' ');
+            }
+            Pair {
+                display: name,
+                replacement,
+            }
+        })
+        .collect()
+}
+
+/// path_matches returns the paths starting with `text` and whether they're directories.
+/// If `executables_only` is set, only directories and executable files are returned.
+pub(crate) fn path_matches(
+    shell: &Shell,
+    text: &str,
+    dirs_only: bool,
+    executables_only: bool,
+) -> Vec<(String, bool)> {
+    let (dir_part, file_prefix) = match text.rfind('/') {
+        Some(idx) => text.split_at(idx + 1),
+        None => ("", text),
+    };
+    let lookup_dir = match dir_part {
+        "" => ".".to_string(),
+        _ => expand::expand_tilde_prefix(shell, dir_part),
+    };
+
+    let entries = match fs::read_dir(&lookup_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut matches: Vec<(String, bool)> = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(file_prefix) {
+            continue;
+        }
+        // Hidden files are only completed when explicitly asked for
+        if name.starts_with('.') && !file_prefix.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks so that links to directories get a trailing `/`
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if dirs_only && !metadata.is_dir() {
+            continue;
+        }
+        if executables_only && !metadata.is_dir() && !hash::is_executable(&metadata) {
+            continue;
+        }
+        matches.push((format!("{dir_part}{name}"), metadata.is_dir()));
+    }
+    matches.sort();
+    matches
+}
+
+/// complete_path completes file and directory names, appending `/` to directories.
+/// If `executables_only` is set, only directories and executable files are returned.
+fn complete_path(shell: &Shell, word: &Word, executables_only: bool) -> Vec<Pair> {
+    // A bare `~` completes to the home directory itself
+    if word.quote.is_none() && word.text == "~" {
+        return vec![Pair {
+            display: "~/".into(),
+            replacement: "~/".into(),
+        }];
+    }
+
+    let matches = path_matches(shell, &word.text, false, executables_only);
+    let single = matches.len() == 1;
+    matches
+        .into_iter()
+        .map(|(path, is_dir)| {
+            let mut replacement = word.quote_replacement(&path);
+            let mut display = file_name(&path);
+            if is_dir {
+                replacement.push('/');
+                display.push('/');
+            } else if single {
+                // Unique file, close the quote and move on to the next word
+                if let Some(q) = word.quote {
+                    replacement.push(q);
+                }
+                replacement.push(' ');
+            }
+            Pair {
+                display,
+                replacement,
+            }
+        })
+        .collect()
+}
+
+fn file_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_string()
+}
+
+/// complete_with_spec completes the word with the candidates generated by a spec
+/// registered with `complete`.
+fn complete_with_spec(
+    shell: &mut Shell,
+    spec: &CompSpec,
+    word: &Word,
+    line: &str,
+    pos: usize,
+) -> Vec<Pair> {
+    let mut words = word.words.clone();
+    words.push(word.text.clone());
+    let context = CompContext {
+        line: line.to_string(),
+        point: pos,
+        cword: words.len() - 1,
+        words,
+    };
+
+    let candidates = generate(shell, spec, &context).unwrap_or_default();
+    if candidates.is_empty() && spec.options.contains(&CompOption::Default) {
+        return complete_path(shell, word, false);
+    }
+
+    let filenames = spec.options.contains(&CompOption::Filenames);
+    let nospace = spec.options.contains(&CompOption::Nospace);
+    let single = candidates.len() == 1;
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let is_dir = filenames
+                && fs::metadata(expand::expand_tilde_prefix(shell, &candidate))
+                    .is_ok_and(|m| m.is_dir());
+            let (mut display, mut replacement) = match filenames {
+                true => (file_name(&candidate), word.quote_replacement(&candidate)),
+                false => (candidate.clone(), candidate),
+            };
+            if is_dir && !replacement.ends_with('/') {
+                display.push('/');
+                replacement.push('/');
+            }
+            if single && !nospace && !is_dir {
+                if let Some(q) = word.quote.filter(|_| filenames) {
+                    replacement.push(q);
+                }
+                replacement.push(' ');
+            }
+            Pair {
+                display,
+                replacement,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Action {
+    Alias,
+    Command,
+    Directory,
+    File,
+    Function,
+    Variable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum CompOption {
+    /// Fall back to filename completion if there are no candidates.
+    Default,
+    /// Candidates are filenames, so quote them and add `/` to directories.
+    Filenames,
+    /// Don't add a space after a single candidate.
+    Nospace,
+}
+
+/// How to generate the candidates when completing the arguments of a command.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CompSpec {
+    actions: BTreeSet<Action>,
+    options: BTreeSet<CompOption>,
+    /// Word list of `-W`, expanded when completing.
+    words: Option<String>,
+    /// Function of `-F`, which sets `COMPREPLY`.
+    function: Option<String>,
+    /// Command of `-C`, which prints one candidate per line.
+    command: Option<String>,
+}
+
+impl CompSpec {
+    /// to_command formats the spec as the `complete` command that creates it.
+    fn to_command(&self, name: &str) -> String {
+        let mut parts = vec!["complete".to_string()];
+        for option in &self.options {
+            parts.push(format!("-o {option}"));
+        }
+        for action in &self.actions {
+            parts.push(format!("-A {action}"));
+        }
+        if let Some(words) = &self.words {
+            parts.push(format!("-W {}", util::quote(words)));
+        }
+        if let Some(function) = &self.function {
+            parts.push(format!("-F {function}"));
+        }
+        if let Some(command) = &self.command {
+            parts.push(format!("-C {}", util::quote(command)));
+        }
+        parts.push(util::quote(name));
+        parts.join(" ")
+    }
+}
+
+/// The command line being completed, exposed to `-F` functions as the `COMP_*` variables.
+struct CompContext {
+    line: String,
+    point: usize,
+    words: Vec<String>,
+    cword: usize,
+}
+
+impl CompContext {
+    fn word(&self, idx: Option<usize>) -> String {
+        idx.and_then(|i| self.words.get(i))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn command(&self) -> String {
+        self.word(Some(0))
+    }
+
+    fn cur(&self) -> String {
+        self.word(Some(self.cword))
+    }
+
+    fn prev(&self) -> String {
+        self.word(self.cword.checked_sub(1))
+    }
+}
+
+/// generate returns the sorted candidates for the current word from all sources of the spec.
+fn generate(
+    shell: &mut Shell,
+    spec: &CompSpec,
+    context: &CompContext,
+) -> anyhow::Result<Vec<String>> {
+    let cur = context.cur();
+    let mut candidates = BTreeSet::new();
+
+    for action in &spec.actions {
+        candidates.extend(action_candidates(shell, *action, &cur));
+    }
+    if let Some(words) = &spec.words {
+        // The word list is expanded and then split like an unquoted expansion
+        if let Ok(words) = expand::expand_string(shell, words) {
+            candidates.extend(
+                expand::split_fields(shell, &words)
+                    .into_iter()
+                    .filter(|w| w.starts_with(&cur)),
+            );
+        }
+    }
+    if let Some(function) = &spec.function {
+        candidates.extend(run_function(shell, function, context)?);
+    }
+    if let Some(command) = &spec.command {
+        candidates.extend(run_command(shell, command, context));
+    }
+
+    Ok(candidates.into_iter().collect())
+}
+
+fn action_candidates(shell: &mut Shell, action: Action, cur: &str) -> Vec<String> {
+    let names: Vec<String> = match action {
+        Action::File | Action::Directory => {
+            return path_matches(shell, cur, action == Action::Directory, false)
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect()
+        }
+        Action::Command => command_names(shell).into_iter().collect(),
+        Action::Variable => shell.var_names(),
+        Action::Function => shell.function_names(),
+        Action::Alias => shell.aliases.keys().cloned().collect(),
+    };
+    names.into_iter().filter(|n| n.starts_with(cur)).collect()
+}
+
+/// run_function calls the `-F` function with the command name, the current word and
+/// the previous word as arguments, and returns what it put in `COMPREPLY`.
+fn run_function(
+    shell: &mut Shell,
+    function: &str,
+    context: &CompContext,
+) -> anyhow::Result<Vec<String>> {
+    if !shell.has_function(function) {
+        return Ok(Vec::new());
+    }
+
+    shell.set_var("COMP_LINE", &context.line);
+    shell.set_var("COMP_POINT", &context.point.to_string());
+    shell.set_array("COMP_WORDS", context.words.clone());
+    shell.set_var("COMP_CWORD", &context.cword.to_string());
+    shell.unset_var("COMPREPLY");
+
+    {
+        // Whatever the function prints would only garble the line being edited
+        let mut saved = SavedFds::new();
+        if let Ok(devnull) = File::options().write(true).open("/dev/null") {
+            saved.redirect(1, devnull.as_raw_fd())?;
+        }
+        let args = vec![context.command(), context.cur(), context.prev()];
+        shell.execute_name(function, args)?;
+    }
+
+    // A string rather than an array is split into fields
+    let reply = match shell
+        .variable("COMPREPLY")
+        .and_then(|var| var.value.clone())
+    {
+        Some(Value::Scalar(reply)) => expand::split_fields(shell, &reply),
+        Some(array) => array.values(),
+        None => Vec::new(),
+    };
+    for name in [
+        "COMP_LINE",
+        "COMP_POINT",
+        "COMP_WORDS",
+        "COMP_CWORD",
+        "COMPREPLY",
+    ] {
+        shell.unset_var(name);
+    }
+    Ok(reply)
+}
+
+/// run_command runs the `-C` command with the command name, the current word and the
+/// previous word as arguments, with each line of its output being a candidate.
+fn run_command(shell: &mut Shell, command: &str, context: &CompContext) -> Vec<String> {
+    let input = format!(
+        "{command} {} {} {}",
+        util::quote(&context.command()),
+        util::quote(&context.cur()),
+        util::quote(&context.prev())
+    );
+    let saved = shell.set_temporary_vars(&[
+        ("COMP_LINE".to_string(), context.line.clone()),
+        ("COMP_POINT".to_string(), context.point.to_string()),
+    ]);
+    let output = shell.capture(&input);
+    shell.restore_vars(saved);
+
+    match output {
+        Ok(output) => output.lines().map(String::from).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parsed arguments of `complete` and `compgen`.
+struct SpecArgs<'a> {
+    spec: CompSpec,
+    print: bool,
+    remove: bool,
+    operands: Vec<&'a str>,
+}
+
+fn parse_spec_args<'a>(builtin: &str, args: &[&'a str]) -> Result<SpecArgs<'a>, String> {
+    let allowed = match builtin {
+        "complete" => "proAWFCfdcva",
+        _ => "oAWFCfdcva",
+    };
+    let mut parsed = SpecArgs {
+        spec: CompSpec::default(),
+        print: false,
+        remove: false,
+        operands: Vec::new(),
+    };
+
+    let mut idx = 0;
+    while idx < args.len() {
+        let arg = args[idx];
+        idx += 1;
+        if arg == "--" {
+            parsed.operands.extend(&args[idx..]);
+            break;
+        }
+        let flags = match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => flags,
+            _ => {
+                parsed.operands.push(arg);
+                continue;
+            }
+        };
+
+        for (i, flag) in flags.char_indices() {
+            if !allowed.contains(flag) {
+                return Err(format!("{builtin}: -{flag}: invalid option"));
+            }
+            let action = match flag {
+                'p' => {
+                    parsed.print = true;
+                    continue;
+                }
+                'r' => {
+                    parsed.remove = true;
+                    continue;
+                }
+                'f' => Action::File,
+                'd' => Action::Directory,
+                'c' => Action::Command,
+                'v' => Action::Variable,
+                'a' => Action::Alias,
+                _ => {
+                    // The value is either the rest of this arg or the next arg
+                    let value = match &flags[i + 1..] {
+                        "" => {
+                            let value = args.get(idx).ok_or_else(|| {
+                                format!("{builtin}: -{flag}: option requires an argument")
+                            })?;
+                            idx += 1;
+                            value.to_string()
+                        }
+                        rest => rest.to_string(),
+                    };
+                    match flag {
+                        'o' => {
+                            let option = value
+                                .parse()
+                                .map_err(|_| format!("{builtin}: {value}: invalid option name"))?;
+                            parsed.spec.options.insert(option);
+                        }
+                        'A' => {
+                            let action = value
+                                .parse()
+                                .map_err(|_| format!("{builtin}: {value}: invalid action name"))?;
+                            parsed.spec.actions.insert(action);
+                        }
+                        'W' => parsed.spec.words = Some(value),
+                        'F' => parsed.spec.function = Some(value),
+                        _ => parsed.spec.command = Some(value),
+                    }
+                    break;
+                }
+            };
+            parsed.spec.actions.insert(action);
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// complete registers how the arguments of the named commands are completed.
+///  - `complete -W words|-F function|-C command|-A action|-o option name...` adds specs.
+///  - `complete -p [name...]` or `complete` prints specs in a reusable form.
+///  - `complete -r [name...]` removes specs, or all of them if no name is given.
+pub(crate) fn complete<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let parsed = match parse_spec_args("complete", args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            write_and_flush_str(&mut w.err, &e)?;
+            return Ok(2);
+        }
+    };
+
+    if parsed.remove {
+        if parsed.operands.is_empty() {
+            shell.completions.clear();
+        }
+        for name in parsed.operands {
+            shell.completions.remove(name);
+        }
+        return Ok(0);
+    }
+
+    if parsed.print || args.is_empty() {
+        let mut names: Vec<String> = match parsed.operands.is_empty() {
+            true => shell.completions.keys().cloned().collect(),
+            false => parsed.operands.iter().map(|n| n.to_string()).collect(),
+        };
+        names.sort();
+
+        let mut status = 0;
+        for name in names {
+            match shell.completions.get(&name) {
+                Some(spec) => write_and_flush_str(&mut w.out, &spec.to_command(&name))?,
+                None => {
+                    write_and_flush_str(
+                        &mut w.err,
+                        &format!("complete: {name}: no completion specification"),
+                    )?;
+                    status = 1;
+                }
+            }
+        }
+        return Ok(status);
+    }
+
+    if parsed.operands.is_empty() {
+        write_and_flush_str(
+            &mut w.err,
+            "complete: usage: complete [-pr] [-o option] [-A action] [-W wordlist] [-F function] [-C command] [name ...]",
+        )?;
+        return Ok(2);
+    }
+    for name in parsed.operands {
+        shell
+            .completions
+            .insert(name.to_string(), parsed.spec.clone());
+    }
+    Ok(0)
+}
+
+/// compgen prints the candidates the options would generate for the word, one per line.
+pub(crate) fn compgen<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let parsed = match parse_spec_args("compgen", args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            write_and_flush_str(&mut w.err, &e)?;
+            return Ok(2);
+        }
+    };
+
+    let word = parsed.operands.first().unwrap_or(&"").to_string();
+    let context = CompContext {
+        line: word.clone(),
+        point: word.len(),
+        words: vec![word.clone()],
+        cword: 0,
+    };
+    let mut candidates = generate(shell, &parsed.spec, &context)?;
+    if candidates.is_empty() && parsed.spec.options.contains(&CompOption::Default) {
+        candidates = action_candidates(shell, Action::File, &word);
+    }
+
+    for candidate in &candidates {
+        write_and_flush_str(&mut w.out, candidate)?;
+    }
+    Ok(if candidates.is_empty() { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod word_test {
+    use crate::completion::Word;
+
+    #[test]
+    fn test_empty_line() {
+        let word = Word::parse("");
+        assert_eq!(word.start, 0);
+        assert_eq!(word.text, "");
+        assert!(word.command_position);
+    }
+
+    #[test]
+    fn test_command() {
+        let word = Word::parse("ech");
+        assert_eq!(word.start, 0);
+        assert_eq!(word.text, "ech");
+        assert!(word.command_position);
+    }
+
+    #[test]
+    fn test_argument() {
+        let word = Word::parse("cat src/li");
+        assert_eq!(word.start, 4);
+        assert_eq!(word.text, "src/li");
+        assert!(!word.command_position);
+    }
+
+    #[test]
+    fn test_new_argument() {
+        let word = Word::parse("cat ");
+        assert_eq!(word.start, 4);
+        assert_eq!(word.text, "");
+        assert!(!word.command_position);
+    }
+
+    #[test]
+    fn test_after_operators() {
+        assert!(Word::parse("ls | gr").command_position);
+        assert!(Word::parse("make && ./ru").command_position);
+        assert!(Word::parse("cd src; ca").command_position);
+        assert!(Word::parse("FOO=bar ca").command_position);
+        assert!(!Word::parse("echo hi > fi").command_position);
+    }
+
+    #[test]
+    fn test_quoted() {
+        let word = Word::parse("cat \"my fi");
+        assert_eq!(word.start, 4);
+        assert_eq!(word.text, "my fi");
+        assert_eq!(word.quote, Some('"'));
+
+        let word = Word::parse("cat 'it''s a");
+        assert_eq!(word.start, 4);
+        assert_eq!(word.text, "its a");
+        assert_eq!(word.quote, Some('\''));
+    }
+
+    #[test]
+    fn test_escaped() {
+        let word = Word::parse(r"cat my\ fi");
+        assert_eq!(word.start, 4);
+        assert_eq!(word.text, "my fi");
+        assert_eq!(word.quote, None);
+    }
+
+    #[test]
+    fn test_command_words() {
+        let word = Word::parse("cd /tmp && FOO=1 git 'check out' --f");
+        assert_eq!(word.words, vec!["FOO=1", "git", "check out"]);
+        assert_eq!(word.command(), Some("git"));
+        assert_eq!(word.text, "--f");
+    }
+
+    #[test]
+    fn test_quote_replacement() {
+        let word = Word::parse("cat my");
+        assert_eq!(word.quote_replacement("my file"), r"my\ file");
+        assert_eq!(word.quote_replacement("~/my dir"), r"~/my\ dir");
+
+        let word = Word::parse("cat \"my");
+        assert_eq!(word.quote_replacement("my $file"), "\"my \\$file");
+
+        let word = Word::parse("cat 'my");
+        assert_eq!(word.quote_replacement("my 'file"), r"'my '\''file");
+    }
+}
+
+#[cfg(test)]
+mod spec_test {
+    use crate::{
+        completion::{generate, parse_spec_args, Action, CompContext, CompOption},
+        shell::Shell,
+    };
+
+    fn context(words: &[&str]) -> CompContext {
+        let line = words.join(" ");
+        CompContext {
+            point: line.len(),
+            line,
+            words: words.iter().map(|w| w.to_string()).collect(),
+            cword: words.len() - 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_spec_args() {
+        let parsed = parse_spec_args(
+            "complete",
+            &["-o", "nospace", "-fA", "directory", "-W", "a b", "foo"],
+        )
+        .unwrap();
+        assert!(parsed.spec.options.contains(&CompOption::Nospace));
+        assert!(parsed.spec.actions.contains(&Action::File));
+        assert!(parsed.spec.actions.contains(&Action::Directory));
+        assert_eq!(parsed.spec.words.as_deref(), Some("a b"));
+        assert_eq!(parsed.operands, vec!["foo"]);
+        assert_eq!(
+            parsed.spec.to_command("foo"),
+            "complete -o nospace -A directory -A file -W 'a b' foo"
+        );
+
+        assert_eq!(
+            parse_spec_args("compgen", &["-p"]).err().unwrap(),
+            "compgen: -p: invalid option"
+        );
+        assert_eq!(
+            parse_spec_args("complete", &["-A", "nope"]).err().unwrap(),
+            "complete: nope: invalid action name"
+        );
+        assert_eq!(
+            parse_spec_args("complete", &["-F"]).err().unwrap(),
+            "complete: -F: option requires an argument"
+        );
+    }
+
+    #[test]
+    fn test_word_list() {
+        let mut shell = Shell::new();
+        shell.set_var("SUBCOMMANDS", "status stash commit");
+        let spec = parse_spec_args("complete", &["-W", "$SUBCOMMANDS"])
+            .unwrap()
+            .spec;
+        assert_eq!(
+            generate(&mut shell, &spec, &context(&["git", "st"])).unwrap(),
+            vec!["stash", "status"]
+        );
+    }
+
+    #[test]
+    fn test_function() {
+        let mut shell = Shell::new();
+        shell
+            .run(r#"_f() { COMPREPLY="$1:$2:$3 $COMP_CWORD $(compgen -W 'x y' -- y)"; }"#)
+            .unwrap();
+        let spec = parse_spec_args("complete", &["-F", "_f"]).unwrap().spec;
+        assert_eq!(
+            generate(&mut shell, &spec, &context(&["cmd", "prev", "cu"])).unwrap(),
+            vec!["2", "cmd:cu:prev", "y"]
+        );
+        assert_eq!(shell.var("COMPREPLY"), None);
+    }
+
+    #[test]
+    fn test_command() {
+        let mut shell = Shell::new();
+        let spec = parse_spec_args("complete", &["-C", "printf '%s\\n' $COMP_LINE"])
+            .unwrap()
+            .spec;
+        assert_eq!(
+            generate(&mut shell, &spec, &context(&["cmd", "a"])).unwrap(),
+            vec!["a", "cmd"]
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::fs::{FileTypeExt as _, MetadataExt as _, PermissionsExt as _},
+};
+
+use crate::{
+    arith,
+    builtin::Output,
+    expand,
+    parser::{self, CondExpr},
+    pattern::{self, Regex},
+    shell::Shell,
+    util::write_and_flush_str,
+};
+
+/// test evaluates the expression made of the arguments, with a status of 0 if it's true,
+/// 1 if it's false and 2 if it's invalid.
+///  - `-e file`, `-f file`, `-d file` and the other file operators check its type,
+///    permissions and other attributes.
+///  - `-z string` and `-n string` check if the string is empty or not, and a string
+///    on its own if it's not empty.
+///  - `a = b`, `a != b`, `a < b` and `a > b` compare strings.
+///  - `a -eq b`, `a -ne b`, `a -lt b`, `a -le b`, `a -gt b` and `a -ge b` compare integers.
+///  - `a -nt b`, `a -ot b` and `a -ef b` compare files.
+///  - `-v name` checks if the variable, or the element `name[subscript]`, is set, and
+///    `-o name` if the option is on.
+///  - `!`, `-a`, `-o` and parentheses negate and combine expressions.
+///
+/// Like POSIX says, what the arguments mean depends on how many there are, so that
+/// `test ! = x` compares `!` with `x`.
+pub(crate) fn test<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    run_test(shell, w, "test", args)
+}
+
+/// bracket is `test` in the form `[ expression ]`.
+pub(crate) fn bracket<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    match args.split_last() {
+        Some((&"]", args)) => run_test(shell, w, "[", args),
+        _ => {
+            write_and_flush_str(&mut w.err, "[: missing `]'")?;
+            Ok(2)
+        }
+    }
+}
+
+fn run_test<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    name: &str,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    match evaluate_test(shell, args) {
+        Ok(result) => Ok(i32::from(!result)),
+        Err(e) => {
+            write_and_flush_str(&mut w.err, &format!("{name}: {e}"))?;
+            Ok(2)
+        }
+    }
+}
+
+/// evaluate_test evaluates the arguments of `test`, which mean different things depending
+/// on how many there are.
+fn evaluate_test(shell: &mut Shell, args: &[&str]) -> Result<bool, String> {
+    let is_binary =
+        |op: &str| (parser::is_binary_operator(op) && op != "=~") || op == "-a" || op == "-o";
+    match *args {
+        [] => Ok(false),
+        [arg] => Ok(!arg.is_empty()),
+        ["!", arg] => Ok(arg.is_empty()),
+        [op, operand] if parser::is_unary_operator(op) => unary(shell, op, operand),
+        [op, _] => Err(format!("{op}: unary operator expected")),
+        [left, op, right] if is_binary(op) => match op {
+            "-a" => Ok(!left.is_empty() && !right.is_empty()),
+            "-o" => Ok(!left.is_empty() || !right.is_empty()),
+            _ => binary(left, op, right),
+        },
+        ["!", ..] if args.len() <= 4 => evaluate_test(shell, &args[1..]).map(|result| !result),
+        ["(", arg, ")"] => Ok(!arg.is_empty()),
+        [_, op, _] => Err(format!("{op}: binary operator expected")),
+        ["(", _, _, ")"] => evaluate_test(shell, &args[1..3]),
+        _ => {
+            let mut parser = TestParser {
+                shell,
+                args,
+                pos: 0,
+            };
+            let result = parser.parse_or()?;
+            match args.get(parser.pos) {
+                Some(_) => Err("too many arguments".into()),
+                None => Ok(result),
+            }
+        }
+    }
+}
+
+/// Parser of `test` expressions with more than four arguments, which evaluates them as
+/// it goes.
+struct TestParser<'a> {
+    shell: &'a mut Shell,
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.args.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let arg = self.args.get(self.pos).copied();
+        self.pos += 1;
+        arg.ok_or_else(|| "argument expected".into())
+    }
+
+    fn parse_or(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_and()?;
+        while self.peek() == Some("-o") {
+            self.pos += 1;
+            result |= self.parse_and()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_term()?;
+        while self.peek() == Some("-a") {
+            self.pos += 1;
+            result &= self.parse_term()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_term(&mut self) -> Result<bool, String> {
+        let arg = self.next()?;
+        if self.pos < self.args.len() {
+            if let Some(op) = self
+                .peek()
+                .filter(|op| parser::is_binary_operator(op) && *op != "=~")
+            {
+                self.pos += 1;
+                let right = self.next()?;
+                return binary(arg, op, right);
+            }
+        }
+        match arg {
+            "!" => self.parse_term().map(|result| !result),
+            "(" => {
+                let result = self.parse_or()?;
+                match self.next() {
+                    Ok(")") => Ok(result),
+                    _ => Err("`)' expected".into()),
+                }
+            }
+            op if parser::is_unary_operator(op) && self.pos < self.args.len() => {
+                let operand = self.next()?;
+                unary(self.shell, op, operand)
+            }
+            arg => Ok(!arg.is_empty()),
+        }
+    }
+}
+
+/// evaluate_conditional evaluates the expression of `[[ ... ]]`. Its words are expanded
+/// without field splitting, and only when needed, so `&&` and `||` short-circuit. The
+/// right side of `==` and `!=` is a glob pattern, and that of `=~` an extended regular
+/// expression whose match and groups are assigned to `BASH_REMATCH`. The operands of
+/// `-eq` and the other integer comparisons are arithmetic expressions.
+pub(crate) fn evaluate_conditional(shell: &mut Shell, expr: &CondExpr) -> Result<bool, String> {
+    match expr {
+        CondExpr::Word(word) => Ok(!expand::expand_string(shell, word)?.is_empty()),
+        CondExpr::Unary(op, word) => {
+            let operand = expand::expand_string(shell, word)?;
+            unary(shell, op, &operand)
+        }
+        CondExpr::Binary(left, op, right) => {
+            let left = expand::expand_string(shell, left)?;
+            match op.as_str() {
+                "=" | "==" | "!=" => {
+                    let pattern = expand::expand_pattern(shell, right, pattern::GLOB_CHARS)?;
+                    Ok(pattern::glob_match(&pattern, &left) == (op != "!="))
+                }
+                "=~" => {
+                    let pattern = expand::expand_pattern(shell, right, pattern::REGEX_CHARS)?;
+                    let regex = Regex::new(&pattern).map_err(|e| format!("{pattern}: {e}"))?;
+                    let captures = regex.captures(&left);
+                    let matched = captures.is_some();
+                    shell.set_array("BASH_REMATCH", captures.unwrap_or_default());
+                    Ok(matched)
+                }
+                // Unlike with `test`, the operands are arithmetic expressions
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let right = expand::expand_string(shell, right)?;
+                    let left = arith::evaluate(shell, &left)?;
+                    Ok(compare_integers(left, op, arith::evaluate(shell, &right)?))
+                }
+                _ => {
+                    let right = expand::expand_string(shell, right)?;
+                    binary(&left, op, &right)
+                }
+            }
+        }
+        CondExpr::Not(expr) => evaluate_conditional(shell, expr).map(|result| !result),
+        CondExpr::And(left, right) => {
+            Ok(evaluate_conditional(shell, left)? && evaluate_conditional(shell, right)?)
+        }
+        CondExpr::Or(left, right) => {
+            Ok(evaluate_conditional(shell, left)? || evaluate_conditional(shell, right)?)
+        }
+    }
+}
+
+/// unary evaluates an expression with a unary operator.
+fn unary(shell: &mut Shell, op: &str, operand: &str) -> Result<bool, String> {
+    let result = match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-v" => match parser::split_subscript(operand) {
+            Some((name, Some(subscript), "")) => shell.element(name, subscript)?.is_some(),
+            _ => shell.var(operand).is_some(),
+        },
+        "-o" => shell.editor.edit_mode.to_string() == operand,
+        "-t" => match operand.trim().parse::<i32>() {
+            Ok(fd) => unsafe { libc::isatty(fd) == 1 },
+            Err(_) => false,
+        },
+        "-h" | "-L" => fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-r" => is_accessible(operand, libc::R_OK),
+        "-w" => is_accessible(operand, libc::W_OK),
+        "-x" => is_accessible(operand, libc::X_OK),
+        _ => {
+            let Ok(metadata) = fs::metadata(operand) else {
+                return Ok(false);
+            };
+            let file_type = metadata.file_type();
+            match op {
+                "-a" | "-e" => true,
+                "-b" => file_type.is_block_device(),
+                "-c" => file_type.is_char_device(),
+                "-d" => file_type.is_dir(),
+                "-f" => file_type.is_file(),
+                "-p" => file_type.is_fifo(),
+                "-S" => file_type.is_socket(),
+                "-s" => metadata.len() > 0,
+                "-g" => metadata.permissions().mode() & libc::S_ISGID != 0,
+                "-u" => metadata.permissions().mode() & libc::S_ISUID != 0,
+                "-k" => metadata.permissions().mode() & libc::S_ISVTX != 0,
+                "-O" => metadata.uid() == unsafe { libc::geteuid() },
+                "-G" => metadata.gid() == unsafe { libc::getegid() },
+                "-N" => {
+                    (metadata.mtime(), metadata.mtime_nsec())
+                        > (metadata.atime(), metadata.atime_nsec())
+                }
+                _ => return Err(format!("{op}: unary operator expected")),
+            }
+        }
+    };
+    Ok(result)
+}
+
+/// is_accessible returns whether the file can be accessed in the mode with the effective
+/// user and group ids.
+fn is_accessible(path: &str, mode: libc::c_int) -> bool {
+    match CString::new(path) {
+        Ok(path) => unsafe {
+            libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0
+        },
+        Err(_) => false,
+    }
+}
+
+/// binary evaluates an expression with a binary operator comparing strings, integers or
+/// files.
+fn binary(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    let integer = |s: &str| {
+        s.trim()
+            .parse::<i64>()
+            .map_err(|_| format!("{s}: integer expression expected"))
+    };
+    let modified = |path: &str| fs::metadata(path).map(|m| (m.mtime(), m.mtime_nsec())).ok();
+
+    let result = match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+            compare_integers(integer(left)?, op, integer(right)?)
+        }
+        // A file that exists is newer than one that doesn't
+        "-nt" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left > right,
+            (left, _) => left.is_some(),
+        },
+        "-ot" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left < right,
+            (_, right) => right.is_some(),
+        },
+        "-ef" => match (fs::metadata(left), fs::metadata(right)) {
+            (Ok(left), Ok(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        },
+        _ => return Err(format!("{op}: binary operator expected")),
+    };
+    Ok(result)
+}
+
+/// compare_integers evaluates an expression with one of the integer comparison
+/// operators.
+fn compare_integers(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+#[cfg(test)]
+mod cond_test {
+    use std::fs;
+
+    use crate::{shell::Shell, util::TempDir};
+
+    fn status(shell: &mut Shell, input: &str) -> String {
+        shell.capture(&format!("{input}; echo $?")).unwrap()
+    }
+
+    #[test]
+    fn test_test() {
+        let mut shell = Shell::new();
+        assert_eq!(status(&mut shell, "test"), "1");
+        assert_eq!(status(&mut shell, "test ''"), "1");
+        assert_eq!(status(&mut shell, "[ -n x ]"), "0");
+        assert_eq!(status(&mut shell, "[ ! = x ]"), "1");
+        assert_eq!(status(&mut shell, "[ ! -z '' ]"), "1");
+        assert_eq!(status(&mut shell, "[ 10 -gt 9 -a abc \\< abd ]"), "0");
+        assert_eq!(status(&mut shell, "[ ! '(' a = b -o 1 -eq 2 ')' ]"), "0");
+        assert_eq!(
+            status(&mut shell, "test x -eq 1 2>&1"),
+            "test: x: integer expression expected\n2"
+        );
+        assert_eq!(status(&mut shell, "[ a 2>&1"), "[: missing `]'\n2");
+        assert_eq!(
+            status(&mut shell, "[ a b c ] 2>&1"),
+            "[: b: binary operator expected\n2"
+        );
+        assert_eq!(
+            status(&mut shell, "FOO=; [ -v FOO -a ! -v NO_SUCH_VAR ]"),
+            "0"
+        );
+    }
+
+    #[test]
+    fn test_file_operators() {
+        let dir = TempDir::new("cond_test");
+        let file = dir.join("file");
+        fs::write(&file, "x").unwrap();
+        let (dir, file) = (dir.display(), file.display());
+        let mut shell = Shell::new();
+        assert_eq!(
+            status(
+                &mut shell,
+                &format!("[ -e {file} -a -f {file} -a -s {file} -a -r {file} ]")
+            ),
+            "0"
+        );
+        assert_eq!(
+            status(
+                &mut shell,
+                &format!("[ -d {file} -o -x {file} -o -L {file} ]")
+            ),
+            "1"
+        );
+        assert_eq!(
+            status(
+                &mut shell,
+                &format!("[ -d {dir} ] && [ {file} -ef {file} ]")
+            ),
+            "0"
+        );
+        assert_eq!(
+            status(&mut shell, &format!("[ {file} -nt /no/such/file ]")),
+            "0"
+        );
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mut shell = Shell::new();
+        shell.run("x='a b*'; empty=").unwrap();
+        assert_eq!(status(&mut shell, "[[ $x == 'a b*' && -z $empty ]]"), "0");
+        assert_eq!(
+            status(&mut shell, "[[ $x == a* ]] && [[ $x != \"a \"* ]]"),
+            "1"
+        );
+        assert_eq!(
+            status(&mut shell, "[[ ab == \"a*\" || ( ! -n $empty && b < c ) ]]"),
+            "0"
+        );
+        assert_eq!(status(&mut shell, "[[ $empty ]]"), "1");
+        assert_eq!(status(&mut shell, "[[ 2 -lt 10 && 2 > 10 ]]"), "0");
+        assert_eq!(status(&mut shell, "[[ -v empty && ! -v nothing ]]"), "0");
+    }
+
+    #[test]
+    fn test_regex_match() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            shell
+                .capture(
+                    "[[ 'key=value 12' =~ ^([a-z]+)=(.*)\\ ([0-9]+|x)$ ]]; echo $? ${BASH_REMATCH[@]}"
+                )
+                .unwrap(),
+            "0 key=value 12 key value 12"
+        );
+        assert_eq!(
+            status(&mut shell, "[[ a.c =~ 'a.c' && ! abc =~ \"a.c\" ]]"),
+            "0"
+        );
+        assert_eq!(status(&mut shell, "[[ abc =~ [b ]] 2>/dev/null"), "2");
+    }
+}
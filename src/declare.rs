@@ -0,0 +1,455 @@
+use std::{collections::BTreeMap, io};
+
+use crate::{
+    builtin::Output,
+    expand,
+    parser::{self, AssignedValue, Assignment},
+    shell::Shell,
+    util::write_and_flush_str,
+    variable::{self, Value},
+};
+
+/// The flags of `declare` that set attributes, which `+` instead of `-` removes.
+const ATTRIBUTE_FLAGS: &str = "aAilnrux";
+
+/// declare declares variables, giving them values and the attributes of the flags.
+///  - `-a` and `-A` make indexed and associative arrays.
+///  - `-i` evaluates assigned values as arithmetic expressions.
+///  - `-l` and `-u` convert assigned values to lowercase and uppercase.
+///  - `-n` makes them refer to the variable named by their value.
+///  - `-r` makes them readonly and `-x` exports them.
+///
+/// `-p` prints them as `declare` commands instead, as is done for all variables, or
+/// those with the attributes, if there are no names. In a function, the variables are
+/// local to it unless `-g` is given.
+pub(crate) fn declare<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    run(shell, w, "declare", args)
+}
+
+/// typeset is another name for declare.
+pub(crate) fn typeset<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    run(shell, w, "typeset", args)
+}
+
+/// local declares variables that are only visible in the running function, taking the
+/// same flags as declare. A variable declared without a value keeps its current one.
+pub(crate) fn local<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    if !shell.in_function() {
+        write_and_flush_str(&mut w.err, "local: can only be used in a function")?;
+        return Ok(1);
+    }
+    run(shell, w, "local", args)
+}
+
+/// unset unsets the variables, or the elements of `name[subscript]`, and functions.
+///  - `-v` only unsets variables, and `-f` only functions. Without either, a function
+///    is unset if there is no variable with the name.
+///  - `-n` unsets name references themselves instead of what they refer to.
+pub(crate) fn unset<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let (mut functions, mut variables, mut nameref) = (false, false, false);
+    let mut idx = 0;
+    while let Some(flags) = args.get(idx).and_then(|arg| arg.strip_prefix('-')) {
+        idx += 1;
+        if flags == "-" {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'f' => functions = true,
+                'v' => variables = true,
+                'n' => nameref = true,
+                _ => {
+                    write_and_flush_str(&mut w.err, &format!("unset: -{flag}: invalid option"))?;
+                    write_and_flush_str(
+                        &mut w.err,
+                        "unset: usage: unset [-f] [-v] [-n] [name ...]",
+                    )?;
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    if functions && variables {
+        write_and_flush_str(
+            &mut w.err,
+            "unset: cannot simultaneously unset a function and a variable",
+        )?;
+        return Ok(1);
+    }
+
+    let mut status = 0;
+    for arg in &args[idx..] {
+        if functions {
+            shell.remove_function(arg);
+            continue;
+        }
+        let result = match parser::split_subscript(arg) {
+            Some((name, None, "")) if nameref && shell.raw_variable(name).is_some() => {
+                match shell
+                    .raw_variable(name)
+                    .is_some_and(|var| var.attributes.readonly)
+                {
+                    true => Err(format!("{name}: cannot unset: readonly variable")),
+                    false => {
+                        shell.unset_var(name);
+                        Ok(())
+                    }
+                }
+            }
+            Some((name, None, "")) if !variables && shell.variable(name).is_none() => {
+                shell.remove_function(name);
+                Ok(())
+            }
+            Some((name, subscript, "")) => shell.unset(name, subscript),
+            _ => Err(format!("`{arg}': not a valid identifier")),
+        };
+        if let Err(e) = result {
+            write_and_flush_str(&mut w.err, &format!("unset: {e}"))?;
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// The flags given to `declare`, `typeset` or `local`.
+#[derive(Default)]
+struct Flags {
+    /// The attribute flags given with `-`.
+    on: String,
+    /// The attribute flags given with `+`.
+    off: String,
+    print: bool,
+    global: bool,
+}
+
+impl Flags {
+    fn has(&self, flag: char) -> bool {
+        self.on.contains(flag)
+    }
+}
+
+/// run runs `declare` or one of the builtins like it, named `builtin` in messages.
+fn run<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    builtin: &str,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let mut flags = Flags::default();
+    let mut idx = 0;
+    while let Some(arg) = args
+        .get(idx)
+        .filter(|arg| arg.len() > 1 && arg.starts_with(['-', '+']))
+    {
+        idx += 1;
+        if *arg == "--" {
+            break;
+        }
+        let on = arg.starts_with('-');
+        for flag in arg[1..].chars() {
+            match flag {
+                flag if ATTRIBUTE_FLAGS.contains(flag) => match on {
+                    true => flags.on.push(flag),
+                    false => flags.off.push(flag),
+                },
+                'p' => flags.print = true,
+                'g' if builtin != "local" => flags.global = true,
+                _ => {
+                    let sign = if on { '-' } else { '+' };
+                    write_and_flush_str(
+                        &mut w.err,
+                        &format!("{builtin}: {sign}{flag}: invalid option"),
+                    )?;
+                    let usage = match builtin {
+                        "local" => "local: usage: local [option] name[=value] ...".to_string(),
+                        _ => {
+                            format!("{builtin}: usage: {builtin} [-aAgilnprux] [name[=value] ...]")
+                        }
+                    };
+                    write_and_flush_str(&mut w.err, &usage)?;
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    let names = &args[idx..];
+
+    if names.is_empty() {
+        let mut names = shell.var_names();
+        names.sort();
+        for name in names {
+            let Some(var) = shell.raw_variable(&name) else {
+                continue;
+            };
+            if flags.on.chars().all(|flag| var.flags().contains(flag)) {
+                write_and_flush_str(&mut w.out, &variable::declaration(&name, var))?;
+            }
+        }
+        return Ok(0);
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let result = match flags.print {
+            true => match shell.raw_variable(arg) {
+                Some(var) => {
+                    write_and_flush_str(&mut w.out, &variable::declaration(arg, var))?;
+                    Ok(())
+                }
+                None => Err(format!("{arg}: not found")),
+            },
+            false => declare_one(shell, &flags, builtin, arg),
+        };
+        if let Err(e) = result {
+            write_and_flush_str(&mut w.err, &format!("{builtin}: {e}"))?;
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// declare_one declares the variable of `name` or `name=value`.
+fn declare_one(shell: &mut Shell, flags: &Flags, builtin: &str, arg: &str) -> Result<(), String> {
+    let assignment = parser::parse_assignment(arg)?;
+    let name = match &assignment {
+        Some(assignment) => assignment.name.clone(),
+        None if parser::is_name(arg) => arg.to_string(),
+        None => return Err(format!("`{arg}': not a valid identifier")),
+    };
+    if builtin == "local" || (shell.in_function() && !flags.global) {
+        shell.make_local(&name)?;
+    }
+
+    // With -n or +n, the name reference itself is declared, not what it refers to
+    let nameref = flags.has('n') || flags.off.contains('n');
+    let name = match nameref {
+        true => name,
+        false => shell.resolve(&name),
+    };
+    let current = shell.raw_variable(&name).cloned();
+    let readonly = current.as_ref().is_some_and(|var| var.attributes.readonly);
+    if readonly && (assignment.is_some() || !flags.off.is_empty()) {
+        return Err(format!("{name}: readonly variable"));
+    }
+    let converted = match (
+        current.and_then(|var| var.value),
+        flags.has('a'),
+        flags.has('A'),
+    ) {
+        (Some(Value::Associative(_)), true, _) => {
+            return Err(format!(
+                "{name}: cannot convert associative to indexed array"
+            ));
+        }
+        (Some(Value::Indexed(_)), _, true) => {
+            return Err(format!(
+                "{name}: cannot convert indexed to associative array"
+            ));
+        }
+        (Some(Value::Scalar(value)), true, _) => Some(Value::indexed(vec![value])),
+        (Some(Value::Scalar(value)), _, true) => Some(Value::Associative(BTreeMap::from([(
+            "0".to_string(),
+            value,
+        )]))),
+        (None, true, _) => Some(Value::Indexed(BTreeMap::new())),
+        (None, _, true) => Some(Value::Associative(BTreeMap::new())),
+        _ => None,
+    };
+
+    let var = shell.variable_entry(&name);
+    if converted.is_some() {
+        var.value = converted;
+    }
+    for (flag, on) in flags
+        .on
+        .chars()
+        .map(|flag| (flag, true))
+        .chain(flags.off.chars().map(|flag| (flag, false)))
+    {
+        let attributes = &mut var.attributes;
+        match flag {
+            'i' => attributes.integer = on,
+            'l' => (attributes.lowercase, attributes.uppercase) = (on, attributes.uppercase && !on),
+            'u' => (attributes.uppercase, attributes.lowercase) = (on, attributes.lowercase && !on),
+            'n' => attributes.nameref = on,
+            'x' => attributes.exported = on,
+            _ => (),
+        }
+    }
+
+    let result = match assignment {
+        Some(Assignment {
+            value: AssignedValue::Scalar(target),
+            ..
+        }) if flags.has('n') => match expand::expand_assignment(shell, &target) {
+            Ok(target)
+                if parser::split_subscript(&target).is_some_and(|(_, _, rest)| rest.is_empty()) =>
+            {
+                shell.variable_entry(&name).value = Some(Value::Scalar(target));
+                Ok(())
+            }
+            Ok(target) => Err(format!(
+                "`{target}': invalid variable name for name reference"
+            )),
+            Err(e) => Err(e),
+        },
+        Some(assignment) => shell.execute_assignment(&Assignment {
+            name: name.clone(),
+            ..assignment
+        }),
+        None => Ok(()),
+    };
+    if result.is_ok() && flags.has('r') {
+        shell.variable_entry(&name).attributes.readonly = true;
+    }
+    result
+}
+
+#[cfg(test)]
+mod declare_test {
+    use crate::shell::Shell;
+
+    fn run(shell: &mut Shell, input: &str) -> String {
+        shell.capture(input).unwrap()
+    }
+
+    #[test]
+    fn test_declare() {
+        let mut shell = Shell::new();
+        shell.run("declare -a a=(x 'y z' [5]=w); declare b='(p q)'; declare -A m; m[k]=v; m[a b]=\\\"").unwrap();
+        assert_eq!(
+            run(&mut shell, "declare -p a b m"),
+            "declare -a a=([0]=\"x\" [1]=\"y z\" [5]=\"w\")\ndeclare -- b=\"(p q)\"\ndeclare -A m=([\"a b\"]=\"\\\"\" [k]=\"v\" )"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "declare -i n=3+4; n+=2; declare -lx s=ABC; declare -p n s"
+            ),
+            "declare -i n=\"9\"\ndeclare -xl s=\"abc\""
+        );
+        assert_eq!(
+            run(&mut shell, "declare -u u; u=up; declare -p u; echo $u"),
+            "declare -u u=\"UP\"\nUP"
+        );
+        assert_eq!(
+            run(&mut shell, "declare -p nope 2>&1; echo $?"),
+            "declare: nope: not found\n1"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "declare -A a 2>&1; x=1; declare -A x; declare -p x"
+            ),
+            "declare: a: cannot convert indexed to associative array\ndeclare -A x=([0]=\"1\" )"
+        );
+        assert_eq!(
+            run(&mut shell, "declare -q 2>&1; echo $?").lines().last(),
+            Some("2")
+        );
+    }
+
+    #[test]
+    fn test_readonly_and_nameref() {
+        let mut shell = Shell::new();
+        shell
+            .run("declare -r ro=1; declare -n ref=a; ref=(x y)")
+            .unwrap();
+        assert_eq!(
+            run(&mut shell, "declare +r ro 2>&1; unset ro 2>&1; echo $ro"),
+            "declare: ro: readonly variable\nunset: ro: cannot unset: readonly variable\n1"
+        );
+        assert_eq!(
+            run(&mut shell, "printf -v ro x 2>&1; echo $? $ro"),
+            "printf: ro: readonly variable\n1 1"
+        );
+        assert_eq!(
+            run(&mut shell, "echo ${a[1]} ${ref[0]}; declare -p ref"),
+            "y x\ndeclare -n ref=\"a\""
+        );
+        assert_eq!(run(&mut shell, "unset -n ref; echo ${a[@]}"), "x y");
+        assert_eq!(
+            run(&mut shell, "declare -n bad=1 2>&1; echo $?"),
+            "declare: `1': invalid variable name for name reference\n1"
+        );
+    }
+
+    #[test]
+    fn test_local() {
+        let mut shell = Shell::new();
+        shell
+            .run("f() { local v=2 -a l=(1 2); declare d=3; declare -g g=4; echo $v ${l[1]} $d; }")
+            .unwrap();
+        assert_eq!(
+            run(&mut shell, "v=1; f; echo $v [$l] [$d] $g"),
+            "2 2 3\n1 [] [] 4"
+        );
+        assert_eq!(
+            run(&mut shell, "local x 2>&1; echo $?"),
+            "local: can only be used in a function\n1"
+        );
+        assert_eq!(
+            run(&mut shell, "f() { local 1x; }; f 2>&1"),
+            "local: `1x': not a valid identifier"
+        );
+    }
+
+    #[test]
+    fn test_unset() {
+        let mut shell = Shell::new();
+        shell.run("arr=(a b c); g() { :; }; g=1").unwrap();
+        assert_eq!(run(&mut shell, "unset 'arr[1]'; echo ${!arr[@]}"), "0 2");
+        assert_eq!(
+            run(&mut shell, "unset 'arr[@]'; declare -p arr"),
+            "declare -a arr=()"
+        );
+        assert_eq!(
+            run(&mut shell, "unset g; type g 2>&1; unset g; type g 2>&1"),
+            "g is a function\ng: not found"
+        );
+        assert_eq!(
+            run(&mut shell, "unset -f g; type g 2>&1; echo $g"),
+            "g: not found\n1"
+        );
+        assert_eq!(
+            run(&mut shell, "unset -x 2>&1; echo $?").lines().last(),
+            Some("2")
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::io;
+
+use crate::{
+    builtin::{self, Output},
+    shell::Shell,
+    util::write_and_flush_str,
+};
+
+/// entries returns the directory stack, starting with the current directory.
+pub(crate) fn entries(shell: &Shell) -> Vec<String> {
+    let mut entries = vec![shell.current_dir()];
+    entries.extend(shell.dir_stack.iter().cloned());
+    entries
+}
+
+/// entry returns the directory `~N`, `~+N` or `~-N` refers to, i.e. what `dirs +N`
+/// or `dirs -N` prints.
+pub(crate) fn entry(shell: &Shell, prefix: &str) -> Option<String> {
+    let entries = entries(shell);
+    let arg = match prefix.starts_with(['+', '-']) {
+        true => prefix.to_string(),
+        false => format!("+{prefix}"),
+    };
+    let idx = parse_index(&arg, entries.len())?.ok()?;
+    entries.into_iter().nth(idx)
+}
+
+/// parse_index parses `+N` (counting from the left of `dirs`, starting at 0) or `-N`
+/// (counting from the right) into an index of [`entries`].
+fn parse_index(arg: &str, len: usize) -> Option<Result<usize, String>> {
+    let (from_right, n) = match arg.split_at_checked(1)? {
+        ("+", n) => (false, n),
+        ("-", n) => (true, n),
+        _ => return None,
+    };
+    let n: usize = n.parse().ok()?;
+    let idx = match from_right {
+        true => len.checked_sub(n + 1),
+        false => Some(n).filter(|n| *n < len),
+    };
+    Some(idx.ok_or_else(|| format!("{arg}: directory stack index out of range")))
+}
+
+/// set_stack makes `entries[0]` the current directory and saves the rest on the stack.
+fn set_stack(shell: &mut Shell, builtin: &str, mut entries: Vec<String>) -> Result<(), String> {
+    let dir = entries.remove(0);
+    shell
+        .change_dir(&dir, false)
+        .map_err(|e| format!("{builtin}: {e}"))?;
+    shell.dir_stack = entries;
+    shell.update_dir_stack();
+    Ok(())
+}
+
+/// pushd saves the current directory on the directory stack and changes directory.
+///  - `pushd dir` pushes the current directory and changes to `dir`.
+///  - `pushd` swaps the top two directories.
+///  - `pushd +N` or `pushd -N` rotates the stack so that the Nth directory is on top.
+pub(crate) fn pushd<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    if args.len() > 1 {
+        write_and_flush_str(&mut w.err, "pushd: too many arguments")?;
+        return Ok(1);
+    }
+
+    let mut entries = entries(shell);
+    let result = match args.first() {
+        None if entries.len() < 2 => Err("pushd: no other directory".to_string()),
+        None => {
+            entries.swap(0, 1);
+            set_stack(shell, "pushd", entries)
+        }
+        Some(arg) => match parse_index(arg, entries.len()) {
+            Some(Ok(idx)) => {
+                entries.rotate_left(idx);
+                set_stack(shell, "pushd", entries)
+            }
+            Some(Err(e)) => Err(format!("pushd: {e}")),
+            None if arg.starts_with('-') && *arg != "-" => {
+                Err(format!("pushd: {arg}: invalid option"))
+            }
+            None => {
+                entries.insert(0, arg.to_string());
+                set_stack(shell, "pushd", entries)
+            }
+        },
+    };
+
+    if let Err(e) = result {
+        write_and_flush_str(&mut w.err, &e)?;
+        return Ok(1);
+    }
+    print_entries(shell, w, &Format::default())
+}
+
+/// popd removes a directory from the directory stack.
+///  - `popd` removes the top directory and changes to the new top directory.
+///  - `popd +N` or `popd -N` removes the Nth directory.
+pub(crate) fn popd<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    if args.len() > 1 {
+        write_and_flush_str(&mut w.err, "popd: too many arguments")?;
+        return Ok(1);
+    }
+
+    let mut entries = entries(shell);
+    let idx = match args.first() {
+        _ if entries.len() < 2 => Err("popd: directory stack empty".to_string()),
+        None => Ok(0),
+        Some(arg) => match parse_index(arg, entries.len()) {
+            Some(Ok(idx)) => Ok(idx),
+            Some(Err(e)) => Err(format!("popd: {e}")),
+            None => Err(format!("popd: {arg}: invalid argument")),
+        },
+    };
+    let result = idx.and_then(|idx| {
+        entries.remove(idx);
+        match idx {
+            // Removing the current directory changes to the next one
+            0 => set_stack(shell, "popd", entries),
+            _ => {
+                shell.dir_stack = entries.split_off(1);
+                shell.update_dir_stack();
+                Ok(())
+            }
+        }
+    });
+
+    if let Err(e) = result {
+        write_and_flush_str(&mut w.err, &e)?;
+        return Ok(1);
+    }
+    print_entries(shell, w, &Format::default())
+}
+
+#[derive(Debug, Default)]
+struct Format {
+    /// Print full paths instead of abbreviating the home directory as `~`.
+    long: bool,
+    /// Print one directory per line.
+    per_line: bool,
+    /// Print one directory per line, prefixed with its index.
+    verbose: bool,
+}
+
+/// dirs prints the directory stack, starting with the current directory.
+///  - `-c` clears the stack.
+///  - `-l` prints full paths, `-p` prints one directory per line.
+///  - `-v` prints one directory per line with its index.
+///  - `+N` or `-N` prints only the Nth directory.
+pub(crate) fn dirs<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let mut format = Format::default();
+    let mut index = None;
+    let mut clear = false;
+    let len = entries(shell).len();
+    for arg in args {
+        match parse_index(arg, len) {
+            Some(Ok(idx)) => {
+                index = Some(idx);
+                continue;
+            }
+            Some(Err(e)) => {
+                write_and_flush_str(&mut w.err, &format!("dirs: {e}"))?;
+                return Ok(1);
+            }
+            None => (),
+        }
+
+        let flags = match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => flags,
+            _ => {
+                write_and_flush_str(&mut w.err, &format!("dirs: {arg}: invalid argument"))?;
+                return Ok(1);
+            }
+        };
+        for flag in flags.chars() {
+            match flag {
+                'c' => clear = true,
+                'l' => format.long = true,
+                'p' => format.per_line = true,
+                'v' => format.verbose = true,
+                _ => {
+                    write_and_flush_str(&mut w.err, &format!("dirs: -{flag}: invalid option"))?;
+                    write_and_flush_str(&mut w.err, "dirs: usage: dirs [-clpv] [+N] [-N]")?;
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    if clear {
+        shell.dir_stack.clear();
+        shell.update_dir_stack();
+        return Ok(0);
+    }
+
+    match index {
+        Some(idx) => {
+            let dir = &entries(shell)[idx];
+            write_and_flush_str(&mut w.out, &display(shell, dir, &format))?;
+            Ok(0)
+        }
+        None => print_entries(shell, w, &format),
+    }
+}
+
+fn display(shell: &Shell, dir: &str, format: &Format) -> String {
+    match format.long {
+        true => dir.to_string(),
+        false => builtin::Command::replace_home_with_tilde(shell, dir),
+    }
+}
+
+fn print_entries<T, K>(shell: &Shell, w: &mut Output<T, K>, format: &Format) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let entries: Vec<String> = entries(shell)
+        .iter()
+        .map(|dir| display(shell, dir, format))
+        .collect();
+
+    if format.verbose {
+        for (idx, dir) in entries.iter().enumerate() {
+            write_and_flush_str(&mut w.out, &format!("{idx:2}  {dir}"))?;
+        }
+    } else if format.per_line {
+        for dir in &entries {
+            write_and_flush_str(&mut w.out, dir)?;
+        }
+    } else {
+        write_and_flush_str(&mut w.out, &entries.join(" "))?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod dirstack_test {
+    use std::fs;
+
+    use crate::{dirstack::parse_index, shell::Shell, util::TempDir};
+
+    #[test]
+    fn test_parse_index() {
+        assert_eq!(parse_index("+0", 3), Some(Ok(0)));
+        assert_eq!(parse_index("+2", 3), Some(Ok(2)));
+        assert_eq!(parse_index("-0", 3), Some(Ok(2)));
+        assert_eq!(parse_index("-2", 3), Some(Ok(0)));
+        assert_eq!(
+            parse_index("+3", 3),
+            Some(Err("+3: directory stack index out of range".to_string()))
+        );
+        assert_eq!(
+            parse_index("-3", 3),
+            Some(Err("-3: directory stack index out of range".to_string()))
+        );
+        assert_eq!(parse_index("-v", 3), None);
+        assert_eq!(parse_index("dir", 3), None);
+        assert_eq!(parse_index("+", 3), None);
+    }
+
+    #[test]
+    fn test_rotate_and_remove() {
+        let dir = TempDir::new("dirstack_test");
+        let root = dir.canonicalize().unwrap();
+        let root = root.to_str().unwrap();
+        for name in ["a", "b", "c"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+
+        // The shell is forked, so changing directory doesn't affect other tests
+        let mut shell = Shell::new();
+        let output = shell
+            .capture(&format!(
+                "cd {root}; pushd a >/dev/null; pushd {root}/b >/dev/null; \
+                 pushd {root}/c >/dev/null; dirs -v; pushd +2; popd +1; dirs -v; pwd"
+            ))
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                format!(" 0  {root}/c"),
+                format!(" 1  {root}/b"),
+                format!(" 2  {root}/a"),
+                format!(" 3  {root}"),
+                format!("{root}/a {root} {root}/c {root}/b"),
+                format!("{root}/a {root}/c {root}/b"),
+                format!(" 0  {root}/a"),
+                format!(" 1  {root}/c"),
+                format!(" 2  {root}/b"),
+                format!("{root}/a"),
+            ]
+            .join("\n")
+        );
+    }
+}
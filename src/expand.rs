@@ -0,0 +1,1042 @@
+use crate::{arith, dirstack, parser, shell::Shell, util, variable::Value};
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// expand_words expands every word into fields, see [`expand_word`].
+pub(crate) fn expand_words<S: AsRef<str>>(
+    shell: &mut Shell,
+    words: &[S],
+) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    for word in words {
+        fields.extend(expand_word(shell, word.as_ref())?);
+    }
+    Ok(fields)
+}
+
+/// expand_word performs tilde expansion, parameter expansion, command substitution,
+/// field splitting and quote removal on a word, which may result in zero or more fields.
+pub(crate) fn expand_word(shell: &mut Shell, word: &str) -> Result<Vec<String>, String> {
+    Expander::new(shell, true).expand(word)
+}
+
+/// expand_redirect_target expands the target of a redirection like [`expand_string`],
+/// except that it may be a process substitution, as in `< <(list)`.
+pub(crate) fn expand_redirect_target(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let mut expander = Expander::new(shell, false);
+    expander.substitute_processes = true;
+    expander.expand(word).map(|fields| fields.join(" "))
+}
+
+/// expand_assignment expands the value of a variable assignment like [`expand_string`],
+/// except that a `~` after a `:` is expanded too, as in `PATH=~/bin:~/.local/bin`.
+pub(crate) fn expand_assignment(shell: &mut Shell, value: &str) -> Result<String, String> {
+    let mut expander = Expander::new(shell, false);
+    expander.assignment = true;
+    expander.expand(value).map(|fields| fields.join(" "))
+}
+
+/// expand_string expands a word like [`expand_word`] but without field splitting,
+/// which is what assignments and redirect targets need.
+pub(crate) fn expand_string(shell: &mut Shell, word: &str) -> Result<String, String> {
+    Expander::new(shell, false)
+        .expand(word)
+        .map(|fields| fields.join(" "))
+}
+
+/// expand_array expands the words of `name=(...)` into the elements of the array, with
+/// their subscripts for words of the form `[subscript]=value`. Other words are expanded
+/// into fields like the arguments of a command.
+pub(crate) fn expand_array(
+    shell: &mut Shell,
+    words: &[String],
+) -> Result<Vec<(Option<String>, String)>, String> {
+    let mut elements = Vec::new();
+    for word in words {
+        let subscripted = word.strip_prefix('[').and_then(|inner| {
+            let end = parser::subscript_end(inner)?;
+            Some((&inner[..end], inner[end + 1..].strip_prefix('=')?))
+        });
+        match subscripted {
+            Some((subscript, value)) => {
+                let subscript = expand_string(shell, subscript)?;
+                elements.push((Some(subscript), expand_assignment(shell, value)?));
+            }
+            None => {
+                let fields = expand_word(shell, word)?;
+                elements.extend(fields.into_iter().map(|field| (None, field)));
+            }
+        }
+    }
+    Ok(elements)
+}
+
+/// expand_pattern expands a word like [`expand_string`], except that the `special`
+/// characters of a pattern are escaped with a backslash where they were quoted, so that
+/// they only match themselves. Unquoted, they keep their meaning.
+pub(crate) fn expand_pattern(
+    shell: &mut Shell,
+    word: &str,
+    special: &'static str,
+) -> Result<String, String> {
+    let mut expander = Expander::new(shell, false);
+    expander.special = special;
+    expander.expand(word).map(|fields| fields.join(" "))
+}
+
+/// expand_prompt expands parameters and command substitutions in a prompt string as
+/// if it were in double quotes, except that a `"` is kept as is.
+pub(crate) fn expand_prompt(shell: &mut Shell, prompt: &str) -> Result<String, String> {
+    let chars: Vec<char> = prompt.chars().collect();
+    let mut expander = Expander::new(shell, false);
+    expander.expand_quoted(&chars, 0, false)?;
+    Ok(expander.current)
+}
+
+/// split_fields splits `s` on the chars in `$IFS`, like unquoted expansions are split.
+pub(crate) fn split_fields(shell: &Shell, s: &str) -> Vec<String> {
+    let ifs = shell.var("IFS").unwrap_or(DEFAULT_IFS);
+    s.split(|c| ifs.contains(c))
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+struct Expander<'a> {
+    shell: &'a mut Shell,
+    /// Whether unquoted expansion results are split into fields.
+    split: bool,
+    /// Whether the word is the value of an assignment, where `~` is expanded after `:`.
+    assignment: bool,
+    /// Characters that are escaped where quoted, when expanding a pattern.
+    special: &'static str,
+    /// Whether `<(list)` and `>(list)` are process substitutions, which they are in
+    /// words of commands and redirect targets only.
+    substitute_processes: bool,
+    fields: Vec<String>,
+    current: String,
+    /// Whether the current field exists even if empty, e.g. because of `""`.
+    has_current: bool,
+}
+
+impl<'a> Expander<'a> {
+    fn new(shell: &'a mut Shell, split: bool) -> Self {
+        Self {
+            shell,
+            split,
+            assignment: false,
+            special: "",
+            substitute_processes: split,
+            fields: Vec::new(),
+            current: String::new(),
+            has_current: false,
+        }
+    }
+
+    fn expand(mut self, word: &str) -> Result<Vec<String>, String> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut idx = 0;
+
+        while idx < chars.len() {
+            let tilde_allowed = idx == 0 || (self.assignment && chars[idx - 1] == ':');
+            idx = match chars[idx] {
+                '~' if tilde_allowed => self.expand_tilde(&chars, idx),
+                '\\' => {
+                    match chars.get(idx + 1) {
+                        Some(&c) => self.push_quoted(c),
+                        // A trailing backslash has nothing to escape, so keep it
+                        None => self.push_literal('\\'),
+                    }
+                    idx + 2
+                }
+                '\'' => {
+                    let end = parser::skip_construct(&chars, idx)?.unwrap_or(chars.len());
+                    self.has_current = true;
+                    for &c in &chars[idx + 1..end - 1] {
+                        self.push_quoted(c);
+                    }
+                    end
+                }
+                '"' => self.expand_quoted(&chars, idx + 1, true)?,
+                '$' => self.expand_dollar(&chars, idx, false)?,
+                '`' => self.expand_backquote(&chars, idx, false)?,
+                '<' | '>'
+                    if self.substitute_processes
+                        && parser::is_process_substitution(&chars, idx) =>
+                {
+                    let end = parser::find_closing(&chars, idx + 2, '(', ')')?;
+                    let list: String = chars[idx + 2..end].iter().collect();
+                    let path = self.shell.process_substitution(&list, chars[idx] == '>')?;
+                    self.push_expansion(&path, true);
+                    end + 1
+                }
+                c => {
+                    self.push_literal(c);
+                    idx + 1
+                }
+            };
+        }
+
+        if self.has_current {
+            self.fields.push(self.current);
+        }
+        Ok(self.fields)
+    }
+
+    fn push_literal(&mut self, ch: char) {
+        self.current.push(ch);
+        self.has_current = true;
+    }
+
+    /// push_quoted appends a quoted char, escaping it if it's special in the pattern
+    /// being expanded.
+    fn push_quoted(&mut self, ch: char) {
+        if self.special.contains(ch) {
+            self.current.push('\\');
+        }
+        self.push_literal(ch);
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(std::mem::take(&mut self.current));
+        self.has_current = false;
+    }
+
+    /// push_expansion appends the result of an expansion, splitting it into fields
+    /// on `$IFS` if it's unquoted.
+    fn push_expansion(&mut self, value: &str, quoted: bool) {
+        if quoted || !self.split {
+            match quoted {
+                true => value.chars().for_each(|c| self.push_quoted(c)),
+                false => self.current.push_str(value),
+            }
+            self.has_current |= quoted || !value.is_empty();
+            return;
+        }
+
+        let ifs = self.shell.var("IFS").unwrap_or(DEFAULT_IFS).to_string();
+        for ch in value.chars() {
+            if !ifs.contains(ch) {
+                self.push_literal(ch);
+            } else if !ch.is_whitespace() || self.has_current {
+                // Whitespace delimiters are collapsed while others always end a field
+                self.end_field();
+            }
+        }
+    }
+
+    /// expand_tilde expands the `~` prefix at `idx`, which goes up to the first `/`,
+    /// or `:` in assignments, returning the index after it. The prefix is kept as is if
+    /// it's quoted or can't be expanded.
+    fn expand_tilde(&mut self, chars: &[char], idx: usize) -> usize {
+        let len = chars[idx + 1..]
+            .iter()
+            .take_while(|c| **c != '/' && !(self.assignment && **c == ':'))
+            .count();
+        let end = idx + 1 + len;
+        let prefix: String = chars[idx + 1..end].iter().collect();
+        if prefix.contains(['\\', '\'', '"', '$', '`']) {
+            self.push_literal('~');
+            return idx + 1;
+        }
+
+        match tilde_expansion(self.shell, &prefix) {
+            Some(dir) => {
+                // The expansion isn't split, like a quoted expansion
+                dir.chars().for_each(|c| self.push_quoted(c));
+                self.has_current = true;
+            }
+            None => {
+                self.push_literal('~');
+                self.current.push_str(&prefix);
+            }
+        }
+        end
+    }
+
+    /// expand_quoted expands the inside of a double quoted string starting at `start`,
+    /// returning the index right after the closing quote. If not `closed`, the string
+    /// goes on to the end of the chars instead.
+    fn expand_quoted(
+        &mut self,
+        chars: &[char],
+        start: usize,
+        closed: bool,
+    ) -> Result<usize, String> {
+        let had_current = self.has_current;
+        self.has_current = true;
+
+        let mut idx = start;
+        while idx < chars.len() {
+            idx = match chars[idx] {
+                '"' if closed => {
+                    // `"$@"` with no positional parameters expands to nothing at all
+                    let inner: String = chars[start..idx].iter().collect();
+                    if (inner == "$@" || inner == "${@}") && self.shell.positional().is_empty() {
+                        self.has_current = had_current;
+                    }
+                    return Ok(idx + 1);
+                }
+                '\\' => {
+                    match chars.get(idx + 1) {
+                        Some(&c @ ('\\' | '$' | '"' | '`')) => self.push_quoted(c),
+                        Some('\n') => (),
+                        Some(&c) => {
+                            self.push_quoted('\\');
+                            self.push_quoted(c);
+                        }
+                        None => self.push_quoted('\\'),
+                    }
+                    idx + 2
+                }
+                '$' => self.expand_dollar(chars, idx, true)?,
+                '`' => self.expand_backquote(chars, idx, true)?,
+                c => {
+                    self.push_quoted(c);
+                    idx + 1
+                }
+            };
+        }
+
+        match closed {
+            true => Err("quotes unfinished".into()),
+            false => Ok(idx),
+        }
+    }
+
+    /// expand_dollar expands the `$` expression at `idx`, returning the index after it.
+    fn expand_dollar(&mut self, chars: &[char], idx: usize, quoted: bool) -> Result<usize, String> {
+        match chars.get(idx + 1) {
+            Some('(') => {
+                if let Some(end) = parser::arithmetic_end(chars, idx + 1)? {
+                    let expr: String = chars[idx + 3..end - 1].iter().collect();
+                    let expr = expand_string(self.shell, &expr)?;
+                    let value = arith::evaluate(self.shell, &expr)?;
+                    self.push_expansion(&value.to_string(), quoted);
+                    return Ok(end + 1);
+                }
+                let end = parser::find_closing(chars, idx + 2, '(', ')')?;
+                let command: String = chars[idx + 2..end].iter().collect();
+                let output = self.shell.capture(&command)?;
+                self.push_expansion(&output, quoted);
+                Ok(end + 1)
+            }
+            Some('{') => {
+                let end = parser::find_closing(chars, idx + 2, '{', '}')?;
+                let expr: String = chars[idx + 2..end].iter().collect();
+                self.expand_parameter_expression(&expr, quoted)?;
+                Ok(end + 1)
+            }
+            Some(&which @ ('@' | '*')) => {
+                let params = self.shell.positional().to_vec();
+                self.expand_list(&params, which, quoted);
+                Ok(idx + 2)
+            }
+            Some(&c) if c.is_ascii_digit() || matches!(c, '?' | '#' | '$' | '!' | '-') => {
+                let value = self.shell.special_param(c);
+                self.push_expansion(&value, quoted);
+                Ok(idx + 2)
+            }
+            Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[idx + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .count();
+                let name: String = chars[idx + 1..idx + 1 + len].iter().collect();
+                let value = self.shell.var(&name).unwrap_or_default().to_string();
+                self.push_expansion(&value, quoted);
+                Ok(idx + 1 + len)
+            }
+            // Not an expansion, e.g. a lone `$`
+            _ => {
+                self.current.push('$');
+                self.has_current = true;
+                Ok(idx + 1)
+            }
+        }
+    }
+
+    /// expand_backquote runs the old style `` `command` `` substitution at `idx`.
+    fn expand_backquote(
+        &mut self,
+        chars: &[char],
+        idx: usize,
+        quoted: bool,
+    ) -> Result<usize, String> {
+        let end = parser::skip_construct(chars, idx)?.unwrap_or(chars.len());
+        let mut command = String::new();
+        let mut inner = chars[idx + 1..end - 1].iter().peekable();
+        while let Some(&c) = inner.next() {
+            // Only `\$`, `` \` `` and `\\` are escapes inside backquotes
+            match (c, inner.peek()) {
+                ('\\', Some(&&next @ ('$' | '`' | '\\'))) => {
+                    command.push(next);
+                    inner.next();
+                }
+                _ => command.push(c),
+            }
+        }
+
+        let output = self.shell.capture(&command)?;
+        self.push_expansion(&output, quoted);
+        Ok(end)
+    }
+
+    /// expand_list expands the values of `$@`, `${name[@]}` and the like into separate
+    /// fields, or for `*` joined with the first character of `$IFS` when quoted.
+    fn expand_list(&mut self, values: &[String], which: char, quoted: bool) {
+        if quoted && which == '*' {
+            let sep = match self.shell.var("IFS") {
+                Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                None => " ".into(),
+            };
+            self.push_expansion(&values.join(&sep), true);
+            return;
+        }
+        if !self.split {
+            self.push_expansion(&values.join(" "), quoted);
+            return;
+        }
+
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 && (quoted || self.has_current) {
+                self.end_field();
+            }
+            self.push_expansion(value, quoted);
+        }
+    }
+
+    /// expand_parameter_expression expands `${...}`, supporting:
+    ///  - `${name[subscript]}` for elements of arrays, and `${name[@]}` for all of them.
+    ///  - `${#param}` for the length of a value or the number of elements.
+    ///  - `${!name[@]}` for the indices or keys of an array.
+    ///  - `${param:offset}` and `${param:offset:length}` for substrings and slices.
+    ///  - The `-`, `=`, `+`, `?` operators with or without `:`.
+    fn expand_parameter_expression(&mut self, expr: &str, quoted: bool) -> Result<(), String> {
+        let bad_substitution = || format!("${{{expr}}}: bad substitution");
+
+        if let Some(reference) = expr.strip_prefix('#').filter(|r| !r.is_empty()) {
+            let len = match split_parameter(reference) {
+                Some((name, subscript, "")) => match self.lookup(name, subscript)? {
+                    Param::Value(value) => value.unwrap_or_default().chars().count(),
+                    Param::List(elements, _) => elements.len(),
+                },
+                _ => return Err(bad_substitution()),
+            };
+            self.push_expansion(&len.to_string(), quoted);
+            return Ok(());
+        }
+
+        let is_name_start = |c: char| c.is_ascii_alphabetic() || c == '_';
+        if let Some(reference) = expr
+            .strip_prefix('!')
+            .filter(|r| r.starts_with(is_name_start))
+        {
+            let (name, which) = match split_parameter(reference) {
+                Some((name, Some(which @ ("@" | "*")), "")) if parser::is_name(name) => {
+                    (name, which)
+                }
+                _ => return Err(bad_substitution()),
+            };
+            let keys = match self.shell.variable(name).and_then(|var| var.value.as_ref()) {
+                Some(value) => value.keys(),
+                None => Vec::new(),
+            };
+            self.expand_list(&keys, which.chars().next().unwrap_or('@'), quoted);
+            return Ok(());
+        }
+
+        let (name, subscript, op) = split_parameter(expr).ok_or_else(bad_substitution)?;
+        let param = self.lookup(name, subscript)?;
+        if op.is_empty() {
+            self.push_param(param, quoted);
+            return Ok(());
+        }
+
+        let (check_null, op) = match op.strip_prefix(':') {
+            Some(slice) if !slice.starts_with(['-', '=', '+', '?']) => {
+                let param = self.slice(name, param, slice)?;
+                self.push_param(param, quoted);
+                return Ok(());
+            }
+            Some(op) => (true, op),
+            None => (false, op),
+        };
+        let mut op_chars = op.chars();
+        let operator = op_chars.next().ok_or_else(bad_substitution)?;
+        let word = op_chars.as_str();
+
+        let is_set = match &param {
+            Param::Value(Some(v)) => !(check_null && v.is_empty()),
+            Param::Value(None) => false,
+            Param::List(elements, _) => match check_null {
+                true => elements.iter().any(|(_, v)| !v.is_empty()),
+                false => !elements.is_empty(),
+            },
+        };
+
+        let result = match operator {
+            '-' | '=' | '?' if is_set => {
+                self.push_param(param, quoted);
+                return Ok(());
+            }
+            '-' => expand_string(self.shell, word)?,
+            '=' => {
+                if !parser::is_name(name) || matches!(subscript, Some("@" | "*")) {
+                    return Err(format!("${name}: cannot assign in this way"));
+                }
+                let subscript = match subscript {
+                    Some(subscript) => Some(expand_string(self.shell, subscript)?),
+                    None => None,
+                };
+                let word = expand_string(self.shell, word)?;
+                self.shell
+                    .assign(name, subscript.as_deref(), &word, false)?;
+                word
+            }
+            '+' if is_set => expand_string(self.shell, word)?,
+            '+' => String::new(),
+            '?' => {
+                let message = match expand_string(self.shell, word)? {
+                    m if m.is_empty() => "parameter null or not set".to_string(),
+                    m => m,
+                };
+                return Err(format!("{name}: {message}"));
+            }
+            _ => return Err(bad_substitution()),
+        };
+        self.push_expansion(&result, quoted);
+        Ok(())
+    }
+
+    /// lookup returns the value of a parameter, or of the element of an array if there
+    /// is a subscript. `@` and `*`, and arrays subscripted with them, are lists.
+    fn lookup(&mut self, name: &str, subscript: Option<&str>) -> Result<Param, String> {
+        match (name, subscript) {
+            ("@" | "*", _) => {
+                let params = (1..).zip(self.shell.positional().iter().cloned()).collect();
+                Ok(Param::List(params, name.chars().next().unwrap_or('@')))
+            }
+            (_, None) => Ok(Param::Value(self.parameter(name))),
+            (_, Some(which @ ("@" | "*"))) => {
+                let elements = match self.shell.variable(name).and_then(|var| var.value.as_ref()) {
+                    Some(value @ Value::Indexed(_)) => value
+                        .elements()
+                        .into_iter()
+                        .map(|(idx, value)| (idx.parse().unwrap_or_default(), value))
+                        .collect(),
+                    // Elements of associative arrays are sliced by their position
+                    Some(value) => (0..).zip(value.values()).collect(),
+                    None => Vec::new(),
+                };
+                Ok(Param::List(elements, which.chars().next().unwrap_or('@')))
+            }
+            (_, Some(subscript)) => {
+                let subscript = expand_string(self.shell, subscript)?;
+                Ok(Param::Value(self.shell.element(name, &subscript)?))
+            }
+        }
+    }
+
+    /// slice applies `:offset` or `:offset:length`, which are arithmetic expressions, to
+    /// a parameter: a value is cut down to the substring, and a list to the elements
+    /// from the index offset. A negative offset counts from the end, and so does a
+    /// negative length for values.
+    fn slice(&mut self, name: &str, param: Param, spec: &str) -> Result<Param, String> {
+        let (offset, length) = match spec.split_once(':') {
+            Some((offset, length)) => (offset, Some(length)),
+            None => (spec, None),
+        };
+        let offset = expand_string(self.shell, offset)?;
+        let offset = arith::evaluate(self.shell, &offset)?;
+        let length = match length {
+            Some(length) => {
+                let length = expand_string(self.shell, length)?;
+                Some((arith::evaluate(self.shell, &length)?, length))
+            }
+            None => None,
+        };
+        let negative_length = |length: &str| format!("{}: substring expression < 0", length.trim());
+
+        match param {
+            Param::Value(None) => Ok(Param::Value(None)),
+            Param::Value(Some(value)) => {
+                let chars: Vec<char> = value.chars().collect();
+                let len = chars.len() as i64;
+                let start = if offset < 0 { len + offset } else { offset };
+                if !(0..=len).contains(&start) {
+                    return Ok(Param::Value(Some(String::new())));
+                }
+                let end = match &length {
+                    None => len,
+                    Some((n, _)) if *n < 0 => len + n,
+                    Some((n, _)) => (start + n).min(len),
+                };
+                match (end < start, length) {
+                    (true, Some((_, length))) => Err(negative_length(&length)),
+                    _ => Ok(Param::Value(Some(
+                        chars[start as usize..end as usize].iter().collect(),
+                    ))),
+                }
+            }
+            Param::List(mut elements, which) => {
+                if let Some((n, length)) = &length {
+                    if *n < 0 {
+                        return Err(negative_length(length));
+                    }
+                }
+                // `$0` comes before the positional parameters in slices
+                if matches!(name, "@" | "*") {
+                    elements.insert(0, (0, self.shell.special_param('0')));
+                }
+                let next = elements.last().map_or(0, |(idx, _)| idx + 1);
+                let start = if offset < 0 { next + offset } else { offset };
+                if start < 0 {
+                    return Ok(Param::List(Vec::new(), which));
+                }
+                let count = length.map_or(usize::MAX, |(n, _)| n as usize);
+                let elements = elements
+                    .into_iter()
+                    .filter(|(idx, _)| *idx >= start)
+                    .take(count)
+                    .collect();
+                Ok(Param::List(elements, which))
+            }
+        }
+    }
+
+    fn push_param(&mut self, param: Param, quoted: bool) {
+        match param {
+            Param::Value(value) => self.push_expansion(&value.unwrap_or_default(), quoted),
+            Param::List(elements, which) => {
+                let values: Vec<String> = elements.into_iter().map(|(_, value)| value).collect();
+                self.expand_list(&values, which, quoted);
+            }
+        }
+    }
+
+    /// parameter returns the value of a variable, positional or special parameter.
+    fn parameter(&self, name: &str) -> Option<String> {
+        if let Ok(n) = name.parse::<usize>() {
+            return match n {
+                0 => Some(self.shell.special_param('0')),
+                _ => self.shell.positional().get(n - 1).cloned(),
+            };
+        }
+        match name {
+            "?" | "#" | "$" | "!" | "-" => Some(self.shell.special_param(name.chars().next()?)),
+            _ => self.shell.var(name).map(String::from),
+        }
+    }
+}
+
+/// expand_tilde_prefix expands the `~` prefix of an unquoted path, such as one being
+/// completed, keeping the path as is if it has none or it can't be expanded.
+pub(crate) fn expand_tilde_prefix(shell: &Shell, path: &str) -> String {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return path.to_string(),
+    };
+    let (prefix, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    match tilde_expansion(shell, prefix) {
+        Some(dir) => dir + rest,
+        None => path.to_string(),
+    }
+}
+
+/// A parameter in `${...}`.
+enum Param {
+    /// A single value, which is None if it's unset.
+    Value(Option<String>),
+    /// The elements of `@` or `*`, or of an array subscripted with them, with their
+    /// indices.
+    List(Vec<(i64, String)>, char),
+}
+
+/// split_parameter splits the parameter that `expr` starts with into its name and
+/// subscript, followed by the rest of `expr`.
+fn split_parameter(expr: &str) -> Option<(&str, Option<&str>, &str)> {
+    match expr.chars().next()? {
+        c if c.is_ascii_digit() => {
+            let len = expr.chars().take_while(|c| c.is_ascii_digit()).count();
+            Some((&expr[..len], None, &expr[len..]))
+        }
+        '@' | '*' | '?' | '#' | '$' | '!' | '-' => Some((&expr[..1], None, &expr[1..])),
+        _ => parser::split_subscript(expr),
+    }
+}
+
+/// tilde_expansion returns the directory `~prefix` refers to.
+///  - `~` is `$HOME`, or the current user's home directory if it's unset.
+///  - `~user` is the home directory of the user.
+///  - `~+` is `$PWD` and `~-` is `$OLDPWD`.
+///  - `~N`, `~+N` and `~-N` are the entries of the directory stack, as shown by `dirs`.
+fn tilde_expansion(shell: &Shell, prefix: &str) -> Option<String> {
+    match prefix {
+        "" => match shell.var("HOME") {
+            Some(home) => Some(home.to_string()),
+            None => util::user_home_dir(None),
+        },
+        "+" => shell.var("PWD").map(String::from),
+        "-" => shell.var("OLDPWD").map(String::from),
+        _ if prefix
+            .trim_start_matches(['+', '-'])
+            .starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            dirstack::entry(shell, prefix)
+        }
+        user => util::user_home_dir(Some(user)),
+    }
+}
+
+#[cfg(test)]
+mod expand_test {
+    use crate::{
+        expand::{expand_assignment, expand_string, expand_tilde_prefix, expand_word},
+        shell::Shell,
+        util,
+    };
+
+    fn shell() -> Shell {
+        let mut shell = Shell::new();
+        shell.set_var("FOO", "foo");
+        shell.set_var("SPACED", "  a  b ");
+        shell.set_var("EMPTY", "");
+        shell.set_positional(vec!["one".into(), "two words".into()]);
+        shell
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut shell = shell();
+        assert_eq!(expand_word(&mut shell, "$FOO").unwrap(), vec!["foo"]);
+        assert_eq!(
+            expand_word(&mut shell, "${FOO}bar").unwrap(),
+            vec!["foobar"]
+        );
+        assert_eq!(expand_word(&mut shell, "'$FOO'").unwrap(), vec!["$FOO"]);
+        assert_eq!(expand_word(&mut shell, r"\$FOO").unwrap(), vec!["$FOO"]);
+        assert_eq!(expand_word(&mut shell, "a$").unwrap(), vec!["a$"]);
+    }
+
+    #[test]
+    fn test_field_splitting() {
+        let mut shell = shell();
+        assert_eq!(expand_word(&mut shell, "$SPACED").unwrap(), vec!["a", "b"]);
+        assert_eq!(
+            expand_word(&mut shell, "x${SPACED}y").unwrap(),
+            vec!["x", "a", "b", "y"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"$SPACED\"").unwrap(),
+            vec!["  a  b "]
+        );
+        assert!(expand_word(&mut shell, "$EMPTY").unwrap().is_empty());
+        assert!(expand_word(&mut shell, "$UNSET_VARIABLE")
+            .unwrap()
+            .is_empty());
+        assert_eq!(expand_word(&mut shell, "\"$EMPTY\"").unwrap(), vec![""]);
+        assert_eq!(expand_string(&mut shell, "$SPACED").unwrap(), "  a  b ");
+    }
+
+    #[test]
+    fn test_positionals() {
+        let mut shell = shell();
+        assert_eq!(expand_word(&mut shell, "$1").unwrap(), vec!["one"]);
+        assert_eq!(expand_word(&mut shell, "$#").unwrap(), vec!["2"]);
+        assert_eq!(
+            expand_word(&mut shell, "\"$@\"").unwrap(),
+            vec!["one", "two words"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "$@").unwrap(),
+            vec!["one", "two", "words"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"$*\"").unwrap(),
+            vec!["one two words"]
+        );
+
+        shell.set_positional(Vec::new());
+        assert!(expand_word(&mut shell, "\"$@\"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parameter_operators() {
+        let mut shell = shell();
+        assert_eq!(expand_word(&mut shell, "${#FOO}").unwrap(), vec!["3"]);
+        assert_eq!(expand_word(&mut shell, "${NOPE:-x}").unwrap(), vec!["x"]);
+        assert_eq!(
+            expand_word(&mut shell, "${EMPTY-x}").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(expand_word(&mut shell, "${EMPTY:-x}").unwrap(), vec!["x"]);
+        assert_eq!(expand_word(&mut shell, "${FOO:+set}").unwrap(), vec!["set"]);
+        assert_eq!(
+            expand_word(&mut shell, "${NEW:=$FOO}").unwrap(),
+            vec!["foo"]
+        );
+        assert_eq!(shell.var("NEW"), Some("foo"));
+        assert_eq!(
+            expand_word(&mut shell, "${NOPE:?oops}").unwrap_err(),
+            "NOPE: oops"
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${%FOO}").unwrap_err(),
+            "${%FOO}: bad substitution"
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut shell = shell();
+        shell.set_array("ARR", vec!["a".into(), "b c".into(), "d".into()]);
+        assert_eq!(
+            expand_word(&mut shell, "${ARR[1]}").unwrap(),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"${ARR[@]}\"").unwrap(),
+            vec!["a", "b c", "d"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"${ARR[*]}\"").unwrap(),
+            vec!["a b c d"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${#ARR[@]}-${#ARR[1]}-${#ARR}").unwrap(),
+            vec!["3-3-1"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"${!ARR[@]}\"").unwrap(),
+            vec!["0", "1", "2"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${ARR[-1]}${ARR[5]:-x}").unwrap(),
+            vec!["dx"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${ARR[-9]}").unwrap_err(),
+            "ARR[-9]: bad array subscript"
+        );
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut shell = shell();
+        shell.set_array("ARR", vec!["a".into(), "b".into(), "c".into()]);
+        assert_eq!(
+            expand_word(&mut shell, "\"${ARR[@]:1}\"").unwrap(),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"${ARR[@]: -2:1}\"").unwrap(),
+            vec!["b"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"${@:2}\"").unwrap(),
+            vec!["two words"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${FOO:1}-${FOO: -2:1}-${FOO:0:-1}").unwrap(),
+            vec!["oo-o-fo"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "${FOO:2:-3}").unwrap_err(),
+            "-3: substring expression < 0"
+        );
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        let mut shell = shell();
+        assert_eq!(
+            expand_word(&mut shell, "$(echo a b)").unwrap(),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "\"$(echo a b; echo)\"").unwrap(),
+            vec!["a b"]
+        );
+        assert_eq!(expand_word(&mut shell, "`echo $FOO`").unwrap(), vec!["foo"]);
+    }
+
+    #[test]
+    fn test_tilde() {
+        let mut shell = shell();
+        let home = shell
+            .var("HOME")
+            .map(String::from)
+            .or_else(|| util::user_home_dir(None))
+            .unwrap();
+        shell.set_var("OLDPWD", "/old dir");
+        assert_eq!(expand_word(&mut shell, "~").unwrap(), vec![home.clone()]);
+        assert_eq!(
+            expand_word(&mut shell, "~/a").unwrap(),
+            vec![format!("{home}/a")]
+        );
+        assert_eq!(expand_word(&mut shell, "~-/x").unwrap(), vec!["/old dir/x"]);
+        assert_eq!(expand_word(&mut shell, "a~").unwrap(), vec!["a~"]);
+        assert_eq!(expand_word(&mut shell, "'~'").unwrap(), vec!["~"]);
+        assert_eq!(expand_word(&mut shell, r"\~").unwrap(), vec!["~"]);
+        assert_eq!(expand_word(&mut shell, "~'me'").unwrap(), vec!["~me"]);
+        assert_eq!(
+            expand_word(&mut shell, "~nosuchuser/x").unwrap(),
+            vec!["~nosuchuser/x"]
+        );
+        assert_eq!(
+            expand_word(&mut shell, "~root").unwrap(),
+            vec![util::user_home_dir(Some("root")).unwrap()]
+        );
+        assert_eq!(
+            expand_assignment(&mut shell, "~-/bin:~-:a~").unwrap(),
+            "/old dir/bin:/old dir:a~"
+        );
+        assert_eq!(expand_string(&mut shell, "a:~").unwrap(), "a:~");
+
+        assert_eq!(expand_tilde_prefix(&shell, "~/a/"), format!("{home}/a/"));
+        assert_eq!(expand_tilde_prefix(&shell, "~-"), "/old dir");
+        assert_eq!(expand_tilde_prefix(&shell, "~nosuchuser/"), "~nosuchuser/");
+        assert_eq!(expand_tilde_prefix(&shell, "a/~"), "a/~");
+    }
+}
+
+#[cfg(test)]
+mod tokenize_test {
+    use crate::{expand::expand_words, parser, shell::Shell};
+
+    fn tokenize(input: &str) -> Result<Vec<String>, String> {
+        let words: Vec<String> = parser::tokenize(input)?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        expand_words(&mut Shell::new(), &words)
+    }
+
+    #[test]
+    fn test_trailing_whitespace() {
+        let args = tokenize("script  shell  ");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["script", "shell"]);
+    }
+
+    #[test]
+    fn test_whitespace_between() {
+        let args = tokenize("script    shell");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["script", "shell"]);
+    }
+
+    #[test]
+    fn test_single_quoted() {
+        let args = tokenize("'script    shell'");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["script    shell"]);
+    }
+
+    #[test]
+    fn test_whitespace_between_single_quoteds() {
+        let args = tokenize("' script '   ' shell '");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![" script ", " shell "]);
+    }
+
+    #[test]
+    fn test_no_space_between_single_quoteds() {
+        let args = tokenize("' script''shell'");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![" scriptshell"]);
+    }
+
+    #[test]
+    fn test_no_space_between_single_quoted_and_normal() {
+        let args = tokenize("'script'shell");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["scriptshell"]);
+    }
+
+    #[test]
+    fn test_double_quoted() {
+        let args = tokenize(r#""quz  hello"  "bar""#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["quz  hello", "bar"]);
+    }
+
+    #[test]
+    fn test_no_space_between_double_quoted_and_normal() {
+        let args = tokenize("\"script\"shell");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["scriptshell"]);
+    }
+
+    #[test]
+    fn test_single_quoted_in_double_quoted() {
+        let args = tokenize("\"'quz''hello'\"");
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec!["'quz''hello'"]);
+    }
+
+    #[test]
+    fn test_backslash() {
+        let args = tokenize(r#"world\ \ \ \\\ \ \ script"#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![r#"world   \   script"#]);
+    }
+
+    #[test]
+    fn test_backslash_in_single_quoted() {
+        let args = tokenize(r#"'example\"testhello\"shell'"#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![r#"example\"testhello\"shell"#]);
+    }
+
+    #[test]
+    fn test_backslash_in_double_quoted() {
+        let args = tokenize(r#""hello'script'\\n'world""#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![r#"hello'script'\n'world"#]);
+    }
+
+    #[test]
+    fn test_backslash_before_quotes() {
+        let args = tokenize(r#""hello\"insidequotes"script\""#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![r#"hello"insidequotesscript""#]);
+    }
+
+    #[test]
+    fn test_backslash_before_newline_in_double_quoted() {
+        let args = tokenize(r#""hello'script'\\n'world""#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(args, vec![r#"hello'script'\n'world"#]);
+    }
+
+    #[test]
+    fn test_backslash_in_single_quoted_in_double_quoted() {
+        let args = tokenize(r#""/tmp/foo/'f 46'" "/tmp/foo/'f  \80'" "/tmp/foo/'f \84\'""#);
+        assert!(args.is_ok());
+        let args = args.unwrap();
+        assert_eq!(
+            args,
+            vec![
+                r#"/tmp/foo/'f 46'"#,
+                r#"/tmp/foo/'f  \80'"#,
+                r#"/tmp/foo/'f \84\'"#
+            ]
+        );
+    }
+}
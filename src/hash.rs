@@ -0,0 +1,435 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    builtin::{self, Output},
+    shell::Shell,
+    util::write_and_flush_str,
+};
+
+/// Remembers where commands were found in `$PATH` so that the directories don't have
+/// to be searched for every command, and indexes their executables for completion.
+#[derive(Debug, Default)]
+pub(crate) struct CommandHash {
+    /// The `$PATH` the table and index were built for.
+    path: String,
+    table: HashMap<String, HashEntry>,
+    index: Vec<DirIndex>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HashEntry {
+    path: PathBuf,
+    hits: u32,
+}
+
+/// Names of the files in a `$PATH` directory, re-read only when the directory changes.
+#[derive(Debug)]
+struct DirIndex {
+    dir: String,
+    modified: Option<SystemTime>,
+    read_at: SystemTime,
+    names: Vec<String>,
+}
+
+impl DirIndex {
+    /// is_fresh returns whether the names are still up to date. Timestamps are coarse,
+    /// so a directory modified around the time it was read is read again to be sure.
+    /// Relative directories depend on the current directory, so they're always read.
+    fn is_fresh(&self, modified: Option<SystemTime>) -> bool {
+        if !self.dir.starts_with('/') {
+            return false;
+        }
+        match (self.modified, modified) {
+            (Some(old), Some(new)) => old == new && new + Duration::from_secs(1) < self.read_at,
+            _ => false,
+        }
+    }
+}
+
+impl CommandHash {
+    /// sync forgets all remembered commands if `$PATH` changed since the last lookup.
+    fn sync(&mut self, path: &str) {
+        if self.path != path {
+            self.path = path.to_string();
+            self.table.clear();
+        }
+    }
+
+    /// lookup returns where the command is, searching `$PATH` only if it's not
+    /// remembered yet or the remembered file is gone, and counts a hit.
+    pub(crate) fn lookup(&mut self, path: &str, name: &str) -> Option<PathBuf> {
+        self.sync(path);
+        if let Some(entry) = self.table.get_mut(name) {
+            if is_executable_file(&entry.path) {
+                entry.hits += 1;
+                return Some(entry.path.clone());
+            }
+            self.table.remove(name);
+        }
+
+        let found = find_in_path(path, name)?;
+        self.table.insert(
+            name.to_string(),
+            HashEntry {
+                path: found.clone(),
+                hits: 1,
+            },
+        );
+        Some(found)
+    }
+
+    /// get returns where the command was remembered to be, if it is.
+    pub(crate) fn get(&mut self, path: &str, name: &str) -> Option<PathBuf> {
+        self.sync(path);
+        self.table.get(name).map(|entry| entry.path.clone())
+    }
+
+    /// insert remembers the command to be at `location` without searching `$PATH`.
+    pub(crate) fn insert(&mut self, path: &str, name: &str, location: PathBuf) {
+        self.sync(path);
+        self.table.insert(
+            name.to_string(),
+            HashEntry {
+                path: location,
+                hits: 0,
+            },
+        );
+    }
+
+    /// remove forgets the command, returning whether it was remembered.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        self.table.remove(name).is_some()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.table.clear();
+    }
+
+    /// executables returns the names of all files in `$PATH`. Only the directories
+    /// that changed since the last call are read again.
+    pub(crate) fn executables(&mut self, path: &str) -> BTreeSet<String> {
+        let mut old: HashMap<String, DirIndex> = self
+            .index
+            .drain(..)
+            .map(|index| (index.dir.clone(), index))
+            .collect();
+
+        for dir in path.split(':') {
+            if self.index.iter().any(|index| index.dir == dir) {
+                continue;
+            }
+            let modified = fs::metadata(path_dir(dir)).and_then(|m| m.modified()).ok();
+            let index = match old.remove(dir) {
+                Some(index) if index.is_fresh(modified) => index,
+                _ => DirIndex {
+                    dir: dir.to_string(),
+                    modified,
+                    read_at: SystemTime::now(),
+                    names: read_files(dir),
+                },
+            };
+            self.index.push(index);
+        }
+
+        self.index
+            .iter()
+            .flat_map(|index| index.names.iter().cloned())
+            .collect()
+    }
+}
+
+/// find_in_path returns the first executable named `name` in the `$PATH` directories.
+pub(crate) fn find_in_path(path: &str, name: &str) -> Option<PathBuf> {
+    path_candidates(path, name).find(|candidate| is_executable_file(candidate))
+}
+
+/// find_file_in_path returns the first file named `name` in the `$PATH` directories,
+/// even if it's not executable.
+pub(crate) fn find_file_in_path(path: &str, name: &str) -> Option<PathBuf> {
+    path_candidates(path, name).find(|candidate| candidate.is_file())
+}
+
+/// path_candidates returns where `name` would be in each `$PATH` directory.
+/// An empty directory means the current directory.
+fn path_candidates<'a>(path: &'a str, name: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+    let name = match name.is_empty() || name.contains('/') {
+        true => None,
+        false => Some(name),
+    };
+    name.into_iter().flat_map(move |name| {
+        path.split(':')
+            .map(move |dir| Path::new(path_dir(dir)).join(name))
+    })
+}
+
+fn path_dir(dir: &str) -> &str {
+    match dir {
+        "" => ".",
+        dir => dir,
+    }
+}
+
+pub(crate) fn is_executable(metadata: &fs::Metadata) -> bool {
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+/// is_executable_file returns whether the path is a regular file with an execute bit set.
+pub(crate) fn is_executable_file(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && is_executable(&m))
+}
+
+/// read_files returns the names of the executables in the directory, following symlinks.
+fn read_files(dir: &str) -> Vec<String> {
+    let entries = match fs::read_dir(path_dir(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() || file_type.is_file() => {
+                is_executable_file(&entry.path())
+            }
+            _ => false,
+        })
+        .filter_map(|entry| entry.file_name().to_str().map(String::from))
+        .collect()
+}
+
+/// hash manages the remembered locations of commands.
+///  - `hash` lists remembered commands with how many times they were run.
+///  - `hash name...` searches `$PATH` and remembers the commands.
+///  - `hash -r` forgets all commands, `hash -d name...` forgets the named ones.
+///  - `hash -p path name` remembers `path` as the location of `name`.
+///  - `hash -t name...` prints the remembered locations.
+pub(crate) fn hash<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let (mut reset, mut delete, mut print) = (false, false, false);
+    let mut location = None;
+    let mut idx = 0;
+    while let Some(arg) = args
+        .get(idx)
+        .filter(|a| a.len() > 1)
+        .and_then(|a| a.strip_prefix('-'))
+    {
+        idx += 1;
+        if arg == "-" {
+            break;
+        }
+        for (i, flag) in arg.char_indices() {
+            match flag {
+                'r' => reset = true,
+                'd' => delete = true,
+                't' => print = true,
+                'p' => {
+                    let value = match &arg[i + 1..] {
+                        "" => {
+                            idx += 1;
+                            args.get(idx - 1).copied()
+                        }
+                        rest => Some(rest),
+                    };
+                    match value {
+                        Some(value) => location = Some(PathBuf::from(value)),
+                        None => {
+                            write_and_flush_str(
+                                &mut w.err,
+                                "hash: -p: option requires an argument",
+                            )?;
+                            return Ok(2);
+                        }
+                    }
+                    break;
+                }
+                _ => {
+                    write_and_flush_str(&mut w.err, &format!("hash: -{flag}: invalid option"))?;
+                    write_and_flush_str(
+                        &mut w.err,
+                        "hash: usage: hash [-r] [-p pathname] [-dt] [name ...]",
+                    )?;
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    let names = &args[idx..];
+    let path = shell.var("PATH").unwrap_or_default().to_string();
+
+    if reset {
+        shell.hash.clear();
+    }
+    if names.is_empty() {
+        if reset {
+            return Ok(0);
+        }
+        if print || delete || location.is_some() {
+            write_and_flush_str(&mut w.err, "hash: argument expected")?;
+            return Ok(1);
+        }
+        return list(shell, w, &path);
+    }
+
+    let mut status = 0;
+    for name in names {
+        if let Some(location) = &location {
+            shell.hash.insert(&path, name, location.clone());
+            continue;
+        }
+        if delete {
+            if !shell.hash.remove(name) {
+                write_and_flush_str(&mut w.err, &format!("hash: {name}: not found"))?;
+                status = 1;
+            }
+            continue;
+        }
+        if print {
+            match shell.hash.get(&path, name) {
+                Some(found) if names.len() > 1 => {
+                    write_and_flush_str(&mut w.out, &format!("{name}\t{}", found.display()))?
+                }
+                Some(found) => write_and_flush_str(&mut w.out, &found.display().to_string())?,
+                None => {
+                    write_and_flush_str(&mut w.err, &format!("hash: {name}: not found"))?;
+                    status = 1;
+                }
+            }
+            continue;
+        }
+
+        // Builtins and functions are never searched for in `$PATH`
+        let is_builtin = !matches!(
+            builtin::Command::parse(name),
+            builtin::Command::Executable { .. }
+        );
+        if is_builtin || shell.has_function(name) {
+            continue;
+        }
+        match find_in_path(&path, name) {
+            Some(found) => shell.hash.insert(&path, name, found),
+            None => {
+                write_and_flush_str(&mut w.err, &format!("hash: {name}: not found"))?;
+                status = 1;
+            }
+        }
+    }
+    Ok(status)
+}
+
+fn list<T, K>(shell: &mut Shell, w: &mut Output<T, K>, path: &str) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    shell.hash.sync(path);
+    if shell.hash.table.is_empty() {
+        write_and_flush_str(&mut w.out, "hash: hash table empty")?;
+        return Ok(0);
+    }
+
+    let entries: BTreeMap<&String, &HashEntry> = shell.hash.table.iter().collect();
+    write_and_flush_str(&mut w.out, "hits\tcommand")?;
+    for entry in entries.values() {
+        write_and_flush_str(
+            &mut w.out,
+            &format!("{:4}\t{}", entry.hits, entry.path.display()),
+        )?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod hash_test {
+    use std::{fs, os::unix::fs::PermissionsExt as _, path::Path};
+
+    use crate::{
+        hash::{find_file_in_path, find_in_path, CommandHash},
+        util::TempDir,
+    };
+
+    fn write_file(path: &Path, mode: u32) {
+        fs::write(path, "").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = TempDir::new("hash_test");
+        let path = dir.to_str().unwrap().to_string();
+        let tool = dir.join("tool");
+        write_file(&tool, 0o755);
+
+        let mut hash = CommandHash::default();
+        assert_eq!(hash.lookup(&path, "tool"), Some(tool.clone()));
+        assert_eq!(hash.lookup(&path, "tool"), Some(tool.clone()));
+        assert_eq!(hash.table["tool"].hits, 2);
+        assert_eq!(hash.lookup(&path, "missing"), None);
+
+        // A removed file is searched for again
+        fs::remove_file(&tool).unwrap();
+        assert_eq!(hash.lookup(&path, "tool"), None);
+        assert!(hash.table.is_empty());
+
+        // Changing `$PATH` forgets everything
+        hash.insert(&path, "other", tool.clone());
+        assert_eq!(hash.get(&path, "other"), Some(tool));
+        assert_eq!(hash.get("/nonexistent", "other"), None);
+    }
+
+    #[test]
+    fn test_executables() {
+        let dir = TempDir::new("hash_test");
+        let path = format!("{}:/nonexistent", dir.to_str().unwrap());
+        write_file(&dir.join("one"), 0o755);
+        write_file(&dir.join("data"), 0o644);
+        fs::create_dir(dir.join("subdir")).unwrap();
+
+        let mut hash = CommandHash::default();
+        assert_eq!(
+            hash.executables(&path).into_iter().collect::<Vec<_>>(),
+            vec!["one"]
+        );
+
+        write_file(&dir.join("two"), 0o700);
+        assert_eq!(
+            hash.executables(&path).into_iter().collect::<Vec<_>>(),
+            vec!["one", "two"]
+        );
+        assert!(hash.executables("/nonexistent").is_empty());
+    }
+
+    #[test]
+    fn test_find_in_path() {
+        let dir = TempDir::new("hash_test");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        write_file(&first.join("tool"), 0o644);
+        write_file(&second.join("tool"), 0o755);
+        let path = format!("{}:{}", first.display(), second.display());
+
+        // Files without the execute bit are skipped
+        assert_eq!(find_in_path(&path, "tool"), Some(second.join("tool")));
+        assert_eq!(find_file_in_path(&path, "tool"), Some(first.join("tool")));
+        assert_eq!(find_in_path(&path, "./tool"), None);
+
+        // An empty directory is the current directory
+        assert_eq!(
+            find_file_in_path(":/nonexistent", "Cargo.toml"),
+            Some("./Cargo.toml".into())
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, rc::Rc};
+
+use rustyline::highlight::{CmdKind, Highlighter};
+use strum::EnumString;
+
+use crate::{
+    builtin::{self, CommandType},
+    parser::{self, Operator, Token},
+    prompt::Prompt,
+    shell::Shell,
+};
+
+/// Kinds of text in the line being edited, each highlighted in its own color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Style {
+    Builtin,
+    Keyword,
+    /// Functions, aliases and programs that were found.
+    Command,
+    /// Commands that weren't found.
+    Unknown,
+    String,
+    Variable,
+    Operator,
+    Comment,
+    /// Quotes and substitutions that aren't closed.
+    Error,
+    /// Suggestions shown after the cursor.
+    Hint,
+}
+
+impl Style {
+    /// default_color returns the SGR parameters of the style if `HIGHLIGHT_COLORS`
+    /// doesn't set them.
+    fn default_color(&self) -> &'static str {
+        match self {
+            Self::Builtin => "1;36",
+            Self::Keyword => "1;35",
+            Self::Command => "32",
+            Self::Unknown => "31",
+            Self::String => "33",
+            Self::Variable => "36",
+            Self::Operator => "35",
+            Self::Comment => "90",
+            Self::Error => "1;31",
+            Self::Hint => "90",
+        }
+    }
+}
+
+/// A styled part of the line, from `start` up to `end` in chars.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    start: usize,
+    end: usize,
+    style: Style,
+}
+
+/// ShellHighlighter styles what rustyline displays.
+pub(crate) struct ShellHighlighter {
+    shell: Rc<RefCell<Shell>>,
+    prompt: Prompt,
+}
+
+impl ShellHighlighter {
+    pub(crate) fn new(shell: Rc<RefCell<Shell>>) -> Self {
+        Self {
+            shell,
+            prompt: Prompt::default(),
+        }
+    }
+
+    /// set_prompt sets the prompt about to be read with. rustyline measures the plain
+    /// prompt it's given, and the styled prompt is what's displayed instead.
+    pub(crate) fn set_prompt(&mut self, prompt: Prompt) {
+        self.prompt = prompt;
+    }
+}
+
+impl Highlighter for ShellHighlighter {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let mut shell = match self.shell.try_borrow_mut() {
+            Ok(shell) => shell,
+            Err(_) => return Cow::Borrowed(line),
+        };
+        let colors = match colors(&shell) {
+            Some(colors) => colors,
+            None => return Cow::Borrowed(line),
+        };
+
+        let spans = spans(
+            line,
+            &mut |name| match builtin::Command::command_type(&mut shell, name) {
+                CommandType::Builtin => Style::Builtin,
+                CommandType::NotFound => Style::Unknown,
+                _ => Style::Command,
+            },
+        );
+        Cow::Owned(render(line, &spans, &colors))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        let colors = match self.shell.try_borrow().ok().as_deref().and_then(colors) {
+            Some(colors) => colors,
+            None => return Cow::Borrowed(hint),
+        };
+        let span = Span {
+            start: 0,
+            end: hint.chars().count(),
+            style: Style::Hint,
+        };
+        Cow::Owned(render(hint, &[span], &colors))
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        match prompt == self.prompt.plain {
+            true => Cow::Borrowed(&self.prompt.styled),
+            false => Cow::Borrowed(prompt),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        // Any edit can change how the rest of the line is highlighted
+        kind != CmdKind::MoveCursor
+    }
+}
+
+/// colors returns the color of each style, with those set in `HIGHLIGHT_COLORS` like
+/// `string=33:comment=2;37` replacing the defaults. There are none if `NO_COLOR` is
+/// set or the terminal is dumb.
+fn colors(shell: &Shell) -> Option<HashMap<Style, String>> {
+    if shell.var("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        return None;
+    }
+    match shell.var("TERM") {
+        None | Some("" | "dumb") => return None,
+        _ => (),
+    }
+
+    let mut colors = HashMap::new();
+    for entry in shell.var("HIGHLIGHT_COLORS").unwrap_or_default().split(':') {
+        if let Some((name, color)) = entry.split_once('=') {
+            if let Ok(style) = name.parse::<Style>() {
+                colors.insert(style, color.to_string());
+            }
+        }
+    }
+    Some(colors)
+}
+
+/// render wraps the spans of the line in the escape sequences of their colors.
+fn render(line: &str, spans: &[Span], colors: &HashMap<Style, String>) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut rendered = String::new();
+    let mut idx = 0;
+    for span in spans {
+        rendered.extend(&chars[idx..span.start]);
+        let color = match colors.get(&span.style) {
+            Some(color) => color.as_str(),
+            None => span.style.default_color(),
+        };
+        rendered.push_str(&format!("\x1b[{color}m"));
+        rendered.extend(&chars[span.start..span.end]);
+        rendered.push_str("\x1b[0m");
+        idx = span.end;
+    }
+    rendered.extend(&chars[idx..]);
+    rendered
+}
+
+/// spans splits the line into the parts to highlight, in order. Words in command
+/// position are styled by `classify` unless they are quoted or have expansions.
+/// Unlike the parser, this never fails: everything after an unclosed quote or
+/// substitution is an error.
+fn spans(line: &str, classify: &mut dyn FnMut(&str) -> Style) -> Vec<Span> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut spans = Vec::new();
+    let mut command_position = true;
+    let mut redirect_target = false;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+        if ch.is_whitespace() {
+            command_position |= ch == '\n';
+            idx += 1;
+            continue;
+        }
+        if ch == '#' {
+            let len = chars[idx..].iter().take_while(|c| **c != '\n').count();
+            spans.push(Span {
+                start: idx,
+                end: idx + len,
+                style: Style::Comment,
+            });
+            idx += len;
+            continue;
+        }
+
+        let digits = chars[idx..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        let redirect_fd = digits > 0 && matches!(chars.get(idx + digits), Some('<' | '>'));
+        if parser::is_metachar(ch) || redirect_fd {
+            let op_start = match redirect_fd {
+                true => idx + digits,
+                false => idx,
+            };
+            let (token, len) = parser::operator_at(&chars, op_start, None);
+            match token {
+                Token::Redirect { .. } => redirect_target = true,
+                Token::Operator(op) => command_position = op != Operator::RParen,
+                Token::Word(_) => (),
+            }
+            spans.push(Span {
+                start: idx,
+                end: op_start + len,
+                style: Style::Operator,
+            });
+            idx = op_start + len;
+            continue;
+        }
+
+        let start = idx;
+        let (end, plain) = match word_spans(&chars, idx, &mut spans) {
+            Some(end) => end,
+            None => return spans,
+        };
+        let word: String = chars[start..end].iter().collect();
+        idx = end;
+
+        if redirect_target {
+            redirect_target = false;
+        } else if command_position {
+            match word.split_once('=') {
+                // Assignments before the command keep the command position
+                Some((name, _)) if parser::is_name(name) => continue,
+                _ => (),
+            }
+            let style = match word.as_str() {
+                word if parser::is_reserved_word(word) => Style::Keyword,
+                "function" | "[[" => {
+                    command_position = false;
+                    Style::Keyword
+                }
+                _ if plain => {
+                    command_position = false;
+                    classify(&word)
+                }
+                _ => {
+                    command_position = false;
+                    continue;
+                }
+            };
+            spans.push(Span { start, end, style });
+        }
+    }
+    spans
+}
+
+/// word_spans adds the spans of the quoted strings and expansions in the word starting
+/// at `start`, returning the index after the word and whether it is plain, i.e. has
+/// none of those. If a quote or substitution isn't closed, the rest of the line is
+/// an error and None is returned.
+fn word_spans(chars: &[char], start: usize, spans: &mut Vec<Span>) -> Option<(usize, bool)> {
+    let mut plain = true;
+    let mut idx = start;
+    while idx < chars.len() && !chars[idx].is_whitespace() && !parser::is_metachar(chars[idx]) {
+        let end = match parser::skip_construct(chars, idx) {
+            Ok(Some(end)) => end,
+            Ok(None) if chars[idx] == '$' && variable_end(chars, idx) > idx + 1 => {
+                variable_end(chars, idx)
+            }
+            Ok(None) => {
+                idx += 1;
+                continue;
+            }
+            Err(_) => {
+                spans.push(Span {
+                    start: idx,
+                    end: chars.len(),
+                    style: Style::Error,
+                });
+                return None;
+            }
+        };
+
+        match chars[idx] {
+            '\\' => (),
+            '"' => quoted_spans(chars, idx, end, spans),
+            '\'' | '`' => spans.push(Span {
+                start: idx,
+                end,
+                style: Style::String,
+            }),
+            _ => spans.push(Span {
+                start: idx,
+                end,
+                style: Style::Variable,
+            }),
+        }
+        plain = false;
+        idx = end;
+    }
+    Some((idx, plain))
+}
+
+/// quoted_spans adds the spans of a double quoted string, which is split around the
+/// expansions in it.
+fn quoted_spans(chars: &[char], start: usize, end: usize, spans: &mut Vec<Span>) {
+    let mut string_start = start;
+    let mut idx = start + 1;
+    while idx < end - 1 {
+        let expansion_end = match chars[idx] {
+            '\\' => {
+                idx += 2;
+                continue;
+            }
+            '$' | '`' => match parser::skip_construct(chars, idx) {
+                Ok(Some(expansion_end)) => expansion_end,
+                _ => variable_end(chars, idx),
+            },
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        if expansion_end == idx + 1 {
+            idx += 1;
+            continue;
+        }
+
+        spans.push(Span {
+            start: string_start,
+            end: idx,
+            style: Style::String,
+        });
+        spans.push(Span {
+            start: idx,
+            end: expansion_end,
+            style: Style::Variable,
+        });
+        string_start = expansion_end;
+        idx = expansion_end;
+    }
+    spans.push(Span {
+        start: string_start,
+        end,
+        style: Style::String,
+    });
+}
+
+/// variable_end returns the index after a `$name` or special parameter like `$?`, or
+/// right after the `$` if it's followed by neither.
+fn variable_end(chars: &[char], idx: usize) -> usize {
+    match chars.get(idx + 1) {
+        Some(c) if c.is_ascii_digit() || "?#$!@*-".contains(*c) => idx + 2,
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let len = chars[idx + 1..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            idx + 1 + len
+        }
+        _ => idx + 1,
+    }
+}
+
+#[cfg(test)]
+mod highlight_test {
+    use crate::highlight::{spans, Style};
+
+    /// styled returns each highlighted part of the line with its style.
+    fn styled(line: &str) -> Vec<(String, Style)> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut classify = |name: &str| match name {
+            "echo" => Style::Builtin,
+            "ls" => Style::Command,
+            _ => Style::Unknown,
+        };
+        spans(line, &mut classify)
+            .into_iter()
+            .map(|span| (chars[span.start..span.end].iter().collect(), span.style))
+            .collect()
+    }
+
+    fn part(text: &str, style: Style) -> (String, Style) {
+        (text.to_string(), style)
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            styled("ls -l | nope && FOO=1 echo x 2>err # done"),
+            vec![
+                part("ls", Style::Command),
+                part("|", Style::Operator),
+                part("nope", Style::Unknown),
+                part("&&", Style::Operator),
+                part("echo", Style::Builtin),
+                part("2>", Style::Operator),
+                part("# done", Style::Comment),
+            ]
+        );
+        assert_eq!(
+            styled("\"ls\" >ls"),
+            vec![part("\"ls\"", Style::String), part(">", Style::Operator)]
+        );
+    }
+
+    #[test]
+    fn test_strings_and_variables() {
+        assert_eq!(
+            styled("echo 'a b' \"x $HOME y\" $(ls) $1"),
+            vec![
+                part("echo", Style::Builtin),
+                part("'a b'", Style::String),
+                part("\"x ", Style::String),
+                part("$HOME", Style::Variable),
+                part(" y\"", Style::String),
+                part("$(ls)", Style::Variable),
+                part("$1", Style::Variable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_quote() {
+        assert_eq!(
+            styled("echo \"it's"),
+            vec![part("echo", Style::Builtin), part("\"it's", Style::Error)]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::{cell::RefCell, rc::Rc};
+
+use rustyline::{
+    hint::Hinter, Cmd, ConditionalEventHandler, Context, Editor, Event, EventContext, EventHandler,
+    KeyCode, KeyEvent, Modifiers, RepeatCount,
+};
+
+use crate::{
+    completion::{self, Word},
+    history::Entry,
+    shell::Shell,
+    util,
+};
+
+/// ShellHinter suggests how the line could go on, shown after the cursor like fish does.
+pub(crate) struct ShellHinter {
+    shell: Rc<RefCell<Shell>>,
+}
+
+impl ShellHinter {
+    pub(crate) fn new(shell: Rc<RefCell<Shell>>) -> Self {
+        Self { shell }
+    }
+}
+
+impl Hinter for ShellHinter {
+    type Hint = String;
+
+    /// hint suggests the rest of the most recent history entry that starts with the
+    /// line, preferring those run in the current directory, or else the rest of a
+    /// file path the last word is the start of.
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if line.trim().is_empty() || pos < line.len() {
+            return None;
+        }
+        let shell = self.shell.try_borrow().ok()?;
+        history_hint(shell.history.entries(), line, &shell.current_dir())
+            .or_else(|| path_hint(&shell, line))
+    }
+}
+
+fn history_hint(history: &[Entry], line: &str, dir: &str) -> Option<String> {
+    let matches = |entry: &&Entry| {
+        entry.line.len() > line.len() && entry.line.starts_with(line) && !entry.line.contains('\n')
+    };
+    let entry = match history
+        .iter()
+        .rev()
+        .filter(matches)
+        .find(|entry| entry.dir == dir)
+    {
+        Some(entry) => entry,
+        None => history.iter().rev().find(matches)?,
+    };
+    Some(entry.line[line.len()..].to_string())
+}
+
+/// path_hint suggests the rest of the first path that starts with the last word. In
+/// command position, only paths to executables are suggested.
+fn path_hint(shell: &Shell, line: &str) -> Option<String> {
+    let word = Word::parse(line);
+    if word.text.is_empty() || (word.command_position && !word.text.contains('/')) {
+        return None;
+    }
+    let (path, is_dir) = completion::path_matches(shell, &word.text, false, word.command_position)
+        .into_iter()
+        .next()?;
+
+    let rest = path.strip_prefix(&word.text)?;
+    // Anything that would need quoting can't simply be appended
+    if !rest.is_empty() && util::quote(rest) != rest {
+        return None;
+    }
+    let hint = match is_dir {
+        true => format!("{rest}/"),
+        false => rest.to_string(),
+    };
+    Some(hint).filter(|hint| !hint.is_empty())
+}
+
+/// bind_keys binds the keys that accept a hint: End accepts all of it like the right
+/// arrow does, and Alt-f accepts its next word. Otherwise they do what they normally do.
+pub(crate) fn bind_keys<H, I>(rl: &mut Editor<H, I>)
+where
+    H: rustyline::Helper,
+    I: rustyline::history::History,
+{
+    rl.bind_sequence(
+        KeyEvent(KeyCode::End, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(AcceptHint)),
+    );
+    rl.bind_sequence(
+        KeyEvent::alt('f'),
+        EventHandler::Conditional(Box::new(AcceptHintWord)),
+    );
+}
+
+struct AcceptHint;
+
+impl ConditionalEventHandler for AcceptHint {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        match ctx.has_hint() && ctx.pos() == ctx.line().len() {
+            true => Some(Cmd::CompleteHint),
+            false => None,
+        }
+    }
+}
+
+struct AcceptHintWord;
+
+impl ConditionalEventHandler for AcceptHintWord {
+    fn handle(&self, _: &Event, n: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.pos() != ctx.line().len() {
+            return None;
+        }
+        let word = next_word(ctx.hint_text()?);
+        Some(Cmd::Insert(n, word.to_string())).filter(|_| !word.is_empty())
+    }
+}
+
+/// next_word returns the start of the hint up to the end of its first word.
+fn next_word(hint: &str) -> &str {
+    let start = hint.len() - hint.trim_start().len();
+    let end = hint[start..]
+        .find(char::is_whitespace)
+        .map_or(hint.len(), |len| start + len);
+    &hint[..end]
+}
+
+#[cfg(test)]
+mod hint_test {
+    use crate::{
+        hint::{history_hint, next_word},
+        history::Entry,
+    };
+
+    fn entry(line: &str, dir: &str) -> Entry {
+        Entry {
+            line: line.to_string(),
+            dir: dir.to_string(),
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn test_history_hint() {
+        let history = vec![
+            entry("cargo", "/tmp"),
+            entry("cargo test", "/src"),
+            entry("cargo build", "/tmp"),
+        ];
+        assert_eq!(
+            history_hint(&history, "car", "/tmp"),
+            Some("go build".into())
+        );
+        assert_eq!(
+            history_hint(&history, "car", "/src"),
+            Some("go test".into())
+        );
+        assert_eq!(history_hint(&history, "car", "/"), Some("go build".into()));
+        assert_eq!(
+            history_hint(&history, "cargo", "/tmp"),
+            Some(" build".into())
+        );
+        assert_eq!(history_hint(&history, "ls", "/tmp"), None);
+    }
+
+    #[test]
+    fn test_next_word() {
+        assert_eq!(next_word("go build --release"), "go");
+        assert_eq!(next_word(" build --release"), " build");
+        assert_eq!(next_word("last"), "last");
+    }
+}
@@ -0,0 +1,928 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    path::PathBuf,
+};
+
+use crate::{shell::Shell, util};
+
+/// Number of entries kept if `HISTSIZE` isn't set.
+const DEFAULT_HISTSIZE: usize = 1000;
+/// History file used if `HISTFILE` isn't set, relative to the home directory.
+const DEFAULT_HISTFILE: &str = ".shell_history";
+
+/// A line entered in the session, with where and when it was run.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) line: String,
+    /// Directory the line was run in, empty if unknown.
+    pub(crate) dir: String,
+    /// Seconds since the epoch when the line was run, 0 if unknown.
+    pub(crate) time: u64,
+}
+
+impl Entry {
+    /// new creates an entry for a line run now.
+    pub(crate) fn new(line: &str, dir: &str) -> Self {
+        Self {
+            line: line.to_string(),
+            dir: dir.to_string(),
+            time: util::unix_time(),
+        }
+    }
+}
+
+/// The history of the shell, which is kept in the history file across sessions.
+#[derive(Debug)]
+pub(crate) struct HistoryStore {
+    /// Entries, oldest first.
+    entries: Vec<Entry>,
+    file: Option<PathBuf>,
+    /// Maximum number of entries kept.
+    size: usize,
+}
+
+impl Default for HistoryStore {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            file: None,
+            size: DEFAULT_HISTSIZE,
+        }
+    }
+}
+
+impl HistoryStore {
+    /// new creates an empty history kept in the file named by `HISTFILE`,
+    /// `~/.shell_history` by default, with at most `HISTSIZE` entries.
+    pub(crate) fn new(shell: &Shell) -> Self {
+        let file = match shell.var("HISTFILE") {
+            Some("") => None,
+            Some(file) => Some(PathBuf::from(file)),
+            None => shell
+                .var("HOME")
+                .map(str::to_string)
+                .or_else(|| util::user_home_dir(None))
+                .map(|home| PathBuf::from(home).join(DEFAULT_HISTFILE)),
+        };
+        let size = shell
+            .var("HISTSIZE")
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_HISTSIZE);
+        Self {
+            entries: Vec::new(),
+            file,
+            size,
+        }
+    }
+
+    pub(crate) fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// load reads the entries in the history file, keeping the last `size` of them.
+    /// The file is rewritten if it held more.
+    pub(crate) fn load(&mut self) -> Result<(), String> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let error = |e: io::Error| format!("{}: {}", file.display(), util::error_message(&e));
+
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(error(e)),
+        };
+        let mut entries = parse_file(&contents);
+        if entries.len() > self.size {
+            entries.drain(..entries.len() - self.size);
+            let contents: String = entries.iter().map(format_entry).collect();
+            fs::write(file, contents).map_err(error)?;
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// add adds the entry and appends it to the history file right away, so that it's
+    /// kept even if the shell doesn't exit normally.
+    pub(crate) fn add(&mut self, entry: Entry) -> Result<(), String> {
+        if self.size == 0 {
+            return Ok(());
+        }
+        if self.entries.len() >= self.size {
+            self.entries.drain(..=self.entries.len() - self.size);
+        }
+        let formatted = format_entry(&entry);
+        self.entries.push(entry);
+
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .and_then(|mut f| f.write_all(formatted.as_bytes()))
+            .map_err(|e| format!("{}: {}", file.display(), util::error_message(&e)))
+    }
+}
+
+/// format_entry formats the entry as it's saved in the history file: a `#<time> <dir>`
+/// line followed by the line, with backslashes and newlines escaped. A `#` that starts
+/// the line is escaped too so that it's not taken for the next entry's time.
+fn format_entry(entry: &Entry) -> String {
+    let mut line = entry.line.replace('\\', "\\\\").replace('\n', "\\n");
+    if line.starts_with('#') {
+        line.insert(0, '\\');
+    }
+    format!("#{} {}\n{line}\n", entry.time, entry.dir)
+}
+
+/// parse_file parses the entries of a history file. Lines without a `#<time> <dir>`
+/// line before them, as in plain history files, are entries without a time or dir.
+fn parse_file(contents: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut meta: Option<(u64, String)> = None;
+    for line in contents.lines() {
+        if let Some(rest) = line.strip_prefix('#') {
+            let (time, dir) = rest.split_once(' ').unwrap_or((rest, ""));
+            if let Ok(time) = time.parse() {
+                meta = Some((time, dir.to_string()));
+                continue;
+            }
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (time, dir) = meta.take().unwrap_or_default();
+        entries.push(Entry {
+            line: unescape(line),
+            dir,
+            time,
+        });
+    }
+    entries
+}
+
+fn unescape(line: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some(escaped @ ('\\' | '#'))) => {
+                unescaped.push(escaped);
+                chars.next();
+            }
+            _ => unescaped.push(ch),
+        }
+    }
+    unescaped
+}
+
+/// Result of running history expansion over an input line.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Expansion {
+    /// The line after all history references have been replaced.
+    pub(crate) line: String,
+    /// Whether any history reference was found in the line.
+    pub(crate) expanded: bool,
+    /// Whether the `:p` modifier was used, i.e. the line should be printed but not executed.
+    pub(crate) print_only: bool,
+}
+
+/// Last `:s` substitution, remembered so that `:&` and an empty `old` can reuse it.
+#[derive(Default)]
+struct Substitution {
+    old: String,
+    new: String,
+}
+
+/// expand performs bash-style history expansion on the raw input line.
+/// `history` is ordered from the oldest entry to the newest one.
+///
+/// Supported syntax:
+///  - Event designators: `!!`, `!n`, `!-n`, `!string`, `!?string?`, `!#`.
+///  - Word designators: `:0`, `:n`, `:^`, `:$`, `:%`, `:n-m`, `:n-`, `:n*`, `:*`,
+///    and the `!^`, `!$`, `!*` shorthands.
+///  - Modifiers: `:h`, `:t`, `:r`, `:e`, `:p`, `:q`, `:s/old/new/`, `:gs/old/new/`, `:&`.
+///  - Quick substitution at the start of the line: `^old^new^`.
+///
+/// Anything inside single quotes or escaped with a backslash is left alone.
+pub(crate) fn expand<S: AsRef<str>>(line: &str, history: &[S]) -> Result<Expansion, String> {
+    let mut expander = Expander {
+        history,
+        subst: Substitution::default(),
+        print_only: false,
+    };
+
+    // `^old^new^` is a shorthand for `!!:s^old^new^`
+    if let Some(rest) = line.strip_prefix('^') {
+        let chars: Vec<char> = rest.chars().collect();
+        let (old, new, consumed) = parse_substitution(&chars, 0, '^');
+        let event = expander.event_line(Event::Relative(1), "^")?;
+        let mut quick = String::new();
+        quick.push('^');
+        quick.extend(&chars[..consumed]);
+        let replaced = expander.substitute(&event, &old, &new, false, &quick)?;
+        let rest: String = chars[consumed..].iter().collect();
+        let mut output = expander.expand_line(&rest)?;
+        output.line = replaced + &output.line;
+        output.expanded = true;
+        return Ok(output);
+    }
+
+    expander.expand_line(line)
+}
+
+enum Event {
+    /// The n-th entry counting backwards from the newest one, starting at 1.
+    Relative(usize),
+    /// The n-th entry counting from the oldest one, starting at 1.
+    Absolute(usize),
+    /// The newest entry that starts with the string.
+    Prefix(String),
+    /// The newest entry that contains the string.
+    Contains(String),
+    /// The current line typed so far.
+    Current,
+}
+
+struct Expander<'a, S: AsRef<str>> {
+    history: &'a [S],
+    subst: Substitution,
+    print_only: bool,
+}
+
+impl<S: AsRef<str>> Expander<'_, S> {
+    fn expand_line(&mut self, line: &str) -> Result<Expansion, String> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut output = String::new();
+        let mut expanded = false;
+        let mut in_single_quotes = false;
+        let mut in_double_quotes = false;
+        let mut idx = 0;
+
+        while idx < chars.len() {
+            let ch = chars[idx];
+            match ch {
+                // Escaped chars are never expanded, keep the backslash for the tokenizer
+                '\\' if !in_single_quotes => {
+                    output.push(ch);
+                    if let Some(&next) = chars.get(idx + 1) {
+                        output.push(next);
+                    }
+                    idx += 2;
+                    continue;
+                }
+                '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+                '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+                '!' if !in_single_quotes
+                    && Self::starts_reference(&chars, idx, in_double_quotes) =>
+                {
+                    let (replacement, consumed) = self.expand_reference(&chars, idx, &output)?;
+                    output.push_str(&replacement);
+                    expanded = true;
+                    idx += consumed;
+                    continue;
+                }
+                _ => (),
+            }
+            output.push(ch);
+            idx += 1;
+        }
+
+        Ok(Expansion {
+            line: output,
+            expanded,
+            print_only: self.print_only,
+        })
+    }
+
+    /// starts_reference checks if the `!` at `idx` begins a history reference.
+    /// Like bash, `!` followed by whitespace, `=`, `(` or the end of line is a literal,
+    /// and so is the indirection in `${!name}`.
+    fn starts_reference(chars: &[char], idx: usize, in_double_quotes: bool) -> bool {
+        if idx >= 2 && chars[idx - 2] == '$' && chars[idx - 1] == '{' {
+            return false;
+        }
+        match chars.get(idx + 1) {
+            None => false,
+            Some(c) if c.is_whitespace() || *c == '=' || *c == '(' => false,
+            Some('"') if in_double_quotes => false,
+            Some(_) => true,
+        }
+    }
+
+    /// expand_reference expands the history reference starting with `!` at `start`.
+    /// Returns the replacement and the number of chars consumed.
+    fn expand_reference(
+        &mut self,
+        chars: &[char],
+        start: usize,
+        current: &str,
+    ) -> Result<(String, usize), String> {
+        let mut idx = start + 1;
+
+        // Event designator
+        let event = match chars[idx] {
+            '!' => {
+                idx += 1;
+                Event::Relative(1)
+            }
+            '#' => {
+                idx += 1;
+                Event::Current
+            }
+            // `!$`, `!^`, `!*` and `!%` refer to words of the previous command
+            '$' | '^' | '*' | '%' => Event::Relative(1),
+            '-' if chars.get(idx + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                idx += 1;
+                let (n, len) = parse_number(chars, idx);
+                idx += len;
+                Event::Relative(n)
+            }
+            c if c.is_ascii_digit() => {
+                let (n, len) = parse_number(chars, idx);
+                idx += len;
+                Event::Absolute(n)
+            }
+            '?' => {
+                idx += 1;
+                let mut s = String::new();
+                while idx < chars.len() && chars[idx] != '?' && chars[idx] != '\n' {
+                    s.push(chars[idx]);
+                    idx += 1;
+                }
+                // Trailing `?` may be omitted at the end of the line
+                if chars.get(idx) == Some(&'?') {
+                    idx += 1;
+                }
+                Event::Contains(s)
+            }
+            _ => {
+                let mut s = String::new();
+                while idx < chars.len() && !is_event_terminator(chars[idx]) {
+                    s.push(chars[idx]);
+                    idx += 1;
+                }
+                Event::Prefix(s)
+            }
+        };
+
+        let spec: String = chars[start..idx].iter().collect();
+        let event_line = match event {
+            Event::Current => current.to_string(),
+            event => self.event_line(event, &spec)?,
+        };
+
+        // Word designator, either after `:` or directly for `$`, `^`, `*`, `%`, `-`
+        let mut text = event_line.clone();
+        let designator_start = match chars.get(idx) {
+            Some(':') if chars.get(idx + 1).is_some_and(|&c| is_word_designator(c)) => {
+                Some(idx + 1)
+            }
+            Some('$' | '^' | '*' | '%') => Some(idx),
+            Some('-') if idx > start + 1 => Some(idx),
+            _ => None,
+        };
+        if let Some(ds) = designator_start {
+            let (words, consumed) = select_words(&event_line, chars, ds).map_err(|consumed| {
+                let spec: String = chars[start..ds + consumed].iter().collect();
+                format!("{spec}: bad word specifier")
+            })?;
+            text = words;
+            idx = ds + consumed;
+        }
+
+        // Modifiers
+        while chars.get(idx) == Some(&':') {
+            let modifier_start = idx;
+            idx += 1;
+            let Some(&m) = chars.get(idx) else {
+                break;
+            };
+            idx += 1;
+            match m {
+                'h' => {
+                    if let Some(pos) = text.rfind('/') {
+                        text.truncate(if pos == 0 { 1 } else { pos });
+                    }
+                }
+                't' => {
+                    if let Some(pos) = text.rfind('/') {
+                        text = text[pos + 1..].to_string();
+                    }
+                }
+                'r' => {
+                    if let Some(pos) = suffix_position(&text) {
+                        text.truncate(pos);
+                    }
+                }
+                'e' => {
+                    if let Some(pos) = suffix_position(&text) {
+                        text = text[pos..].to_string();
+                    }
+                }
+                'p' => self.print_only = true,
+                'q' => text = format!("'{}'", text.replace('\'', r"'\''")),
+                's' | 'g' | 'a' | '&' => {
+                    let global = m == 'g' || m == 'a';
+                    let op = if global {
+                        let Some(&op) = chars.get(idx) else {
+                            return Err(format!("{spec}: unrecognized history modifier"));
+                        };
+                        idx += 1;
+                        op
+                    } else {
+                        m
+                    };
+
+                    let (old, new) = match op {
+                        's' => {
+                            let Some(&delim) = chars.get(idx) else {
+                                return Err(format!("{spec}: bad substitution"));
+                            };
+                            let (old, new, consumed) = parse_substitution(chars, idx + 1, delim);
+                            idx += 1 + consumed;
+                            (old, new)
+                        }
+                        '&' => (self.subst.old.clone(), self.subst.new.clone()),
+                        _ => return Err(format!("{spec}: unrecognized history modifier")),
+                    };
+                    let modifier: String = chars[modifier_start..idx].iter().collect();
+                    text = self.substitute(&text, &old, &new, global, &modifier)?;
+                }
+                _ => {
+                    let modifier: String = chars[modifier_start..idx].iter().collect();
+                    return Err(format!("{modifier}: unrecognized history modifier"));
+                }
+            }
+        }
+
+        Ok((text, idx - start))
+    }
+
+    /// event_line looks up the history entry referred to by `event`.
+    fn event_line(&self, event: Event, spec: &str) -> Result<String, String> {
+        let len = self.history.len();
+        let found = match event {
+            Event::Relative(n) if n >= 1 && n <= len => Some(self.history[len - n].as_ref()),
+            Event::Absolute(n) if n >= 1 && n <= len => Some(self.history[n - 1].as_ref()),
+            Event::Prefix(s) => self
+                .history
+                .iter()
+                .rev()
+                .map(|h| h.as_ref())
+                .find(|h| h.starts_with(&s)),
+            Event::Contains(s) => self
+                .history
+                .iter()
+                .rev()
+                .map(|h| h.as_ref())
+                .find(|h| h.contains(&s)),
+            _ => None,
+        };
+
+        match found {
+            Some(line) => Ok(line.to_string()),
+            None => Err(format!("{spec}: event not found")),
+        }
+    }
+
+    /// substitute replaces the first (or every if `global`) `old` in `text` with `new`.
+    /// An empty `old` reuses the last substitution, and `&` in `new` stands for `old`.
+    fn substitute(
+        &mut self,
+        text: &str,
+        old: &str,
+        new: &str,
+        global: bool,
+        modifier: &str,
+    ) -> Result<String, String> {
+        let old = if old.is_empty() {
+            self.subst.old.clone()
+        } else {
+            old.to_string()
+        };
+        if old.is_empty() {
+            return Err(format!("{modifier}: no previous substitution"));
+        }
+        self.subst = Substitution {
+            old: old.clone(),
+            new: new.to_string(),
+        };
+
+        let new = expand_ampersand(new, &old);
+        if !text.contains(&old) {
+            return Err(format!("{modifier}: substitution failed"));
+        }
+        if global {
+            Ok(text.replace(&old, &new))
+        } else {
+            Ok(text.replacen(&old, &new, 1))
+        }
+    }
+}
+
+fn parse_number(chars: &[char], start: usize) -> (usize, usize) {
+    let digits: String = chars[start..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    (digits.parse().unwrap_or(usize::MAX), digits.len())
+}
+
+fn is_event_terminator(ch: char) -> bool {
+    ch.is_whitespace()
+        || matches!(
+            ch,
+            ':' | ';' | '&' | '|' | '<' | '>' | '(' | ')' | '"' | '\''
+        )
+}
+
+fn is_word_designator(ch: char) -> bool {
+    ch.is_ascii_digit() || matches!(ch, '^' | '$' | '*' | '-' | '%')
+}
+
+/// suffix_position returns the position of the `.` starting the filename suffix, if any.
+fn suffix_position(text: &str) -> Option<usize> {
+    let base_start = text.rfind('/').map(|p| p + 1).unwrap_or(0);
+    text[base_start..].rfind('.').map(|p| base_start + p)
+}
+
+/// parse_substitution parses `old<delim>new<delim>` starting at `start`.
+/// The final delimiter may be omitted at the end of the line.
+/// A backslash escapes the delimiter and `&`.
+fn parse_substitution(chars: &[char], start: usize, delim: char) -> (String, String, usize) {
+    let mut parts = [String::new(), String::new()];
+    let mut part = 0;
+    let mut idx = start;
+
+    while idx < chars.len() && part < 2 {
+        let ch = chars[idx];
+        if ch == '\\' && chars.get(idx + 1).is_some_and(|&c| c == delim || c == '&') {
+            // Keep escaped `&` escaped so that it's not replaced later
+            if chars[idx + 1] == '&' && part == 1 {
+                parts[part].push('\\');
+            }
+            parts[part].push(chars[idx + 1]);
+            idx += 2;
+            continue;
+        }
+        if ch == delim {
+            part += 1;
+        } else {
+            parts[part].push(ch);
+        }
+        idx += 1;
+    }
+
+    let [old, new] = parts;
+    (old, new, idx - start)
+}
+
+/// expand_ampersand replaces unescaped `&` in `new` with `old`.
+fn expand_ampersand(new: &str, old: &str) -> String {
+    let mut out = String::new();
+    let mut chars = new.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if chars.peek() == Some(&'&') => {
+                out.push('&');
+                chars.next();
+            }
+            '&' => out.push_str(old),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// select_words applies the word designator starting at `start` to `line`.
+/// Returns the selected words joined with spaces and the number of chars consumed,
+/// or the number of chars of the designator if it selects no words.
+fn select_words(line: &str, chars: &[char], start: usize) -> Result<(String, usize), usize> {
+    let words = split_words(line);
+    let last = words.len().saturating_sub(1);
+    let mut idx = start;
+
+    let parse_index = |idx: &mut usize| -> Option<usize> {
+        match chars.get(*idx) {
+            Some('^') => {
+                *idx += 1;
+                Some(1)
+            }
+            Some('$') => {
+                *idx += 1;
+                Some(last)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let (n, len) = parse_number(chars, *idx);
+                *idx += len;
+                Some(n)
+            }
+            _ => None,
+        }
+    };
+
+    let (from, to) = match chars.get(idx) {
+        Some('*') => {
+            idx += 1;
+            if words.len() <= 1 {
+                return Ok((String::new(), idx - start));
+            }
+            (1, last)
+        }
+        Some('%') => {
+            // `%` is the word matched by the most recent `?string?` search, which we
+            // don't track, so it refers to the last word like bash does with no search.
+            idx += 1;
+            (last, last)
+        }
+        Some('-') => {
+            idx += 1;
+            let to = parse_index(&mut idx).unwrap_or(last.saturating_sub(1));
+            (0, to)
+        }
+        _ => {
+            let from = parse_index(&mut idx).ok_or(idx - start)?;
+            match chars.get(idx) {
+                Some('*') => {
+                    idx += 1;
+                    (from, last)
+                }
+                Some('-') => {
+                    idx += 1;
+                    // `x-` abbreviates `x-$` but omits the last word
+                    let to = parse_index(&mut idx).unwrap_or(last.saturating_sub(1));
+                    (from, to)
+                }
+                _ => (from, from),
+            }
+        }
+    };
+
+    if from > to || to > last || words.is_empty() {
+        return Err(idx - start);
+    }
+    Ok((words[from..=to].join(" "), idx - start))
+}
+
+/// split_words splits a history line into words, keeping quoted strings intact and
+/// treating the shell operators `;`, `&`, `|`, `<`, `>`, `(`, `)` as separate words.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if quote != Some('\'') => {
+                word.push(ch);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+            }
+            '\'' | '"' if quote.is_none() => {
+                quote = Some(ch);
+                word.push(ch);
+            }
+            _ if quote == Some(ch) => {
+                quote = None;
+                word.push(ch);
+            }
+            _ if quote.is_some() => word.push(ch),
+            _ if ch.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            ';' | '&' | '|' | '<' | '>' | '(' | ')' => {
+                // Redirections like `2>` keep the fd with the operator
+                let is_fd = word.chars().all(|c| c.is_ascii_digit()) && matches!(ch, '<' | '>');
+                if !is_fd && !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                word.push(ch);
+                // Double char operators such as `&&`, `||`, `>>`, `;;`
+                if chars.peek() == Some(&ch) && ch != '(' && ch != ')' {
+                    word.push(ch);
+                    chars.next();
+                }
+                words.push(std::mem::take(&mut word));
+            }
+            _ => word.push(ch),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod expand_test {
+    use crate::history::expand;
+
+    const HISTORY: [&str; 4] = [
+        "cd /usr/local/src",
+        "tar xzf archive.tar.gz -C /tmp/out",
+        "echo 'hello world' foo.txt",
+        "apt update",
+    ];
+
+    fn expanded(line: &str) -> String {
+        expand(line, &HISTORY).unwrap().line
+    }
+
+    #[test]
+    fn test_no_reference() {
+        let exp = expand("echo hello", &HISTORY).unwrap();
+        assert_eq!(exp.line, "echo hello");
+        assert!(!exp.expanded);
+        assert!(!exp.print_only);
+    }
+
+    #[test]
+    fn test_bang_bang() {
+        let exp = expand("sudo !!", &HISTORY).unwrap();
+        assert_eq!(exp.line, "sudo apt update");
+        assert!(exp.expanded);
+    }
+
+    #[test]
+    fn test_literal_bang() {
+        assert_eq!(expanded("echo hi!"), "echo hi!");
+        assert_eq!(expanded("echo ! x"), "echo ! x");
+        assert_eq!(expanded("a != b"), "a != b");
+    }
+
+    #[test]
+    fn test_single_quotes_and_escapes() {
+        assert_eq!(expanded("echo '!!'"), "echo '!!'");
+        assert_eq!(expanded(r"echo \!!"), r"echo \!!");
+        assert_eq!(expanded("echo \"!!\""), "echo \"apt update\"");
+    }
+
+    #[test]
+    fn test_absolute_and_relative() {
+        assert_eq!(expanded("!1"), "cd /usr/local/src");
+        assert_eq!(expanded("!-2"), "echo 'hello world' foo.txt");
+    }
+
+    #[test]
+    fn test_prefix_and_contains() {
+        assert_eq!(expanded("!ta"), "tar xzf archive.tar.gz -C /tmp/out");
+        assert_eq!(expanded("!?hello?"), "echo 'hello world' foo.txt");
+        assert_eq!(expanded("!?local"), "cd /usr/local/src");
+    }
+
+    #[test]
+    fn test_event_not_found() {
+        assert_eq!(
+            expand("!nope", &HISTORY).unwrap_err(),
+            "!nope: event not found"
+        );
+        assert_eq!(expand("!9", &HISTORY).unwrap_err(), "!9: event not found");
+    }
+
+    #[test]
+    fn test_word_designators() {
+        assert_eq!(expanded("vim !$"), "vim update");
+        assert_eq!(expanded("!-2:1"), "'hello world'");
+        assert_eq!(expanded("!-2:$"), "foo.txt");
+        assert_eq!(expanded("!-3:0"), "tar");
+        assert_eq!(expanded("!-3:^"), "xzf");
+        assert_eq!(expanded("!-3:1-3"), "xzf archive.tar.gz -C");
+        assert_eq!(expanded("!-3:2*"), "archive.tar.gz -C /tmp/out");
+        assert_eq!(expanded("!-3:2-"), "archive.tar.gz -C");
+        assert_eq!(expanded("!-3:*"), "xzf archive.tar.gz -C /tmp/out");
+        assert_eq!(
+            expand("!!:5", &HISTORY).unwrap_err(),
+            "!!:5: bad word specifier"
+        );
+    }
+
+    #[test]
+    fn test_modifiers() {
+        assert_eq!(expanded("!1:$:h"), "/usr/local");
+        assert_eq!(expanded("!1:$:t"), "src");
+        assert_eq!(expanded("!-3:2:r"), "archive.tar");
+        assert_eq!(expanded("!-3:2:e"), ".gz");
+        assert_eq!(expanded("!-3:2:r:r"), "archive");
+        assert_eq!(expanded("!!:s/update/upgrade/"), "apt upgrade");
+        assert_eq!(
+            expanded("!-3:gs/a/A/"),
+            "tAr xzf Archive.tAr.gz -C /tmp/out"
+        );
+        assert_eq!(expanded("!!:s/apt/& &"), "apt apt update");
+        assert_eq!(
+            expand("!!:s/nope/x/", &HISTORY).unwrap_err(),
+            ":s/nope/x/: substitution failed"
+        );
+    }
+
+    #[test]
+    fn test_print_only() {
+        let exp = expand("!!:p", &HISTORY).unwrap();
+        assert_eq!(exp.line, "apt update");
+        assert!(exp.print_only);
+    }
+
+    #[test]
+    fn test_quick_substitution() {
+        assert_eq!(expanded("^update^upgrade^"), "apt upgrade");
+        assert_eq!(expanded("^update^upgrade"), "apt upgrade");
+        assert_eq!(expanded("^update^upgrade^ -y"), "apt upgrade -y");
+        assert_eq!(
+            expand("^nope^x", &HISTORY).unwrap_err(),
+            "^nope^x: substitution failed"
+        );
+    }
+
+    #[test]
+    fn test_current_line() {
+        assert_eq!(expanded("cp file.txt !#:1.bak"), "cp file.txt file.txt.bak");
+    }
+}
+
+#[cfg(test)]
+mod store_test {
+    use std::fs;
+
+    use crate::{
+        history::{format_entry, parse_file, Entry, HistoryStore},
+        shell::Shell,
+        util::TempDir,
+    };
+
+    fn entry(line: &str, dir: &str, time: u64) -> Entry {
+        Entry {
+            line: line.to_string(),
+            dir: dir.to_string(),
+            time,
+        }
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let entries = vec![
+            entry("echo 'a\\b'", "/tmp", 10),
+            entry("for x in 1 2\ndo echo $x\ndone", "/", 20),
+            entry("#1 not a time", "/", 30),
+        ];
+        let contents: String = entries.iter().map(format_entry).collect();
+        assert_eq!(parse_file(&contents), entries);
+
+        // Files without times, as other shells write them
+        assert_eq!(
+            parse_file("ls\n\ncd /\n#12 /home\npwd\n"),
+            vec![
+                entry("ls", "", 0),
+                entry("cd /", "", 0),
+                entry("pwd", "/home", 12)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_and_add() {
+        let dir = TempDir::new("history_test");
+        let file = dir.join("history");
+        let contents: String = (1..=5)
+            .map(|n| format_entry(&entry(&format!("echo {n}"), "/", n)))
+            .collect();
+        fs::write(&file, contents).unwrap();
+
+        let mut shell = Shell::new();
+        shell.set_var("HISTFILE", file.to_str().unwrap());
+        shell.set_var("HISTSIZE", "3");
+        let mut history = HistoryStore::new(&shell);
+        history.load().unwrap();
+        let lines = |history: &HistoryStore| -> Vec<String> {
+            history.entries().iter().map(|e| e.line.clone()).collect()
+        };
+        assert_eq!(lines(&history), vec!["echo 3", "echo 4", "echo 5"]);
+
+        history.add(entry("echo 6", "/", 6)).unwrap();
+        assert_eq!(lines(&history), vec!["echo 4", "echo 5", "echo 6"]);
+
+        // The file was trimmed when loaded and the new entry appended
+        assert_eq!(fs::read_to_string(&file).unwrap().lines().count(), 8);
+        let mut reloaded = HistoryStore::new(&shell);
+        reloaded.load().unwrap();
+        assert_eq!(lines(&reloaded), vec!["echo 4", "echo 5", "echo 6"]);
+    }
+}
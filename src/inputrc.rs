@@ -0,0 +1,606 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rustyline::{KeyCode, KeyEvent, Modifiers};
+
+use crate::util;
+
+/// What a key sequence is bound to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Action {
+    /// A readline function, by name.
+    Function(String),
+    /// Text inserted into the line.
+    Macro(String),
+    /// A shell command run with `bind -x`.
+    Command(String),
+}
+
+/// A line of the inputrc file, also given to `bind` as an argument.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Line {
+    /// `set name value`, which sets a readline variable.
+    Set { name: String, value: String },
+    /// A key sequence bound to a function or a macro.
+    Bind { keys: Vec<char>, action: Action },
+}
+
+/// parse_line parses a line of the inputrc file, which is either `set variable value`
+/// or binds a key sequence to a function, like `"\C-g": forward-word`, or to a macro,
+/// like `"\C-xg": "git status"`. Returns none for blank lines and comments.
+pub(crate) fn parse_line(line: &str) -> Result<Option<Line>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    if let Some(rest) = line
+        .strip_prefix("set")
+        .filter(|rest| rest.starts_with([' ', '\t']))
+    {
+        let mut words = rest.split_whitespace();
+        return Ok(match (words.next(), words.next()) {
+            (Some(name), Some(value)) => Some(Line::Set {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => None,
+        });
+    }
+
+    let (keys, value) = parse_binding(line)?;
+    let action = match value.chars().next().filter(|c| matches!(c, '"' | '\'')) {
+        Some(quote) => {
+            let text = value[1..].strip_suffix(quote).unwrap_or(&value[1..]);
+            Action::Macro(unescape(text).into_iter().collect())
+        }
+        None => {
+            let name = value.split_whitespace().next().unwrap_or_default();
+            Action::Function(name.to_string())
+        }
+    };
+    Ok(Some(Line::Bind { keys, action }))
+}
+
+/// parse_command parses the value of `bind -x`, a key sequence and the shell command it
+/// runs, like `"\C-t": "fzf-file-widget"`. The command may be in quotes.
+pub(crate) fn parse_command(value: &str) -> Result<(Vec<char>, String), String> {
+    let (keys, command) = parse_binding(value)?;
+    let command = ["\"", "'"]
+        .iter()
+        .find_map(|q| command.strip_prefix(q)?.strip_suffix(q))
+        .unwrap_or(command);
+    Ok((keys, command.to_string()))
+}
+
+/// parse_binding splits a binding into its key sequence and what's after the colon.
+fn parse_binding(line: &str) -> Result<(Vec<char>, &str), String> {
+    let (keys, rest) = parse_keys(line)?;
+    let value = rest
+        .trim_start()
+        .strip_prefix(':')
+        .ok_or("missing colon separator")?
+        .trim();
+    Ok((keys, value))
+}
+
+/// Number of files that can be included in each other, to stop include loops.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Reader reads inputrc files, handling the `$if`, `$else`, `$endif` and `$include`
+/// directives, and passes the lines that apply on.
+pub(crate) struct Reader {
+    /// Editing mode that `$if mode=` tests, which lines setting `editing-mode` change.
+    mode: String,
+    term: String,
+    home: String,
+    /// Errors in the lines read, with the file and line number. They don't stop the
+    /// rest of the file from being read.
+    pub(crate) errors: Vec<String>,
+}
+
+impl Reader {
+    pub(crate) fn new(mode: &str, term: &str, home: &str) -> Self {
+        Self {
+            mode: mode.to_string(),
+            term: term.to_string(),
+            home: home.to_string(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// read reads the file at `path`, calling `apply` with each line that applies, in
+    /// order. The errors `apply` returns are kept with those of the file.
+    pub(crate) fn read<F>(&mut self, path: &Path, apply: &mut F) -> io::Result<()>
+    where
+        F: FnMut(Line) -> Result<(), String>,
+    {
+        self.read_nested(path, 0, apply)
+    }
+
+    fn read_nested<F>(&mut self, path: &Path, depth: usize, apply: &mut F) -> io::Result<()>
+    where
+        F: FnMut(Line) -> Result<(), String>,
+    {
+        let contents = fs::read_to_string(path)?;
+        // Whether each of the enclosing `$if` branches is being read
+        let mut branches: Vec<bool> = Vec::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let error = |e: &str| format!("{}: line {}: {e}", path.display(), idx + 1);
+            let line = line.trim();
+            let reading = branches.iter().all(|b| *b);
+            let directive = match line.strip_prefix('$') {
+                Some(directive) => directive,
+                None => {
+                    if reading {
+                        if let Err(e) =
+                            parse_line(line).and_then(|line| self.apply_line(line, apply))
+                        {
+                            self.errors.push(error(&e));
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let (name, arg) = directive
+                .split_once(char::is_whitespace)
+                .unwrap_or((directive, ""));
+            match name {
+                "if" => branches.push(self.test(arg.trim())),
+                "else" => match branches.last_mut() {
+                    Some(branch) => *branch = !*branch,
+                    None => self.errors.push(error("$else found without matching $if")),
+                },
+                "endif" => {
+                    if branches.pop().is_none() {
+                        self.errors.push(error("$endif without matching $if"));
+                    }
+                }
+                "include" if !reading => (),
+                "include" if depth == MAX_INCLUDE_DEPTH => {
+                    self.errors.push(error("too many nested $include"));
+                }
+                "include" => {
+                    let file = arg.trim();
+                    let file = match file.strip_prefix("~/") {
+                        Some(rest) => Path::new(&self.home).join(rest),
+                        None => PathBuf::from(file),
+                    };
+                    if let Err(e) = self.read_nested(&file, depth + 1, apply) {
+                        let e = format!("{}: {}", file.display(), util::error_message(&e));
+                        self.errors.push(error(&e));
+                    }
+                }
+                _ => self
+                    .errors
+                    .push(error(&format!("{name}: unknown parser directive"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// apply_line passes a line on, keeping track of the editing mode it sets.
+    fn apply_line<F>(&mut self, line: Option<Line>, apply: &mut F) -> Result<(), String>
+    where
+        F: FnMut(Line) -> Result<(), String>,
+    {
+        let line = match line {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        if let Line::Set { name, value } = &line {
+            if name == "editing-mode" && ["emacs", "vi"].contains(&value.as_str()) {
+                self.mode = value.clone();
+            }
+        }
+        apply(line)
+    }
+
+    /// test checks the condition of `$if`: `mode=emacs` or `mode=vi` for the editing
+    /// mode, `term=name` for the terminal, or the name of the application. The shell
+    /// answers to `Bash` so that files written for bash apply to it.
+    fn test(&self, condition: &str) -> bool {
+        if let Some(mode) = condition.strip_prefix("mode=") {
+            return mode == self.mode;
+        }
+        if let Some(term) = condition.strip_prefix("term=") {
+            return term == self.term || Some(term) == self.term.split('-').next();
+        }
+        condition.eq_ignore_ascii_case("bash")
+    }
+}
+
+/// key_events converts the chars a key sequence sends to the keys rustyline reads them
+/// as, e.g. `\e[A` to Up and `\ef` to Alt-f.
+pub(crate) fn key_events(keys: &[char]) -> Vec<KeyEvent> {
+    let mut events = Vec::new();
+    let mut idx = 0;
+    while idx < keys.len() {
+        if keys[idx] != '\x1b' || idx + 1 == keys.len() {
+            events.push(KeyEvent::new(keys[idx], Modifiers::NONE));
+            idx += 1;
+            continue;
+        }
+        if let Some((event, len)) = special_key(&keys[idx..]) {
+            events.push(event);
+            idx += len;
+            continue;
+        }
+        // Otherwise escape is how meta is sent, also before a special key
+        match special_key(&keys[idx + 1..]) {
+            Some((KeyEvent(code, mods), len)) => {
+                events.push(KeyEvent(code, mods | Modifiers::ALT));
+                idx += 1 + len;
+            }
+            None => {
+                events.push(KeyEvent::new(keys[idx + 1], Modifiers::ALT));
+                idx += 2;
+            }
+        }
+    }
+    events
+}
+
+/// special_key parses the escape sequence of a special key at the start of `keys`,
+/// like `\e[A` for Up or `\e[1;5C` for Ctrl-Right. Returns the key and the length of
+/// the sequence.
+fn special_key(keys: &[char]) -> Option<(KeyEvent, usize)> {
+    if keys.first() != Some(&'\x1b') || !matches!(keys.get(1), Some('[' | 'O')) {
+        return None;
+    }
+    let params: String = keys[2..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit() || **c == ';')
+        .collect();
+    let end = 2 + params.len();
+    let (number, modifier) = params.split_once(';').unwrap_or((&params, ""));
+    let code = match (keys.get(end)?, number) {
+        ('A', "" | "1") => KeyCode::Up,
+        ('B', "" | "1") => KeyCode::Down,
+        ('C', "" | "1") => KeyCode::Right,
+        ('D', "" | "1") => KeyCode::Left,
+        ('H', "" | "1") | ('~', "1" | "7") => KeyCode::Home,
+        ('F', "" | "1") | ('~', "4" | "8") => KeyCode::End,
+        ('~', "2") => KeyCode::Insert,
+        ('~', "3") => KeyCode::Delete,
+        ('~', "5") => KeyCode::PageUp,
+        ('~', "6") => KeyCode::PageDown,
+        _ => return None,
+    };
+
+    // Modifiers are sent as one more than a bit mask of shift, alt and ctrl
+    let mask = modifier.parse::<u8>().unwrap_or(1).saturating_sub(1);
+    let mut mods = Modifiers::NONE;
+    for (bit, modifier) in [
+        (1, Modifiers::SHIFT),
+        (2, Modifiers::ALT),
+        (4, Modifiers::CTRL),
+    ] {
+        if mask & bit != 0 {
+            mods |= modifier;
+        }
+    }
+    Some((KeyEvent(code, mods), end + 1))
+}
+
+/// parse_keys parses a key sequence, either in double quotes with readline's escapes
+/// like `"\C-x\M-a"`, or a key name like `Control-x` or `M-Rubout`. Returns the chars
+/// it sends and the rest of the line.
+pub(crate) fn parse_keys(line: &str) -> Result<(Vec<char>, &str), String> {
+    if let Some(quoted) = line.strip_prefix('"') {
+        let mut end = None;
+        let mut escaped = false;
+        for (idx, ch) in quoted.char_indices() {
+            match ch {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => {
+                    end = Some(idx);
+                    break;
+                }
+                _ => escaped = false,
+            }
+        }
+        let end = end.ok_or("no closing `\"' in key binding")?;
+        return Ok((unescape(&quoted[..end]), &quoted[end + 1..]));
+    }
+
+    let end = line
+        .find(|c: char| c == ':' || c.is_whitespace())
+        .unwrap_or(line.len());
+    let name = &line[..end];
+    let mut meta = false;
+    let mut control = false;
+    let mut key = name;
+    loop {
+        if let Some(rest) = ["Control-", "C-"].iter().find_map(|p| key.strip_prefix(p)) {
+            control = true;
+            key = rest;
+        } else if let Some(rest) = ["Meta-", "M-"].iter().find_map(|p| key.strip_prefix(p)) {
+            meta = true;
+            key = rest;
+        } else {
+            break;
+        }
+    }
+    let mut ch = match key.to_lowercase().as_str() {
+        "rubout" | "del" => '\x7f',
+        "escape" | "esc" => '\x1b',
+        "lfd" | "newline" => '\n',
+        "ret" | "return" => '\r',
+        "spc" | "space" => ' ',
+        "tab" => '\t',
+        _ => {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), None) => ch,
+                _ => return Err(format!("{name}: unknown key name")),
+            }
+        }
+    };
+    if control {
+        ch = control_char(ch);
+    }
+    let keys = match meta {
+        true => vec!['\x1b', ch],
+        false => vec![ch],
+    };
+    Ok((keys, &line[end..]))
+}
+
+fn control_char(ch: char) -> char {
+    match ch {
+        '?' => '\x7f',
+        _ => ((ch.to_ascii_lowercase() as u8) & 0x1f) as char,
+    }
+}
+
+/// unescape replaces readline's backslash escapes in key sequences and macros.
+pub(crate) fn unescape(s: &str) -> Vec<char> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut unescaped = Vec::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let (keys, len) = unescape_key(&chars[idx..]);
+        unescaped.extend(keys);
+        idx += len;
+    }
+    unescaped
+}
+
+/// unescape_key unescapes the key at the start of `chars`, returning the chars it sends
+/// and how many chars it took. Keys can be escaped again after `\C-` and `\M-`.
+fn unescape_key(chars: &[char]) -> (Vec<char>, usize) {
+    if chars[0] != '\\' || chars.len() == 1 {
+        return (vec![chars[0]], 1);
+    }
+    let escape = chars[1];
+    let ch = match escape {
+        'C' | 'M' if chars.get(2) == Some(&'-') && chars.len() > 3 => {
+            let (mut keys, len) = unescape_key(&chars[3..]);
+            match escape {
+                'C' => {
+                    let last = keys.len() - 1;
+                    keys[last] = control_char(keys[last]);
+                }
+                _ => keys.insert(0, '\x1b'),
+            }
+            return (keys, 3 + len);
+        }
+        '0'..='7' => {
+            let digits: String = chars[1..]
+                .iter()
+                .take(3)
+                .take_while(|c| c.is_digit(8))
+                .collect();
+            let code = u32::from_str_radix(&digits, 8).unwrap_or_default();
+            return (
+                vec![char::from_u32(code).unwrap_or_default()],
+                1 + digits.len(),
+            );
+        }
+        'x' if chars.get(2).is_some_and(char::is_ascii_hexdigit) => {
+            let digits: String = chars[2..]
+                .iter()
+                .take(2)
+                .take_while(|c| c.is_ascii_hexdigit())
+                .collect();
+            let code = u32::from_str_radix(&digits, 16).unwrap_or_default();
+            return (
+                vec![char::from_u32(code).unwrap_or_default()],
+                2 + digits.len(),
+            );
+        }
+        'e' => '\x1b',
+        'a' => '\x07',
+        'b' => '\x08',
+        'd' => '\x7f',
+        'f' => '\x0c',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'v' => '\x0b',
+        _ => escape,
+    };
+    (vec![ch], 2)
+}
+
+/// format_keys formats a key sequence as it's written in double quotes.
+pub(crate) fn format_keys(keys: &[char]) -> String {
+    let mut formatted = String::new();
+    for ch in keys {
+        match ch {
+            '\x1b' => formatted.push_str("\\e"),
+            '\x7f' => formatted.push_str("\\C-?"),
+            '"' | '\\' => {
+                formatted.push('\\');
+                formatted.push(*ch);
+            }
+            '\0'..='\x1f' => {
+                let key = (*ch as u8 | 0x40) as char;
+                formatted.push_str("\\C-");
+                if key == '\\' {
+                    formatted.push('\\');
+                }
+                formatted.push(key.to_ascii_lowercase());
+            }
+            _ => formatted.push(*ch),
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod inputrc_test {
+    use std::fs;
+
+    use rustyline::{KeyCode, KeyEvent, Modifiers};
+
+    use crate::{
+        inputrc::{format_keys, key_events, parse_keys, parse_line, Action, Line, Reader},
+        util::TempDir,
+    };
+
+    fn keys(spec: &str) -> Vec<char> {
+        parse_keys(spec).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(keys(r#""\C-x\C-r""#), vec!['\x18', '\x12']);
+        assert_eq!(keys(r#""\M-f\e\d""#), vec!['\x1b', 'f', '\x1b', '\x7f']);
+        assert_eq!(keys(r#""\C-\M-h""#), vec!['\x1b', '\x08']);
+        assert_eq!(keys(r#""\101\x42\"""#), vec!['A', 'B', '"']);
+        assert_eq!(keys("Control-u: kill-whole-line"), vec!['\x15']);
+        assert_eq!(keys("M-Rubout"), vec!['\x1b', '\x7f']);
+        assert_eq!(parse_keys(r#""\C-x"  : x"#).unwrap().1, "  : x");
+        assert!(parse_keys("Hyper-x").is_err());
+        assert!(parse_keys(r#""\C-x"#).is_err());
+    }
+
+    #[test]
+    fn test_key_events() {
+        assert_eq!(
+            key_events(&keys(r#""\e[A\e[1;5C\e[3~""#)),
+            vec![
+                KeyEvent(KeyCode::Up, Modifiers::NONE),
+                KeyEvent(KeyCode::Right, Modifiers::CTRL),
+                KeyEvent(KeyCode::Delete, Modifiers::NONE),
+            ]
+        );
+        assert_eq!(
+            key_events(&keys(r#""\ef\e\e[D\C-xa""#)),
+            vec![
+                KeyEvent::alt('f'),
+                KeyEvent(KeyCode::Left, Modifiers::ALT),
+                KeyEvent::ctrl('X'),
+                KeyEvent::from('a'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_keys() {
+        for spec in [r"\C-x\C-r", r"\ef", r"\C-?", r#"a\"\\"#] {
+            assert_eq!(format_keys(&keys(&format!("\"{spec}\""))), spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("  # comment"), Ok(None));
+        assert_eq!(
+            parse_line("set bell-style none"),
+            Ok(Some(Line::Set {
+                name: "bell-style".to_string(),
+                value: "none".to_string(),
+            }))
+        );
+        assert_eq!(
+            parse_line(r#""\C-o": "git \"status\"""#),
+            Ok(Some(Line::Bind {
+                keys: vec!['\x0f'],
+                action: Action::Macro("git \"status\"".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse_line("Control-g: forward-word"),
+            Ok(Some(Line::Bind {
+                keys: vec!['\x07'],
+                action: Action::Function("forward-word".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse_line(r#""\C-b" backward-char"#),
+            Err("missing colon separator".to_string())
+        );
+    }
+
+    #[test]
+    fn test_inputrc() {
+        let dir = TempDir::new("inputrc_test");
+        let file = dir.join("inputrc");
+        let contents = [
+            "# comment",
+            "$if mode=emacs",
+            r#"  "\C-a": end-of-line"#,
+            "  $if term=xterm",
+            "    set bell-style none",
+            "  $else",
+            "    set bell-style visible",
+            "  $endif",
+            "$else",
+            r#"  "\C-a": beginning-of-line"#,
+            "$endif",
+            "$if Bash",
+            "set editing-mode vi",
+            "$endif",
+            r#""\C-b" backward-char"#,
+            "$endif",
+            "$if mode=vi",
+            r#""\C-a": no-such-function"#,
+            "$endif",
+        ];
+        fs::write(&file, contents.join("\n")).unwrap();
+
+        let mut reader = Reader::new("emacs", "xterm-256color", "");
+        let mut lines = Vec::new();
+        reader
+            .read(&file, &mut |line| match line {
+                Line::Bind {
+                    action: Action::Function(name),
+                    ..
+                } if name == "no-such-function" => Err(format!("`{name}': unknown")),
+                line => {
+                    lines.push(line);
+                    Ok(())
+                }
+            })
+            .unwrap();
+        let path = file.display();
+        assert_eq!(
+            reader.errors,
+            vec![
+                format!("{path}: line 15: missing colon separator"),
+                format!("{path}: line 16: $endif without matching $if"),
+                format!("{path}: line 18: `no-such-function': unknown"),
+            ]
+        );
+        let set = |name: &str, value: &str| Line::Set {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        assert_eq!(
+            lines,
+            vec![
+                Line::Bind {
+                    keys: vec!['\x01'],
+                    action: Action::Function("end-of-line".to_string()),
+                },
+                set("bell-style", "none"),
+                set("editing-mode", "vi"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1516 @@
+use std::{collections::BTreeMap, fmt, rc::Rc};
+
+use crate::alias;
+
+/// A list of commands separated by `;` or newlines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct List(pub(crate) Vec<AndOr>);
+
+/// Commands joined with `&&` and `||`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AndOr {
+    pub(crate) first: Command,
+    pub(crate) rest: Vec<(Connector, Command)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Command {
+    Simple(SimpleCommand),
+    FunctionDef {
+        name: String,
+        body: Rc<List>,
+    },
+    /// A compound command with the redirections after it, which apply to all of it.
+    Compound(Compound, Vec<Redirect>),
+    /// Commands joined with `|`, which run at the same time with the output of each
+    /// going to the input of the next.
+    Pipeline(Vec<Command>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Compound {
+    /// `if condition; then body; elif condition; then body; else body; fi`
+    If(If),
+    /// `while condition; do body; done`, or `until` which loops while the condition fails.
+    Loop(Loop),
+    /// `[[ expression ]]`
+    Conditional(CondExpr),
+    /// `((expression))`, with the expression still unexpanded.
+    Arithmetic(String),
+    /// `( list )`, which runs in a copy of the shell so that it can't change its state.
+    Subshell(List),
+    /// `{ list; }`
+    Group(List),
+}
+
+/// An expression of `[[ ... ]]`, with its words still unexpanded.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CondExpr {
+    /// A word on its own, which is true if it's not empty.
+    Word(String),
+    Unary(String, String),
+    Binary(String, String, String),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct If {
+    /// Conditions with the body run if they succeed, for the `if` and then each `elif`.
+    pub(crate) branches: Vec<(List, List)>,
+    pub(crate) else_body: Option<List>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Loop {
+    pub(crate) until: bool,
+    pub(crate) condition: List,
+    pub(crate) body: List,
+}
+
+/// A command with its words still unexpanded, i.e. quotes and `$` are kept as typed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct SimpleCommand {
+    pub(crate) assignments: Vec<Assignment>,
+    pub(crate) words: Vec<String>,
+    pub(crate) redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Assignment {
+    pub(crate) name: String,
+    /// The subscript of `name[subscript]=value`, unexpanded.
+    pub(crate) subscript: Option<String>,
+    /// Whether it's `name+=value`, which adds to the current value.
+    pub(crate) append: bool,
+    pub(crate) value: AssignedValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AssignedValue {
+    Scalar(String),
+    /// The words of `name=(...)`, some of which may be `[subscript]=value`.
+    Array(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Redirect {
+    pub(crate) fd: i32,
+    pub(crate) kind: RedirectKind,
+    pub(crate) target: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RedirectKind {
+    /// `>`
+    Out,
+    /// `>>`
+    Append,
+    /// `<`
+    In,
+    /// `>&`
+    DupOut,
+    /// `<&`
+    DupIn,
+    /// `&>`, both stdout and stderr
+    OutErr,
+    /// `&>>`, both stdout and stderr
+    AppendOutErr,
+}
+
+impl RedirectKind {
+    fn default_fd(&self) -> i32 {
+        match self {
+            Self::In | Self::DupIn => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for RedirectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Out => ">",
+            Self::Append => ">>",
+            Self::In => "<",
+            Self::DupOut => ">&",
+            Self::DupIn => "<&",
+            Self::OutErr => "&>",
+            Self::AppendOutErr => "&>>",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Operator {
+    Semi,
+    Newline,
+    AndIf,
+    OrIf,
+    Amp,
+    Pipe,
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Semi => ";",
+            Self::Newline => "newline",
+            Self::AndIf => "&&",
+            Self::OrIf => "||",
+            Self::Amp => "&",
+            Self::Pipe => "|",
+            Self::LParen => "(",
+            Self::RParen => ")",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// A word with its quotes and escapes still in place.
+    Word(String),
+    Operator(Operator),
+    Redirect {
+        fd: Option<i32>,
+        kind: RedirectKind,
+    },
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "{w}"),
+            Self::Operator(op) => write!(f, "{op}"),
+            Self::Redirect { fd: Some(fd), kind } => write!(f, "{fd}{kind}"),
+            Self::Redirect { fd: None, kind } => write!(f, "{kind}"),
+        }
+    }
+}
+
+/// An error parsing the input.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ParseError {
+    /// The input ended in the middle of a command, e.g. inside quotes or before the `}`
+    /// of a function, so that more input could complete it.
+    Incomplete(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incomplete(e) | Self::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// parse parses the input into a list of commands.
+pub(crate) fn parse(input: &str, aliases: &BTreeMap<String, String>) -> Result<List, ParseError> {
+    // The tokenizer only fails when the input ends inside quotes or a substitution
+    let tokens = tokenize(input).map_err(ParseError::Incomplete)?;
+    let tokens = alias::expand_aliases(tokens, aliases).map_err(ParseError::Invalid)?;
+    let mut parser = Parser::new(tokens, None);
+    let list = parser.parse_list(&[]).map_err(|e| parser.error(e))?;
+    match parser.peek() {
+        Some(token) => Err(ParseError::Invalid(format!("parse error near {token}"))),
+        None => Ok(list),
+    }
+}
+
+/// Source parses the commands of a file one at a time, so that each can run before the
+/// next is parsed, e.g. to define an alias it uses. A command is read a line at a time
+/// until it's complete, and every line is tokenized and parsed once.
+pub(crate) struct Source {
+    chars: Vec<char>,
+    /// Index of the next line to read.
+    idx: usize,
+    /// Number of the next line to read, starting at 1.
+    line: usize,
+}
+
+impl Source {
+    pub(crate) fn new(input: &str) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            idx: 0,
+            line: 1,
+        }
+    }
+
+    /// next_command parses the next complete command, returning the number of the line
+    /// it starts on, or None at the end of the input. After an error, parsing continues
+    /// with the line after the ones read for the failed command.
+    pub(crate) fn next_command(
+        &mut self,
+        aliases: &BTreeMap<String, String>,
+    ) -> Option<(usize, Result<List, ParseError>)> {
+        let line = self.line;
+        let tokens = match self.read_line(aliases)? {
+            Ok(tokens) => tokens,
+            Err(e) => return Some((line, Err(e))),
+        };
+        let mut parser = Parser::new(tokens, Some((self, aliases)));
+        let parsed = parser.parse_line().map_err(|e| parser.error(e));
+        Some((line, parsed))
+    }
+
+    /// read_line tokenizes the next line, which goes on to the lines after it if it
+    /// ends inside quotes or a substitution, and expands its aliases.
+    fn read_line(
+        &mut self,
+        aliases: &BTreeMap<String, String>,
+    ) -> Option<Result<Vec<Token>, ParseError>> {
+        if self.idx == self.chars.len() {
+            return None;
+        }
+        let start = self.idx;
+        let tokens = tokenize_line(&self.chars, start);
+        // The rest of the input can't be tokenized, so it's all used up by the error
+        self.idx = match &tokens {
+            Ok((_, end)) => *end,
+            Err(_) => self.chars.len(),
+        };
+        self.line += self.chars[start..self.idx]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count();
+
+        let tokens = match tokens {
+            Ok((tokens, _)) => tokens,
+            Err(e) => return Some(Err(ParseError::Incomplete(e))),
+        };
+        Some(alias::expand_aliases(tokens, aliases).map_err(ParseError::Invalid))
+    }
+}
+
+/// tokenize splits the input into words and operators.
+/// Words keep their quotes so that expansions can tell quoted and unquoted parts apart.
+pub(crate) fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let (line, end) = tokenize_line(&chars, idx)?;
+        tokens.extend(line);
+        idx = end;
+    }
+    Ok(tokens)
+}
+
+/// tokenize_line tokenizes the input from `start` up to and including the next newline
+/// that isn't quoted or escaped, returning the tokens and the index after them.
+fn tokenize_line(chars: &[char], start: usize) -> Result<(Vec<Token>, usize), String> {
+    let mut tokens = Vec::new();
+    let mut idx = start;
+    // Whether the tokens are inside `[[ ... ]]`
+    let mut conditional = false;
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+        // The regular expression after `=~` can have `(`, `|` and `)` in it
+        if conditional
+            && !ch.is_whitespace()
+            && matches!(tokens.last(), Some(Token::Word(w)) if w == "=~")
+        {
+            let end = regex_word_end(chars, idx)?;
+            tokens.push(Token::Word(chars[idx..end].iter().collect()));
+            idx = end;
+            continue;
+        }
+        match ch {
+            '\n' => {
+                tokens.push(Token::Operator(Operator::Newline));
+                idx += 1;
+                break;
+            }
+            _ if ch.is_whitespace() => idx += 1,
+            // Line continuation
+            '\\' if chars.get(idx + 1) == Some(&'\n') => idx += 2,
+            '#' => {
+                while idx < chars.len() && chars[idx] != '\n' {
+                    idx += 1;
+                }
+            }
+            _ if is_metachar(ch) && !is_process_substitution(chars, idx) => {
+                // `((expression))` where a command can start is an arithmetic command
+                if starts_command(&tokens) {
+                    if let Some(end) = arithmetic_end(chars, idx)? {
+                        tokens.push(Token::Word(chars[idx..=end].iter().collect()));
+                        idx = end + 1;
+                        continue;
+                    }
+                }
+                let (token, len) = operator_at(chars, idx, None);
+                tokens.push(token);
+                idx += len;
+            }
+            _ => {
+                // Digits right before a redirect are the fd to redirect, e.g. `2>`
+                let digits = chars[idx..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_digit())
+                    .count();
+                if digits > 0 && matches!(chars.get(idx + digits), Some('<' | '>')) {
+                    let fd: String = chars[idx..idx + digits].iter().collect();
+                    let (token, len) = operator_at(chars, idx + digits, fd.parse().ok());
+                    tokens.push(token);
+                    idx += digits + len;
+                    continue;
+                }
+
+                let end = word_end(chars, idx)?;
+                let word: String = chars[idx..end].iter().collect();
+                match word.as_str() {
+                    "[[" => conditional = true,
+                    "]]" => conditional = false,
+                    _ => (),
+                }
+                tokens.push(Token::Word(word));
+                idx = end;
+            }
+        }
+    }
+
+    Ok((tokens, idx))
+}
+
+/// starts_command returns whether a command can start after the tokens.
+fn starts_command(tokens: &[Token]) -> bool {
+    match tokens.last() {
+        None | Some(Token::Operator(_)) => true,
+        Some(Token::Word(word)) => is_reserved_word(word),
+        Some(Token::Redirect { .. }) => false,
+    }
+}
+
+/// is_process_substitution returns whether a `<(list)` or `>(list)` process
+/// substitution starts at `idx`, which is part of a word rather than a redirection.
+pub(crate) fn is_process_substitution(chars: &[char], idx: usize) -> bool {
+    matches!(chars.get(idx), Some('<' | '>')) && chars.get(idx + 1) == Some(&'(')
+}
+
+pub(crate) fn is_metachar(ch: char) -> bool {
+    matches!(ch, '|' | '&' | ';' | '<' | '>' | '(' | ')')
+}
+
+/// operator_at lexes the operator starting at `idx`, returning it and its length.
+pub(crate) fn operator_at(chars: &[char], idx: usize, fd: Option<i32>) -> (Token, usize) {
+    let next = chars.get(idx + 1).copied();
+    let after = chars.get(idx + 2).copied();
+    let redirect = |kind| Token::Redirect { fd, kind };
+
+    match (chars[idx], next, after) {
+        ('&', Some('>'), Some('>')) => (redirect(RedirectKind::AppendOutErr), 3),
+        ('&', Some('>'), _) => (redirect(RedirectKind::OutErr), 2),
+        ('&', Some('&'), _) => (Token::Operator(Operator::AndIf), 2),
+        ('&', _, _) => (Token::Operator(Operator::Amp), 1),
+        ('|', Some('|'), _) => (Token::Operator(Operator::OrIf), 2),
+        ('|', _, _) => (Token::Operator(Operator::Pipe), 1),
+        (';', _, _) => (Token::Operator(Operator::Semi), 1),
+        ('(', _, _) => (Token::Operator(Operator::LParen), 1),
+        (')', _, _) => (Token::Operator(Operator::RParen), 1),
+        ('>', Some('>'), _) => (redirect(RedirectKind::Append), 2),
+        ('>', Some('&'), _) => (redirect(RedirectKind::DupOut), 2),
+        // `>|` overrides noclobber, which we don't have, so it's a plain `>`
+        ('>', Some('|'), _) => (redirect(RedirectKind::Out), 2),
+        ('>', _, _) => (redirect(RedirectKind::Out), 1),
+        ('<', Some('&'), _) => (redirect(RedirectKind::DupIn), 2),
+        _ => (redirect(RedirectKind::In), 1),
+    }
+}
+
+/// word_end returns the index right after the word starting at `start`.
+fn word_end(chars: &[char], start: usize) -> Result<usize, String> {
+    let mut idx = start;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        // The parentheses of `name=(words)` are part of the word
+        if ch == '(' && idx > start && chars[idx - 1] == '=' {
+            let prefix: String = chars[start..idx].iter().collect();
+            if parse_assignment(&prefix).is_ok_and(|a| a.is_some_and(|a| a.subscript.is_none())) {
+                idx = find_closing(chars, idx + 1, '(', ')')? + 1;
+                continue;
+            }
+        }
+        // So are blanks in the subscript of `name[subscript]=value`, and of
+        // `[subscript]=value` in the words of an array
+        if ch == '[' && (idx == start || is_name(&chars[start..idx].iter().collect::<String>())) {
+            let rest: String = chars[idx + 1..].iter().collect();
+            if let Some(len) = subscript_end(&rest) {
+                let end = idx + 1 + rest[..len].chars().count();
+                if matches!(chars[end + 1..], ['=', ..] | ['+', '=', ..]) {
+                    idx = end + 1;
+                    continue;
+                }
+            }
+        }
+        if is_process_substitution(chars, idx) {
+            idx = find_closing(chars, idx + 2, '(', ')')? + 1;
+            continue;
+        }
+        if ch.is_whitespace() || is_metachar(ch) {
+            break;
+        }
+        idx = match skip_construct(chars, idx)? {
+            Some(end) => end,
+            None => idx + 1,
+        };
+    }
+    Ok(idx)
+}
+
+/// regex_word_end returns the index right after the regular expression word starting at
+/// `start`, in which `(`, `|` and `)` are ordinary characters, and so is whitespace
+/// inside parentheses.
+fn regex_word_end(chars: &[char], start: usize) -> Result<usize, String> {
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        match ch {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            '|' => (),
+            _ if depth > 0 && ch.is_whitespace() && ch != '\n' => (),
+            _ if ch.is_whitespace() || is_metachar(ch) => break,
+            _ => {
+                if let Some(end) = skip_construct(chars, idx)? {
+                    idx = end;
+                    continue;
+                }
+            }
+        }
+        idx += 1;
+    }
+    Ok(idx)
+}
+
+/// skip_construct returns the index right after the quoted string, escape or
+/// substitution starting at `idx`, or None if there is none starting there.
+pub(crate) fn skip_construct(chars: &[char], idx: usize) -> Result<Option<usize>, String> {
+    match (chars[idx], chars.get(idx + 1)) {
+        ('\\', _) => Ok(Some((idx + 2).min(chars.len()))),
+        ('\'', _) => match chars[idx + 1..].iter().position(|&c| c == '\'') {
+            Some(len) => Ok(Some(idx + len + 2)),
+            None => Err("quotes unfinished".into()),
+        },
+        ('"', _) => {
+            let mut i = idx + 1;
+            while i < chars.len() {
+                match chars[i] {
+                    '"' => return Ok(Some(i + 1)),
+                    '\\' => i += 2,
+                    '$' | '`' => {
+                        i = match skip_construct(chars, i)? {
+                            Some(end) => end,
+                            None => i + 1,
+                        }
+                    }
+                    _ => i += 1,
+                }
+            }
+            Err("quotes unfinished".into())
+        }
+        ('`', _) => {
+            let mut i = idx + 1;
+            while i < chars.len() {
+                match chars[i] {
+                    '`' => return Ok(Some(i + 1)),
+                    '\\' => i += 2,
+                    _ => i += 1,
+                }
+            }
+            Err("quotes unfinished".into())
+        }
+        ('$', Some('(')) => Ok(Some(find_closing(chars, idx + 2, '(', ')')? + 1)),
+        ('$', Some('{')) => Ok(Some(find_closing(chars, idx + 2, '{', '}')? + 1)),
+        _ => Ok(None),
+    }
+}
+
+/// find_closing returns the index of the `close` char matching an already opened `open`,
+/// skipping over nested quotes and substitutions.
+pub(crate) fn find_closing(
+    chars: &[char],
+    start: usize,
+    open: char,
+    close: char,
+) -> Result<usize, String> {
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        if ch == close {
+            if depth == 0 {
+                return Ok(idx);
+            }
+            depth -= 1;
+        } else if ch == open {
+            depth += 1;
+        } else if let Some(end) = skip_construct(chars, idx)? {
+            idx = end;
+            continue;
+        }
+        idx += 1;
+    }
+    Err(format!("{open} unfinished"))
+}
+
+/// arithmetic_end returns the index of the final `)` if the parenthesis at `idx` starts
+/// the `((expression))` of an arithmetic expansion or command, rather than a nested
+/// parenthesis of a command substitution or subshell.
+pub(crate) fn arithmetic_end(chars: &[char], idx: usize) -> Result<Option<usize>, String> {
+    if chars.get(idx) != Some(&'(') || chars.get(idx + 1) != Some(&'(') {
+        return Ok(None);
+    }
+    let end = find_closing(chars, idx + 2, '(', ')')?;
+    Ok((chars.get(end + 1) == Some(&')')).then_some(end + 1))
+}
+
+/// is_unary_operator returns whether the word is one of the unary operators of `test`
+/// and `[[ ... ]]`.
+pub(crate) fn is_unary_operator(word: &str) -> bool {
+    matches!(
+        word,
+        "-a" | "-b"
+            | "-c"
+            | "-d"
+            | "-e"
+            | "-f"
+            | "-g"
+            | "-h"
+            | "-k"
+            | "-n"
+            | "-o"
+            | "-p"
+            | "-r"
+            | "-s"
+            | "-t"
+            | "-u"
+            | "-v"
+            | "-w"
+            | "-x"
+            | "-z"
+            | "-G"
+            | "-L"
+            | "-N"
+            | "-O"
+            | "-S"
+    )
+}
+
+/// is_binary_operator returns whether the word is one of the binary operators of `test`
+/// and `[[ ... ]]`, not counting `<` and `>` which are lexed as redirections.
+pub(crate) fn is_binary_operator(word: &str) -> bool {
+    matches!(
+        word,
+        "=" | "=="
+            | "!="
+            | "=~"
+            | "<"
+            | ">"
+            | "-eq"
+            | "-ne"
+            | "-lt"
+            | "-le"
+            | "-gt"
+            | "-ge"
+            | "-nt"
+            | "-ot"
+            | "-ef"
+    )
+}
+
+/// is_reserved_word returns whether the word is one of the reserved words of compound
+/// commands, which keep the command position for the word after them.
+pub(crate) fn is_reserved_word(word: &str) -> bool {
+    matches!(
+        word,
+        "{" | "}" | "if" | "then" | "elif" | "else" | "fi" | "while" | "until" | "do" | "done"
+    )
+}
+
+/// parse_assignment parses a word of the form `name=value`, `name+=value`,
+/// `name[subscript]=value` or `name=(words)`, returning None if it's not one.
+pub(crate) fn parse_assignment(word: &str) -> Result<Option<Assignment>, String> {
+    let Some((name, subscript, rest)) = split_subscript(word) else {
+        return Ok(None);
+    };
+    let (append, value) = match rest.strip_prefix("+=") {
+        Some(value) => (true, value),
+        None => match rest.strip_prefix('=') {
+            Some(value) => (false, value),
+            None => return Ok(None),
+        },
+    };
+    let value = match value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+        Some(inner) if subscript.is_none() => {
+            let mut words = Vec::new();
+            for token in tokenize(inner)? {
+                match token {
+                    Token::Word(word) => words.push(word),
+                    Token::Operator(Operator::Newline) => (),
+                    token => return Err(format!("parse error near {token}")),
+                }
+            }
+            AssignedValue::Array(words)
+        }
+        _ => AssignedValue::Scalar(value.to_string()),
+    };
+    Ok(Some(Assignment {
+        name: name.to_string(),
+        subscript: subscript.map(String::from),
+        append,
+        value,
+    }))
+}
+
+/// split_subscript splits `name[subscript]rest` or `namerest` where `rest` doesn't
+/// start with a name character into its parts, returning None if it doesn't start with
+/// a name.
+pub(crate) fn split_subscript(s: &str) -> Option<(&str, Option<&str>, &str)> {
+    let len = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    let (name, rest) = s.split_at(len);
+    if !is_name(name) {
+        return None;
+    }
+    let Some(inner) = rest.strip_prefix('[') else {
+        return Some((name, None, rest));
+    };
+    let end = subscript_end(inner)?;
+    Some((name, Some(&inner[..end]), &inner[end + 1..]))
+}
+
+/// subscript_end returns the index of the `]` that closes the subscript `s` starts
+/// with, right after its `[`.
+pub(crate) fn subscript_end(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, ch) in s.char_indices() {
+        match ch {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(idx),
+            ']' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// is_name checks if `s` is a valid variable or function name.
+pub(crate) fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Where to read more lines from when a command goes on past the tokens read so far.
+    source: Option<(&'a mut Source, &'a BTreeMap<String, String>)>,
+    /// Error reading more lines, which ended the input.
+    read_error: Option<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(
+        tokens: Vec<Token>,
+        source: Option<(&'a mut Source, &'a BTreeMap<String, String>)>,
+    ) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            source,
+            read_error: None,
+        }
+    }
+
+    /// error types an error the parser returned: running out of input makes the input
+    /// incomplete, unless reading more of it failed.
+    fn error(&mut self, e: String) -> ParseError {
+        if let Some(e) = self.read_error.take() {
+            return e;
+        }
+        match self.pos >= self.tokens.len() {
+            true => ParseError::Incomplete(e),
+            false => ParseError::Invalid(e),
+        }
+    }
+
+    /// parse_line parses the commands up to the end of the line, which is the end of a
+    /// complete command unless it's in the middle of one.
+    fn parse_line(&mut self) -> Result<List, String> {
+        let mut items = Vec::new();
+        while self
+            .peek()
+            .is_some_and(|t| *t != Token::Operator(Operator::Newline))
+        {
+            items.push(self.parse_and_or()?);
+            match self.peek() {
+                Some(Token::Operator(Operator::Semi)) => self.pos += 1,
+                Some(Token::Operator(Operator::Newline)) | None => break,
+                Some(_) => return Err(self.unexpected()),
+            }
+        }
+        Ok(List(items))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n)
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(w)) => Some(w),
+            _ => None,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// skip_newlines skips newlines, reading the next line from the source once all the
+    /// tokens read so far are used.
+    fn skip_newlines(&mut self) {
+        loop {
+            if self.pos == self.tokens.len() {
+                self.read_line();
+            }
+            if self.peek() != Some(&Token::Operator(Operator::Newline)) {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn read_line(&mut self) {
+        let Some((source, aliases)) = &mut self.source else {
+            return;
+        };
+        match source.read_line(aliases) {
+            Some(Ok(tokens)) => self.tokens.extend(tokens),
+            Some(Err(e)) => self.read_error = Some(e),
+            None => (),
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(token) => format!("parse error near {token}"),
+            None => "parse error near EOF".into(),
+        }
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), String> {
+        if self.peek_word() == Some(word) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    /// parse_list parses commands until the end of input, a `)` or one of the
+    /// reserved `terminators` words in command position.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<List, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                None | Some(Token::Operator(Operator::RParen)) => break,
+                Some(Token::Word(w)) if terminators.contains(&w.as_str()) => break,
+                _ => (),
+            }
+
+            items.push(self.parse_and_or()?);
+            match self.peek() {
+                Some(Token::Operator(Operator::Semi | Operator::Newline)) => self.pos += 1,
+                _ => break,
+            }
+        }
+        Ok(List(items))
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, String> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek() {
+                Some(Token::Operator(Operator::AndIf)) => Connector::And,
+                Some(Token::Operator(Operator::OrIf)) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.parse_pipeline()?));
+        }
+        Ok(AndOr { first, rest })
+    }
+
+    /// parse_pipeline parses commands joined with `|`, returning the command itself if
+    /// there is only one.
+    fn parse_pipeline(&mut self) -> Result<Command, String> {
+        let first = self.parse_command()?;
+        if self.peek() != Some(&Token::Operator(Operator::Pipe)) {
+            return Ok(first);
+        }
+        let mut commands = vec![first];
+        while self.peek() == Some(&Token::Operator(Operator::Pipe)) {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.parse_command()?);
+        }
+        Ok(Command::Pipeline(commands))
+    }
+
+    fn parse_command(&mut self) -> Result<Command, String> {
+        if self.peek() == Some(&Token::Operator(Operator::LParen)) {
+            self.pos += 1;
+            let list = self.parse_list(&[])?;
+            if list.0.is_empty() || self.peek() != Some(&Token::Operator(Operator::RParen)) {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+            return self.parse_compound_redirects(Compound::Subshell(list));
+        }
+
+        if let Some(word) = self.peek_word() {
+            if word == "function" {
+                self.pos += 1;
+                let name = match self.next() {
+                    Some(Token::Word(name)) if is_name(&name) => name,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.unexpected());
+                    }
+                };
+                // The parentheses are optional with the `function` keyword
+                if self.peek() == Some(&Token::Operator(Operator::LParen)) {
+                    self.pos += 1;
+                    if self.next() != Some(Token::Operator(Operator::RParen)) {
+                        self.pos -= 1;
+                        return Err(self.unexpected());
+                    }
+                }
+                return self.parse_function_body(name);
+            }
+
+            if is_name(word)
+                && self.peek_nth(1) == Some(&Token::Operator(Operator::LParen))
+                && self.peek_nth(2) == Some(&Token::Operator(Operator::RParen))
+            {
+                let name = word.to_string();
+                self.pos += 3;
+                return self.parse_function_body(name);
+            }
+
+            let compound = match word {
+                "if" => Some(Compound::If(self.parse_if()?)),
+                "while" | "until" => Some(Compound::Loop(self.parse_loop()?)),
+                word if word.starts_with("((") => {
+                    let expr = word[2..word.len() - 2].to_string();
+                    self.pos += 1;
+                    Some(Compound::Arithmetic(expr))
+                }
+                "[[" => {
+                    self.pos += 1;
+                    let expr = self.parse_cond_or()?;
+                    self.expect_word("]]")?;
+                    Some(Compound::Conditional(expr))
+                }
+                "{" => {
+                    self.pos += 1;
+                    let list = self.parse_list(&["}"])?;
+                    if list.0.is_empty() {
+                        return Err(self.unexpected());
+                    }
+                    self.expect_word("}")?;
+                    Some(Compound::Group(list))
+                }
+                _ => None,
+            };
+            if let Some(compound) = compound {
+                return self.parse_compound_redirects(compound);
+            }
+        }
+
+        self.parse_simple_command().map(Command::Simple)
+    }
+
+    /// parse_compound_redirects parses the redirections after a compound command.
+    fn parse_compound_redirects(&mut self, compound: Compound) -> Result<Command, String> {
+        let mut redirects = Vec::new();
+        while let Some(&Token::Redirect { fd, kind }) = self.peek() {
+            redirects.push(self.parse_redirect(fd, kind)?);
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    fn parse_function_body(&mut self, name: String) -> Result<Command, String> {
+        self.skip_newlines();
+        self.expect_word("{")?;
+        let body = self.parse_list(&["}"])?;
+        if body.0.is_empty() {
+            return Err(self.unexpected());
+        }
+        self.expect_word("}")?;
+        Ok(Command::FunctionDef {
+            name,
+            body: Rc::new(body),
+        })
+    }
+
+    fn parse_if(&mut self) -> Result<If, String> {
+        let mut branches = Vec::new();
+        let mut else_body = None;
+        loop {
+            // Skip `if` or `elif`
+            self.pos += 1;
+            let condition = self.parse_list(&["then"])?;
+            if condition.0.is_empty() {
+                return Err(self.unexpected());
+            }
+            self.expect_word("then")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            if body.0.is_empty() {
+                return Err(self.unexpected());
+            }
+            branches.push((condition, body));
+            match self.peek_word() {
+                Some("elif") => continue,
+                Some("else") => {
+                    self.pos += 1;
+                    let body = self.parse_list(&["fi"])?;
+                    if body.0.is_empty() {
+                        return Err(self.unexpected());
+                    }
+                    else_body = Some(body);
+                }
+                _ => (),
+            }
+            break;
+        }
+        self.expect_word("fi")?;
+        Ok(If {
+            branches,
+            else_body,
+        })
+    }
+
+    fn parse_loop(&mut self) -> Result<Loop, String> {
+        let until = self.peek_word() == Some("until");
+        self.pos += 1;
+        let condition = self.parse_list(&["do"])?;
+        if condition.0.is_empty() {
+            return Err(self.unexpected());
+        }
+        self.expect_word("do")?;
+        let body = self.parse_list(&["done"])?;
+        if body.0.is_empty() {
+            return Err(self.unexpected());
+        }
+        self.expect_word("done")?;
+        Ok(Loop {
+            until,
+            condition,
+            body,
+        })
+    }
+
+    fn parse_cond_or(&mut self) -> Result<CondExpr, String> {
+        let mut expr = self.parse_cond_and()?;
+        while self.peek() == Some(&Token::Operator(Operator::OrIf)) {
+            self.pos += 1;
+            self.skip_newlines();
+            expr = CondExpr::Or(Box::new(expr), Box::new(self.parse_cond_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_and(&mut self) -> Result<CondExpr, String> {
+        let mut expr = self.parse_cond_not()?;
+        while self.peek() == Some(&Token::Operator(Operator::AndIf)) {
+            self.pos += 1;
+            self.skip_newlines();
+            expr = CondExpr::And(Box::new(expr), Box::new(self.parse_cond_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_not(&mut self) -> Result<CondExpr, String> {
+        if self.peek_word() == Some("!") {
+            self.pos += 1;
+            return Ok(CondExpr::Not(Box::new(self.parse_cond_not()?)));
+        }
+        self.parse_cond_primary()
+    }
+
+    fn parse_cond_primary(&mut self) -> Result<CondExpr, String> {
+        if self.peek() == Some(&Token::Operator(Operator::LParen)) {
+            self.pos += 1;
+            let expr = self.parse_cond_or()?;
+            if self.next() != Some(Token::Operator(Operator::RParen)) {
+                self.pos -= 1;
+                return Err(self.unexpected());
+            }
+            return Ok(expr);
+        }
+
+        let word = match self.peek_word() {
+            Some(word) if word != "]]" => word.to_string(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        if is_unary_operator(&word) {
+            if let Some(operand) = self.peek_word().filter(|w| *w != "]]") {
+                let operand = operand.to_string();
+                self.pos += 1;
+                return Ok(CondExpr::Unary(word, operand));
+            }
+        }
+
+        // `<` and `>` compare strings here rather than redirect
+        let operator = match self.peek() {
+            Some(Token::Word(op)) if is_binary_operator(op) => op.clone(),
+            Some(Token::Redirect { fd: None, kind })
+                if matches!(kind, RedirectKind::In | RedirectKind::Out) =>
+            {
+                kind.to_string()
+            }
+            _ => return Ok(CondExpr::Word(word)),
+        };
+        self.pos += 1;
+        match self.next() {
+            Some(Token::Word(right)) if right != "]]" => {
+                Ok(CondExpr::Binary(word, operator, right))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    /// parse_redirect parses the redirection operator at the current token and its target.
+    fn parse_redirect(&mut self, fd: Option<i32>, kind: RedirectKind) -> Result<Redirect, String> {
+        self.pos += 1;
+        let target = match self.next() {
+            Some(Token::Word(target)) => target,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        Ok(Redirect {
+            fd: fd.unwrap_or(kind.default_fd()),
+            kind,
+            target,
+        })
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, String> {
+        let mut cmd = SimpleCommand::default();
+        loop {
+            match self.peek() {
+                Some(Token::Word(w)) => {
+                    match parse_assignment(w)? {
+                        Some(assignment) if cmd.words.is_empty() => {
+                            cmd.assignments.push(assignment)
+                        }
+                        _ => cmd.words.push(w.clone()),
+                    }
+                    self.pos += 1;
+                }
+                Some(&Token::Redirect { fd, kind }) => {
+                    let redirect = self.parse_redirect(fd, kind)?;
+                    cmd.redirects.push(redirect);
+                }
+                _ => break,
+            }
+        }
+
+        if cmd.assignments.is_empty() && cmd.words.is_empty() && cmd.redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod split_test {
+    use std::collections::BTreeMap;
+
+    use crate::parser::{
+        self, AssignedValue, Command, List, Redirect, RedirectKind, SimpleCommand,
+    };
+
+    fn parse(input: &str) -> Result<List, String> {
+        parser::parse(input, &BTreeMap::new()).map_err(|e| e.to_string())
+    }
+
+    fn parse_simple(input: &str) -> SimpleCommand {
+        let list = parse(input).unwrap();
+        match &list.0[0].first {
+            Command::Simple(cmd) => cmd.clone(),
+            cmd => panic!("not a simple command: {cmd:?}"),
+        }
+    }
+
+    fn targets(cmd: &SimpleCommand, fd: i32, kind: RedirectKind) -> Vec<&str> {
+        cmd.redirects
+            .iter()
+            .filter(|r| r.fd == fd && r.kind == kind)
+            .map(|r| r.target.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_only_command() {
+        let split = parse_simple("echo hello world");
+        assert_eq!(split.words, vec!["echo", "hello", "world"]);
+        assert!(split.redirects.is_empty());
+    }
+
+    #[test]
+    fn test_redirect_out() {
+        let split = parse_simple("echo hello world > /tmp/data");
+        assert_eq!(split.words, vec!["echo", "hello", "world"]);
+        assert_eq!(
+            split.redirects,
+            vec![Redirect {
+                fd: 1,
+                kind: RedirectKind::Out,
+                target: "/tmp/data".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_multiple_redirect_outs() {
+        let split = parse_simple("echo thisistest > /tmp/data > ./a/b");
+        assert_eq!(split.words, vec!["echo", "thisistest"]);
+        assert_eq!(
+            targets(&split, 1, RedirectKind::Out),
+            vec!["/tmp/data", "./a/b"]
+        );
+        assert_eq!(split.redirects.len(), 2);
+    }
+
+    #[test]
+    fn test_mutliple_redirect_errs() {
+        let split = parse_simple("echo 'big bad error' 2> ./error.log 2> ./warn.log");
+        assert_eq!(split.words, vec!["echo", "'big bad error'"]);
+        assert_eq!(
+            targets(&split, 2, RedirectKind::Out),
+            vec!["./error.log", "./warn.log"]
+        );
+        assert_eq!(split.redirects.len(), 2);
+    }
+
+    #[test]
+    fn test_mixed_redirect() {
+        let split = parse_simple(
+            "cat ./something.txt > /tmp/data >> /tmp/extra_data 2> ./error.log 2>> dump",
+        );
+        assert_eq!(split.words, vec!["cat", "./something.txt"]);
+        assert_eq!(targets(&split, 1, RedirectKind::Out), vec!["/tmp/data"]);
+        assert_eq!(
+            targets(&split, 1, RedirectKind::Append),
+            vec!["/tmp/extra_data"]
+        );
+        assert_eq!(targets(&split, 2, RedirectKind::Out), vec!["./error.log"]);
+        assert_eq!(targets(&split, 2, RedirectKind::Append), vec!["dump"]);
+    }
+
+    #[test]
+    fn test_redirect_without_space() {
+        let split = parse_simple("echo hi>out 2>&1");
+        assert_eq!(split.words, vec!["echo", "hi"]);
+        assert_eq!(targets(&split, 1, RedirectKind::Out), vec!["out"]);
+        assert_eq!(targets(&split, 2, RedirectKind::DupOut), vec!["1"]);
+    }
+
+    #[test]
+    fn test_double_redirect() {
+        assert_eq!(parse("echo > > out").unwrap_err(), "parse error near >");
+        assert_eq!(parse("echo >").unwrap_err(), "parse error near EOF");
+    }
+
+    #[test]
+    fn test_assignments() {
+        let split = parse_simple("FOO=bar BAZ='a b' env FOO=qux");
+        assert_eq!(split.assignments.len(), 2);
+        assert_eq!(split.assignments[1].name, "BAZ");
+        assert_eq!(
+            split.assignments[1].value,
+            AssignedValue::Scalar("'a b'".into())
+        );
+        assert_eq!(split.words, vec!["env", "FOO=qux"]);
+    }
+
+    #[test]
+    fn test_array_assignments() {
+        let split = parse_simple("arr=(a 'b c'\n [5 ]=$x) arr+=(d) arr[i + 1]=e x+=f");
+        let values: Vec<_> = split.assignments.iter().map(|a| &a.value).collect();
+        assert_eq!(
+            values,
+            vec![
+                &AssignedValue::Array(vec!["a".into(), "'b c'".into(), "[5 ]=$x".into()]),
+                &AssignedValue::Array(vec!["d".into()]),
+                &AssignedValue::Scalar("e".into()),
+                &AssignedValue::Scalar("f".into()),
+            ]
+        );
+        assert_eq!(split.assignments[2].subscript.as_deref(), Some("i + 1"));
+        assert!(split.assignments[1].append && split.assignments[3].append);
+        assert!(split.words.is_empty());
+        assert_eq!(
+            parser::split_subscript("m[a[1]]=x"),
+            Some(("m", Some("a[1]"), "=x"))
+        );
+        assert_eq!(parser::split_subscript("1a"), None);
+    }
+}
+
+#[cfg(test)]
+mod parse_test {
+    use std::collections::BTreeMap;
+
+    use crate::parser::{self, Command, Compound, Connector, List, ParseError};
+
+    fn parse(input: &str) -> Result<List, String> {
+        parser::parse(input, &BTreeMap::new()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_list() {
+        let list = parse("echo a; echo b\necho c").unwrap();
+        assert_eq!(list.0.len(), 3);
+    }
+
+    #[test]
+    fn test_and_or() {
+        let list = parse("true && echo yes || echo no").unwrap();
+        assert_eq!(list.0.len(), 1);
+        let connectors: Vec<Connector> = list.0[0].rest.iter().map(|(c, _)| *c).collect();
+        assert_eq!(connectors, vec![Connector::And, Connector::Or]);
+    }
+
+    #[test]
+    fn test_substitution_is_one_word() {
+        let list = parse("echo \"$(echo 'a;b' | tr ; x)\" ${x:-a b}").unwrap();
+        match &list.0[0].first {
+            Command::Simple(cmd) => assert_eq!(
+                cmd.words,
+                vec!["echo", "\"$(echo 'a;b' | tr ; x)\"", "${x:-a b}"]
+            ),
+            cmd => panic!("not a simple command: {cmd:?}"),
+        }
+    }
+
+    #[test]
+    fn test_comment() {
+        let list = parse("echo a # echo b").unwrap();
+        match &list.0[0].first {
+            Command::Simple(cmd) => assert_eq!(cmd.words, vec!["echo", "a"]),
+            cmd => panic!("not a simple command: {cmd:?}"),
+        }
+    }
+
+    #[test]
+    fn test_function_def() {
+        let list =
+            parse("_foo() { local cur=$2; COMPREPLY=$cur; }\nfunction bar {\necho\n}").unwrap();
+        assert_eq!(list.0.len(), 2);
+        match &list.0[0].first {
+            Command::FunctionDef { name, body } => {
+                assert_eq!(name, "_foo");
+                assert_eq!(body.0.len(), 2);
+            }
+            cmd => panic!("not a function: {cmd:?}"),
+        }
+        match &list.0[1].first {
+            Command::FunctionDef { name, .. } => assert_eq!(name, "bar"),
+            cmd => panic!("not a function: {cmd:?}"),
+        }
+    }
+
+    #[test]
+    fn test_loop() {
+        let list =
+            parse("while read line\ndo echo $line; done < in; until false; do :; done").unwrap();
+        assert_eq!(list.0.len(), 2);
+        match &list.0[0].first {
+            Command::Compound(Compound::Loop(lp), redirects) => {
+                assert!(!lp.until);
+                assert_eq!((lp.condition.0.len(), lp.body.0.len()), (1, 1));
+                assert_eq!(redirects[0].target, "in");
+            }
+            cmd => panic!("not a loop: {cmd:?}"),
+        }
+        assert!(matches!(&list.0[1].first, Command::Compound(Compound::Loop(lp), _) if lp.until));
+        assert_eq!(
+            parse("while true; do done").unwrap_err(),
+            "parse error near done"
+        );
+        assert_eq!(
+            parse("while true; do echo").unwrap_err(),
+            "parse error near EOF"
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let list = parse("if a; then b; elif c\nthen d; e; else f; fi > out").unwrap();
+        match &list.0[0].first {
+            Command::Compound(Compound::If(clause), redirects) => {
+                let lens: Vec<(usize, usize)> = clause
+                    .branches
+                    .iter()
+                    .map(|(condition, body)| (condition.0.len(), body.0.len()))
+                    .collect();
+                assert_eq!(lens, vec![(1, 1), (1, 2)]);
+                assert_eq!(clause.else_body.as_ref().map(|body| body.0.len()), Some(1));
+                assert_eq!(redirects[0].target, "out");
+            }
+            cmd => panic!("not an if: {cmd:?}"),
+        }
+        assert!(matches!(
+            &parse("if a; then echo fi; fi").unwrap().0[0].first,
+            Command::Compound(Compound::If(clause), _) if clause.else_body.is_none()
+        ));
+        assert_eq!(parse("if a; then fi").unwrap_err(), "parse error near fi");
+        assert_eq!(parse("if a; then b").unwrap_err(), "parse error near EOF");
+    }
+
+    #[test]
+    fn test_arithmetic_command() {
+        let list = parse("((x = (1 + 2) * 3)) > out && echo $((x))").unwrap();
+        match &list.0[0].first {
+            Command::Compound(Compound::Arithmetic(expr), redirects) => {
+                assert_eq!(expr, "x = (1 + 2) * 3");
+                assert_eq!(redirects[0].target, "out");
+            }
+            cmd => panic!("not an arithmetic command: {cmd:?}"),
+        }
+        // Elsewhere, and without the closing `))`, these are parentheses
+        assert_eq!(parse("echo ((x))").unwrap_err(), "parse error near (");
+        assert!(matches!(
+            &parse("((x) )").unwrap().0[0].first,
+            Command::Compound(Compound::Subshell(inner), _)
+                if matches!(inner.0[0].first, Command::Compound(Compound::Subshell(_), _))
+        ));
+        assert_eq!(parse("((x").unwrap_err(), "( unfinished");
+    }
+
+    #[test]
+    fn test_groups_and_pipelines() {
+        let list =
+            parse("(cd /tmp; ls) 2> err | { read x; echo $x; } > out && echo a|cat").unwrap();
+        let Command::Pipeline(commands) = &list.0[0].first else {
+            panic!("not a pipeline: {:?}", list.0[0].first);
+        };
+        match &commands[..] {
+            [Command::Compound(Compound::Subshell(sub), sub_redirects), Command::Compound(Compound::Group(group), group_redirects)] =>
+            {
+                assert_eq!((sub.0.len(), group.0.len()), (2, 2));
+                assert_eq!((sub_redirects[0].fd, group_redirects[0].fd), (2, 1));
+            }
+            commands => panic!("not a subshell and a group: {commands:?}"),
+        }
+        assert!(matches!(&list.0[0].rest[0].1, Command::Pipeline(commands) if commands.len() == 2));
+        // `{` and `}` are only reserved words in command position
+        let list = parse("echo { }").unwrap();
+        assert!(
+            matches!(&list.0[0].first, Command::Simple(cmd) if cmd.words == ["echo", "{", "}"])
+        );
+        assert_eq!(parse("{ echo }").unwrap_err(), "parse error near EOF");
+        assert_eq!(parse("( )").unwrap_err(), "parse error near )");
+        assert_eq!(parse("echo |").unwrap_err(), "parse error near EOF");
+    }
+
+    #[test]
+    fn test_process_substitution() {
+        let list = parse("diff <(sort a) >(cat) < <(echo z)").unwrap();
+        let Command::Simple(cmd) = &list.0[0].first else {
+            panic!("not a simple command: {:?}", list.0[0].first);
+        };
+        assert_eq!(cmd.words, ["diff", "<(sort a)", ">(cat)"]);
+        assert_eq!(cmd.redirects[0].target, "<(echo z)");
+        assert_eq!(parse("cat < (x)").unwrap_err(), "parse error near (");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("echo 'a").unwrap_err(), "quotes unfinished");
+        assert_eq!(parse("; echo").unwrap_err(), "parse error near ;");
+        assert_eq!(parse("f() { echo").unwrap_err(), "parse error near EOF");
+        assert_eq!(parse("echo && ").unwrap_err(), "parse error near EOF");
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let incomplete = |input: &str| {
+            matches!(
+                parser::parse(input, &BTreeMap::new()),
+                Err(ParseError::Incomplete(_))
+            )
+        };
+        assert!(incomplete("f() {\n"));
+        assert!(incomplete("echo 'a\n"));
+        assert!(incomplete("echo $(\n"));
+        assert!(incomplete("echo a &&"));
+        assert!(!incomplete("echo a\n"));
+        assert!(!incomplete("; echo\n"));
+        assert!(!incomplete("echo >\n"));
+    }
+
+    #[test]
+    fn test_source() {
+        let input = "f() {\n  echo 'a\nb'\n}; x \\\nc\n\necho )\nx\ng() {\n";
+        let mut source = parser::Source::new(input);
+        let mut aliases = BTreeMap::new();
+        let mut next = |aliases: &BTreeMap<String, String>| {
+            let (line, parsed) = source.next_command(aliases)?;
+            let parsed = parsed.map(|list| match list.0.last().map(|item| &item.first) {
+                Some(Command::Simple(cmd)) => cmd.words.join(" "),
+                None => String::new(),
+                cmd => panic!("not a simple command: {cmd:?}"),
+            });
+            Some((line, parsed))
+        };
+
+        // A command goes on to the lines it needs, even inside quotes
+        assert_eq!(next(&aliases), Some((1, Ok("x c".to_string()))));
+        assert_eq!(next(&aliases), Some((6, Ok(String::new()))));
+        let error = ParseError::Invalid("parse error near )".to_string());
+        assert_eq!(next(&aliases), Some((7, Err(error))));
+        // Aliases defined by earlier commands apply
+        aliases.insert("x".to_string(), "echo y".to_string());
+        assert_eq!(next(&aliases), Some((8, Ok("echo y".to_string()))));
+        let error = ParseError::Incomplete("parse error near EOF".to_string());
+        assert_eq!(next(&aliases), Some((9, Err(error))));
+        assert_eq!(next(&aliases), None);
+    }
+}
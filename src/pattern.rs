@@ -0,0 +1,150 @@
+use std::{ffi::CString, mem};
+
+/// Characters with a special meaning in glob patterns.
+pub(crate) const GLOB_CHARS: &str = "*?[]\\";
+
+/// Characters with a special meaning in extended regular expressions.
+pub(crate) const REGEX_CHARS: &str = "\\.^$*+?()[]{}|";
+
+/// glob_match returns whether the glob pattern matches all of `s`. A backslash makes
+/// the character after it match literally.
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let (Ok(pattern), Ok(s)) = (CString::new(pattern), CString::new(s)) else {
+        return false;
+    };
+    unsafe { libc::fnmatch(pattern.as_ptr(), s.as_ptr(), 0) == 0 }
+}
+
+/// A compiled POSIX extended regular expression.
+pub(crate) struct Regex {
+    regex: libc::regex_t,
+    groups: usize,
+}
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        let c_pattern =
+            CString::new(pattern).map_err(|_| format!("{pattern}: invalid regular expression"))?;
+        let mut regex: libc::regex_t = unsafe { mem::zeroed() };
+        let code = unsafe { libc::regcomp(&mut regex, c_pattern.as_ptr(), libc::REG_EXTENDED) };
+        if code != 0 {
+            let mut buf = vec![0u8; 256];
+            let len = unsafe { libc::regerror(code, &regex, buf.as_mut_ptr().cast(), buf.len()) };
+            buf.truncate(len.saturating_sub(1));
+            return Err(String::from_utf8_lossy(&buf).to_string());
+        }
+        Ok(Self {
+            regex,
+            groups: count_groups(pattern),
+        })
+    }
+
+    /// captures returns the first match in `s` followed by what each group matched,
+    /// which is empty for groups that took no part in the match, or None if it doesn't
+    /// match at all.
+    pub(crate) fn captures(&self, s: &str) -> Option<Vec<String>> {
+        let c_s = CString::new(s).ok()?;
+        let mut matches = vec![
+            libc::regmatch_t {
+                rm_so: -1,
+                rm_eo: -1
+            };
+            self.groups + 1
+        ];
+        let code = unsafe {
+            libc::regexec(
+                &self.regex,
+                c_s.as_ptr(),
+                matches.len(),
+                matches.as_mut_ptr(),
+                0,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        let captures = matches
+            .iter()
+            .map(
+                |m| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+                    (Ok(start), Ok(end)) => {
+                        String::from_utf8_lossy(&s.as_bytes()[start..end]).to_string()
+                    }
+                    _ => String::new(),
+                },
+            )
+            .collect();
+        Some(captures)
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut self.regex) };
+    }
+}
+
+/// count_groups counts the parenthesized groups of the regular expression, as the
+/// number the compiled expression keeps isn't accessible.
+fn count_groups(pattern: &str) -> usize {
+    let mut groups = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '(' => groups += 1,
+            '[' => {
+                // A `]` right at the start of a bracket expression is part of it
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        ']' => break,
+                        // Classes like `[:alpha:]` have brackets of their own
+                        '[' if chars.next_if(|c| ":.=".contains(*c)).is_some() => {
+                            while chars.next().is_some_and(|c| c != ']') {}
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod pattern_test {
+    use crate::pattern::{count_groups, glob_match, Regex};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("[[:digit:]]*[!x]", "1ab"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(glob_match("a\\*", "a*"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let regex = Regex::new("([a-z]+)-([0-9]+)?(x)?").unwrap();
+        assert_eq!(
+            regex.captures("id: abc-12 "),
+            Some(vec!["abc-12".into(), "abc".into(), "12".into(), "".into()])
+        );
+        assert_eq!(regex.captures("ABC"), None);
+        assert!(Regex::new("a(").is_err());
+    }
+
+    #[test]
+    fn test_count_groups() {
+        assert_eq!(count_groups("(a)(b(c))"), 3);
+        assert_eq!(count_groups(r"\(a[(]"), 0);
+        assert_eq!(count_groups("[](][[:alpha:](]"), 0);
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    ffi::{CStr, CString},
+    io,
+    iter::Peekable,
+    str::Chars,
+};
+
+use crate::{
+    builtin::Output,
+    parser,
+    shell::Shell,
+    util::{self, write_and_flush_str},
+};
+
+const PRINTF_USAGE: &str = "printf: usage: printf [-v var] format [arguments]";
+
+/// Where backslash escapes are expanded, as each place knows slightly different ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escapes {
+    /// `echo -e`: octal values need a leading `\0`, and `\c` stops the output.
+    Echo,
+    /// The format of printf: octal values don't need the `\0`, and `\c` is kept as is.
+    Format,
+    /// Arguments of `%b`: octal values work either way, and `\c` stops the output.
+    Argument,
+}
+
+/// printf formats the arguments under control of the format and prints them. The format
+/// is reused for as long as arguments are left, and missing ones count as empty or zero.
+/// With `-v var`, the output is assigned to the variable instead.
+pub(crate) fn printf<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let mut args = args;
+    let mut var = None;
+    while let Some(arg) = args.first() {
+        match *arg {
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            "-v" if args.len() > 1 => {
+                var = Some(args[1]);
+                args = &args[2..];
+            }
+            "-v" => {
+                write_and_flush_str(&mut w.err, "printf: -v: option requires an argument")?;
+                write_and_flush_str(&mut w.err, PRINTF_USAGE)?;
+                return Ok(2);
+            }
+            arg if arg.starts_with('-') && arg.len() > 1 => {
+                write_and_flush_str(&mut w.err, &format!("printf: {arg}: invalid option"))?;
+                write_and_flush_str(&mut w.err, PRINTF_USAGE)?;
+                return Ok(2);
+            }
+            _ => break,
+        }
+    }
+    let Some((format, args)) = args.split_first() else {
+        write_and_flush_str(&mut w.err, PRINTF_USAGE)?;
+        return Ok(2);
+    };
+    if let Some(var) = var.filter(|var| !parser::is_name(var)) {
+        write_and_flush_str(
+            &mut w.err,
+            &format!("printf: `{var}': not a valid identifier"),
+        )?;
+        return Ok(2);
+    }
+
+    let formatted = format_args(format, args);
+    for e in &formatted.errors {
+        write_and_flush_str(&mut w.err, &format!("printf: {e}"))?;
+    }
+    match var {
+        Some(var) => {
+            if let Err(e) = shell.assign(
+                var,
+                None,
+                &String::from_utf8_lossy(&formatted.output),
+                false,
+            ) {
+                write_and_flush_str(&mut w.err, &format!("printf: {e}"))?;
+                return Ok(1);
+            }
+        }
+        None => util::write_and_flush_bytes(&mut w.out, &formatted.output)?,
+    }
+    Ok(i32::from(!formatted.errors.is_empty()))
+}
+
+/// echo_escapes expands the backslash escapes `echo -e` knows. It also returns whether
+/// a `\c` was found, after which nothing more is to be printed.
+pub(crate) fn echo_escapes(s: &str) -> (Vec<u8>, bool) {
+    let mut out = Vec::new();
+    let stopped = expand_escapes(s, Escapes::Echo, &mut out);
+    (out, stopped)
+}
+
+fn expand_escapes(s: &str, escapes: Escapes, out: &mut Vec<u8>) -> bool {
+    let mut chars = s.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if escape(&mut chars, escapes, out) => return true,
+            '\\' => {}
+            ch => push_char(out, ch),
+        }
+    }
+    false
+}
+
+/// escape expands the escape after a backslash, returning true if it's a `\c` that
+/// stops the output. Unknown escapes are kept as they are.
+fn escape(chars: &mut Peekable<Chars>, escapes: Escapes, out: &mut Vec<u8>) -> bool {
+    let Some(ch) = chars.next() else {
+        out.push(b'\\');
+        return false;
+    };
+    let byte = match ch {
+        'a' => b'\x07',
+        'b' => b'\x08',
+        'e' | 'E' => b'\x1b',
+        'f' => b'\x0c',
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        'v' => b'\x0b',
+        '\\' => b'\\',
+        '"' | '?' if escapes == Escapes::Format => ch as u8,
+        'c' if escapes != Escapes::Format => return true,
+        '0' if escapes != Escapes::Format => digits(chars, 8, 3, 0) as u8,
+        '0'..='7' if escapes != Escapes::Echo => {
+            digits(chars, 8, 2, ch.to_digit(8).unwrap_or_default()) as u8
+        }
+        'x' | 'u' | 'U' => {
+            let max = match ch {
+                'x' => 2,
+                'u' => 4,
+                _ => 8,
+            };
+            if !chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                out.push(b'\\');
+                push_char(out, ch);
+                return false;
+            }
+            let value = digits(chars, 16, max, 0);
+            match ch {
+                'x' => out.push(value as u8),
+                _ => push_char(
+                    out,
+                    char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER),
+                ),
+            }
+            return false;
+        }
+        ch => {
+            out.push(b'\\');
+            push_char(out, ch);
+            return false;
+        }
+    };
+    out.push(byte);
+    false
+}
+
+/// digits consumes up to `max` digits in the radix and returns their value, following
+/// the digits already read into `value`.
+fn digits(chars: &mut Peekable<Chars>, radix: u32, max: usize, mut value: u32) -> u32 {
+    for _ in 0..max {
+        match chars.peek().and_then(|c| c.to_digit(radix)) {
+            Some(digit) => value = value.wrapping_mul(radix).wrapping_add(digit),
+            None => break,
+        }
+        chars.next();
+    }
+    value
+}
+
+fn push_char(out: &mut Vec<u8>, ch: char) {
+    out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Output of printf, with the problems found in its arguments.
+#[derive(Debug, Default)]
+struct Formatted {
+    output: Vec<u8>,
+    errors: Vec<String>,
+}
+
+/// format_args formats the arguments, reusing the format while any are left.
+fn format_args(format: &str, args: &[&str]) -> Formatted {
+    let mut formatter = Formatter {
+        args,
+        pos: 0,
+        formatted: Formatted::default(),
+    };
+    loop {
+        let start = formatter.pos;
+        let stopped = formatter.format(format);
+        if stopped || formatter.pos == start || formatter.pos >= args.len() {
+            break;
+        }
+    }
+    formatter.formatted
+}
+
+/// A conversion specification like `%-8.3s`, without its conversion character.
+#[derive(Debug, Default)]
+struct Spec {
+    flags: String,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+struct Formatter<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+    formatted: Formatted,
+}
+
+impl Formatter<'_> {
+    /// format formats the next arguments with the format once. It returns true if the
+    /// output is to stop, because of a `\c` or an invalid conversion.
+    fn format(&mut self, format: &str) -> bool {
+        let mut chars = format.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => {
+                    escape(&mut chars, Escapes::Format, &mut self.formatted.output);
+                }
+                '%' if chars.peek() == Some(&'%') => {
+                    chars.next();
+                    self.formatted.output.push(b'%');
+                }
+                '%' => {
+                    if self.conversion(&mut chars) {
+                        return true;
+                    }
+                }
+                ch => push_char(&mut self.formatted.output, ch),
+            }
+        }
+        false
+    }
+
+    fn next_arg(&mut self) -> Option<&str> {
+        let arg = self.args.get(self.pos).copied();
+        self.pos += 1;
+        arg
+    }
+
+    /// conversion formats the next argument with the conversion after a `%`, returning
+    /// true if the output is to stop.
+    fn conversion(&mut self, chars: &mut Peekable<Chars>) -> bool {
+        let mut spec = Spec::default();
+        while let Some(flag) = chars.next_if(|c| "-+ #0".contains(*c)) {
+            spec.flags.push(flag);
+        }
+        spec.width = match chars.next_if_eq(&'*') {
+            Some(_) => {
+                let width = self.integer_arg();
+                if width < 0 {
+                    spec.flags.push('-');
+                }
+                Some(width.unsigned_abs() as usize)
+            }
+            None => number(chars),
+        };
+        if chars.next_if_eq(&'.').is_some() {
+            spec.precision = match chars.next_if_eq(&'*') {
+                // A negative precision counts as if none was given
+                Some(_) => usize::try_from(self.integer_arg()).ok(),
+                None => Some(number(chars).unwrap_or_default()),
+            };
+        }
+        // Length modifiers mean nothing as all integers are 64 bits wide
+        while chars.next_if(|c| "hjlLtz".contains(*c)).is_some() {}
+
+        let Some(conversion) = chars.next() else {
+            self.formatted
+                .errors
+                .push("`%': missing format character".into());
+            return true;
+        };
+        let output = match conversion {
+            's' => {
+                let arg = self.next_arg().unwrap_or_default().as_bytes().to_vec();
+                pad(arg, &spec)
+            }
+            'b' => {
+                let mut arg = Vec::new();
+                let stopped = expand_escapes(
+                    self.next_arg().unwrap_or_default(),
+                    Escapes::Argument,
+                    &mut arg,
+                );
+                self.formatted.output.extend(pad(arg, &spec));
+                return stopped;
+            }
+            'q' => {
+                let arg = util::quote(self.next_arg().unwrap_or_default());
+                pad(arg.into_bytes(), &spec)
+            }
+            'c' => {
+                let arg = self.next_arg().unwrap_or_default();
+                let arg = arg.chars().next().map(String::from).unwrap_or_default();
+                pad(
+                    arg.into_bytes(),
+                    &Spec {
+                        precision: None,
+                        ..spec
+                    },
+                )
+            }
+            'd' | 'i' => {
+                let arg = self.integer_arg();
+                c_format(&spec, "ll", conversion, |format, buf, len| unsafe {
+                    libc::snprintf(buf, len, format, arg as libc::c_longlong)
+                })
+            }
+            'u' | 'o' | 'x' | 'X' => {
+                let arg = self.unsigned_arg();
+                c_format(&spec, "ll", conversion, |format, buf, len| unsafe {
+                    libc::snprintf(buf, len, format, arg as libc::c_ulonglong)
+                })
+            }
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' | 'a' | 'A' => {
+                let arg = self.float_arg();
+                c_format(&spec, "", conversion, |format, buf, len| unsafe {
+                    libc::snprintf(buf, len, format, arg)
+                })
+            }
+            ch => {
+                self.formatted
+                    .errors
+                    .push(format!("`{ch}': invalid format character"));
+                return true;
+            }
+        };
+        self.formatted.output.extend(output);
+        false
+    }
+
+    /// integer_arg converts the next argument to an integer. Like C, leading `0x` and `0`
+    /// make it hexadecimal and octal, and a leading quote gives the value of the character
+    /// after it.
+    fn integer_arg(&mut self) -> i64 {
+        self.number_arg(|arg, end| unsafe { libc::strtoll(arg, end, 0) })
+    }
+
+    fn unsigned_arg(&mut self) -> u64 {
+        self.number_arg(|arg, end| unsafe { libc::strtoull(arg, end, 0) })
+    }
+
+    fn float_arg(&mut self) -> f64 {
+        self.number_arg(|arg, end| unsafe { libc::strtod(arg, end) })
+    }
+
+    /// number_arg converts the next argument with the C function, which sets the end of
+    /// the number it read. Arguments that aren't entirely a number are reported, and
+    /// give the value of the number they start with.
+    fn number_arg<N, F>(&mut self, convert: F) -> N
+    where
+        N: Default + TryFrom<u32>,
+        F: Fn(*const libc::c_char, *mut *mut libc::c_char) -> N,
+    {
+        let Some(arg) = self.next_arg().map(str::to_string) else {
+            return N::default();
+        };
+        if let Some(quoted) = arg.strip_prefix(['\'', '"']) {
+            let ch = quoted.chars().next().map_or(0, u32::from);
+            return N::try_from(ch).unwrap_or_default();
+        }
+        let Ok(c_arg) = CString::new(arg.as_str()) else {
+            return N::default();
+        };
+
+        let mut end = std::ptr::null_mut();
+        unsafe { *libc::__errno_location() = 0 };
+        let value = convert(c_arg.as_ptr(), &mut end);
+        let errno = io::Error::last_os_error();
+        let read = unsafe { CStr::from_ptr(end) }.to_bytes().len();
+        if arg.trim().is_empty() {
+            // An empty argument is zero
+        } else if read != 0 || end.cast_const() == c_arg.as_ptr() {
+            self.formatted.errors.push(format!("{arg}: invalid number"));
+        } else if errno.raw_os_error() == Some(libc::ERANGE) {
+            let message = util::error_message(&errno);
+            self.formatted.errors.push(format!("{arg}: {message}"));
+        }
+        value
+    }
+}
+
+/// number consumes the digits of a width or precision.
+fn number(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut number = None;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        chars.next();
+        number = Some(
+            number
+                .unwrap_or(0usize)
+                .saturating_mul(10)
+                .saturating_add(digit as usize),
+        );
+    }
+    number
+}
+
+/// pad cuts the value to the precision and pads it with spaces to the width.
+fn pad(mut value: Vec<u8>, spec: &Spec) -> Vec<u8> {
+    if let Some(precision) = spec.precision {
+        value.truncate(precision);
+    }
+    let padding = vec![b' '; spec.width.unwrap_or_default().saturating_sub(value.len())];
+    match spec.flags.contains('-') {
+        true => [value, padding].concat(),
+        false => [padding, value].concat(),
+    }
+}
+
+/// c_format formats a number with `snprintf`, so that its flags work just like in C.
+fn c_format<F>(spec: &Spec, length: &str, conversion: char, snprintf: F) -> Vec<u8>
+where
+    F: Fn(*const libc::c_char, *mut libc::c_char, libc::size_t) -> libc::c_int,
+{
+    let mut format = format!("%{}", spec.flags);
+    if let Some(width) = spec.width {
+        format += &width.to_string();
+    }
+    if let Some(precision) = spec.precision {
+        format += &format!(".{precision}");
+    }
+    format += &format!("{length}{conversion}");
+    let Ok(format) = CString::new(format) else {
+        return Vec::new();
+    };
+
+    let Ok(len) = usize::try_from(snprintf(format.as_ptr(), std::ptr::null_mut(), 0)) else {
+        return Vec::new();
+    };
+    let mut buf = vec![0u8; len + 1];
+    snprintf(format.as_ptr(), buf.as_mut_ptr().cast(), buf.len());
+    buf.truncate(len);
+    buf
+}
+
+#[cfg(test)]
+mod printf_test {
+    use crate::printf::{echo_escapes, format_args};
+
+    fn printf(format: &str, args: &[&str]) -> (String, Vec<String>) {
+        let formatted = format_args(format, args);
+        (
+            String::from_utf8_lossy(&formatted.output).to_string(),
+            formatted.errors,
+        )
+    }
+
+    #[test]
+    fn test_echo_escapes() {
+        assert_eq!(echo_escapes(r"a\tb\n"), (b"a\tb\n".to_vec(), false));
+        assert_eq!(
+            echo_escapes(r"\101\0101\x41\x4"),
+            (b"\\101AA\x04".to_vec(), false)
+        );
+        assert_eq!(echo_escapes(r"☺\xq\q\"), ("☺\\xq\\q\\".into(), false));
+        assert_eq!(echo_escapes(r"a\cb"), (b"a".to_vec(), true));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            printf(
+                r"[%5.2s][%-4d][%04x][%e][%g][%c]\n",
+                &["abc", "7", "255", "3.5", "0.0001", "hi"]
+            )
+            .0,
+            "[   ab][7   ][00ff][3.500000e+00][0.0001][h]\n"
+        );
+        assert_eq!(
+            printf("%d %i %u %o %X %%", &["0x1f", "077", "-1", "8", "'A"]).0,
+            "31 63 18446744073709551615 10 41 %"
+        );
+        assert_eq!(
+            printf("[%*d][%.*f]", &["-3", "5", "2", "3.14159"]).0,
+            "[5  ][3.14]"
+        );
+        assert_eq!(
+            printf("%q %q %q", &["a b", "it's", ""]).0,
+            r"'a b' 'it'\''s' ''"
+        );
+    }
+
+    #[test]
+    fn test_format_escapes() {
+        assert_eq!(printf(r#"\101\0101\c\""#, &[]).0, "A\x081\\c\"");
+        assert_eq!(printf("%b|%s", &[r"\101\0101\t", r"\t"]).0, "AA\t|\\t");
+        assert_eq!(printf("%b|%s\n", &[r"a\cb", "x"]).0, "a");
+    }
+
+    #[test]
+    fn test_format_reuse() {
+        assert_eq!(printf("%s=%d\n", &["a", "1", "b"]).0, "a=1\nb=0\n");
+        assert_eq!(printf("x\n", &["a", "b"]).0, "x\n");
+        assert_eq!(printf("%s %s\n", &[]).0, " \n");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert_eq!(
+            printf("%d\n", &["12abc"]),
+            ("12\n".into(), vec!["12abc: invalid number".into()])
+        );
+        assert_eq!(
+            printf("%d\n", &["abc"]),
+            ("0\n".into(), vec!["abc: invalid number".into()])
+        );
+        assert_eq!(
+            printf("a%kb", &["x"]),
+            ("a".into(), vec!["`k': invalid format character".into()])
+        );
+        assert_eq!(
+            printf("%d", &["99999999999999999999"]),
+            (
+                "9223372036854775807".into(),
+                vec!["99999999999999999999: Numerical result out of range".into()]
+            )
+        );
+    }
+}
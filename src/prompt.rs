@@ -0,0 +1,244 @@
+use std::{io, time::Duration};
+
+use crate::{builtin, expand, segment, shell::Shell, util};
+
+/// Prompt used when `PS1` isn't set.
+const DEFAULT_PS1: &str = "$ ";
+/// Marks the start of a non-printing sequence, what `\[` decodes to.
+const START_IGNORE: char = '\x01';
+/// Marks the end of a non-printing sequence, what `\]` decodes to.
+const END_IGNORE: char = '\x02';
+
+/// A prompt ready to be displayed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Prompt {
+    /// The prompt without the non-printing sequences, which is what takes up space
+    /// on the screen.
+    pub(crate) plain: String,
+    /// The prompt as it's written to the terminal, including colors and such.
+    pub(crate) styled: String,
+}
+
+impl Prompt {
+    /// from_markers splits the prompt into its plain and styled forms, where the
+    /// non-printing sequences are enclosed in [`START_IGNORE`] and [`END_IGNORE`].
+    fn from_markers(prompt: &str) -> Self {
+        let mut plain = String::new();
+        let mut styled = String::new();
+        let mut ignoring = false;
+        for ch in prompt.chars() {
+            match ch {
+                START_IGNORE => ignoring = true,
+                END_IGNORE => ignoring = false,
+                _ => {
+                    styled.push(ch);
+                    if !ignoring {
+                        plain.push(ch);
+                    }
+                }
+            }
+        }
+        Self { plain, styled }
+    }
+}
+
+/// State of the session shown by some of the prompt escapes.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PromptContext {
+    /// History number of the command about to be read, for `\!`.
+    pub(crate) history_number: usize,
+    /// Number of commands run in this session so far plus one, for `\#`.
+    pub(crate) command_number: usize,
+    /// How long the last command took, if one was run.
+    pub(crate) last_duration: Option<Duration>,
+}
+
+/// run_prompt_command runs `PROMPT_COMMAND` as is done before printing each prompt.
+/// It doesn't change `$?`, so the prompt can still show the status of the last command.
+pub(crate) fn run_prompt_command(shell: &mut Shell) -> anyhow::Result<()> {
+    let command = match shell.var("PROMPT_COMMAND") {
+        Some(command) if !command.is_empty() => command.to_string(),
+        _ => return Ok(()),
+    };
+    let status = shell.last_status;
+    shell.run(&command)?;
+    shell.control = None;
+    shell.last_status = status;
+    Ok(())
+}
+
+/// render decodes the backslash escapes in `PS1` and then expands parameters and
+/// command substitutions in it. The segments in `PROMPT_SEGMENTS` come before it.
+pub(crate) fn render(shell: &mut Shell, context: &PromptContext) -> anyhow::Result<Prompt> {
+    let ps1 = shell.var("PS1").unwrap_or(DEFAULT_PS1).to_string();
+    let decoded = decode_escapes(shell, &ps1, context);
+
+    // Substitutions in the prompt mustn't change `$?`
+    let status = shell.last_status;
+    let expanded = expand::expand_prompt(shell, &decoded);
+    shell.last_status = status;
+    let prompt = match expanded {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            util::write_and_flush_str(&mut io::stderr(), &e)?;
+            decoded
+        }
+    };
+    let segments = segment::render(shell, context, (START_IGNORE, END_IGNORE));
+    Ok(Prompt::from_markers(&(segments + &prompt)))
+}
+
+/// decode_escapes replaces the backslash escapes in the prompt, like `\w` with the
+/// current directory. Their values are escaped so that they aren't expanded later.
+fn decode_escapes(shell: &Shell, ps1: &str, context: &PromptContext) -> String {
+    let chars: Vec<char> = ps1.chars().collect();
+    let mut decoded = String::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        if chars[idx] != '\\' || idx + 1 == chars.len() {
+            decoded.push(chars[idx]);
+            idx += 1;
+            continue;
+        }
+
+        let escape = chars[idx + 1];
+        idx += 2;
+        let value = match escape {
+            'u' => shell
+                .var("USER")
+                .map(str::to_string)
+                .or_else(util::user_name)
+                .unwrap_or_default(),
+            'h' | 'H' => {
+                let host = util::hostname().unwrap_or_default();
+                match escape {
+                    'h' => host.split('.').next().unwrap_or_default().to_string(),
+                    _ => host,
+                }
+            }
+            'w' => builtin::Command::replace_home_with_tilde(shell, &shell.current_dir()),
+            'W' => {
+                let dir = shell.current_dir();
+                match builtin::Command::replace_home_with_tilde(shell, &dir).as_str() {
+                    "~" => "~".to_string(),
+                    "/" => "/".to_string(),
+                    _ => dir.rsplit('/').next().unwrap_or_default().to_string(),
+                }
+            }
+            '$' => match unsafe { libc::geteuid() } {
+                0 => "#".to_string(),
+                _ => "$".to_string(),
+            },
+            's' => {
+                let name = shell.special_param('0');
+                name.rsplit('/').next().unwrap_or_default().to_string()
+            }
+            'v' | 'V' => env!("CARGO_PKG_VERSION").to_string(),
+            't' => util::format_time("%H:%M:%S"),
+            'T' => util::format_time("%I:%M:%S"),
+            '@' => util::format_time("%I:%M %p"),
+            'A' => util::format_time("%H:%M"),
+            'd' => util::format_time("%a %b %d"),
+            'D' if chars.get(idx) == Some(&'{') => {
+                let len = chars[idx..].iter().position(|c| *c == '}');
+                let end = len.map_or(chars.len(), |len| idx + len);
+                let format: String = chars[idx + 1..end].iter().collect();
+                idx = (end + 1).min(chars.len());
+                match format.is_empty() {
+                    true => util::format_time("%X"),
+                    false => util::format_time(&format),
+                }
+            }
+            'j' => shell.job_count().to_string(),
+            '!' => context.history_number.to_string(),
+            '#' => context.command_number.to_string(),
+            'n' => "\n".to_string(),
+            'r' => "\r".to_string(),
+            'a' => "\x07".to_string(),
+            'e' => "\x1b".to_string(),
+            '\\' => "\\".to_string(),
+            '0'..='7' => {
+                let digits: String = chars[idx - 1..]
+                    .iter()
+                    .take(3)
+                    .take_while(|c| c.is_digit(8))
+                    .collect();
+                idx += digits.len() - 1;
+                let code = u32::from_str_radix(&digits, 8).unwrap_or_default();
+                char::from_u32(code).unwrap_or_default().to_string()
+            }
+            '[' => {
+                decoded.push(START_IGNORE);
+                continue;
+            }
+            ']' => {
+                decoded.push(END_IGNORE);
+                continue;
+            }
+            _ => {
+                decoded.push('\\');
+                decoded.push(escape);
+                continue;
+            }
+        };
+        decoded.push_str(&protect(&value));
+    }
+    decoded
+}
+
+/// protect escapes the chars that would otherwise be expanded in double quotes.
+fn protect(value: &str) -> String {
+    let mut protected = String::new();
+    for ch in value.chars() {
+        if matches!(ch, '\\' | '$' | '`') {
+            protected.push('\\');
+        }
+        protected.push(ch);
+    }
+    protected
+}
+
+#[cfg(test)]
+mod prompt_test {
+    use crate::{
+        prompt::{render, Prompt, PromptContext},
+        shell::Shell,
+    };
+
+    fn render_ps1(shell: &mut Shell, ps1: &str) -> Prompt {
+        shell.set_var("PS1", ps1);
+        let context = PromptContext {
+            history_number: 12,
+            command_number: 3,
+            ..PromptContext::default()
+        };
+        render(shell, &context).unwrap()
+    }
+
+    #[test]
+    fn test_escapes() {
+        let mut shell = Shell::new();
+        assert_eq!(render_ps1(&mut shell, r"\!:\# \\ \101").plain, r"12:3 \ A");
+        assert_eq!(render_ps1(&mut shell, r"[\j]").plain, "[0]");
+        assert_eq!(render_ps1(&mut shell, r"\q\").plain, r"\q\");
+    }
+
+    #[test]
+    fn test_non_printing() {
+        let mut shell = Shell::new();
+        let prompt = render_ps1(&mut shell, r"\[\e[32m\]> \[\e[0m\]");
+        assert_eq!(prompt.plain, "> ");
+        assert_eq!(prompt.styled, "\x1b[32m> \x1b[0m");
+    }
+
+    #[test]
+    fn test_expansion() {
+        let mut shell = Shell::new();
+        shell.run("X='$HOME'; false").unwrap();
+        assert_eq!(
+            render_ps1(&mut shell, r#"$X "$?" $(echo hi) "#).plain,
+            r#"$HOME "1" hi "#
+        );
+        assert_eq!(shell.last_status, 1);
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    io, mem,
+    os::fd::RawFd,
+    str,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    builtin::Output,
+    parser,
+    shell::Shell,
+    util::{self, write_and_flush_str},
+};
+
+const READ_USAGE: &str = "read: usage: read [-rs] [-a array] [-d delim] [-n nchars] \
+                          [-N nchars] [-p prompt] [-t timeout] [-u fd] [name ...]";
+
+/// Status of `read` when the timeout runs out, like that of a command killed by `SIGALRM`.
+const TIMEOUT_STATUS: i32 = 128 + libc::SIGALRM;
+
+const DEFAULT_IFS: &str = " \t\n";
+
+#[derive(Debug)]
+struct Options {
+    /// `-r`: backslashes are kept instead of escaping the next character.
+    raw: bool,
+    /// `-s`: what's typed on a terminal isn't echoed.
+    silent: bool,
+    array: Option<String>,
+    delim: char,
+    /// `-n` or `-N`: the most characters to read.
+    nchars: Option<usize>,
+    /// `-N`: exactly `nchars` characters are read, whatever they are, and not split.
+    exact: bool,
+    prompt: Option<String>,
+    timeout: Option<Duration>,
+    fd: RawFd,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            raw: false,
+            silent: false,
+            array: None,
+            delim: '\n',
+            nchars: None,
+            exact: false,
+            prompt: None,
+            timeout: None,
+            fd: libc::STDIN_FILENO,
+        }
+    }
+}
+
+/// How reading the input stopped.
+#[derive(Debug, PartialEq)]
+enum Ending {
+    /// The delimiter was read, or as many characters as asked for.
+    Complete,
+    Eof,
+    Timeout,
+}
+
+/// read reads a line from stdin and splits it into fields on the characters of `IFS`,
+/// which are assigned to the names in order. The last name gets the rest of the line,
+/// and without names the whole line goes to `REPLY`. Unless `-r` is given, a backslash
+/// escapes the character after it and joins lines when it ends one.
+///  - `-a array` assigns all the fields to the array.
+///  - `-d delim` reads up to the first character of delim instead of a newline.
+///  - `-n nchars` reads at most nchars characters, and `-N nchars` exactly nchars.
+///  - `-p prompt` prints the prompt first if the input is a terminal.
+///  - `-s` doesn't echo the input on a terminal.
+///  - `-t timeout` gives up after timeout seconds, or checks if there's input if it's 0.
+///  - `-u fd` reads from fd instead of stdin.
+///
+/// The status is 1 on the end of the input and above 128 if the timeout runs out, but
+/// what was read is assigned even then.
+pub(crate) fn read<T, K>(
+    shell: &mut Shell,
+    w: &mut Output<T, K>,
+    args: &[&str],
+) -> anyhow::Result<i32>
+where
+    T: io::Write,
+    K: io::Write,
+{
+    let mut options = Options::default();
+    let mut idx = 0;
+    while idx < args.len() {
+        let flags = match args[idx].strip_prefix('-') {
+            Some("-") => {
+                idx += 1;
+                break;
+            }
+            Some(flags) if !flags.is_empty() => flags,
+            _ => break,
+        };
+        idx += 1;
+
+        for (pos, flag) in flags.char_indices() {
+            match flag {
+                'r' => options.raw = true,
+                's' => options.silent = true,
+                'a' | 'd' | 'n' | 'N' | 'p' | 't' | 'u' => {
+                    // The rest of the word or the next argument is the option's value
+                    let value = match &flags[pos + 1..] {
+                        "" if idx < args.len() => {
+                            idx += 1;
+                            args[idx - 1]
+                        }
+                        "" => {
+                            let e = format!("read: -{flag}: option requires an argument");
+                            write_and_flush_str(&mut w.err, &e)?;
+                            write_and_flush_str(&mut w.err, READ_USAGE)?;
+                            return Ok(2);
+                        }
+                        value => value,
+                    };
+                    if let Err(e) = set_option(&mut options, flag, value) {
+                        write_and_flush_str(&mut w.err, &format!("read: {e}"))?;
+                        return Ok(1);
+                    }
+                    break;
+                }
+                _ => {
+                    write_and_flush_str(&mut w.err, &format!("read: -{flag}: invalid option"))?;
+                    write_and_flush_str(&mut w.err, READ_USAGE)?;
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    let names = &args[idx..];
+    if let Some(name) = names
+        .iter()
+        .chain(&options.array.as_deref())
+        .find(|name| !parser::is_name(name))
+    {
+        write_and_flush_str(
+            &mut w.err,
+            &format!("read: `{name}': not a valid identifier"),
+        )?;
+        return Ok(1);
+    }
+
+    let is_terminal = unsafe { libc::isatty(options.fd) } == 1;
+    if options.timeout == Some(Duration::ZERO) {
+        return match poll(options.fd, Some(Duration::ZERO)) {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(1),
+            Err(e) => {
+                write_and_flush_str(
+                    &mut w.err,
+                    &format!("read: {}: {}", options.fd, util::error_message(&e)),
+                )?;
+                Ok(1)
+            }
+        };
+    }
+    if let Some(prompt) = options.prompt.as_ref().filter(|_| is_terminal) {
+        util::write_and_flush_bytes(&mut w.err, prompt.as_bytes())?;
+    }
+
+    let mut input = Vec::new();
+    let ending = {
+        let _mode = match is_terminal {
+            true => TerminalMode::set(options.fd, options.silent, options.nchars.is_some()),
+            false => None,
+        };
+        read_input(&options, &mut input)
+    };
+    let status = match ending {
+        Ok(Ending::Complete) => 0,
+        Ok(Ending::Eof) => 1,
+        Ok(Ending::Timeout) => TIMEOUT_STATUS,
+        Err(e) => {
+            let e = format!(
+                "read: read error: {}: {}",
+                options.fd,
+                util::error_message(&e)
+            );
+            write_and_flush_str(&mut w.err, &e)?;
+            1
+        }
+    };
+
+    let ifs = shell.var("IFS").unwrap_or(DEFAULT_IFS).to_string();
+    let line = || input.iter().map(|(ch, _)| ch).collect::<String>();
+    let assigned = if let Some(array) = &options.array {
+        let fields = split_fields(&input, &ifs, None);
+        shell.assign_array(
+            array,
+            fields.into_iter().map(|f| (None, f)).collect(),
+            false,
+        )
+    } else if names.is_empty() {
+        shell.assign("REPLY", None, &line(), false)
+    } else {
+        // What `-N` read is assigned as it is
+        let fields = match options.exact {
+            true => vec![line()],
+            false => split_fields(&input, &ifs, Some(names.len())),
+        };
+        let mut fields = fields.into_iter();
+        names.iter().try_for_each(|name| {
+            shell.assign(name, None, &fields.next().unwrap_or_default(), false)
+        })
+    };
+    if let Err(e) = assigned {
+        write_and_flush_str(&mut w.err, &format!("read: {e}"))?;
+        return Ok(1);
+    }
+    Ok(status)
+}
+
+fn set_option(options: &mut Options, flag: char, value: &str) -> Result<(), String> {
+    match flag {
+        'a' => options.array = Some(value.to_string()),
+        'd' => options.delim = value.chars().next().unwrap_or('\0'),
+        'n' | 'N' => {
+            let nchars = value
+                .parse()
+                .map_err(|_| format!("{value}: invalid number"))?;
+            options.nchars = Some(nchars);
+            options.exact = flag == 'N';
+        }
+        'p' => options.prompt = Some(value.to_string()),
+        't' => {
+            let seconds = value
+                .parse::<f64>()
+                .ok()
+                .filter(|_| {
+                    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit() || c == '.')
+                })
+                .ok_or(format!("{value}: invalid timeout specification"))?;
+            options.timeout = Some(Duration::from_secs_f64(seconds));
+        }
+        'u' => {
+            let fd = value
+                .parse::<RawFd>()
+                .ok()
+                .filter(|fd| *fd >= 0)
+                .ok_or(format!("{value}: invalid file descriptor specification"))?;
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                let e = io::Error::last_os_error();
+                return Err(format!(
+                    "{fd}: invalid file descriptor: {}",
+                    util::error_message(&e)
+                ));
+            }
+            options.fd = fd;
+        }
+        _ => unreachable!("read has no -{flag} option with a value"),
+    }
+    Ok(())
+}
+
+/// read_input reads the characters of the input, each marked with whether a backslash
+/// escaped it. It reads one byte at a time, so that nothing after the delimiter is taken
+/// from the input that following commands read from.
+fn read_input(options: &Options, input: &mut Vec<(char, bool)>) -> io::Result<Ending> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut bytes = Vec::new();
+    let mut escaped = false;
+    let mut count = 0;
+    loop {
+        if options.nchars.is_some_and(|nchars| count >= nchars) {
+            return Ok(Ending::Complete);
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        // Input that never ends in a delimiter mustn't keep it from timing out
+        if let Some(remaining) = remaining {
+            if remaining.is_zero() || !poll(options.fd, Some(remaining))? {
+                return Ok(Ending::Timeout);
+            }
+        }
+        let mut byte = 0u8;
+        match unsafe { libc::read(options.fd, (&mut byte as *mut u8).cast(), 1) } {
+            0 => return Ok(Ending::Eof),
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            _ => bytes.push(byte),
+        }
+
+        // Characters can take several bytes, so wait until all of them have been read
+        let ch = match str::from_utf8(&bytes) {
+            Ok(s) => s.chars().next().unwrap_or_default(),
+            Err(e) if e.error_len().is_none() => continue,
+            Err(_) => char::REPLACEMENT_CHARACTER,
+        };
+        bytes.clear();
+
+        if escaped {
+            escaped = false;
+            // An escaped newline joins the lines
+            if ch != '\n' {
+                input.push((ch, true));
+                count += 1;
+            }
+        } else if ch == '\\' && !options.raw {
+            escaped = true;
+        } else if ch == options.delim && !options.exact {
+            return Ok(Ending::Complete);
+        } else {
+            input.push((ch, false));
+            count += 1;
+        }
+    }
+}
+
+/// poll waits up to the timeout, or forever if there's none, for input on the fd and
+/// returns whether there is any.
+fn poll(fd: RawFd, timeout: Option<Duration>) -> io::Result<bool> {
+    let timeout = timeout.map_or(-1, |timeout| {
+        libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX)
+    });
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            ready => return Ok(ready > 0),
+        }
+    }
+}
+
+/// split_fields splits the input into fields on the characters of `IFS` that weren't
+/// escaped. Whitespace in `IFS` is trimmed around fields, while each of its other
+/// characters ends one. With a limit, the last field gets the rest of the input
+/// without its trailing whitespace.
+fn split_fields(input: &[(char, bool)], ifs: &str, limit: Option<usize>) -> Vec<String> {
+    let is_space =
+        |&(ch, escaped): &(char, bool)| !escaped && ifs.contains(ch) && ch.is_whitespace();
+    let is_delim =
+        |&(ch, escaped): &(char, bool)| !escaped && ifs.contains(ch) && !ch.is_whitespace();
+    let text = |chars: &[(char, bool)]| chars.iter().map(|(ch, _)| ch).collect::<String>();
+
+    let mut fields = Vec::new();
+    let mut pos = input.iter().take_while(|c| is_space(c)).count();
+    while pos < input.len() {
+        if limit.is_some_and(|limit| fields.len() + 1 == limit) {
+            let rest = &input[pos..];
+            let len = rest.len() - rest.iter().rev().take_while(|c| is_space(c)).count();
+            fields.push(text(&rest[..len]));
+            break;
+        }
+
+        let len = input[pos..]
+            .iter()
+            .take_while(|c| !is_space(c) && !is_delim(c))
+            .count();
+        fields.push(text(&input[pos..pos + len]));
+        pos += len;
+        pos += input[pos..].iter().take_while(|c| is_space(c)).count();
+        if input.get(pos).is_some_and(is_delim) {
+            pos += 1;
+            pos += input[pos..].iter().take_while(|c| is_space(c)).count();
+        }
+    }
+    fields
+}
+
+/// TerminalMode changes how the terminal handles input until dropped: it can stop
+/// echoing it, and pass it on as soon as it's typed rather than a line at a time.
+struct TerminalMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl TerminalMode {
+    fn set(fd: RawFd, silent: bool, unbuffered: bool) -> Option<Self> {
+        if !silent && !unbuffered {
+            return None;
+        }
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } == -1 {
+            return None;
+        }
+        let saved = termios;
+        if silent {
+            termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        }
+        if unbuffered {
+            termios.c_lflag &= !libc::ICANON;
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+        }
+        if unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, &termios) } == -1 {
+            return None;
+        }
+        Some(Self { fd, saved })
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.saved) };
+    }
+}
+
+#[cfg(test)]
+mod read_test {
+    use std::fs;
+
+    use crate::{read::split_fields, shell::Shell, util::TempDir};
+
+    fn chars(s: &str) -> Vec<(char, bool)> {
+        // `\` marks the character after it as escaped
+        let mut chars = Vec::new();
+        let mut escaped = false;
+        for ch in s.chars() {
+            match ch {
+                '\\' if !escaped => escaped = true,
+                ch => {
+                    chars.push((ch, escaped));
+                    escaped = false;
+                }
+            }
+        }
+        chars
+    }
+
+    #[test]
+    fn test_split_fields() {
+        let split = |s: &str, ifs: &str, limit: Option<usize>| split_fields(&chars(s), ifs, limit);
+        assert_eq!(split("  a  b c  ", " \t\n", Some(2)), vec!["a", "b c"]);
+        assert_eq!(split("  a  b c  ", " \t\n", None), vec!["a", "b", "c"]);
+        assert_eq!(split("a\\ b c", " ", None), vec!["a b", "c"]);
+        assert_eq!(split("a,,b , c", ", ", None), vec!["a", "", "b", "c"]);
+        assert_eq!(split("a:b:c", ":", Some(2)), vec!["a", "b:c"]);
+        assert_eq!(split("a b", "", Some(2)), vec!["a b"]);
+        assert_eq!(split("   ", " ", Some(1)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = TempDir::new("read_test");
+        let file = dir.join("input");
+        fs::write(&file, "one two three\n  a\\ b\\\nc  \nx:y:z\nlast").unwrap();
+        let path = file.display();
+        let mut shell = Shell::new();
+
+        let input = format!(
+            "while read first rest; do echo \"[$first][$rest]\"; done < {path}; echo $?; \
+             echo \"[$first][$rest]\""
+        );
+        assert_eq!(
+            shell.capture(&input).unwrap(),
+            "[one][two three]\n[a bc][]\n[x:y:z][]\n0\n[last][]"
+        );
+
+        fs::write(&file, " one\\ two \nw1 w2  w3\nabc:defgh\nk:v:w\npartial").unwrap();
+        let input = format!(
+            "exec < {path}; read -r; echo \"[$REPLY]\"; read -a words; echo \"${{words[@]}}\" ${{#words[@]}}; \
+             read -d : -n 5 x; read -N 3 y; read; echo \"$x $y $REPLY\"; \
+             IFS=: read -r a b; echo \"$a-$b\"; read; echo \"$?-$REPLY\"; read; echo $?"
+        );
+        assert_eq!(
+            shell.capture(&input).unwrap(),
+            "[ one\\ two ]\nw1 w2 w3 3\nabc def gh\nk-v:w\n1-partial\n1"
+        );
+        assert_eq!(shell.capture("read -n x; echo $?").unwrap(), "1");
+        assert_eq!(shell.capture("read 1x < /dev/null; echo $?").unwrap(), "1");
+    }
+}
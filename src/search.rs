@@ -0,0 +1,366 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write as _},
+    mem,
+};
+
+use rustyline::{
+    Cmd, ConditionalEventHandler, Editor, Event, EventContext, EventHandler, KeyCode, KeyEvent,
+    Modifiers, RepeatCount,
+};
+
+use crate::{history::Entry, shell, util};
+
+/// Number of matches the picker shows at once.
+const PICKER_ROWS: usize = 10;
+
+/// bind_keys binds Up and Down to search the history for lines that start with what's
+/// before the cursor, and Ctrl-X Ctrl-R to open the picker with the line as the query.
+pub(crate) fn bind_keys<H, I>(rl: &mut Editor<H, I>)
+where
+    H: rustyline::Helper,
+    I: rustyline::history::History,
+{
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Up, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(PrefixSearch(Cmd::HistorySearchBackward))),
+    );
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Down, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(PrefixSearch(Cmd::HistorySearchForward))),
+    );
+    rl.bind_sequence(
+        Event::KeySeq(vec![KeyEvent::ctrl('X'), KeyEvent::ctrl('R')]),
+        EventHandler::Conditional(Box::new(OpenPicker)),
+    );
+}
+
+struct PrefixSearch(Cmd);
+
+impl ConditionalEventHandler for PrefixSearch {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        // Up and Down move between the lines of a multi-line command instead
+        match ctx.line().contains('\n') {
+            true => None,
+            false => Some(self.0.clone()),
+        }
+    }
+}
+
+struct OpenPicker;
+
+impl ConditionalEventHandler for OpenPicker {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let picked = shell::with_editor_shell(|shell, _| {
+            let dir = shell.current_dir();
+            pick(shell.history.entries(), ctx.line(), &dir, util::unix_time())
+        })?;
+        match picked {
+            Ok(Some(line)) => Some(util::edit_line(ctx.line(), ctx.pos(), &line, line.len())),
+            Ok(None) => Some(Cmd::Repaint),
+            Err(e) => {
+                // Put the error on the row of the line, which is then redrawn below it
+                let message = format!("\rfailed to pick from history: {e}\r");
+                util::write_and_flush_str(&mut io::stderr(), &message).ok()?;
+                Some(Cmd::Repaint)
+            }
+        }
+    }
+}
+
+/// rank returns the distinct lines of the history that fuzzy match the query, the best
+/// first. Lines are ranked by frecency: each time a line was run counts more the more
+/// recent it was, and twice as much if it was run in `dir`.
+pub(crate) fn rank(entries: &[Entry], query: &str, dir: &str, now: u64) -> Vec<String> {
+    // Score and position of the last run of each line
+    let mut scores: HashMap<&str, (f64, usize)> = HashMap::new();
+    for (idx, entry) in entries.iter().enumerate() {
+        if !fuzzy_match(&entry.line, query) {
+            continue;
+        }
+        let mut weight = recency_weight(now.saturating_sub(entry.time));
+        if entry.dir == dir {
+            weight *= 2.0;
+        }
+        let score = scores.entry(&entry.line).or_default();
+        score.0 += weight;
+        score.1 = idx;
+    }
+
+    let mut ranked: Vec<(&str, (f64, usize))> = scores.into_iter().collect();
+    ranked.sort_by(|(_, a), (_, b)| b.0.total_cmp(&a.0).then(b.1.cmp(&a.1)));
+    ranked
+        .into_iter()
+        .map(|(line, _)| line.to_string())
+        .collect()
+}
+
+/// recency_weight returns how much a run of a line counts given how many seconds ago it
+/// was, in the buckets that `z` and `zoxide` use for directories.
+fn recency_weight(age: u64) -> f64 {
+    match age {
+        0..3600 => 4.0,
+        3600..86400 => 2.0,
+        86400..604800 => 0.5,
+        _ => 0.25,
+    }
+}
+
+/// fuzzy_match returns whether the chars of the query appear in the line in order. The
+/// match ignores case unless the query has uppercase letters.
+fn fuzzy_match(line: &str, query: &str) -> bool {
+    let ignore_case = !query.chars().any(char::is_uppercase);
+    let mut chars = line.chars();
+    query.chars().all(|q| {
+        chars.any(|c| match ignore_case {
+            true => c.to_lowercase().eq(q.to_lowercase()),
+            false => c == q,
+        })
+    })
+}
+
+/// pick lets the user choose one of the lines of the history on the terminal, narrowing
+/// them down by typing. Up, Down, Ctrl-P and Ctrl-N move the selection, Enter chooses it
+/// and Escape, Ctrl-C or Ctrl-G cancel. Returns none if canceled or not on a terminal.
+///
+/// The picker is drawn from the row of the cursor down, and cleared once done with the
+/// cursor back at the start of that row, for the editor to redraw its line from there.
+pub(crate) fn pick(
+    entries: &[Entry],
+    query: &str,
+    dir: &str,
+    now: u64,
+) -> io::Result<Option<String>> {
+    let _raw = match RawMode::enable()? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let width = util::terminal_width();
+    let mut stdout = io::stdout();
+
+    let mut picker = Picker {
+        query: query.to_string(),
+        matches: rank(entries, query, dir, now),
+        selected: 0,
+    };
+    loop {
+        stdout.write_all(picker.draw(width).as_bytes())?;
+        stdout.flush()?;
+
+        let key = read_key()?;
+        let action = picker.handle(&key);
+        if action != Action::Continue {
+            // Leave the screen as it was before the picker
+            stdout.write_all(b"\r\x1b[J")?;
+            stdout.flush()?;
+        }
+        match action {
+            Action::Continue => picker.matches = rank(entries, &picker.query, dir, now),
+            Action::Choose => return Ok(picker.matches.get(picker.selected).cloned()),
+            Action::Cancel => return Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Continue,
+    Choose,
+    Cancel,
+}
+
+struct Picker {
+    query: String,
+    matches: Vec<String>,
+    selected: usize,
+}
+
+impl Picker {
+    /// handle updates the picker for the bytes of a key.
+    fn handle(&mut self, key: &[u8]) -> Action {
+        match key {
+            b"\r" | b"\n" => return Action::Choose,
+            b"\x1b" | b"\x03" | b"\x07" => return Action::Cancel,
+            b"\x04" if self.query.is_empty() => return Action::Cancel,
+            b"\x1b[A" | b"\x1bOA" | b"\x10" => self.selected = self.selected.saturating_sub(1),
+            b"\x1b[B" | b"\x1bOB" | b"\x0e" | b"\x12" => self.selected += 1,
+            b"\x7f" | b"\x08" => {
+                self.query.pop();
+            }
+            b"\x15" => self.query.clear(),
+            b"\x17" => {
+                let trimmed = self.query.trim_end();
+                let len = trimmed.rfind(' ').map_or(0, |idx| idx + 1);
+                self.query.truncate(len);
+            }
+            _ => {
+                let text = String::from_utf8_lossy(key);
+                if text.chars().any(char::is_control) {
+                    return Action::Continue;
+                }
+                self.query.push_str(&text);
+                self.selected = 0;
+            }
+        }
+        self.selected = self
+            .selected
+            .min(self.matches.len().min(PICKER_ROWS).saturating_sub(1));
+        Action::Continue
+    }
+
+    /// draw returns what redraws the picker from the start of its first row: the query
+    /// followed by the best matches, with the cursor left after the query.
+    fn draw(&self, width: usize) -> String {
+        let mut drawn = format!("\r\x1b[J> {}", self.query);
+        let shown = self.matches.len().min(PICKER_ROWS);
+        for (idx, line) in self.matches.iter().take(shown).enumerate() {
+            let line: String = line
+                .replace('\n', " ")
+                .chars()
+                .take(width.saturating_sub(3))
+                .collect();
+            match idx == self.selected {
+                true => drawn.push_str(&format!("\r\n\x1b[7m> {line}\x1b[0m")),
+                false => drawn.push_str(&format!("\r\n  {line}")),
+            }
+        }
+        if shown > 0 {
+            drawn.push_str(&format!("\x1b[{shown}A"));
+        }
+        drawn.push_str(&format!("\r\x1b[{}C", 2 + self.query.chars().count()));
+        drawn
+    }
+}
+
+/// RawMode puts the terminal on stdin in raw mode until dropped.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> io::Result<Option<Self>> {
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+            return Ok(None);
+        }
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = termios;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Self(saved)))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.0) };
+    }
+}
+
+/// read_key reads the bytes of the next key from stdin. Escape sequences normally arrive
+/// at once, so an escape followed by nothing within a moment is the Escape key itself.
+fn read_key() -> io::Result<Vec<u8>> {
+    let mut key = vec![0; 32];
+    let len = read_stdin(&mut key)?;
+    key.truncate(len);
+    if key == b"\x1b" {
+        let mut pollfd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, 50) } > 0 {
+            let mut rest = [0; 31];
+            let len = read_stdin(&mut rest)?;
+            key.extend_from_slice(&rest[..len]);
+        }
+    }
+    Ok(key)
+}
+
+fn read_stdin(buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let len = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        match len {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            // The terminal went away, which cancels like Ctrl-C
+            0 => {
+                buf[0] = b'\x03';
+                return Ok(1);
+            }
+            len => return Ok(len as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod search_test {
+    use crate::{
+        history::Entry,
+        search::{fuzzy_match, rank, Action, Picker},
+    };
+
+    fn entry(line: &str, dir: &str, time: u64) -> Entry {
+        Entry {
+            line: line.to_string(),
+            dir: dir.to_string(),
+            time,
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert!(fuzzy_match("cargo build --release", "cbr"));
+        assert!(fuzzy_match("Cargo", "cargo"));
+        assert!(!fuzzy_match("cargo", "Cargo"));
+        assert!(!fuzzy_match("cargo", "ogc"));
+        assert!(fuzzy_match("anything", ""));
+    }
+
+    #[test]
+    fn test_rank() {
+        let now = 1_000_000;
+        let history = vec![
+            entry("make test", "/src", now - 2 * 86400),
+            entry("make test", "/src", now - 2 * 86400),
+            entry("make test", "/src", now - 2 * 86400),
+            entry("make", "/tmp", now - 100),
+            entry("ls", "/tmp", now - 10),
+            entry("mkdir out", "/tmp", now - 7200),
+        ];
+        assert_eq!(
+            rank(&history, "mk", "/", now),
+            vec!["make", "mkdir out", "make test"]
+        );
+        // Runs in the current directory count twice
+        assert_eq!(
+            rank(&history, "mk", "/src", now),
+            vec!["make", "make test", "mkdir out"]
+        );
+        assert_eq!(rank(&history, "mtt", "/", now), vec!["make test"]);
+        assert_eq!(rank(&history, "", "/", now)[0], "ls");
+    }
+
+    #[test]
+    fn test_picker_handle() {
+        let mut picker = Picker {
+            query: String::new(),
+            matches: vec!["a".to_string(), "b".to_string()],
+            selected: 0,
+        };
+        assert_eq!(picker.handle(b"\x1b[B"), Action::Continue);
+        assert_eq!(picker.handle(b"\x1b[B"), Action::Continue);
+        assert_eq!(picker.selected, 1);
+        assert_eq!(picker.handle("gi é".as_bytes()), Action::Continue);
+        assert_eq!((picker.query.as_str(), picker.selected), ("gi é", 0));
+        assert_eq!(picker.handle(b"\x17"), Action::Continue);
+        assert_eq!(picker.query, "gi ");
+        assert_eq!(picker.handle(b"\x7f"), Action::Continue);
+        assert_eq!(picker.query, "gi");
+        assert_eq!(picker.handle(b"\r"), Action::Choose);
+        assert_eq!(picker.handle(b"\x1b"), Action::Cancel);
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use strum::EnumString;
+
+use crate::{prompt::PromptContext, shell::Shell};
+
+/// Segments of the prompt that are computed natively, listed by name in
+/// `PROMPT_SEGMENTS` and shown before `PS1` in that order.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Segment {
+    /// Branch checked out in the current repository, or the commit of a detached
+    /// HEAD, with a `*` if tracked files were modified.
+    Git,
+    /// Exit status of the last command, if it failed.
+    Status,
+    /// How long the last command took.
+    Duration,
+    /// Number of jobs, if any.
+    Jobs,
+}
+
+impl Segment {
+    fn color(&self) -> &'static str {
+        match self {
+            Self::Git => "35",
+            Self::Status => "31",
+            Self::Duration => "33",
+            Self::Jobs => "36",
+        }
+    }
+
+    fn render(&self, shell: &Shell, context: &PromptContext) -> Option<String> {
+        match self {
+            Self::Git => {
+                let repo = Repository::discover(Path::new(&shell.current_dir()))?;
+                let head = repo.head()?;
+                match repo.is_dirty() {
+                    true => Some(format!("({head}*)")),
+                    false => Some(format!("({head})")),
+                }
+            }
+            Self::Status => match shell.last_status {
+                0 => None,
+                status => Some(format!("[{status}]")),
+            },
+            Self::Duration => context.last_duration.map(format_duration),
+            Self::Jobs => match shell.job_count() {
+                0 => None,
+                jobs => Some(format!("{jobs}&")),
+            },
+        }
+    }
+}
+
+/// render renders the segments named in `PROMPT_SEGMENTS`, each followed by a space.
+/// Unknown names are skipped. Colors are enclosed in the given markers of
+/// non-printing sequences and left out if `NO_COLOR` is set.
+pub(crate) fn render(
+    shell: &Shell,
+    context: &PromptContext,
+    (start_ignore, end_ignore): (char, char),
+) -> String {
+    let names = shell.var("PROMPT_SEGMENTS").unwrap_or_default();
+    let color = !shell.var("NO_COLOR").is_some_and(|v| !v.is_empty());
+
+    let mut rendered = String::new();
+    for name in names.split_whitespace() {
+        let segment = match name.parse::<Segment>() {
+            Ok(segment) => segment,
+            Err(_) => continue,
+        };
+        let text = match segment.render(shell, context) {
+            Some(text) => text,
+            None => continue,
+        };
+        match color {
+            true => rendered.push_str(&format!(
+                "{start_ignore}\x1b[{}m{end_ignore}{text}{start_ignore}\x1b[0m{end_ignore} ",
+                segment.color()
+            )),
+            false => rendered.push_str(&format!("{text} ")),
+        }
+    }
+    rendered
+}
+
+/// format_duration formats the duration in the largest units that fit it, e.g.
+/// `350ms`, `4.2s` or `1m5s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0 => format!("{}ms", duration.as_millis()),
+        1..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Most tracked files the dirty check looks at. It stats every one of them, so in
+/// larger repositories it's skipped to keep the prompt fast.
+const MAX_DIRTY_CHECK_FILES: usize = 10_000;
+
+/// A git repository, read directly from its files.
+struct Repository {
+    git_dir: PathBuf,
+    work_tree: PathBuf,
+}
+
+impl Repository {
+    /// discover finds the repository `dir` is in by looking for `.git` in it and its
+    /// parents. A `.git` file, as in worktrees and submodules, points to the git dir.
+    fn discover(dir: &Path) -> Option<Self> {
+        for work_tree in dir.ancestors() {
+            let dot_git = work_tree.join(".git");
+            let metadata = match fs::metadata(&dot_git) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let git_dir = match metadata.is_dir() {
+                true => dot_git,
+                false => {
+                    let contents = fs::read_to_string(&dot_git).ok()?;
+                    let git_dir = contents.strip_prefix("gitdir:")?.trim();
+                    work_tree.join(git_dir)
+                }
+            };
+            return Some(Self {
+                git_dir,
+                work_tree: work_tree.to_path_buf(),
+            });
+        }
+        None
+    }
+
+    /// head returns the name of the checked out branch, or the abbreviated commit
+    /// if the HEAD is detached.
+    fn head(&self) -> Option<String> {
+        let head = fs::read_to_string(self.git_dir.join("HEAD")).ok()?;
+        parse_head(&head)
+    }
+
+    /// is_dirty returns whether any tracked file was modified, deleted or is in
+    /// conflict. Like the quick check of `git diff-files`, files are compared with the
+    /// stat data saved in the index rather than by content. Repositories with more than
+    /// [`MAX_DIRTY_CHECK_FILES`] tracked files are never reported as dirty.
+    fn is_dirty(&self) -> bool {
+        let index = match fs::read(self.git_dir.join("index")) {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+        let entries = match parse_index(&index) {
+            Some(entries) => entries,
+            None => return false,
+        };
+        if entries.len() > MAX_DIRTY_CHECK_FILES {
+            return false;
+        }
+        entries
+            .iter()
+            .any(|entry| entry.is_modified(&self.work_tree))
+    }
+}
+
+/// parse_head parses the contents of `.git/HEAD`.
+fn parse_head(head: &str) -> Option<String> {
+    let head = head.trim();
+    match head.strip_prefix("ref:") {
+        Some(reference) => {
+            let reference = reference.trim();
+            Some(
+                reference
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(reference)
+                    .to_string(),
+            )
+        }
+        None if head.len() >= 7 && head.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(head[..7].to_string())
+        }
+        None => None,
+    }
+}
+
+/// A file tracked in the git index.
+#[derive(Debug, PartialEq)]
+struct IndexEntry {
+    path: String,
+    mtime: (u32, u32),
+    size: u32,
+    mode: u32,
+    /// Merge stage, which is non-zero for files in conflict.
+    stage: u16,
+    /// Whether git is told not to look at the file in the work tree, with
+    /// `--assume-unchanged` or sparse checkout.
+    skip: bool,
+}
+
+impl IndexEntry {
+    fn is_modified(&self, work_tree: &Path) -> bool {
+        // Submodules have their own repository
+        if self.skip || self.mode & 0o170000 == 0o160000 {
+            return false;
+        }
+        if self.stage != 0 {
+            return true;
+        }
+        match fs::symlink_metadata(work_tree.join(&self.path)) {
+            Ok(metadata) => {
+                metadata.mtime() as u32 != self.mtime.0
+                    || (self.mtime.1 != 0 && metadata.mtime_nsec() as u32 != self.mtime.1)
+                    || metadata.size() as u32 != self.size
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+/// parse_index parses the entries of a git index file of version 2, 3 or 4.
+fn parse_index(index: &[u8]) -> Option<Vec<IndexEntry>> {
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes = index.get(pos..pos + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = index.get(pos..pos + 2)?;
+        Some(u16::from_be_bytes(bytes.try_into().ok()?))
+    };
+
+    if index.get(..4)? != b"DIRC" {
+        return None;
+    }
+    let version = u32_at(4)?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let count = u32_at(8)?;
+
+    let mut entries = Vec::new();
+    let mut pos = 12;
+    let mut previous_path: Vec<u8> = Vec::new();
+    for _ in 0..count {
+        let start = pos;
+        let mtime = (u32_at(pos + 8)?, u32_at(pos + 12)?);
+        let mode = u32_at(pos + 24)?;
+        let size = u32_at(pos + 36)?;
+        // The object id is followed by the flags
+        let flags = u16_at(pos + 60)?;
+        pos += 62;
+        let mut skip = flags & 0x8000 != 0;
+        if flags & 0x4000 != 0 && version >= 3 {
+            skip |= u16_at(pos)? & 0x4000 != 0;
+            pos += 2;
+        }
+
+        let path = match version {
+            4 => {
+                // The path replaces the end of the previous one
+                let mut byte = *index.get(pos)?;
+                pos += 1;
+                let mut strip = (byte & 0x7f) as usize;
+                while byte & 0x80 != 0 {
+                    byte = *index.get(pos)?;
+                    pos += 1;
+                    strip = ((strip + 1) << 7) | (byte & 0x7f) as usize;
+                }
+                let len = index.get(pos..)?.iter().position(|b| *b == 0)?;
+                let keep = previous_path.len().checked_sub(strip)?;
+                let mut path = previous_path[..keep].to_vec();
+                path.extend_from_slice(&index[pos..pos + len]);
+                pos += len + 1;
+                path
+            }
+            _ => {
+                let len = index.get(pos..)?.iter().position(|b| *b == 0)?;
+                let path = index[pos..pos + len].to_vec();
+                // Entries are padded with NULs to a multiple of 8 bytes
+                pos = start + (pos + len - start + 8) / 8 * 8;
+                path
+            }
+        };
+
+        entries.push(IndexEntry {
+            path: String::from_utf8_lossy(&path).to_string(),
+            mtime,
+            size,
+            mode,
+            stage: (flags >> 12) & 0x3,
+            skip,
+        });
+        previous_path = path;
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod segment_test {
+    use std::time::Duration;
+
+    use crate::{
+        prompt::PromptContext,
+        segment::{format_duration, parse_head, parse_index, render, IndexEntry},
+        shell::Shell,
+    };
+
+    #[test]
+    fn test_render() {
+        let mut shell = Shell::new();
+        shell.set_var("PROMPT_SEGMENTS", "jobs status nosuchsegment duration");
+        shell.set_var("NO_COLOR", "1");
+        shell.last_status = 2;
+        let context = PromptContext {
+            history_number: 1,
+            command_number: 1,
+            last_duration: Some(Duration::from_millis(1500)),
+        };
+        // There are no jobs, so the jobs segment is hidden
+        assert_eq!(render(&shell, &context, ('[', ']')), "[2] 1.5s ");
+    }
+
+    #[test]
+    fn test_parse_head() {
+        assert_eq!(
+            parse_head("ref: refs/heads/main\n"),
+            Some("main".to_string())
+        );
+        assert_eq!(
+            parse_head("ref: refs/heads/feature/x\n"),
+            Some("feature/x".to_string())
+        );
+        assert_eq!(
+            parse_head("0123456789abcdef0123456789abcdef01234567\n"),
+            Some("0123456".to_string())
+        );
+        assert_eq!(parse_head("garbage"), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(350)), "350ms");
+        assert_eq!(format_duration(Duration::from_millis(4240)), "4.2s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m5s");
+        assert_eq!(format_duration(Duration::from_secs(3720)), "1h2m");
+    }
+
+    fn entry(path: &[u8], mtime: u32, size: u32, flags: u16) -> Vec<u8> {
+        let mut entry = Vec::new();
+        for field in [0, 0, mtime, 0, 0, 0, 0o100644, 0, 0, size] {
+            entry.extend(u32::to_be_bytes(field));
+        }
+        entry.extend([0; 20]);
+        entry.extend(u16::to_be_bytes(flags | path.len() as u16));
+        entry.extend(path);
+        entry
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let mut index = b"DIRC".to_vec();
+        index.extend(u32::to_be_bytes(2));
+        index.extend(u32::to_be_bytes(2));
+        for (path, flags) in [(&b"src/a.rs"[..], 0), (b"b", 0x2000)] {
+            let mut entry = entry(path, 7, 3, flags);
+            entry.resize(entry.len() / 8 * 8 + 8, 0);
+            index.extend(entry);
+        }
+
+        let entries = parse_index(&index).unwrap();
+        assert_eq!(
+            entries[0],
+            IndexEntry {
+                path: "src/a.rs".to_string(),
+                mtime: (7, 0),
+                size: 3,
+                mode: 0o100644,
+                stage: 0,
+                skip: false,
+            }
+        );
+        assert_eq!(entries[1].path, "b");
+        assert_eq!(entries[1].stage, 2);
+    }
+
+    #[test]
+    fn test_parse_index_v4() {
+        let mut index = b"DIRC".to_vec();
+        index.extend(u32::to_be_bytes(4));
+        index.extend(u32::to_be_bytes(2));
+        index.extend(entry(b"", 1, 1, 0));
+        index.extend(b"\0src/main.rs\0");
+        index.extend(entry(b"", 1, 1, 0));
+        index.extend(b"\x07lib.rs\0");
+
+        let paths: Vec<String> = parse_index(&index)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, vec!["src/main.rs", "src/lib.rs"]);
+    }
+}
@@ -0,0 +1,1580 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    fs::{self, File},
+    io::{self, Read as _},
+    os::{
+        fd::{AsRawFd as _, OwnedFd},
+        unix::fs::{MetadataExt as _, OpenOptionsExt as _},
+    },
+    path::{Path, PathBuf},
+    process,
+    rc::{Rc, Weak},
+};
+
+use anyhow::Context as _;
+
+use crate::{
+    arith, bind,
+    builtin::{self, Output},
+    completion::CompSpec,
+    cond, dirstack, expand,
+    hash::CommandHash,
+    history,
+    parser::{
+        self, AndOr, AssignedValue, Assignment, Command, Compound, Connector, If, List, Loop,
+        ParseError, Redirect, RedirectKind, SimpleCommand,
+    },
+    util::{self, write_and_flush_str, SavedFds},
+    variable::{Attributes, Key, Value, Variable},
+};
+
+/// Pending change of control flow, set by builtins like `return`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Control {
+    Return,
+}
+
+/// Line of a sourced file being run, reported with errors.
+#[derive(Debug, Clone)]
+pub(crate) struct Location {
+    pub(crate) source: String,
+    pub(crate) line: usize,
+}
+
+/// A process substitution, whose process is waited for once the command it was made
+/// for is done.
+struct ProcessSubstitution {
+    pid: i32,
+    /// The shell's end of the pipe, which the command opens as `/dev/fd/N`.
+    fd: Option<OwnedFd>,
+    /// The named pipe used instead of `/dev/fd` where there is none, in a directory of
+    /// its own, and whether the command writes to it.
+    fifo: Option<(PathBuf, bool)>,
+}
+
+/// State of the running shell that persists between commands.
+pub(crate) struct Shell {
+    vars: HashMap<String, Variable>,
+    functions: HashMap<String, Rc<List>>,
+    positional: Vec<String>,
+    /// Variables shadowed by `local` in each running function, restored on return.
+    local_scopes: Vec<HashMap<String, Option<Variable>>>,
+    pub(crate) control: Option<Control>,
+    /// Exit status of the last command, i.e. `$?`.
+    pub(crate) last_status: i32,
+    /// Exit status of the last command substitution in the current command, if any.
+    substitution_status: Option<i32>,
+    /// Programmable completion specs registered with `complete`, by command name.
+    pub(crate) completions: HashMap<String, CompSpec>,
+    /// Remembered locations of commands in `$PATH`.
+    pub(crate) hash: CommandHash,
+    /// Aliases defined with `alias`, by name.
+    pub(crate) aliases: BTreeMap<String, String>,
+    /// Directories saved by `pushd`, most recent first, not including the current one.
+    pub(crate) dir_stack: Vec<String>,
+    /// Lines entered in this and earlier sessions.
+    pub(crate) history: history::HistoryStore,
+    /// Settings of the line editor, like key bindings.
+    pub(crate) editor: bind::EditorSettings,
+    /// Where the running command is in the sourced file, if one is being run.
+    pub(crate) location: Option<Location>,
+    /// Whether this is a forked copy of the shell, like a subshell, which has to exit
+    /// with [`util::exit_child`].
+    forked: bool,
+    /// Process substitutions of the running commands, innermost last.
+    process_substitutions: Vec<ProcessSubstitution>,
+}
+
+impl Shell {
+    pub(crate) fn new() -> Self {
+        let vars = env::vars()
+            .map(|(name, value)| {
+                let var = Variable::exported(&value);
+                (name, var)
+            })
+            .collect();
+
+        let mut shell = Self {
+            vars,
+            functions: HashMap::new(),
+            positional: Vec::new(),
+            local_scopes: Vec::new(),
+            control: None,
+            last_status: 0,
+            substitution_status: None,
+            completions: HashMap::new(),
+            hash: CommandHash::default(),
+            aliases: BTreeMap::new(),
+            dir_stack: Vec::new(),
+            history: history::HistoryStore::default(),
+            editor: bind::EditorSettings::default(),
+            location: None,
+            forked: false,
+            process_substitutions: Vec::new(),
+        };
+        // An inherited `PWD` keeps the symlinks followed to get here, if it's still valid
+        let pwd = shell.current_dir();
+        shell.set_exported_var("PWD", &pwd);
+        shell
+    }
+
+    /// var returns what `$name` expands to if it's set, following name references.
+    pub(crate) fn var(&self, name: &str) -> Option<&str> {
+        self.variable(name)?.value.as_ref()?.scalar()
+    }
+
+    /// variable returns the variable that `name` refers to, following name references.
+    pub(crate) fn variable(&self, name: &str) -> Option<&Variable> {
+        self.vars.get(&self.resolve(name))
+    }
+
+    /// raw_variable returns the variable called `name`, even if it's a name reference.
+    pub(crate) fn raw_variable(&self, name: &str) -> Option<&Variable> {
+        self.vars.get(name)
+    }
+
+    /// variable_entry returns the variable called `name`, declaring it without a value
+    /// if it doesn't exist.
+    pub(crate) fn variable_entry(&mut self, name: &str) -> &mut Variable {
+        self.vars.entry(name.to_string()).or_insert(Variable {
+            value: None,
+            attributes: Attributes::default(),
+        })
+    }
+
+    /// resolve follows the name references from `name` to the name of the variable they
+    /// refer to, stopping if they go round in a loop.
+    pub(crate) fn resolve(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for _ in 0..self.vars.len() {
+            match self.vars.get(&name) {
+                Some(Variable {
+                    value: Some(Value::Scalar(target)),
+                    attributes,
+                }) if attributes.nameref && parser::is_name(target) => name = target.clone(),
+                _ => break,
+            }
+        }
+        name
+    }
+
+    pub(crate) fn var_names(&self) -> Vec<String> {
+        self.vars.keys().cloned().collect()
+    }
+
+    /// exported_vars returns the variables that spawned programs get as their
+    /// environment, which are the exported scalars.
+    pub(crate) fn exported_vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter_map(|(name, var)| match &var.value {
+            Some(Value::Scalar(value)) if var.attributes.exported => {
+                Some((name.as_str(), value.as_str()))
+            }
+            _ => None,
+        })
+    }
+
+    /// set_var sets a variable for the shell's own use, bypassing its attributes. If
+    /// it's an array, its element 0 is set.
+    pub(crate) fn set_var(&mut self, name: &str, value: &str) {
+        let var = self.variable_entry(name);
+        match &mut var.value {
+            Some(current) => current.set(Key::Index(0), value.to_string()),
+            None => var.value = Some(Value::Scalar(value.to_string())),
+        }
+    }
+
+    /// set_array sets an indexed array for the shell's own use, like `DIRSTACK`.
+    pub(crate) fn set_array(&mut self, name: &str, values: Vec<String>) {
+        self.variable_entry(name).value = Some(Value::indexed(values));
+    }
+
+    fn set_exported_var(&mut self, name: &str, value: &str) {
+        let var = self.variable_entry(name);
+        var.value = Some(Value::Scalar(value.to_string()));
+        var.attributes.exported = true;
+    }
+
+    pub(crate) fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+    }
+
+    /// assign assigns the value to the variable, or to its element if there is a
+    /// subscript, the way an assignment does: the value is evaluated as an arithmetic
+    /// expression for `-i`, converted for `-l` and `-u`, and with `append` added to the
+    /// current value. Without a subscript, element 0 of an array is assigned.
+    pub(crate) fn assign(
+        &mut self,
+        name: &str,
+        subscript: Option<&str>,
+        value: &str,
+        append: bool,
+    ) -> Result<(), String> {
+        let name = self.resolve(name);
+        let var = self.writable(&name)?;
+        let key = match subscript {
+            Some(subscript) => self.key(&name, subscript)?,
+            None => Key::Index(0),
+        };
+        let current = match append {
+            true => var
+                .value
+                .as_ref()
+                .and_then(|v| v.get(&key))
+                .map(String::from),
+            false => None,
+        };
+        let value = self.convert(&var, current, value)?;
+
+        let var = self.variable_entry(&name);
+        match (&mut var.value, key) {
+            (Some(current), key) => current.set(key, value),
+            (None, Key::Index(0)) if subscript.is_none() => var.value = Some(Value::Scalar(value)),
+            (None, key) => {
+                let mut array = Value::Indexed(BTreeMap::new());
+                array.set(key, value);
+                var.value = Some(array);
+            }
+        }
+        Ok(())
+    }
+
+    /// assign_array assigns the elements of `name=(...)` to an array, with their
+    /// subscripts if they have one. The array is associative if it was declared so,
+    /// in which case elements without subscripts are taken as key and value pairs.
+    /// With `append`, the elements are added to the current ones.
+    pub(crate) fn assign_array(
+        &mut self,
+        name: &str,
+        elements: Vec<(Option<String>, String)>,
+        append: bool,
+    ) -> Result<(), String> {
+        let name = self.resolve(name);
+        let var = self.writable(&name)?;
+        let mut array = match (&var.value, append) {
+            (Some(Value::Associative(_)), false) => Value::Associative(BTreeMap::new()),
+            (Some(Value::Scalar(value)), true) => Value::indexed(vec![value.clone()]),
+            (Some(value), true) => value.clone(),
+            _ => Value::Indexed(BTreeMap::new()),
+        };
+
+        if let Value::Associative(_) = array {
+            let mut elements = elements.into_iter();
+            while let Some((subscript, value)) = elements.next() {
+                let (key, value) = match subscript {
+                    Some(key) => (key, value),
+                    None => (value, elements.next().map(|(_, v)| v).unwrap_or_default()),
+                };
+                let value = self.convert(&var, None, &value)?;
+                array.set(Key::Name(key), value);
+            }
+        } else {
+            let mut next = array.next_index();
+            for (subscript, value) in elements {
+                let idx = match subscript {
+                    Some(subscript) => match self.key_in(&name, &array, &subscript)? {
+                        Key::Index(idx) => idx,
+                        Key::Name(_) => next,
+                    },
+                    None => next,
+                };
+                let value = self.convert(&var, None, &value)?;
+                array.set(Key::Index(idx), value);
+                next = idx + 1;
+            }
+        }
+
+        self.variable_entry(&name).value = Some(array);
+        Ok(())
+    }
+
+    /// element returns the element of the array with the subscript, or of a scalar as
+    /// if it were an array with only the index 0.
+    pub(crate) fn element(
+        &mut self,
+        name: &str,
+        subscript: &str,
+    ) -> Result<Option<String>, String> {
+        let name = self.resolve(name);
+        let Some(value) = self.vars.get(&name).and_then(|var| var.value.clone()) else {
+            return Ok(None);
+        };
+        let key = self.key_in(&name, &value, subscript)?;
+        Ok(value.get(&key).map(String::from))
+    }
+
+    /// unset unsets the variable, or only its element if there is a subscript.
+    pub(crate) fn unset(&mut self, name: &str, subscript: Option<&str>) -> Result<(), String> {
+        let name = self.resolve(name);
+        if self
+            .vars
+            .get(&name)
+            .is_some_and(|var| var.attributes.readonly)
+        {
+            return Err(format!("{name}: cannot unset: readonly variable"));
+        }
+        match subscript {
+            None => self.unset_var(&name),
+            // Like bash, this empties arrays but keeps them declared
+            Some("@" | "*") => match self.vars.get_mut(&name).and_then(|var| var.value.as_mut()) {
+                Some(Value::Indexed(elements)) => elements.clear(),
+                Some(Value::Associative(elements)) => elements.clear(),
+                _ => self.unset_var(&name),
+            },
+            Some(subscript) => {
+                let Some(value) = self.vars.get(&name).and_then(|var| var.value.clone()) else {
+                    return Ok(());
+                };
+                let key = self.key_in(&name, &value, subscript)?;
+                if let Some(value) = self.vars.get_mut(&name).and_then(|var| var.value.as_mut()) {
+                    value.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// writable returns a copy of the variable, or a new one, if it can be assigned.
+    fn writable(&self, name: &str) -> Result<Variable, String> {
+        match self.vars.get(name) {
+            Some(var) if var.attributes.readonly => Err(format!("{name}: readonly variable")),
+            Some(var) => Ok(var.clone()),
+            None => Ok(Variable {
+                value: None,
+                attributes: Attributes::default(),
+            }),
+        }
+    }
+
+    /// key returns the key of the element of the variable that the subscript refers to.
+    fn key(&mut self, name: &str, subscript: &str) -> Result<Key, String> {
+        match self.vars.get(name).and_then(|var| var.value.clone()) {
+            Some(value) => self.key_in(name, &value, subscript),
+            None => self.key_in(name, &Value::Indexed(BTreeMap::new()), subscript),
+        }
+    }
+
+    /// key_in returns the key of the element of the value that the subscript refers to:
+    /// the subscript itself for associative arrays, and otherwise the index it evaluates
+    /// to as an arithmetic expression, counting from the end if it's negative.
+    fn key_in(&mut self, name: &str, value: &Value, subscript: &str) -> Result<Key, String> {
+        let bad_subscript = || format!("{name}[{subscript}]: bad array subscript");
+        if let Value::Associative(_) = value {
+            return match subscript {
+                "" => Err(bad_subscript()),
+                _ => Ok(Key::Name(subscript.to_string())),
+            };
+        }
+        match arith::evaluate(self, subscript)? {
+            idx if idx >= 0 => Ok(Key::Index(idx)),
+            idx if value.next_index() + idx >= 0 => Ok(Key::Index(value.next_index() + idx)),
+            _ => Err(bad_subscript()),
+        }
+    }
+
+    /// convert converts a value being assigned to the variable according to its
+    /// attributes, after appending it to `current` if given.
+    fn convert(
+        &mut self,
+        var: &Variable,
+        current: Option<String>,
+        value: &str,
+    ) -> Result<String, String> {
+        let value = match (var.attributes.integer, current) {
+            (true, current) => {
+                let current = match current {
+                    Some(current) => arith::evaluate(self, &current)?,
+                    None => 0,
+                };
+                current
+                    .wrapping_add(arith::evaluate(self, value)?)
+                    .to_string()
+            }
+            (false, Some(current)) => current + value,
+            (false, None) => value.to_string(),
+        };
+        Ok(var.convert_case(value))
+    }
+
+    /// in_function returns whether a function is running.
+    pub(crate) fn in_function(&self) -> bool {
+        !self.local_scopes.is_empty()
+    }
+
+    /// make_local makes `name` local to the running function, so that its previous
+    /// value is restored when the function returns.
+    pub(crate) fn make_local(&mut self, name: &str) -> Result<(), String> {
+        let saved = self.saved_var(name);
+        let scope = self
+            .local_scopes
+            .last_mut()
+            .ok_or("can only be used in a function")?;
+        scope.entry(name.to_string()).or_insert(saved);
+        Ok(())
+    }
+
+    /// restore_var puts back a variable saved with [`Shell::saved_var`].
+    fn restore_var(&mut self, name: &str, saved: Option<Variable>) {
+        self.vars.remove(name);
+        if let Some(var) = saved {
+            self.vars.insert(name.to_string(), var);
+        }
+    }
+
+    fn saved_var(&self, name: &str) -> Option<Variable> {
+        self.vars.get(name).cloned()
+    }
+
+    /// set_temporary_vars sets and exports variables for the duration of one command,
+    /// returning what is needed to restore them with [`Shell::restore_vars`].
+    pub(crate) fn set_temporary_vars(
+        &mut self,
+        vars: &[(String, String)],
+    ) -> Vec<(String, Option<Variable>)> {
+        let mut saved = Vec::new();
+        for (name, value) in vars {
+            saved.push((name.clone(), self.saved_var(name)));
+            self.vars.insert(name.clone(), Variable::exported(value));
+        }
+        saved
+    }
+
+    pub(crate) fn restore_vars(&mut self, saved: Vec<(String, Option<Variable>)>) {
+        for (name, var) in saved.into_iter().rev() {
+            self.restore_var(&name, var);
+        }
+    }
+
+    pub(crate) fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// set_positional replaces the positional parameters, returning the old ones.
+    pub(crate) fn set_positional(&mut self, params: Vec<String>) -> Vec<String> {
+        std::mem::replace(&mut self.positional, params)
+    }
+
+    /// special_param returns the value of `$0`-`$9`, `$?`, `$#`, `$$`, `$!` and `$-`.
+    pub(crate) fn special_param(&self, name: char) -> String {
+        match name {
+            '0' => env::args().next().unwrap_or_default(),
+            '1'..='9' => {
+                let n = name.to_digit(10).unwrap_or_default() as usize;
+                self.positional.get(n - 1).cloned().unwrap_or_default()
+            }
+            '?' => self.last_status.to_string(),
+            '#' => self.positional.len().to_string(),
+            '$' => process::id().to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// job_count returns the number of jobs. There is no job control, so there are
+    /// never any.
+    pub(crate) fn job_count(&self) -> usize {
+        0
+    }
+
+    pub(crate) fn function_names(&self) -> Vec<String> {
+        self.functions.keys().cloned().collect()
+    }
+
+    pub(crate) fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    pub(crate) fn remove_function(&mut self, name: &str) {
+        self.functions.remove(name);
+    }
+
+    /// find_command returns where the command is in `$PATH`, remembering it for next time.
+    pub(crate) fn find_command(&mut self, name: &str) -> Option<PathBuf> {
+        let path = self.var("PATH").unwrap_or_default().to_string();
+        self.hash.lookup(&path, name)
+    }
+
+    /// hashed_command returns where the command was remembered to be in `$PATH`, if it is.
+    pub(crate) fn hashed_command(&mut self, name: &str) -> Option<PathBuf> {
+        let path = self.var("PATH").unwrap_or_default().to_string();
+        self.hash.get(&path, name)
+    }
+
+    /// executables returns the names of all files in `$PATH`.
+    pub(crate) fn executables(&mut self) -> BTreeSet<String> {
+        let path = self.var("PATH").unwrap_or_default().to_string();
+        self.hash.executables(&path)
+    }
+
+    /// current_dir returns the logical current directory, i.e. `$PWD` with the symlinks
+    /// that were followed to get there, falling back to the physical one.
+    pub(crate) fn current_dir(&self) -> String {
+        match self.var("PWD") {
+            Some(pwd) if pwd.starts_with('/') && is_same_file(pwd, ".") => pwd.to_string(),
+            _ => physical_dir(),
+        }
+    }
+
+    /// change_dir changes the current directory and updates `PWD`, `OLDPWD` and
+    /// `DIRSTACK`. Unless `physical` is set, `..` removes the last component of the
+    /// logical path instead of going to the parent of the directory a symlink points to.
+    pub(crate) fn change_dir(&mut self, dir: &str, physical: bool) -> Result<(), String> {
+        let old = self.current_dir();
+        let target = match dir.starts_with('/') {
+            true => normalize_path(dir),
+            false => normalize_path(&format!("{old}/{dir}")),
+        };
+        // The logical path may not exist, e.g. if a directory on it was renamed, in which
+        // case the path is resolved physically instead
+        let physical = physical || env::set_current_dir(&target).is_err();
+        if physical {
+            env::set_current_dir(dir).map_err(|e| format!("{dir}: {}", util::error_message(&e)))?;
+        }
+
+        let pwd = match physical {
+            true => physical_dir(),
+            false => target,
+        };
+        self.set_exported_var("OLDPWD", &old);
+        self.set_exported_var("PWD", &pwd);
+        self.update_dir_stack();
+        Ok(())
+    }
+
+    /// update_dir_stack sets the `DIRSTACK` array to the current directory and the
+    /// saved ones.
+    pub(crate) fn update_dir_stack(&mut self) {
+        let entries: Vec<String> = dirstack::entries(self)
+            .iter()
+            .map(|dir| builtin::Command::replace_home_with_tilde(self, dir))
+            .collect();
+        self.set_array("DIRSTACK", entries);
+    }
+
+    /// run parses and executes the input, reporting syntax errors on stderr.
+    pub(crate) fn run(&mut self, input: &str) -> anyhow::Result<i32> {
+        let parsed = parser::parse(input, &self.aliases);
+        self.execute_parsed(parsed)
+    }
+
+    fn execute_parsed(&mut self, parsed: Result<List, ParseError>) -> anyhow::Result<i32> {
+        match parsed {
+            Ok(list) => self.execute_list(&list),
+            Err(e) => {
+                write_and_flush_str(&mut self.stderr(), &e.to_string())?;
+                self.last_status = 2;
+                Ok(2)
+            }
+        }
+    }
+
+    /// run_source runs the input one complete command at a time, so that aliases defined
+    /// on a line apply to the lines after it, and tracks the line each command starts on.
+    pub(crate) fn run_source(&mut self, name: &str, input: &str) -> anyhow::Result<i32> {
+        let outer = self.location.take();
+        let mut status = 0;
+        let mut source = parser::Source::new(input);
+        while let Some((line, parsed)) = source.next_command(&self.aliases) {
+            self.location = Some(Location {
+                source: name.to_string(),
+                line,
+            });
+            match self.execute_parsed(parsed) {
+                Ok(s) => status = s,
+                Err(e) => {
+                    self.location = outer;
+                    return Err(e);
+                }
+            }
+            if self.control.is_some() {
+                break;
+            }
+        }
+        self.location = outer;
+        Ok(status)
+    }
+
+    /// stderr returns the error output, which starts every line with the location of
+    /// the running command in the sourced file, if any.
+    pub(crate) fn stderr(&self) -> util::PrefixedWriter<io::Stderr> {
+        let prefix = match &self.location {
+            Some(location) => format!("{}: line {}: ", location.source, location.line),
+            None => String::new(),
+        };
+        util::PrefixedWriter::new(io::stderr(), prefix)
+    }
+
+    /// fork forks a copy of the shell, returning 0 in the copy and its pid in the
+    /// shell.
+    fn fork(&mut self) -> io::Result<i32> {
+        let pid = util::fork()?;
+        if pid == 0 {
+            self.forked = true;
+        }
+        Ok(pid)
+    }
+
+    /// exit terminates the shell, or the forked copy of it, with the status.
+    pub(crate) fn exit(&self, status: i32) -> ! {
+        match self.forked {
+            true => util::exit_child(status),
+            false => process::exit(status),
+        }
+    }
+
+    /// capture runs the input in a forked copy of the shell and returns its output
+    /// with trailing newlines removed, as command substitution does.
+    pub(crate) fn capture(&mut self, input: &str) -> Result<String, String> {
+        let (read_end, write_end) = util::pipe().map_err(|e| format!("failed to pipe: {e}"))?;
+        let pid = self.fork().map_err(|e| format!("failed to fork: {e}"))?;
+
+        if pid == 0 {
+            drop(read_end);
+            let mut saved = SavedFds::new();
+            let status = match saved.redirect(1, write_end.as_raw_fd()) {
+                Ok(()) => self.run(input).unwrap_or(1),
+                Err(_) => 1,
+            };
+            util::exit_child(status);
+        }
+
+        drop(write_end);
+        let mut output = Vec::new();
+        let read_result = File::from(read_end).read_to_end(&mut output);
+        self.last_status = util::wait_for(pid).map_err(|e| format!("failed to wait: {e}"))?;
+        self.substitution_status = Some(self.last_status);
+        read_result.map_err(|e| format!("failed to read command output: {e}"))?;
+
+        let mut output = String::from_utf8_lossy(&output).to_string();
+        while output.ends_with('\n') {
+            output.pop();
+        }
+        Ok(output)
+    }
+
+    /// process_substitution runs the input in a forked copy of the shell for `<(input)`,
+    /// or `>(input)` if `output`, returning the path of a pipe which the input's output
+    /// can be read from, or its input written to. The pipe is `/dev/fd/N`, or a named
+    /// pipe on systems without `/dev/fd`.
+    pub(crate) fn process_substitution(
+        &mut self,
+        input: &str,
+        output: bool,
+    ) -> Result<String, String> {
+        // The child reads from the pipe for `>(input)` and writes to it for `<(input)`
+        let child_fd = if output { 0 } else { 1 };
+        if !util::has_dev_fd() {
+            return self.fifo_substitution(input, output, child_fd);
+        }
+
+        let (read_end, write_end) = util::pipe().map_err(|e| format!("failed to pipe: {e}"))?;
+        let (ours, theirs) = if output {
+            (write_end, read_end)
+        } else {
+            (read_end, write_end)
+        };
+        let pid = self.fork().map_err(|e| format!("failed to fork: {e}"))?;
+        if pid == 0 {
+            drop(ours);
+            // Pipes of other substitutions must not be kept open by this one
+            self.process_substitutions.clear();
+            let mut saved = SavedFds::new();
+            let status = match saved.redirect(child_fd, theirs.as_raw_fd()) {
+                Ok(()) => self.run(input).unwrap_or(1),
+                Err(_) => 1,
+            };
+            util::exit_child(status);
+        }
+        drop(theirs);
+
+        // Like bash, keep the fd high so that redirections of the command don't replace it
+        let fd = util::dup_inheritable(ours.as_raw_fd(), 60);
+        let path = match &fd {
+            Ok(fd) => Ok(format!("/dev/fd/{}", fd.as_raw_fd())),
+            Err(e) => Err(format!("failed to duplicate fd: {e}")),
+        };
+        self.process_substitutions.push(ProcessSubstitution {
+            pid,
+            fd: fd.ok(),
+            fifo: None,
+        });
+        path
+    }
+
+    /// fifo_substitution is [`Shell::process_substitution`] with a named pipe.
+    fn fifo_substitution(
+        &mut self,
+        input: &str,
+        output: bool,
+        child_fd: i32,
+    ) -> Result<String, String> {
+        // The fifo is made in a directory of its own so that other users can't replace it
+        let dir = util::mkdtemp("sh-procsub-")
+            .map_err(|e| format!("failed to make fifo directory: {e}"))?;
+        let path = dir.join("fifo");
+        let pid = match util::mkfifo(&path) {
+            Ok(()) => self.fork().map_err(|e| format!("failed to fork: {e}")),
+            Err(e) => Err(format!("failed to make fifo: {e}")),
+        };
+        let pid = pid.inspect_err(|_| {
+            let _ = fs::remove_dir_all(&dir);
+        })?;
+        if pid == 0 {
+            self.process_substitutions.clear();
+            // Opening blocks until the command opens the other end
+            let status = match File::options().read(output).write(!output).open(&path) {
+                Ok(fifo) => {
+                    let mut saved = SavedFds::new();
+                    match saved.redirect(child_fd, fifo.as_raw_fd()) {
+                        Ok(()) => {
+                            drop(fifo);
+                            self.run(input).unwrap_or(1)
+                        }
+                        Err(_) => 1,
+                    }
+                }
+                Err(_) => 1,
+            };
+            util::exit_child(status);
+        }
+
+        let display = path.display().to_string();
+        self.process_substitutions.push(ProcessSubstitution {
+            pid,
+            fd: None,
+            fifo: Some((path, !output)),
+        });
+        Ok(display)
+    }
+
+    /// close_process_substitutions closes the pipes of the process substitutions after
+    /// the first `keep`, and waits for their processes to finish.
+    fn close_process_substitutions(&mut self, keep: usize) {
+        if keep >= self.process_substitutions.len() {
+            return;
+        }
+        for substitution in self.process_substitutions.split_off(keep) {
+            drop(substitution.fd);
+            if let Some((path, child_writes)) = &substitution.fifo {
+                // Open the other end in case the command didn't, so that the process
+                // isn't stuck opening its own
+                let _ = File::options()
+                    .read(*child_writes)
+                    .write(!child_writes)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(path);
+                let _ = fs::remove_file(path);
+                if let Some(dir) = path.parent() {
+                    let _ = fs::remove_dir(dir);
+                }
+            }
+            let _ = util::wait_for(substitution.pid);
+        }
+    }
+
+    /// run_script runs a file the kernel refused to execute in a forked copy of the
+    /// shell, with the arguments as positional parameters, as `sh` does.
+    pub(crate) fn run_script(&mut self, path: &Path, args: Vec<String>) -> anyhow::Result<i32> {
+        let pid = self.fork().context("failed to fork")?;
+        if pid == 0 {
+            self.set_positional(args);
+            let status = self.run_file(path).unwrap_or(1);
+            util::exit_child(status);
+        }
+        util::wait_for(pid).context("failed to wait for script")
+    }
+
+    /// run_file runs the commands in the file. Binary files are refused.
+    pub(crate) fn run_file(&mut self, path: &Path) -> anyhow::Result<i32> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                let e = format!("{}: {}", path.display(), util::error_message(&e));
+                return self.report_error(&e).map(|_| 126);
+            }
+        };
+        // Like `sh`, only a NUL in the first line is taken as a sign of a binary file
+        let first_line = contents.split(|b| *b == b'\n').next().unwrap_or_default();
+        if first_line.contains(&0) {
+            let e = format!("{}: cannot execute binary file", path.display());
+            return self.report_error(&e).map(|_| 126);
+        }
+
+        let name = path.display().to_string();
+        self.run_source(&name, &String::from_utf8_lossy(&contents))
+    }
+
+    pub(crate) fn execute_list(&mut self, list: &List) -> anyhow::Result<i32> {
+        let mut status = 0;
+        for and_or in &list.0 {
+            status = self.execute_and_or(and_or)?;
+            if self.control.is_some() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    fn execute_and_or(&mut self, and_or: &AndOr) -> anyhow::Result<i32> {
+        let mut status = self.execute_command(&and_or.first)?;
+        for (connector, command) in &and_or.rest {
+            if self.control.is_some() {
+                break;
+            }
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                status = self.execute_command(command)?;
+            }
+        }
+        Ok(status)
+    }
+
+    fn execute_command(&mut self, command: &Command) -> anyhow::Result<i32> {
+        let substitutions = self.process_substitutions.len();
+        let result = match command {
+            Command::Simple(simple) => self.execute_simple(simple),
+            Command::FunctionDef { name, body } => {
+                self.functions.insert(name.clone(), Rc::clone(body));
+                Ok(0)
+            }
+            Command::Compound(compound, redirects) => self.execute_compound(compound, redirects),
+            Command::Pipeline(commands) => self.execute_pipeline(commands),
+        };
+        // The substitutions are closed even if the command failed, so that their
+        // processes don't linger
+        self.close_process_substitutions(substitutions);
+        let status = result?;
+        self.last_status = status;
+        Ok(status)
+    }
+
+    fn execute_compound(
+        &mut self,
+        compound: &Compound,
+        redirects: &[Redirect],
+    ) -> anyhow::Result<i32> {
+        let _saved_fds = match self.apply_redirects(redirects) {
+            Ok(saved) => saved,
+            Err(e) => return self.report_error(&e),
+        };
+        match compound {
+            Compound::If(clause) => self.execute_if(clause),
+            Compound::Loop(lp) => self.execute_loop(lp),
+            Compound::Conditional(expr) => match cond::evaluate_conditional(self, expr) {
+                Ok(result) => Ok(i32::from(!result)),
+                Err(e) => {
+                    write_and_flush_str(&mut self.stderr(), &e)?;
+                    Ok(2)
+                }
+            },
+            Compound::Arithmetic(expr) => {
+                match expand::expand_string(self, expr)
+                    .and_then(|expr| arith::evaluate(self, &expr))
+                {
+                    Ok(value) => Ok(i32::from(value == 0)),
+                    Err(e) => self.report_error(&format!("((: {e}")),
+                }
+            }
+            Compound::Subshell(list) => self.execute_subshell(list),
+            Compound::Group(list) => self.execute_list(list),
+        }
+    }
+
+    /// execute_if runs the body of the first condition that succeeds, or the `else` body
+    /// if none does. Its status is that of the body run, or 0 if none was.
+    fn execute_if(&mut self, clause: &If) -> anyhow::Result<i32> {
+        for (condition, body) in &clause.branches {
+            let status = self.execute_list(condition)?;
+            if self.control.is_some() {
+                return Ok(status);
+            }
+            if status == 0 {
+                return self.execute_list(body);
+            }
+        }
+        match &clause.else_body {
+            Some(body) => self.execute_list(body),
+            None => Ok(0),
+        }
+    }
+
+    /// execute_subshell runs the list in a forked copy of the shell, so that it can't
+    /// change the shell's variables, directory and the like.
+    fn execute_subshell(&mut self, list: &List) -> anyhow::Result<i32> {
+        let pid = self.fork().context("failed to fork")?;
+        if pid == 0 {
+            let status = self.execute_list(list).unwrap_or(1);
+            util::exit_child(status);
+        }
+        util::wait_for(pid).context("failed to wait for subshell")
+    }
+
+    /// execute_pipeline runs every command in a forked copy of the shell, with its
+    /// output going to the input of the next command through a pipe. Its status is
+    /// that of the last command.
+    fn execute_pipeline(&mut self, commands: &[Command]) -> anyhow::Result<i32> {
+        let mut pids = Vec::new();
+        // The read end of the pipe from the previous command
+        let mut input: Option<OwnedFd> = None;
+        for (idx, command) in commands.iter().enumerate() {
+            let (read_end, write_end) = match idx + 1 < commands.len() {
+                true => {
+                    let (read_end, write_end) = util::pipe().context("failed to pipe")?;
+                    (Some(read_end), Some(write_end))
+                }
+                false => (None, None),
+            };
+            let pid = self.fork().context("failed to fork")?;
+            if pid == 0 {
+                drop(read_end);
+                let mut saved = SavedFds::new();
+                let redirected = [(0, &input), (1, &write_end)]
+                    .into_iter()
+                    .all(|(fd, target)| match target {
+                        Some(target) => saved.redirect(fd, target.as_raw_fd()).is_ok(),
+                        None => true,
+                    });
+                let status = match redirected {
+                    true => self.execute_command(command).unwrap_or(1),
+                    false => 1,
+                };
+                util::exit_child(status);
+            }
+            pids.push(pid);
+            input = read_end;
+        }
+        drop(input);
+
+        let mut status = 0;
+        for pid in pids {
+            status = util::wait_for(pid).context("failed to wait for pipeline")?;
+        }
+        Ok(status)
+    }
+
+    /// execute_loop runs the body for as long as the condition succeeds, or fails with
+    /// `until`. Its status is that of the last body run, or 0 if none was.
+    fn execute_loop(&mut self, lp: &Loop) -> anyhow::Result<i32> {
+        let mut status = 0;
+        loop {
+            let condition = self.execute_list(&lp.condition)?;
+            if self.control.is_some() || (condition == 0) == lp.until {
+                break;
+            }
+            status = self.execute_list(&lp.body)?;
+            if self.control.is_some() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    fn execute_simple(&mut self, cmd: &SimpleCommand) -> anyhow::Result<i32> {
+        // Status of the last command substitution, if any, becomes the status of
+        // commands without a command name.
+        self.substitution_status = None;
+        let words = match self.expand_command_words(&cmd.words) {
+            Ok(words) => words,
+            Err(e) => return self.report_error(&e),
+        };
+        // Plain assignments before a command only apply to it
+        let mut assignments = Vec::new();
+        for assignment in &cmd.assignments {
+            let assigned = match assignment {
+                Assignment {
+                    name,
+                    subscript: None,
+                    append: false,
+                    value: AssignedValue::Scalar(value),
+                } if !words.is_empty() => expand::expand_assignment(self, value)
+                    .map(|value| assignments.push((name.clone(), value))),
+                _ => self.execute_assignment(assignment),
+            };
+            if let Err(e) = assigned {
+                return self.report_error(&e);
+            }
+        }
+
+        let saved_fds = match self.apply_redirects(&cmd.redirects) {
+            Ok(saved) => saved,
+            Err(e) => return self.report_error(&e),
+        };
+        // `exec` without a command makes its redirections permanent
+        if words.len() == 1 && words[0] == "exec" {
+            saved_fds.keep();
+            return Ok(0);
+        }
+
+        let Some((name, args)) = words.split_first() else {
+            return Ok(self.substitution_status.unwrap_or_default());
+        };
+
+        let saved_vars = self.set_temporary_vars(&assignments);
+        let args: Vec<String> = args.to_vec();
+        let status = self.execute_name(name, args);
+        self.restore_vars(saved_vars);
+        status
+    }
+
+    /// expand_command_words expands the words of a simple command. The arguments of
+    /// `declare`, `typeset` and `local` that are assignments are left for the builtin
+    /// to expand like assignments, so that they aren't split into fields.
+    fn expand_command_words(&mut self, words: &[String]) -> Result<Vec<String>, String> {
+        let declaration = matches!(
+            words.first().map(String::as_str),
+            Some("declare" | "typeset" | "local")
+        );
+        if !declaration {
+            return expand::expand_words(self, words);
+        }
+        let mut expanded = Vec::new();
+        for word in words {
+            if let Ok(Some(_)) = parser::parse_assignment(word) {
+                expanded.push(word.clone());
+                continue;
+            }
+            // Fields that only look like assignments once expanded have their value
+            // quoted, so that the builtin doesn't expand it again
+            for field in expand::expand_word(self, word)? {
+                let value = match parser::split_subscript(&field) {
+                    Some((_, _, rest)) => {
+                        rest.strip_prefix("+=").or_else(|| rest.strip_prefix('='))
+                    }
+                    None => None,
+                };
+                match value {
+                    Some(value) => {
+                        let name = &field[..field.len() - value.len()];
+                        expanded.push(format!("{name}{}", util::quote(value)));
+                    }
+                    None => expanded.push(field),
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// execute_assignment expands the value of the assignment and assigns it.
+    pub(crate) fn execute_assignment(&mut self, assignment: &Assignment) -> Result<(), String> {
+        let subscript = match &assignment.subscript {
+            Some(subscript) => Some(expand::expand_string(self, subscript)?),
+            None => None,
+        };
+        match &assignment.value {
+            AssignedValue::Scalar(value) => {
+                let value = expand::expand_assignment(self, value)?;
+                self.assign(
+                    &assignment.name,
+                    subscript.as_deref(),
+                    &value,
+                    assignment.append,
+                )
+            }
+            AssignedValue::Array(words) => {
+                let elements = expand::expand_array(self, words)?;
+                self.assign_array(&assignment.name, elements, assignment.append)
+            }
+        }
+    }
+
+    /// execute_name runs a function, builtin or executable with the arguments.
+    pub(crate) fn execute_name(&mut self, name: &str, args: Vec<String>) -> anyhow::Result<i32> {
+        if let Some(body) = self.functions.get(name) {
+            let body = Rc::clone(body);
+            return self.call_function(&body, args);
+        }
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        builtin::Command::parse(name).execute(
+            self,
+            &mut Output::new(io::stdout(), self.stderr()),
+            &args,
+        )
+    }
+
+    /// call_function runs a function body with the arguments as positional parameters.
+    pub(crate) fn call_function(&mut self, body: &List, args: Vec<String>) -> anyhow::Result<i32> {
+        let saved_positional = self.set_positional(args);
+        self.local_scopes.push(HashMap::new());
+
+        let status = self.execute_list(body);
+        if self.control == Some(Control::Return) {
+            self.control = None;
+        }
+
+        if let Some(scope) = self.local_scopes.pop() {
+            for (name, var) in scope {
+                self.restore_var(&name, var);
+            }
+        }
+        self.set_positional(saved_positional);
+        status
+    }
+
+    fn report_error(&mut self, e: &str) -> anyhow::Result<i32> {
+        write_and_flush_str(&mut self.stderr(), e)?;
+        Ok(1)
+    }
+
+    /// apply_redirects points the fds of the shell at the redirect targets, returning
+    /// the original fds which are restored once dropped.
+    fn apply_redirects(&mut self, redirects: &[Redirect]) -> Result<SavedFds, String> {
+        let mut saved = SavedFds::new();
+        for redirect in redirects {
+            let target = expand::expand_redirect_target(self, &redirect.target)?;
+            let file = match redirect.kind {
+                RedirectKind::Out | RedirectKind::OutErr => File::create(&target)
+                    .map_err(|e| format!("failed to create file {target}: {e}"))?,
+                RedirectKind::Append | RedirectKind::AppendOutErr => fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&target)
+                    .map_err(|e| format!("failed to open file {target}: {e}"))?,
+                RedirectKind::In => {
+                    File::open(&target).map_err(|e| format!("failed to open file {target}: {e}"))?
+                }
+                RedirectKind::DupOut | RedirectKind::DupIn => {
+                    if target == "-" {
+                        saved.close(redirect.fd);
+                        continue;
+                    }
+                    let fd = target
+                        .parse::<i32>()
+                        .map_err(|_| format!("{target}: ambiguous redirect"))?;
+                    saved
+                        .redirect(redirect.fd, fd)
+                        .map_err(|e| format!("{fd}: {e}"))?;
+                    continue;
+                }
+            };
+
+            let fds: &[i32] = match redirect.kind {
+                RedirectKind::OutErr | RedirectKind::AppendOutErr => &[1, 2],
+                _ => &[redirect.fd],
+            };
+            for &fd in fds {
+                saved
+                    .redirect(fd, file.as_raw_fd())
+                    .map_err(|e| format!("failed to redirect to {target}: {e}"))?;
+            }
+        }
+        Ok(saved)
+    }
+}
+
+thread_local! {
+    /// The shell whose line is being read, and the prompt it's read with. Key handlers
+    /// have to be `Send + Sync`, so they can't hold the shell, but they're called on the
+    /// thread that reads the line.
+    static EDITOR_SHELL: RefCell<(Weak<RefCell<Shell>>, String)> =
+        const { RefCell::new((Weak::new(), String::new())) };
+}
+
+/// set_editor_shell makes `shell` and `prompt` the ones that key handlers get with
+/// [`with_editor_shell`].
+pub(crate) fn set_editor_shell(shell: &Rc<RefCell<Shell>>, prompt: &str) {
+    EDITOR_SHELL.with(|editor_shell| {
+        *editor_shell.borrow_mut() = (Rc::downgrade(shell), prompt.to_string());
+    });
+}
+
+/// with_editor_shell calls `f` with the shell whose line is being read and the prompt.
+/// Returns none if there's no such shell or it's already in use.
+pub(crate) fn with_editor_shell<T>(f: impl FnOnce(&mut Shell, &str) -> T) -> Option<T> {
+    EDITOR_SHELL.with(|editor_shell| {
+        let (shell, prompt) = &*editor_shell.borrow();
+        let shell = shell.upgrade()?;
+        let mut shell = shell.try_borrow_mut().ok()?;
+        Some(f(&mut shell, prompt))
+    })
+}
+
+/// physical_dir returns the current directory with all symlinks resolved.
+fn physical_dir() -> String {
+    env::current_dir()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn is_same_file(a: &str, b: &str) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// normalize_path removes `.` and empty components from an absolute path, and
+/// resolves `..` by removing the previous component without looking at the file system.
+fn normalize_path(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod shell_test {
+    use std::{
+        fs,
+        os::unix::fs::{self as unix_fs, PermissionsExt as _},
+        path::Path,
+    };
+
+    use crate::{
+        shell::{normalize_path, Shell},
+        util::{self, TempDir},
+    };
+
+    fn run(shell: &mut Shell, input: &str) -> String {
+        shell.capture(input).unwrap()
+    }
+
+    #[test]
+    fn test_assignment_and_expansion() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "FOO='a b'; echo $FOO-\"$FOO\""), "a b-a b");
+    }
+
+    #[test]
+    fn test_and_or() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(
+                &mut shell,
+                "nonexistentcmd 2>/dev/null && echo yes || echo no"
+            ),
+            "no"
+        );
+        assert_eq!(run(&mut shell, "echo a && echo b"), "a\nb");
+    }
+
+    #[test]
+    fn test_set() {
+        let mut shell = Shell::new();
+        shell.run("set -o vi").unwrap();
+        assert_eq!(
+            run(&mut shell, "set -o"),
+            "emacs          \toff\nvi             \ton"
+        );
+        shell.run("set +o vi").unwrap();
+        assert_eq!(run(&mut shell, "set +o"), "set -o emacs\nset +o vi");
+        assert_eq!(
+            run(&mut shell, "set -o bogus 2>&1; echo $?"),
+            "set: bogus: invalid option name\n1"
+        );
+        assert_eq!(run(&mut shell, "set -e 2>/dev/null; echo $?"), "2");
+    }
+
+    #[test]
+    fn test_echo_and_printf() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(&mut shell, r"echo -n a; echo -e 'b\tc' -E; echo -nex 'd\c'"),
+            "ab\tc -E\n-nex d\\c"
+        );
+        assert_eq!(run(&mut shell, r"echo -ne 'x\cy' z; echo"), "x");
+        shell.run("printf -v out '%s=%03d,' a 1 b").unwrap();
+        assert_eq!(shell.var("out"), Some("a=001,b=000,"));
+    }
+
+    #[test]
+    fn test_if() {
+        let mut shell = Shell::new();
+        let script = |n: i32| {
+            format!(
+                "if [ {n} -lt 0 ]; then echo negative
+                elif [[ {n} == 0 ]]; then echo zero; else echo positive; echo $?; fi"
+            )
+        };
+        assert_eq!(run(&mut shell, &script(-1)), "negative");
+        assert_eq!(run(&mut shell, &script(0)), "zero");
+        assert_eq!(run(&mut shell, &script(1)), "positive\n0");
+        assert_eq!(run(&mut shell, "if false; then :; fi; echo $?"), "0");
+        assert_eq!(
+            run(&mut shell, "if false; then :; else false; fi; echo $?"),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(&mut shell, "x=4; echo $((x * 2)) \"$(( 16#f + $x ))\""),
+            "8 19"
+        );
+        assert_eq!(
+            run(&mut shell, "x=4; ((x++)); ((x > 9)); echo $? $x"),
+            "1 5"
+        );
+        assert_eq!(
+            run(&mut shell, "let 'y = 5 << 1' z=y/2; echo $? $y $z"),
+            "0 10 5"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "i=0; while ((i < 3)); do echo -n $i; ((i++)); done"
+            ),
+            "012"
+        );
+        assert_eq!(
+            run(&mut shell, "((1 / 0)) 2>&1; echo $?"),
+            "((: 1 / 0: division by 0 (error token is \"0\")\n1"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "a=(1 2); ((a[1] += 5, a[2] = a[0] + 1)); echo ${a[@]}"
+            ),
+            "1 7 2"
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut shell = Shell::new();
+        shell
+            .run("arr=(a 'b c' d); arr[5]=e; arr+=(f); declare -A m=([k]=v [other key]='x y')")
+            .unwrap();
+        assert_eq!(
+            run(
+                &mut shell,
+                "printf '[%s]' \"${arr[@]}\"; echo ${#arr[@]} $arr"
+            ),
+            "[a][b c][d][e][f]5 a"
+        );
+        assert_eq!(
+            run(&mut shell, "echo ${!arr[@]}; IFS=-; echo \"${arr[*]}\""),
+            "0 1 2 5 6\na-b c-d-e-f"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "k=other; echo ${m[k]} \"${m[$k key]}\" ${!m[@]}"
+            ),
+            "v x y k other key"
+        );
+        assert_eq!(
+            run(&mut shell, "[[ -v arr[5] && ! -v arr[4] ]]; echo $?"),
+            "0"
+        );
+        assert_eq!(
+            run(
+                &mut shell,
+                "cd /tmp; pushd / > /dev/null; echo ${DIRSTACK[@]}"
+            ),
+            "/ /tmp"
+        );
+    }
+
+    #[test]
+    fn test_subshells_and_groups() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(
+                &mut shell,
+                "cd /tmp; x=1; (cd /; x=2); echo $PWD $x; { x=3; cd /; }; echo $PWD $x"
+            ),
+            "/tmp 1\n/ 3"
+        );
+        assert_eq!(
+            run(&mut shell, "(exit 3); echo $?; echo $( (echo x) )"),
+            "3\nx"
+        );
+        // exit only ends the forked copy of the shell it runs in
+        assert_eq!(
+            run(&mut shell, "x=$(exit 4); echo $?; echo a | exit 5; echo $?"),
+            "4\n5"
+        );
+
+        let dir = TempDir::new("group_test");
+        let out = dir.join("out");
+        let input = format!(
+            "{{ echo a; echo b >&2; }} > {0} 2>&1; (echo c) >> {0}; cat {0}",
+            out.display()
+        );
+        assert_eq!(run(&mut shell, &input), "a\nb\nc");
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(&mut shell, "printf '3\\n1\\n2\\n' | sort | head -n 2"),
+            "1\n2"
+        );
+        assert_eq!(run(&mut shell, "{ echo a; echo b; } | tr a-z A-Z"), "A\nB");
+        assert_eq!(
+            run(
+                &mut shell,
+                "f() { echo $1; }; f x | cat; echo y | read v; echo [$v]"
+            ),
+            "x\n[]"
+        );
+        assert_eq!(
+            run(&mut shell, "true | false; echo $?; false | true; echo $?"),
+            "1\n0"
+        );
+    }
+
+    #[test]
+    fn test_process_substitution() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "cat <(echo a) <(echo b)"), "a\nb");
+        assert_eq!(
+            run(&mut shell, "diff <(printf '1\\n2\\n') <(seq 2); echo $?"),
+            "0"
+        );
+        assert_eq!(
+            run(&mut shell, "while read l; do echo [$l]; done < <(echo z)"),
+            "[z]"
+        );
+        assert_eq!(run(&mut shell, "{ echo x > >(tr x y); } | cat"), "y");
+        assert_eq!(run(&mut shell, "echo \"<(a)\" '>(b)'"), "<(a) >(b)");
+        if util::has_dev_fd() {
+            let paths = run(&mut shell, "echo <(true) >(true)");
+            assert!(
+                paths.split(' ').all(|path| path.starts_with("/dev/fd/")),
+                "{paths}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fifo_substitution() {
+        let mut shell = Shell::new();
+        let path = shell.fifo_substitution("echo a", false, 1).unwrap();
+        assert_eq!(run(&mut shell, &format!("cat {path}")), "a");
+        shell.close_process_substitutions(0);
+        // The fifo is removed along with its private directory
+        let dir = Path::new(&path).parent().unwrap().to_path_buf();
+        assert!(!dir.exists(), "{}", dir.display());
+
+        let tmp = TempDir::new("fifo_test");
+        let out = tmp.join("out");
+        let input = format!("cat > {}", out.display());
+        let path = shell.fifo_substitution(&input, true, 0).unwrap();
+        run(&mut shell, &format!("echo b > {path}"));
+        shell.close_process_substitutions(0);
+        assert_eq!(fs::read_to_string(out).unwrap(), "b\n");
+
+        // A substitution that isn't opened doesn't keep the shell waiting
+        shell.fifo_substitution("echo c", false, 1).unwrap();
+        shell.close_process_substitutions(0);
+    }
+
+    #[test]
+    fn test_temporary_assignment() {
+        let mut shell = Shell::new();
+        shell.run("FOO=outer").unwrap();
+        assert_eq!(run(&mut shell, "FOO=inner printenv FOO"), "inner");
+        assert_eq!(shell.var("FOO"), Some("outer"));
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut shell = Shell::new();
+        shell
+            .run("greet() { local who=$1; echo hello $who; return 3; echo unreachable; }")
+            .unwrap();
+        assert_eq!(run(&mut shell, "greet world; echo $?"), "hello world\n3");
+        assert_eq!(shell.var("who"), None);
+    }
+
+    #[test]
+    fn test_command_substitution_is_isolated() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "echo $(FOO=x; echo $FOO)$FOO"), "x");
+        assert_eq!(shell.var("FOO"), None);
+    }
+
+    #[test]
+    fn test_script_without_shebang() {
+        let dir = TempDir::new("shell_test");
+        let script = dir.join("script");
+        fs::write(&script, "echo args: $# $2\nexit 3\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let binary = dir.join("binary");
+        fs::write(&binary, b"\0\x7f").unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut shell = Shell::new();
+        let input = format!("{} a b; echo $?", script.display());
+        assert_eq!(run(&mut shell, &input), "args: 2 b\n3");
+        let input = format!("{} 2>/dev/null; echo $?", binary.display());
+        assert_eq!(run(&mut shell, &input), "126");
+    }
+
+    #[test]
+    fn test_cd() {
+        let dir = TempDir::new("shell_test");
+        let root = dir.canonicalize().unwrap();
+        let root = root.to_str().unwrap();
+        fs::create_dir_all(dir.join("real/sub")).unwrap();
+        unix_fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+
+        // The shell is forked, so changing directory doesn't affect other tests
+        let mut shell = Shell::new();
+        let input = format!("cd {root}/real; cd {root}; cd -; pwd");
+        assert_eq!(run(&mut shell, &input), format!("{root}/real\n{root}/real"));
+        let input = format!("CDPATH=/nonexistent:{root}/real; cd /; cd sub; pwd");
+        assert_eq!(
+            run(&mut shell, &input),
+            format!("{root}/real/sub\n{root}/real/sub")
+        );
+        let input = format!("cd {root}/link/sub; pwd; pwd -P; cd ..; pwd; cd -P {root}/link; pwd");
+        assert_eq!(
+            run(&mut shell, &input),
+            format!("{root}/link/sub\n{root}/real/sub\n{root}/link\n{root}/real")
+        );
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = TempDir::new("shell_test");
+        let file = dir.join("env.sh");
+        fs::write(
+            &file,
+            "SOURCED=$1\ngreet() {\n  echo hi\n}\nreturn 4\nSOURCED=no\n",
+        )
+        .unwrap();
+
+        let mut shell = Shell::new();
+        let input = format!("source {} a b; echo $? $SOURCED $#", file.display());
+        assert_eq!(run(&mut shell, &input), "4 a 0");
+        let input = format!("PATH={}; . env.sh x; greet $SOURCED", dir.display());
+        assert_eq!(run(&mut shell, &input), "hi");
+        assert_eq!(run(&mut shell, ". nonexistent 2>/dev/null; echo $?"), "1");
+
+        // Errors name the sourced file and line, and the lines after them still run
+        let file = dir.join("broken.sh");
+        fs::write(&file, "true\ncd /nonexistent\nAFTER=yes\n").unwrap();
+        let input = format!("source {} 2>&1; echo $AFTER", file.display());
+        assert_eq!(
+            run(&mut shell, &input),
+            format!(
+                "{}: line 2: cd: /nonexistent: No such file or directory\nyes",
+                file.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/tmp/link/.."), "/tmp");
+        assert_eq!(normalize_path("/tmp/./a//b/../c/"), "/tmp/a/c");
+        assert_eq!(normalize_path("/.."), "/");
+    }
+}
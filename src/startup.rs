@@ -0,0 +1,196 @@
+use std::{fs, io, path::PathBuf};
+
+use crate::{bind, expand, shell::Shell, util};
+
+/// File sourced by interactive shells unless `--norc` or `--rcfile` is given.
+const RC_FILE: &str = ".shellrc";
+/// File sourced by login shells unless `--noprofile` is given.
+const PROFILE_FILE: &str = ".shell_profile";
+/// Settings of the line editor, read unless `$INPUTRC` names another file.
+const INPUTRC_FILE: &str = ".inputrc";
+/// Settings of the line editor read if the user has no inputrc file.
+const SYSTEM_INPUTRC_FILE: &str = "/etc/inputrc";
+
+/// Options the shell is invoked with.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Options {
+    /// Started with `-l`, `--login` or a name starting with `-`, as `login` does.
+    pub(crate) login: bool,
+    pub(crate) norc: bool,
+    pub(crate) noprofile: bool,
+    pub(crate) rcfile: Option<String>,
+    /// POSIX mode sources `$ENV` instead of the rc file.
+    pub(crate) posix: bool,
+}
+
+impl Options {
+    /// parse parses the command line, including the name the shell is invoked as.
+    pub(crate) fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let mut options = Self {
+            login: args.next().is_some_and(|name| name.starts_with('-')),
+            ..Self::default()
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-l" | "--login" => options.login = true,
+                // The shell is always interactive
+                "-i" => (),
+                "--norc" => options.norc = true,
+                "--noprofile" => options.noprofile = true,
+                "--posix" => options.posix = true,
+                "--rcfile" => match args.next() {
+                    Some(file) => options.rcfile = Some(file),
+                    None => return Err("--rcfile: option requires an argument".into()),
+                },
+                _ => return Err(format!("{arg}: invalid option")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// load reads the startup files of an interactive shell:
+///  - the inputrc file with the settings of the line editor,
+///  - the profile file for login shells,
+///  - then the file named by `$ENV` in POSIX mode, or the rc file otherwise.
+///
+/// Missing files are skipped and errors in them don't stop the shell from starting.
+pub(crate) fn load(shell: &mut Shell, options: &Options) -> anyhow::Result<()> {
+    let home = shell
+        .var("HOME")
+        .map(str::to_string)
+        .or_else(|| util::user_home_dir(None))
+        .unwrap_or_default();
+
+    // Bindings made in the other files override those in the inputrc file
+    let inputrc = match shell.var("INPUTRC") {
+        Some(file) => PathBuf::from(file),
+        None => {
+            let file = PathBuf::from(&home).join(INPUTRC_FILE);
+            match file.exists() {
+                true => file,
+                false => PathBuf::from(SYSTEM_INPUTRC_FILE),
+            }
+        }
+    };
+    match bind::read_inputrc(shell, &inputrc) {
+        Ok(errors) => {
+            for e in errors {
+                util::write_and_flush_str(&mut io::stderr(), &e)?;
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => {
+            let e = format!("{}: {}", inputrc.display(), util::error_message(&e));
+            util::write_and_flush_str(&mut io::stderr(), &e)?;
+        }
+    }
+
+    if options.login && !options.noprofile {
+        source_if_exists(shell, PathBuf::from(&home).join(PROFILE_FILE))?;
+    }
+
+    if options.posix || shell.var("POSIXLY_CORRECT").is_some() {
+        let env = match shell.var("ENV") {
+            Some(env) => env.to_string(),
+            None => return Ok(()),
+        };
+        match expand::expand_string(shell, &env) {
+            Ok(file) if !file.is_empty() => source_if_exists(shell, PathBuf::from(file))?,
+            Ok(_) => (),
+            Err(e) => util::write_and_flush_str(&mut io::stderr(), &format!("ENV: {e}"))?,
+        }
+    } else if !options.norc {
+        let file = match &options.rcfile {
+            Some(file) => PathBuf::from(file),
+            None => PathBuf::from(&home).join(RC_FILE),
+        };
+        source_if_exists(shell, file)?;
+    }
+    Ok(())
+}
+
+fn source_if_exists(shell: &mut Shell, path: PathBuf) -> anyhow::Result<()> {
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            let e = format!("{}: {}", path.display(), util::error_message(&e));
+            return util::write_and_flush_str(&mut io::stderr(), &e);
+        }
+    };
+    let name = path.display().to_string();
+    shell.run_source(&name, &String::from_utf8_lossy(&contents))?;
+    // A `return` at the top level of the file only stops the file
+    shell.control = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod startup_test {
+    use std::{fs, os::fd::AsRawFd as _};
+
+    use crate::{
+        shell::Shell,
+        startup::{load, Options},
+        util::{self, SavedFds, TempDir},
+    };
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse(&["shell"]), Ok(Options::default()));
+        assert!(parse(&["-shell"]).unwrap().login);
+        assert!(parse(&["shell", "-l"]).unwrap().login);
+
+        let options = parse(&["shell", "--norc", "--noprofile", "--rcfile", "rc"]).unwrap();
+        assert!(options.norc && options.noprofile);
+        assert_eq!(options.rcfile.as_deref(), Some("rc"));
+
+        assert_eq!(
+            parse(&["shell", "--rcfile"]),
+            Err("--rcfile: option requires an argument".to_string())
+        );
+        assert_eq!(
+            parse(&["shell", "--bogus"]),
+            Err("--bogus: invalid option".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rc_file_errors() {
+        let dir = TempDir::new("startup_test");
+        let rc = dir.join("rc");
+        fs::write(&rc, "FIRST=1\ncd /nonexistent\nSECOND=2\n").unwrap();
+        let options = Options {
+            rcfile: Some(rc.display().to_string()),
+            ..Options::default()
+        };
+
+        // The shell is forked so that redirecting stderr doesn't affect other tests
+        let err = dir.join("err");
+        let pid = util::fork().unwrap();
+        if pid == 0 {
+            let mut shell = Shell::new();
+            let file = fs::File::create(&err).unwrap();
+            let mut saved = SavedFds::new();
+            saved.redirect(2, file.as_raw_fd()).unwrap();
+            let status = match load(&mut shell, &options) {
+                Ok(()) if shell.var("SECOND") == Some("2") => 0,
+                _ => 1,
+            };
+            util::exit_child(status);
+        }
+
+        // The error is reported with the line of the file and the lines after it still run
+        assert_eq!(util::wait_for(pid).unwrap(), 0);
+        let err = fs::read_to_string(err).unwrap();
+        let expected = format!("{}: line 2: cd: /nonexistent: ", rc.display());
+        assert!(err.contains(&expected), "{err}");
+    }
+}
@@ -1,10 +1,17 @@
 use std::{
-    fs,
+    env,
+    ffi::{CStr, CString, OsString},
     io::{self, Write as _},
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+        unix::ffi::{OsStrExt as _, OsStringExt as _},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context as _;
-use rustyline::{error::ReadlineError, Editor};
+use rustyline::{error::ReadlineError, Cmd, Editor, Movement};
 
 pub(crate) fn write_and_flush_buf<T: io::Write>(w: &mut T, buf: &[u8]) -> anyhow::Result<()> {
     let mut buf = buf.to_owned();
@@ -14,16 +21,25 @@ pub(crate) fn write_and_flush_buf<T: io::Write>(w: &mut T, buf: &[u8]) -> anyhow
     w.flush().context("failed to flush output")
 }
 
+/// write_and_flush_bytes writes the bytes as they are, without adding a newline.
+pub(crate) fn write_and_flush_bytes<T: io::Write>(w: &mut T, buf: &[u8]) -> anyhow::Result<()> {
+    w.write_all(buf).context("failed to write output")?;
+    w.flush().context("failed to flush output")
+}
+
 pub(crate) fn write_and_flush_str<T: io::Write>(w: &mut T, s: &str) -> anyhow::Result<()> {
     write_and_flush_buf(w, s.as_bytes())
 }
 
-pub(crate) fn prompt_and_readline<H, I>(rl: &mut Editor<H, I>) -> anyhow::Result<Option<String>>
+pub(crate) fn prompt_and_readline<H, I>(
+    rl: &mut Editor<H, I>,
+    prompt: &str,
+) -> anyhow::Result<Option<String>>
 where
     H: rustyline::Helper,
     I: rustyline::history::History,
 {
-    let readline = rl.readline("$ ");
+    let readline = rl.readline(prompt);
     let input = match readline {
         Ok(line) => line,
         Err(ReadlineError::Interrupted) => {
@@ -40,50 +56,393 @@ where
     Ok(Some(input))
 }
 
-pub(crate) fn redirect_to(redirects: &[&str], buf: &[u8]) -> anyhow::Result<()> {
-    for r in redirects {
-        match fs::File::create(r) {
-            Ok(mut file) => {
-                let res = file.write_all(buf);
-                if res.is_err() {
-                    write_and_flush_str(
-                        &mut io::stderr(),
-                        &format!("failed to write to file {r}: {}", res.unwrap_err()),
-                    )?;
-                }
-            }
-            Err(e) => write_and_flush_str(
-                &mut io::stderr(),
-                &format!("failed to create file {r}: {e}"),
-            )?,
-        };
+/// edit_line returns the command that makes the editor turn `line`, with the cursor at
+/// `pos`, into `new_line` with the cursor at `new_pos`. The editor can only leave the
+/// cursor where it is or after text inserted at it, so a line that changes otherwise is
+/// replaced with the cursor at its start.
+pub(crate) fn edit_line(line: &str, pos: usize, new_line: &str, new_pos: usize) -> Cmd {
+    let (before, after) = line.split_at(pos);
+    if new_line == line && new_pos == pos {
+        return Cmd::Repaint;
+    }
+    match new_line
+        .get(..new_pos)
+        .and_then(|left| left.strip_prefix(before))
+    {
+        Some(inserted) if new_line[new_pos..] == *after => Cmd::Insert(1, inserted.to_string()),
+        Some("") => Cmd::Replace(Movement::EndOfBuffer, Some(new_line[new_pos..].to_string())),
+        _ => Cmd::Replace(Movement::WholeBuffer, Some(new_line.to_string())),
+    }
+}
+
+/// terminal_width returns the number of columns of the terminal on stdout, or 80 if
+/// it's not a terminal.
+pub(crate) fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        -1 => 80,
+        _ if size.ws_col == 0 => 80,
+        _ => size.ws_col as usize,
+    }
+}
+
+/// unix_time returns the number of seconds since the epoch.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// quote quotes `s` so that it's read back as a single word by the shell.
+/// Words that don't need quoting are returned as is.
+pub(crate) fn quote(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./,:=+@%".contains(c));
+    match plain {
+        true => s.to_string(),
+        false => format!("'{}'", s.replace('\'', r"'\''")),
     }
+}
+
+/// error_message returns the description of the error without the `(os error N)` suffix.
+pub(crate) fn error_message(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.rsplit_once(" (os error ") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    }
+}
+
+/// user_home_dir returns the home directory of the user from the password database,
+/// or of the current user if no name is given.
+pub(crate) fn user_home_dir(name: Option<&str>) -> Option<String> {
+    lookup_passwd(name, |passwd| passwd.pw_dir)
+}
+
+/// user_name returns the name of the current user from the password database.
+pub(crate) fn user_name() -> Option<String> {
+    lookup_passwd(None, |passwd| passwd.pw_name)
+}
+
+/// lookup_passwd looks up the password database entry of the user, or of the current
+/// user if no name is given, and returns the field picked by `field`.
+fn lookup_passwd<F>(name: Option<&str>, field: F) -> Option<String>
+where
+    F: Fn(&libc::passwd) -> *mut libc::c_char,
+{
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
 
-    Ok(())
-}
-
-pub(crate) fn append_to(appends: &[&str], buf: &[u8]) -> anyhow::Result<()> {
-    for a in appends {
-        match fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(a)
-        {
-            Ok(mut file) => {
-                let res = file.write_all(buf);
-                if res.is_err() {
-                    write_and_flush_str(
-                        &mut io::stderr(),
-                        &format!("failed to append to file {a}: {}", res.unwrap_err()),
-                    )?;
-                }
+    let found = match name {
+        Some(name) => {
+            let name = CString::new(name).ok()?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
             }
-            Err(e) => {
-                write_and_flush_str(&mut io::stderr(), &format!("failed to open file {a}: {e}"))?
+        }
+        None => unsafe {
+            libc::getpwuid_r(
+                libc::getuid(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        },
+    };
+    let value = field(&passwd);
+    if found != 0 || result.is_null() || value.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(value) };
+    Some(value.to_string_lossy().to_string())
+}
+
+/// hostname returns the name of the host.
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } < 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().to_string())
+}
+
+/// format_time formats the current local time with `strftime`.
+pub(crate) fn format_time(format: &str) -> String {
+    let format = match CString::new(format) {
+        Ok(format) => format,
+        Err(_) => return String::new(),
+    };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return String::new();
+    }
+
+    let mut buf = vec![0 as libc::c_char; 256];
+    let len = unsafe { libc::strftime(buf.as_mut_ptr(), buf.len(), format.as_ptr(), &tm) };
+    let bytes: Vec<u8> = buf[..len].iter().map(|c| *c as u8).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// pipe creates a pipe whose ends are closed on exec, returning the read and write ends.
+pub(crate) fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// dup_inheritable duplicates the fd to the lowest free one from `min`, which unlike
+/// those of [`pipe`] is inherited by executed programs.
+pub(crate) fn dup_inheritable(fd: RawFd, min: RawFd) -> io::Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD, min) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// has_dev_fd returns whether the system has `/dev/fd`, through which programs can
+/// open the fds they inherit by path.
+pub(crate) fn has_dev_fd() -> bool {
+    Path::new("/dev/fd").is_dir()
+}
+
+/// mkdtemp creates a directory in the temporary directory that only the user can
+/// access, with a unique name starting with `prefix`.
+pub(crate) fn mkdtemp(prefix: &str) -> io::Result<PathBuf> {
+    let template = env::temp_dir().join(format!("{prefix}XXXXXX"));
+    let mut template = CString::new(template.into_os_string().into_vec())?.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+/// mkfifo creates a named pipe that only the user can read and write.
+pub(crate) fn mkfifo(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mkfifo(path.as_ptr(), 0o600) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// fork forks the shell, returning 0 in the child and the child's pid in the parent.
+pub(crate) fn fork() -> io::Result<i32> {
+    // Flush and hold the std locks so that the child doesn't inherit buffered output
+    // or a lock held by another thread.
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+    stdout.flush()?;
+    stderr.flush()?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid),
+    }
+}
+
+/// wait_for waits for the child to terminate and returns its exit status,
+/// or 128 plus the signal number if it was killed by a signal.
+pub(crate) fn wait_for(pid: i32) -> io::Result<i32> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        Ok(128 + libc::WTERMSIG(status))
+    } else {
+        Ok(libc::WEXITSTATUS(status))
+    }
+}
+
+/// exit_child terminates a forked child without running the parent's exit handlers.
+pub(crate) fn exit_child(status: i32) -> ! {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    unsafe { libc::_exit(status) }
+}
+
+/// Writer that starts every line with a prefix, used to tell where in a sourced file
+/// an error comes from.
+pub(crate) struct PrefixedWriter<W: io::Write> {
+    inner: W,
+    prefix: String,
+    line_start: bool,
+}
+
+impl<W: io::Write> PrefixedWriter<W> {
+    pub(crate) fn new(inner: W, prefix: String) -> Self {
+        Self {
+            inner,
+            prefix,
+            line_start: true,
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for PrefixedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|b| *b == b'\n') {
+            if self.line_start {
+                self.inner.write_all(self.prefix.as_bytes())?;
             }
+            self.inner.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Original fds saved before redirecting them, which are restored once dropped.
+pub(crate) struct SavedFds(Vec<(i32, Option<OwnedFd>)>);
+
+impl SavedFds {
+    pub(crate) fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn save(&mut self, fd: i32) -> io::Result<()> {
+        if self.0.iter().any(|(f, _)| *f == fd) {
+            return Ok(());
+        }
+        // Buffered output must go to where it was meant to before the fd changes
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+
+        // Keep the copy above the fds used by redirections and close it on exec
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+        let copy = match copy {
+            -1 => None,
+            copy => Some(unsafe { OwnedFd::from_raw_fd(copy) }),
         };
+        self.0.push((fd, copy));
+        Ok(())
     }
 
-    Ok(())
+    /// redirect makes `fd` refer to the same file as `target`.
+    pub(crate) fn redirect(&mut self, fd: i32, target: RawFd) -> io::Result<()> {
+        self.save(fd)?;
+        if unsafe { libc::dup2(target, fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// keep makes the redirections permanent instead of restoring the original fds.
+    pub(crate) fn keep(mut self) {
+        self.0.clear();
+    }
+
+    /// close closes `fd` until restored.
+    pub(crate) fn close(&mut self, fd: i32) {
+        if self.save(fd).is_ok() {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+impl Drop for SavedFds {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        for (fd, copy) in self.0.drain(..).rev() {
+            match copy {
+                Some(copy) => unsafe { libc::dup2(copy.as_raw_fd(), fd) },
+                None => unsafe { libc::close(fd) },
+            };
+        }
+    }
+}
+
+/// A directory for the files of a test, which is removed with them once dropped, even
+/// if the test fails.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// new creates an empty directory whose name starts with `name` and is unique to
+    /// this call.
+    pub(crate) fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("{name}_{}_{count}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod util_test {
+    use rustyline::{Cmd, Movement};
+
+    use crate::util::edit_line;
+
+    #[test]
+    fn test_edit_line() {
+        assert_eq!(edit_line("ls", 1, "ls", 1), Cmd::Repaint);
+        assert_eq!(
+            edit_line("git ", 4, "git commit", 10),
+            Cmd::Insert(1, "commit".to_string())
+        );
+        assert_eq!(
+            edit_line("ls -l", 2, "ls é -l", 5),
+            Cmd::Insert(1, " é".to_string())
+        );
+        assert_eq!(
+            edit_line("ls -l", 2, "ls done", 2),
+            Cmd::Replace(Movement::EndOfBuffer, Some(" done".to_string()))
+        );
+        assert_eq!(
+            edit_line("ls", 2, "make", 4),
+            Cmd::Replace(Movement::WholeBuffer, Some("make".to_string()))
+        );
+        assert_eq!(
+            edit_line("ls -l", 2, "ls -l", 0),
+            Cmd::Replace(Movement::WholeBuffer, Some("ls -l".to_string()))
+        );
+    }
 }
@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, fmt};
+
+/// The value of a shell variable.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Scalar(String),
+    /// An indexed array, which can have gaps between its indices.
+    Indexed(BTreeMap<i64, String>),
+    /// An associative array. Its keys are kept sorted, as bash doesn't promise any order.
+    Associative(BTreeMap<String, String>),
+}
+
+impl Value {
+    /// indexed makes an indexed array of the values, from index 0.
+    pub(crate) fn indexed(values: Vec<String>) -> Self {
+        Self::Indexed((0..).zip(values).collect())
+    }
+
+    /// get returns the element with the key. A scalar is like an array with only the
+    /// index 0.
+    pub(crate) fn get(&self, key: &Key) -> Option<&str> {
+        match (self, key) {
+            (Self::Scalar(value), Key::Index(0)) => Some(value),
+            (Self::Scalar(_), _) => None,
+            (Self::Indexed(elements), Key::Index(idx)) => elements.get(idx).map(String::as_str),
+            (Self::Indexed(_), Key::Name(_)) => None,
+            (Self::Associative(elements), key) => {
+                elements.get(&key.to_string()).map(String::as_str)
+            }
+        }
+    }
+
+    /// set sets the element with the key, turning a scalar into an indexed array if
+    /// it's not the index 0.
+    pub(crate) fn set(&mut self, key: Key, value: String) {
+        match (self, key) {
+            (this @ Self::Scalar(_), Key::Index(0)) => *this = Self::Scalar(value),
+            (this @ Self::Scalar(_), key) => {
+                let current = this.scalar().unwrap_or_default().to_string();
+                *this = Self::indexed(vec![current]);
+                this.set(key, value);
+            }
+            (Self::Indexed(elements), Key::Index(idx)) => {
+                elements.insert(idx, value);
+            }
+            (Self::Indexed(_), Key::Name(_)) => (),
+            (Self::Associative(elements), key) => {
+                elements.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    /// remove removes the element with the key.
+    pub(crate) fn remove(&mut self, key: &Key) {
+        match self {
+            Self::Scalar(_) => (),
+            Self::Indexed(elements) => {
+                if let Key::Index(idx) = key {
+                    elements.remove(idx);
+                }
+            }
+            Self::Associative(elements) => {
+                elements.remove(&key.to_string());
+            }
+        }
+    }
+
+    /// next_index returns the index after the last element, where appended elements go
+    /// and negative indices count from.
+    pub(crate) fn next_index(&self) -> i64 {
+        match self {
+            Self::Indexed(elements) => elements.keys().next_back().map_or(0, |idx| idx + 1),
+            _ => 1,
+        }
+    }
+
+    /// scalar returns what `$name` expands to: the value of a scalar, or the element at
+    /// index or key 0 of an array.
+    pub(crate) fn scalar(&self) -> Option<&str> {
+        match self {
+            Self::Scalar(value) => Some(value),
+            _ => self.get(&Key::Index(0)),
+        }
+    }
+
+    /// elements returns the indices or keys with their values, in order. A scalar is
+    /// like an array with only the index 0.
+    pub(crate) fn elements(&self) -> Vec<(String, String)> {
+        match self {
+            Self::Scalar(value) => vec![("0".to_string(), value.clone())],
+            Self::Indexed(elements) => elements
+                .iter()
+                .map(|(idx, value)| (idx.to_string(), value.clone()))
+                .collect(),
+            Self::Associative(elements) => elements
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// values returns what `${name[@]}` expands to.
+    pub(crate) fn values(&self) -> Vec<String> {
+        self.elements()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// keys returns what `${!name[@]}` expands to.
+    pub(crate) fn keys(&self) -> Vec<String> {
+        self.elements().into_iter().map(|(key, _)| key).collect()
+    }
+}
+
+/// The index or key of an element of an array.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Key {
+    Index(i64),
+    Name(String),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(idx) => write!(f, "{idx}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Attributes of a variable, as set with `declare`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Attributes {
+    /// `-x`: passed on to the environment of commands, if it's a scalar.
+    pub(crate) exported: bool,
+    /// `-r`: can't be assigned or unset.
+    pub(crate) readonly: bool,
+    /// `-i`: assigned values are evaluated as arithmetic expressions.
+    pub(crate) integer: bool,
+    /// `-l`: assigned values are converted to lowercase.
+    pub(crate) lowercase: bool,
+    /// `-u`: assigned values are converted to uppercase.
+    pub(crate) uppercase: bool,
+    /// `-n`: the value is the name of another variable, which is used instead.
+    pub(crate) nameref: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Variable {
+    /// None if the variable was declared without being given a value.
+    pub(crate) value: Option<Value>,
+    pub(crate) attributes: Attributes,
+}
+
+impl Variable {
+    pub(crate) fn new(value: Value) -> Self {
+        Self {
+            value: Some(value),
+            attributes: Attributes::default(),
+        }
+    }
+
+    /// exported makes an exported scalar, like those from the environment.
+    pub(crate) fn exported(value: &str) -> Self {
+        let mut var = Self::new(Value::Scalar(value.to_string()));
+        var.attributes.exported = true;
+        var
+    }
+
+    /// flags returns the letters of the variable's type and attributes, in the order
+    /// `declare -p` shows them.
+    pub(crate) fn flags(&self) -> String {
+        let attributes = &self.attributes;
+        [
+            ('a', matches!(self.value, Some(Value::Indexed(_)))),
+            ('A', matches!(self.value, Some(Value::Associative(_)))),
+            ('i', attributes.integer),
+            ('n', attributes.nameref),
+            ('r', attributes.readonly),
+            ('x', attributes.exported),
+            ('l', attributes.lowercase),
+            ('u', attributes.uppercase),
+        ]
+        .into_iter()
+        .filter_map(|(flag, set)| set.then_some(flag))
+        .collect()
+    }
+
+    /// convert_case converts the value being assigned for `-l` and `-u`.
+    pub(crate) fn convert_case(&self, value: String) -> String {
+        match (self.attributes.lowercase, self.attributes.uppercase) {
+            (true, _) => value.to_lowercase(),
+            (_, true) => value.to_uppercase(),
+            _ => value,
+        }
+    }
+}
+
+/// declaration returns the `declare` command that recreates the variable, as printed by
+/// `declare -p`.
+pub(crate) fn declaration(name: &str, var: &Variable) -> String {
+    let flags = match var.flags() {
+        flags if flags.is_empty() => "-".to_string(),
+        flags => flags,
+    };
+    match &var.value {
+        Some(value) => format!("declare -{flags} {name}={}", format_value(value)),
+        None => format!("declare -{flags} {name}"),
+    }
+}
+
+/// format_value formats the value the way it's assigned, with arrays as
+/// `([index]="value" ...)`.
+pub(crate) fn format_value(value: &Value) -> String {
+    let elements = |elements: Vec<(String, String)>| {
+        elements
+            .iter()
+            .map(|(key, value)| {
+                let plain = key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@+".contains(c));
+                match plain {
+                    true => format!("[{key}]={}", double_quote(value)),
+                    false => format!("[{}]={}", double_quote(key), double_quote(value)),
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    match value {
+        Value::Scalar(value) => double_quote(value),
+        Value::Indexed(_) => format!("({})", elements(value.elements()).join(" ")),
+        // Like bash, with a space before the parenthesis
+        Value::Associative(_) => format!("({} )", elements(value.elements()).join(" ")),
+    }
+}
+
+/// double_quote quotes the string in double quotes, escaping what is special in them.
+fn double_quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for ch in s.chars() {
+        if matches!(ch, '"' | '$' | '\\' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod variable_test {
+    use std::collections::BTreeMap;
+
+    use crate::variable::{declaration, Attributes, Value, Variable};
+
+    #[test]
+    fn test_value() {
+        let indexed = Value::Indexed(BTreeMap::from([(1, "b".into()), (0, "a".into())]));
+        assert_eq!(indexed.scalar(), Some("a"));
+        assert_eq!(indexed.values(), vec!["a", "b"]);
+        let map = Value::Associative(BTreeMap::from([("k".into(), "v".into())]));
+        assert_eq!(map.scalar(), None);
+        assert_eq!(map.keys(), vec!["k"]);
+        assert_eq!(Value::Scalar("s".into()).keys(), vec!["0"]);
+    }
+
+    #[test]
+    fn test_declaration() {
+        let mut var = Variable::new(Value::indexed(vec!["x".into(), "y \"z\"".into()]));
+        var.attributes.integer = true;
+        assert_eq!(
+            declaration("a", &var),
+            r#"declare -ai a=([0]="x" [1]="y \"z\"")"#
+        );
+
+        let map = Value::Associative(BTreeMap::from([
+            ("k".into(), "$v".into()),
+            ("a b".into(), String::new()),
+        ]));
+        assert_eq!(
+            declaration("m", &Variable::new(map)),
+            r#"declare -A m=(["a b"]="" [k]="\$v" )"#
+        );
+
+        let var = Variable {
+            value: None,
+            attributes: Attributes {
+                readonly: true,
+                lowercase: true,
+                ..Attributes::default()
+            },
+        };
+        assert_eq!(declaration("l", &var), "declare -rl l");
+        assert_eq!(
+            declaration("s", &Variable::exported("a b")),
+            r#"declare -x s="a b""#
+        );
+        assert_eq!(
+            declaration("s", &Variable::new(Value::Scalar(String::new()))),
+            r#"declare -- s="""#
+        );
+    }
+}